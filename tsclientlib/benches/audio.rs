@@ -0,0 +1,129 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use audiopus::coder::Encoder;
+use audiopus::{Application, Channels, SampleRate};
+use criterion::{criterion_group, criterion_main, Criterion};
+use slog::{o, Discard, Logger};
+use tsclientlib::audio::AudioHandler;
+use tsproto_packets::packets::{AudioData, CodecType, Direction, InAudioBuf, OutAudio};
+
+const TALKERS: u16 = 50;
+const FRAME_SIZE: usize = 48_000 / 50;
+
+/// Counts all allocations, including reallocations.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { System.dealloc(ptr, layout) }
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+		System.realloc(ptr, layout, new_size)
+	}
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Encoded packets of a sine wave, the same for all talkers.
+fn create_packets(count: u16) -> Vec<Vec<u8>> {
+	let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Mono, Application::Voip).unwrap();
+	let mut opus_output = [0; 1275];
+	(0..count)
+		.map(|id| {
+			let data = (0..FRAME_SIZE)
+				.map(|i| ((usize::from(id) * FRAME_SIZE + i) as f32 / 10.0).sin() / 2.0)
+				.collect::<Vec<_>>();
+			let len = encoder.encode_float(&data, &mut opus_output[..]).unwrap();
+			OutAudio::new(&AudioData::S2C {
+				id,
+				codec: CodecType::OpusVoice,
+				from: 0,
+				data: &opus_output[..len],
+			})
+			.into_vec()
+		})
+		.collect()
+}
+
+/// Receive one packet per talker and fill one buffer.
+///
+/// With `reuse`, the packets are received into the buffers of played packets
+/// like a connection does when they are handed back, otherwise every packet
+/// gets a new buffer.
+///
+/// Returns the number of allocations while receiving and playing the packets.
+fn step(handler: &mut AudioHandler<u16>, packet: &[u8], buf: &mut [f32], reuse: bool) -> usize {
+	let before = ALLOCATIONS.load(Ordering::Relaxed);
+	for talker in 0..TALKERS {
+		let data = match handler.take_buffer().filter(|_| reuse) {
+			Some(mut data) => {
+				data.clear();
+				data.extend_from_slice(packet);
+				data
+			}
+			None => packet.to_vec(),
+		};
+		let packet = InAudioBuf::try_new(Direction::S2C, data).unwrap();
+		handler.handle_packet(talker, packet).unwrap();
+	}
+	handler.drain_talker_events();
+	for b in buf.iter_mut() {
+		*b = 0.0;
+	}
+	handler.fill_buffer(buf);
+	ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn fill_buffer(c: &mut Criterion) {
+	let packets = create_packets(500);
+	let mut buf = vec![0.0; FRAME_SIZE * 2];
+	let name = |reuse| if reuse { "reused buffers" } else { "new buffers" };
+
+	// Report allocations and a checksum of the output, which has to be the
+	// same with and without reusing buffers.
+	for &reuse in &[false, true] {
+		let mut handler = AudioHandler::<u16>::new(Logger::root(Discard, o!()));
+		let mut allocations = 0;
+		let mut checksum = 0.0;
+		for p in &packets {
+			allocations += step(&mut handler, p, &mut buf, reuse);
+			checksum += buf.iter().map(|s| f64::from(*s).abs()).sum::<f64>();
+		}
+		println!(
+			"{} talkers, {}: {:.2} allocations per filled buffer, output checksum {:.3}",
+			TALKERS,
+			name(reuse),
+			allocations as f64 / packets.len() as f64,
+			checksum
+		);
+	}
+
+	let mut group = c.benchmark_group(format!("fill buffer {} talkers", TALKERS));
+	for &reuse in &[false, true] {
+		group.bench_function(name(reuse), |b| {
+			let mut handler = AudioHandler::<u16>::new(Logger::root(Discard, o!()));
+			let mut packets = packets.iter().enumerate().cycle();
+			b.iter(|| {
+				let (i, p) = packets.next().unwrap();
+				if i == 0 {
+					// Start again with the first packet id
+					handler.reset();
+				}
+				step(&mut handler, p, &mut buf, reuse)
+			})
+		});
+	}
+	group.finish();
+}
+
+criterion_group!(benches, fill_buffer);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+use std::env;
+
+fn main() {
+	// Collect information for `build_info`
+	let mut features = env::vars()
+		.filter_map(|(k, _)| {
+			if k.starts_with("CARGO_FEATURE_") {
+				Some(k["CARGO_FEATURE_".len()..].to_lowercase().replace('_', "-"))
+			} else {
+				None
+			}
+		})
+		.collect::<Vec<_>>();
+	features.sort();
+
+	println!("cargo:rustc-env=TSCLIENTLIB_FEATURES={}", features.join(","));
+	println!("cargo:rustc-env=TSCLIENTLIB_TARGET={}", env::var("TARGET").unwrap());
+	println!("cargo:rustc-env=TSCLIENTLIB_PROFILE={}", env::var("PROFILE").unwrap());
+}
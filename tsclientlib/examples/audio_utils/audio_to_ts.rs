@@ -1,22 +1,22 @@
 use std::sync::{Arc, Mutex};
 
 use anyhow::{format_err, Result};
-use audiopus::coder::Encoder;
 use futures::prelude::*;
-use sdl2::audio::{AudioCallback, AudioDevice, AudioSpec, AudioSpecDesired, AudioStatus};
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired, AudioStatus};
 use sdl2::AudioSubsystem;
 use slog::{debug, error, o, Logger};
 use tokio::sync::mpsc;
 use tokio::task::LocalSet;
 use tokio::time::{self, Duration};
-use tsproto_packets::packets::{AudioData, CodecType, OutAudio, OutPacket};
+use tsclientlib::audio::{self, AudioSender, Channels, SampleRate};
+use tsproto_packets::packets::OutPacket;
 
 use super::*;
 
 pub struct AudioToTs {
 	logger: Logger,
 	audio_subsystem: AudioSubsystem,
-	listener: Arc<Mutex<Option<mpsc::Sender<OutPacket>>>>,
+	sender: Arc<Mutex<Option<AudioSender>>>,
 	device: AudioDevice<SdlCallback>,
 
 	is_playing: bool,
@@ -25,12 +25,8 @@ pub struct AudioToTs {
 
 struct SdlCallback {
 	logger: Logger,
-	spec: AudioSpec,
-	encoder: Encoder,
-	listener: Arc<Mutex<Option<mpsc::Sender<OutPacket>>>>,
+	sender: Arc<Mutex<Option<AudioSender>>>,
 	volume: Arc<Mutex<f32>>,
-
-	opus_output: [u8; MAX_OPUS_FRAME_SIZE],
 }
 
 impl AudioToTs {
@@ -38,16 +34,16 @@ impl AudioToTs {
 		logger: Logger, audio_subsystem: AudioSubsystem, local_set: &LocalSet,
 	) -> Result<Arc<Mutex<Self>>> {
 		let logger = logger.new(o!("pipeline" => "audio-to-ts"));
-		let listener = Arc::new(Mutex::new(Default::default()));
+		let sender = Arc::new(Mutex::new(Default::default()));
 		let volume = Arc::new(Mutex::new(1.0));
 
 		let device =
-			Self::open_capture(logger.clone(), &audio_subsystem, listener.clone(), volume.clone())?;
+			Self::open_capture(logger.clone(), &audio_subsystem, sender.clone(), volume.clone())?;
 
 		let res = Arc::new(Mutex::new(Self {
 			logger,
 			audio_subsystem,
-			listener,
+			sender,
 			device,
 
 			is_playing: false,
@@ -61,7 +57,7 @@ impl AudioToTs {
 
 	fn open_capture(
 		logger: Logger, audio_subsystem: &AudioSubsystem,
-		listener: Arc<Mutex<Option<mpsc::Sender<OutPacket>>>>, volume: Arc<Mutex<f32>>,
+		sender: Arc<Mutex<Option<AudioSender>>>, volume: Arc<Mutex<f32>>,
 	) -> Result<AudioDevice<SdlCallback>>
 	{
 		let desired_spec = AudioSpecDesired {
@@ -75,31 +71,15 @@ impl AudioToTs {
 			// This spec will always be the desired spec, the sdl wrapper passes
 			// zero as `allowed_changes`.
 			debug!(logger, "Got capture spec"; "spec" => ?spec, "driver" => audio_subsystem.current_audio_driver());
-			let opus_channels = if spec.channels == 1 {
-				audiopus::Channels::Mono
-			} else {
-				audiopus::Channels::Stereo
-			};
-
-			let encoder = Encoder::new(audiopus::SampleRate::Hz48000,
-				opus_channels, audiopus::Application::Voip)
-				.expect("Could not create encoder");
-
-			SdlCallback {
-				logger,
-				spec,
-				encoder,
-				listener,
-				volume,
-
-				opus_output: [0; MAX_OPUS_FRAME_SIZE],
-			}
+			SdlCallback { logger, sender, volume }
 		}).map_err(|e| format_err!("SDL error: {}", e))
 	}
 
-	pub fn set_listener(&self, sender: mpsc::Sender<OutPacket>) {
-		let mut listener = self.listener.lock().unwrap();
-		*listener = Some(sender);
+	/// Send the captured audio into this channel.
+	pub fn set_listener(&self, sink: mpsc::Sender<OutPacket>) -> Result<()> {
+		let sender = AudioSender::new(SampleRate::Hz48000, Channels::Mono, sink)?;
+		*self.sender.lock().unwrap() = Some(sender);
+		Ok(())
 	}
 
 	pub fn set_volume(&mut self, volume: f32) { *self.volume.lock().unwrap() = volume; }
@@ -109,6 +89,11 @@ impl AudioToTs {
 			self.device.resume();
 		} else {
 			self.device.pause();
+			if let Some(sender) = &mut *self.sender.lock().unwrap() {
+				if let Err(e) = sender.stop_talking() {
+					debug!(self.logger, "Failed to send end of audio"; "error" => %e);
+				}
+			}
 		}
 		self.is_playing = playing;
 	}
@@ -121,7 +106,7 @@ impl AudioToTs {
 				match Self::open_capture(
 					a2t.logger.clone(),
 					&a2t.audio_subsystem,
-					a2t.listener.clone(),
+					a2t.sender.clone(),
 					a2t.volume.clone(),
 				) {
 					Ok(d) => {
@@ -152,28 +137,12 @@ impl AudioCallback for SdlCallback {
 			}
 		}
 
-		match self.encoder.encode_float(buffer, &mut self.opus_output[..]) {
-			Err(e) => {
-				error!(self.logger, "Failed to encode opus"; "error" => %e);
-			}
-			Ok(len) => {
-				// Create packet
-				let codec = if self.spec.channels == 1 {
-					CodecType::OpusVoice
-				} else {
-					CodecType::OpusMusic
-				};
-				let packet =
-					OutAudio::new(&AudioData::C2S { id: 0, codec, data: &self.opus_output[..len] });
-
-				// Write into packet sink
-				let mut listener = self.listener.lock().unwrap();
-				if let Some(lis) = &mut *listener {
-					match lis.try_send(packet) {
-						Err(mpsc::error::TrySendError::Closed(_)) => *listener = None,
-						_ => {}
-					}
-				}
+		let mut sender = self.sender.lock().unwrap();
+		if let Some(s) = &mut *sender {
+			match s.send_frame(buffer) {
+				Err(audio::Error::SinkClosed) => *sender = None,
+				Err(e) => error!(self.logger, "Failed to send audio"; "error" => %e),
+				Ok(_) => {}
 			}
 		}
 	}
@@ -16,9 +16,6 @@ pub mod ts_to_audio;
 /// This means 1920 samples and 7.5 kiB.
 const USUAL_FRAME_SIZE: usize = 48000 / 50;
 
-/// The maximum size of an opus frame is 1275 as from RFC6716.
-const MAX_OPUS_FRAME_SIZE: usize = 1275;
-
 #[derive(Clone)]
 pub struct AudioData {
 	pub a2ts: Arc<Mutex<AudioToTs>>,
@@ -4,7 +4,8 @@ use slog::{error, o, Drain, Logger};
 use structopt::StructOpt;
 use tokio::time::{self, Duration};
 
-use tsclientlib::{ConnectOptions, Connection, DisconnectOptions, Identity, StreamItem};
+use tsclientlib::manager::ConnectionManager;
+use tsclientlib::{ConnectOptions, DisconnectOptions, Identity};
 
 #[derive(StructOpt, Debug)]
 #[structopt(author, about)]
@@ -53,42 +54,32 @@ async fn real_main() -> Result<()> {
 		C/50CIA8M5nmDBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap();
 	let con_config = con_config.identity(id);
 
-	stream::iter(0..args.count)
-		.for_each_concurrent(None, |_| {
-			let con_config = con_config.clone();
-			let logger = logger.clone();
-			tokio::spawn(async move {
-				// Connect
-				let mut con = Connection::new(con_config).unwrap();
+	let mut manager = ConnectionManager::new();
+	for _ in 0..args.count {
+		manager.add_connection(con_config.clone())?;
+	}
 
-				let r = con
-					.events()
-					.try_filter(|e| future::ready(matches!(e, StreamItem::ConEvents(_))))
-					.next()
-					.await;
-				if let Some(Err(e)) = r {
-					error!(logger, "Connection failed"; "error" => %e);
-					return;
+	// Stay connected for some time
+	let mut deadline = time::delay_for(Duration::from_secs(15));
+	loop {
+		tokio::select! {
+			_ = &mut deadline => break,
+			item = manager.next() => {
+				if let Some((id, Err(e))) = item {
+					error!(logger, "Connection failed"; "id" => id.0, "error" => %e);
 				}
+			}
+		}
+	}
 
-				// Wait some time
-				let mut events = con.events().try_filter(|_| future::ready(false));
-				tokio::select! {
-					_ = &mut time::delay_for(Duration::from_secs(15)) => {}
-					_ = events.next() => {
-						error!(logger, "Disconnected unexpectedly");
-						return;
-					}
-				};
-				drop(events);
-
-				// Disconnect
-				let _ = con.disconnect(DisconnectOptions::new());
-				con.events().for_each(|_| future::ready(())).await;
-			})
-			.map(|_| ())
-		})
-		.await;
+	// Disconnect
+	let ids = manager.connections().map(|(id, _)| id).collect::<Vec<_>>();
+	for id in ids {
+		manager.remove_connection(id, DisconnectOptions::new())?;
+	}
+	while !manager.is_empty() {
+		manager.next().await;
+	}
 
 	Ok(())
 }
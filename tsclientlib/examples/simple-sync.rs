@@ -1,5 +1,4 @@
 use anyhow::Result;
-use futures::prelude::*;
 use structopt::StructOpt;
 use tokio::time::{self, Duration};
 
@@ -44,10 +43,8 @@ async fn real_main() -> Result<()> {
 	// Connect
 	let con = Connection::new(con_config)?;
 	let con: SyncConnection = con.into();
-	let mut handle = con.get_handle();
-
 	// Do event handling in another thread
-	tokio::spawn(con.for_each(|_| future::ready(())));
+	let (mut handle, running) = con.spawn();
 
 	handle.wait_until_connected().await?;
 
@@ -61,6 +58,7 @@ async fn real_main() -> Result<()> {
 
 	// Disconnect
 	handle.disconnect(DisconnectOptions::new()).await?;
+	running.await??;
 
 	Ok(())
 }
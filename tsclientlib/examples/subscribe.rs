@@ -0,0 +1,81 @@
+use anyhow::Result;
+use structopt::StructOpt;
+use tokio::sync::broadcast::RecvError;
+use tokio::time::{self, Duration};
+
+use tsclientlib::events::{Event, PropertyId};
+use tsclientlib::sync::SyncConnection;
+use tsclientlib::{ConnectOptions, Connection, DisconnectOptions};
+
+#[derive(StructOpt, Debug)]
+#[structopt(author, about)]
+struct Args {
+	/// The address of the server to connect to
+	#[structopt(short = "a", long, default_value = "localhost")]
+	address: String,
+	/// How long to stay on the server, in seconds
+	#[structopt(short = "t", long, default_value = "30")]
+	time: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> { real_main().await }
+
+async fn real_main() -> Result<()> {
+	// Parse command line options
+	let args = Args::from_args();
+
+	let con = Connection::new(ConnectOptions::new(args.address))?;
+	let con: SyncConnection = con.into();
+	// Drive the connection, the events are consumed by the subscribers
+	let (mut handle, running) = con.spawn();
+
+	// Every task gets all events
+	let mut chat = handle.subscribe_events();
+	let mut joins = handle.subscribe_events();
+
+	// Print chat messages
+	tokio::spawn(async move {
+		loop {
+			match chat.recv().await {
+				Ok(events) => {
+					for e in events.iter() {
+						if let Event::Message { target, invoker, message } = e {
+							println!("{:?} {}: {}", target, invoker.name, message);
+						}
+					}
+				}
+				Err(RecvError::Lagged(n)) => println!("Missed {} chat events", n),
+				Err(RecvError::Closed) => break,
+			}
+		}
+	});
+
+	// Count clients
+	tokio::spawn(async move {
+		let mut count = 0;
+		loop {
+			match joins.recv().await {
+				Ok(events) => {
+					for e in events.iter() {
+						if let Event::PropertyAdded { id: PropertyId::Client(_), .. } = e {
+							count += 1;
+							println!("Seen {} clients", count);
+						}
+					}
+				}
+				Err(RecvError::Lagged(_)) => {}
+				Err(RecvError::Closed) => break,
+			}
+		}
+	});
+
+	handle.wait_until_connected().await?;
+	time::delay_for(Duration::from_secs(args.time)).await;
+
+	// Disconnect
+	handle.disconnect(DisconnectOptions::new()).await?;
+	running.await??;
+
+	Ok(())
+}
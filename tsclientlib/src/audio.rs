@@ -5,42 +5,49 @@
 //! packets. It automatically adjusts the queue length based on the jitter of
 //! incoming packets.
 //!
+//! The [`AudioSender`] encodes audio and creates packets to send it to the
+//! server.
+//!
 //! [`AudioHandler`]: struct.AudioHandler.html
+//! [`AudioSender`]: struct.AudioSender.html
 
 use std::cmp::Reverse;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::time::{Duration, Instant};
 
-use audiopus::coder::Decoder;
-use audiopus::{packet, Channels, SampleRate};
+use audiopus::coder::{Decoder, Encoder};
+use audiopus::{packet, Application, Bitrate};
+pub use audiopus::{Channels, SampleRate};
+use num_traits::{FromPrimitive, ToPrimitive};
 use slog::{debug, o, trace, warn, Logger};
 use thiserror::Error;
-use tsproto_packets::packets::{AudioData, CodecType, InAudioBuf};
+use tokio::sync::mpsc;
+use tsproto_packets::packets::{AudioData, CodecType, InAudioBuf, OutAudio, OutPacket};
 
-use crate::ClientId;
+use crate::{ClientId, Codec};
 
-const SAMPLE_RATE: SampleRate = SampleRate::Hz48000;
-const CHANNELS: Channels = Channels::Stereo;
-const CHANNEL_NUM: usize = 2;
+/// The default sample rate of the output.
+pub const DEFAULT_SAMPLE_RATE: SampleRate = SampleRate::Hz48000;
+/// The default channels of the output.
+pub const DEFAULT_CHANNELS: Channels = Channels::Stereo;
 /// If this amount of packets is lost consecutively, we assume the stream stopped.
 const MAX_PACKET_LOSSES: usize = 3;
 /// Store the buffer sizes for the last `LAST_BUFFER_SIZE_COUNT` packets.
 const LAST_BUFFER_SIZE_COUNT: u8 = 255;
-/// The amount of samples to maximally buffer. Equivalent to 0.5 s.
-const MAX_BUFFER_SIZE: usize = 48_000 / 2;
 /// Maximum number of packets in the queue.
 const MAX_BUFFER_PACKETS: usize = 50;
-/// Buffer for maximal 0.5 s without playing anything.
-const MAX_BUFFER_TIME: usize = 48_000 / 2;
+/// Keep at most this many buffers of played packets for reuse.
+const MAX_POOLED_BUFFERS: usize = 64;
 /// Duplicate or remove every `step` sample when speeding-up.
 const SPEED_CHANGE_STEPS: usize = 100;
-/// The usual amount of samples in a frame.
-///
-/// Use 48 kHz, 20 ms frames (50 per second) and mono data (1 channel).
-/// This means 1920 samples and 7.5 kiB.
-const USUAL_FRAME_SIZE: usize = 48000 / 50;
+/// The maximum size of an opus frame is 1275 as from RFC6716.
+const MAX_OPUS_FRAME_SIZE: usize = 1275;
+/// The default length of the fade-in when a stream starts and the fade-out when
+/// it ends.
+pub const DEFAULT_FADE: Duration = Duration::from_millis(5);
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -48,6 +55,8 @@ type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
 	#[error("Failed to create opus decoder: {0}")]
 	CreateDecoder(#[source] audiopus::Error),
+	#[error("Failed to create opus encoder: {0}")]
+	CreateEncoder(#[source] audiopus::Error),
 	#[error("Opus decode failed: {error} (packet: {packet:?})")]
 	Decode {
 		#[source]
@@ -56,16 +65,33 @@ pub enum Error {
 	},
 	#[error("Get duplicate packet id {0}")]
 	Duplicate(u16),
+	#[error("Opus encode failed: {0}")]
+	Encode(#[source] audiopus::Error),
+	#[error("Failed to change opus encoder setting: {0}")]
+	EncoderSetting(#[source] audiopus::Error),
 	#[error("Failed to get packet samples: {0}")]
 	GetPacketSample(#[source] audiopus::Error),
 	#[error("Audio queue is full, dropping")]
 	QueueFull,
+	#[error("The receiver of audio packets does not exist anymore")]
+	SinkClosed,
 	#[error("Audio packet is too late, dropping (wanted {wanted}, got {got})")]
 	TooLate { wanted: u16, got: u16 },
 	#[error("Packet has too many samples")]
 	TooManySamples,
-	#[error("Only opus audio is supported, ignoring {0:?}")]
-	UnsupportedCodec(CodecType),
+}
+
+/// The codec of audio packets, which corresponds to a channel codec.
+pub fn codec_type(codec: Codec) -> Option<CodecType> { CodecType::from_u8(codec.to_u8()?) }
+
+/// The channel codec, which corresponds to a codec of audio packets.
+pub fn channel_codec(codec: CodecType) -> Option<Codec> { Codec::from_u8(codec.to_u8()?) }
+
+/// The sample rate and channels of decoded audio.
+#[derive(Clone, Copy, Debug)]
+struct Format {
+	sample_rate: SampleRate,
+	channels: Channels,
 }
 
 #[derive(Clone, Debug)]
@@ -86,6 +112,17 @@ struct SlidingWindowMinimum<T: Copy + Default + Ord> {
 	cur_time: u8,
 }
 
+/// A ring buffer of decoded samples.
+///
+/// Every sample is stored twice, at `i` and `i + capacity`, so the buffered
+/// samples are always available as one contiguous slice.
+#[derive(Debug)]
+struct SampleRing {
+	data: Vec<f32>,
+	start: usize,
+	len: usize,
+}
+
 #[derive(Debug)]
 struct QueuePacket {
 	packet: InAudioBuf,
@@ -93,11 +130,53 @@ struct QueuePacket {
 	id: u16,
 }
 
+/// A change of the talking clients.
+///
+/// See [`AudioHandler::drain_talker_events`].
+///
+/// [`AudioHandler::drain_talker_events`]: struct.AudioHandler.html#method.drain_talker_events
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TalkerEvent<Id> {
+	/// A client started talking or changed between whispering and talking
+	/// normally.
+	Started { id: Id, whispering: bool },
+	/// A client stopped talking.
+	Stopped { id: Id },
+	/// A client started talking with a codec that cannot be decoded.
+	///
+	/// The packets of this client are dropped until it stops talking.
+	UnsupportedCodec { id: Id, codec: CodecType },
+}
+
+/// Statistics about the network quality of an [`AudioQueue`].
+///
+/// [`AudioQueue`]: struct.AudioQueue.html
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct AudioQueueStats {
+	/// Packets which were added to the queue.
+	pub packets_received: u64,
+	/// Packets which did not arrive in time for playing them.
+	pub packets_lost: u64,
+	/// Packets which arrived after they should have been played, they are
+	/// dropped.
+	pub packets_late: u64,
+	/// Lost packets which were restored with forward error correction.
+	pub fec_decodes: u64,
+	/// The amount of samples per channel in the queue.
+	pub buffer_samples: usize,
+	/// How often the queue was empty when audio should be played.
+	pub underruns: u64,
+}
+
 /// A queue for audio packets for one audio stream.
 pub struct AudioQueue {
 	logger: Logger,
+	format: Format,
 	decoder: Decoder,
 	pub volume: f32,
+	/// The position in the stereo output, from `-1` (left) to `1` (right).
+	pub pan: f32,
 	/// The id of the next packet that should be decoded.
 	///
 	/// Used to check for packet loss.
@@ -107,9 +186,15 @@ pub struct AudioQueue {
 	packet_buffer: VecDeque<QueuePacket>,
 	/// Amount of samples in the `packet_buffer`.
 	packet_buffer_samples: usize,
-	/// Temporary buffer that contains the samples of one decoded packet.
-	decoded_buffer: Vec<f32>,
-	/// The current position in the `decoded_buffer`.
+	/// The samples of the last decoded packet.
+	///
+	/// The first `frame_len` samples are valid.
+	frame: Vec<f32>,
+	frame_len: usize,
+	/// Decoded samples which were not yet played.
+	decoded_buffer: SampleRing,
+	/// The number of samples in the `decoded_buffer` which were returned by the
+	/// last call to `get_next_data`.
 	decoded_pos: usize,
 	/// The number of samples in the last packet.
 	last_packet_samples: usize,
@@ -125,6 +210,51 @@ pub struct AudioQueue {
 	last_buffer_size_max: SlidingWindowMinimum<Reverse<u8>>,
 	/// Buffered for this duration.
 	buffered_for_samples: usize,
+	/// Start playing at this sample offset in the next output buffer.
+	///
+	/// Unset when the stream started playing.
+	start_offset: Option<usize>,
+	/// The number of samples that were already played with a fade-in.
+	faded_in: usize,
+	/// Packets of muted queues are dropped without decoding them.
+	muted: bool,
+	/// The number of samples since the last packet arrived while muted.
+	muted_samples: usize,
+	/// Set when the packet for the end of the stream arrived.
+	ended: bool,
+	stats: AudioQueueStats,
+	/// The buffers of packets which are not needed anymore, they are collected
+	/// by the `AudioHandler`.
+	played: Vec<Vec<u8>>,
+}
+
+/// Encodes audio and writes the packets into a channel.
+///
+/// The packets should be sent with [`Connection::send_audio`].
+///
+/// # Example
+///
+/// ```no_run
+/// # use tsclientlib::audio::{AudioSender, Channels, SampleRate};
+/// # let frame = [0.0; 960];
+/// let (send, mut recv) = tokio::sync::mpsc::channel(5);
+/// let mut sender = AudioSender::new(SampleRate::Hz48000, Channels::Mono, send).unwrap();
+/// // Send 20 ms of audio
+/// sender.send_frame(&frame).unwrap();
+/// sender.stop_talking().unwrap();
+/// // Forward the packets from recv to the connection
+/// ```
+///
+/// [`Connection::send_audio`]: ../struct.Connection.html#method.send_audio
+pub struct AudioSender {
+	encoder: Encoder,
+	codec: CodecType,
+	/// The id of the next packet.
+	next_id: u16,
+	/// If packets were sent since the last end of the stream.
+	talking: bool,
+	sink: mpsc::Sender<OutPacket>,
+	opus_output: [u8; MAX_OPUS_FRAME_SIZE],
 }
 
 /// Handles incoming audio, has one [`AudioQueue`] per sending client.
@@ -132,11 +262,120 @@ pub struct AudioQueue {
 /// [`AudioQueue`]: struct.AudioQueue.html
 pub struct AudioHandler<Id: Clone + Debug + Eq + Hash + PartialEq = ClientId> {
 	logger: Logger,
+	format: Format,
 	queues: HashMap<Id, AudioQueue>,
+	/// Volumes which were set for clients.
+	///
+	/// They are kept when a queue is removed, so they apply again when the
+	/// client starts talking the next time.
+	volumes: HashMap<Id, f32>,
+	/// This factor is applied to all queues.
+	master_volume: f32,
+	/// Stereo positions of clients, kept like `volumes`.
+	pans: HashMap<Id, f32>,
+	/// Clients which are muted locally, kept like `volumes`.
+	muted: HashSet<Id>,
+	/// Talker changes until they are fetched with `drain_talker_events`.
+	talker_events: Vec<TalkerEvent<Id>>,
+	/// Clients which talk with an unsupported codec and the number of samples
+	/// since their last packet.
+	unsupported: HashMap<Id, usize>,
 	/// Buffer this amount of samples for new queues before starting to play.
 	///
 	/// Updated when a new queue gets added.
 	avg_buffer_samples: usize,
+	/// The length of fades in samples.
+	fade_samples: usize,
+	/// The time and length in samples of the last filled buffer.
+	last_fill: Option<(Instant, usize)>,
+	/// Mixing buffer for `fill_buffer_i16`, kept to avoid allocations.
+	i16_mix: Vec<f32>,
+	/// Buffers of played packets until they are fetched with `take_buffer`.
+	buffer_pool: Vec<Vec<u8>>,
+}
+
+impl Format {
+	/// Samples per second and channel.
+	fn rate(&self) -> usize { self.sample_rate as i32 as usize }
+
+	fn channel_num(&self) -> usize { if self.channels == Channels::Mono { 1 } else { 2 } }
+
+	/// The usual amount of samples in a frame.
+	///
+	/// Opus uses 20 ms frames (50 per second), for one channel at 48 kHz this
+	/// means 960 samples.
+	fn usual_frame_size(&self) -> usize { self.rate() / 50 }
+
+	/// The amount of samples to maximally buffer. Equivalent to 0.5 s.
+	fn max_buffer_size(&self) -> usize { self.rate() / 2 }
+
+	/// Buffer for maximal 0.5 s without playing anything.
+	fn max_buffer_time(&self) -> usize { self.rate() / 2 }
+
+	/// An opus packet contains at most 120 ms of audio.
+	fn max_frame_size(&self) -> usize { self.rate() * 3 / 25 }
+
+	/// The number of samples per channel for this duration.
+	fn duration_to_samples(&self, duration: Duration) -> usize {
+		(duration.as_micros() * self.rate() as u128 / 1_000_000) as usize
+	}
+}
+
+impl SampleRing {
+	fn new(capacity: usize) -> Self { Self { data: vec![0.0; capacity * 2], start: 0, len: 0 } }
+
+	fn capacity(&self) -> usize { self.data.len() / 2 }
+	fn len(&self) -> usize { self.len }
+	fn as_slice(&self) -> &[f32] { &self.data[self.start..self.start + self.len] }
+
+	fn clear(&mut self) {
+		self.start = 0;
+		self.len = 0;
+	}
+
+	/// Remove `len` samples from the front.
+	fn consume(&mut self, len: usize) {
+		debug_assert!(len <= self.len);
+		self.start = (self.start + len) % self.capacity();
+		self.len -= len;
+	}
+
+	/// Append samples at the back.
+	///
+	/// Only allocates if the samples do not fit into the capacity.
+	fn push(&mut self, samples: &[f32]) {
+		if self.len + samples.len() > self.capacity() {
+			self.grow(self.len + samples.len());
+		}
+		let cap = self.capacity();
+		let pos = (self.start + self.len) % cap;
+		let (first, rest) = samples.split_at(samples.len().min(cap - pos));
+		self.data[pos..pos + first.len()].copy_from_slice(first);
+		self.data[cap + pos..cap + pos + first.len()].copy_from_slice(first);
+		self.data[..rest.len()].copy_from_slice(rest);
+		self.data[cap..cap + rest.len()].copy_from_slice(rest);
+		self.len += samples.len();
+	}
+
+	/// Append samples and skip the first of every `SPEED_CHANGE_STEPS` frames
+	/// to speed-up playback.
+	fn push_speed_up(&mut self, samples: &[f32], channels: usize) {
+		let step = SPEED_CHANGE_STEPS * channels;
+		let mut chunks = samples.chunks_exact(step);
+		for chunk in &mut chunks {
+			self.push(&chunk[channels..]);
+		}
+		self.push(chunks.remainder());
+	}
+
+	fn grow(&mut self, min_capacity: usize) {
+		let cap = min_capacity.max(self.capacity() * 2);
+		let mut data = vec![0.0; cap * 2];
+		data[..self.len].copy_from_slice(self.as_slice());
+		data[cap..cap + self.len].copy_from_slice(self.as_slice());
+		self.data = data;
+		self.start = 0;
+	}
 }
 
 impl<T: Copy + Default + Ord> SlidingWindowMinimum<T> {
@@ -163,24 +402,29 @@ impl<T: Copy + Default + Ord> SlidingWindowMinimum<T> {
 }
 
 impl AudioQueue {
-	fn new(logger: Logger, packet: InAudioBuf) -> Result<Self> {
+	fn new(logger: Logger, format: Format, packet: InAudioBuf) -> Result<Self> {
 		let data = packet.data().data();
-		let last_packet_samples =
-			packet::nb_samples(data.data(), SAMPLE_RATE).map_err(Error::GetPacketSample)?;
-		if last_packet_samples > MAX_BUFFER_SIZE {
+		let last_packet_samples = packet::nb_samples(data.data(), format.sample_rate)
+			.map_err(Error::GetPacketSample)?;
+		if last_packet_samples > format.max_buffer_size() {
 			return Err(Error::TooManySamples);
 		}
 
-		let last_packet_samples = last_packet_samples * CHANNEL_NUM;
+		let last_packet_samples = last_packet_samples * format.channel_num();
 		let mut res = Self {
 			logger,
-			decoder: Decoder::new(SAMPLE_RATE, CHANNELS).map_err(Error::CreateDecoder)?,
+			format,
+			decoder: Decoder::new(format.sample_rate, format.channels)
+				.map_err(Error::CreateDecoder)?,
 			volume: 1.0,
+			pan: 0.0,
 			next_id: data.id(),
-			whispering: false,
-			packet_buffer: Default::default(),
+			whispering: matches!(data, AudioData::S2CWhisper { .. }),
+			packet_buffer: VecDeque::with_capacity(MAX_BUFFER_PACKETS + 1),
 			packet_buffer_samples: 0,
-			decoded_buffer: Default::default(),
+			frame: vec![0.0; format.max_frame_size() * format.channel_num()],
+			frame_len: 0,
+			decoded_buffer: SampleRing::new(format.max_buffer_size() * format.channel_num()),
 			decoded_pos: 0,
 			last_packet_samples,
 			packet_loss_num: 0,
@@ -188,6 +432,13 @@ impl AudioQueue {
 			last_buffer_size_min: SlidingWindowMinimum::new(LAST_BUFFER_SIZE_COUNT),
 			last_buffer_size_max: SlidingWindowMinimum::<Reverse<u8>>::new(LAST_BUFFER_SIZE_COUNT),
 			buffered_for_samples: 0,
+			start_offset: None,
+			faded_in: 0,
+			muted: false,
+			muted_samples: 0,
+			ended: false,
+			stats: Default::default(),
+			played: Vec::with_capacity(MAX_BUFFER_PACKETS + 1),
 		};
 		res.add_buffer_size(0);
 		res.add_packet(packet)?;
@@ -196,10 +447,41 @@ impl AudioQueue {
 
 	pub fn get_decoder(&self) -> &Decoder { &self.decoder }
 	pub fn is_whispering(&self) -> bool { self.whispering }
+	/// If the client is talking but muted locally.
+	pub fn is_muted(&self) -> bool { self.muted }
+
+	pub fn stats(&self) -> AudioQueueStats {
+		AudioQueueStats { buffer_samples: self.packet_buffer_samples, ..self.stats }
+	}
+
+	/// Drop all buffered audio, afterwards the queue only tracks if the client
+	/// is still talking.
+	fn mute(&mut self) {
+		self.muted = true;
+		self.played.extend(self.packet_buffer.drain(..).map(|p| p.packet.into_buffer()));
+		self.packet_buffer_samples = 0;
+		self.decoded_buffer.clear();
+		self.decoded_pos = 0;
+		self.packet_loss_num = 0;
+		self.muted_samples = 0;
+	}
+
+	/// A packet arrived for a muted queue.
+	fn add_muted_packet(&mut self, packet: &InAudioBuf) {
+		if packet.data().data().data().len() <= 1 {
+			// End of stream, remove the queue with the next fill
+			self.packet_loss_num = MAX_PACKET_LOSSES;
+			self.ended = true;
+		} else {
+			self.whispering = matches!(packet.data().data(), AudioData::S2CWhisper { .. });
+			self.packet_loss_num = 0;
+			self.muted_samples = 0;
+		}
+	}
 
 	/// Size is in samples.
 	fn add_buffer_size(&mut self, size: usize) {
-		if let Ok(size) = (size / USUAL_FRAME_SIZE).try_into() {
+		if let Ok(size) = (size / self.format.usual_frame_size()).try_into() {
 			self.last_buffer_size_min.push(size);
 			self.last_buffer_size_max.push(Reverse(size));
 		} else {
@@ -224,9 +506,9 @@ impl AudioQueue {
 			// End of stream
 			samples = 0;
 		} else {
-			samples = packet::nb_samples(packet.data().data().data(), SAMPLE_RATE)
+			samples = packet::nb_samples(packet.data().data().data(), self.format.sample_rate)
 				.map_err(Error::GetPacketSample)?;
-			if samples > MAX_BUFFER_SIZE {
+			if samples > self.format.max_buffer_size() {
 				return Err(Error::TooManySamples);
 			}
 		}
@@ -234,6 +516,7 @@ impl AudioQueue {
 		let id = packet.data().data().id();
 		let packet = QueuePacket { packet, samples, id };
 		if id.wrapping_sub(self.next_id) > MAX_BUFFER_PACKETS as u16 {
+			self.stats.packets_late += 1;
 			return Err(Error::TooLate { wanted: self.next_id, got: id });
 		}
 
@@ -264,7 +547,11 @@ impl AudioQueue {
 		}
 
 		self.packet_buffer_samples += packet.samples;
+		if packet.packet.data().data().data().len() <= 1 {
+			self.ended = true;
+		}
 		self.packet_buffer.insert(i, packet);
+		self.stats.packets_received += 1;
 
 		Ok(())
 	}
@@ -283,18 +570,23 @@ impl AudioQueue {
 			len = self.last_packet_samples;
 		}
 		self.packet_loss_num += 1;
+		if fec {
+			self.stats.fec_decodes += 1;
+		}
 
-		self.decoded_buffer.resize(self.decoded_pos + len * CHANNEL_NUM, 0.0);
+		let channels = self.format.channel_num();
+		if len * channels > self.frame.len() {
+			self.frame.resize(len * channels, 0.0);
+		}
 		let len = self
 			.decoder
-			.decode_float(packet_data, &mut self.decoded_buffer[self.decoded_pos..], fec)
+			.decode_float(packet_data, &mut self.frame[..len * channels], fec)
 			.map_err(|e| Error::Decode {
 				error: e,
 				packet: packet.map(|p| p.packet.raw_data().to_vec()),
 			})?;
 		self.last_packet_samples = len;
-		self.decoded_buffer.truncate(self.decoded_pos + len * CHANNEL_NUM);
-		self.decoded_pos += len * CHANNEL_NUM;
+		self.frame_len = len * channels;
 
 		// Update packet_loss_num
 		if packet.is_some() && !fec {
@@ -324,7 +616,7 @@ impl AudioQueue {
 	/// `false` when it continues normally.
 	pub fn get_next_data(&mut self, len: usize) -> Result<(&[f32], bool)> {
 		if self.buffering_samples > 0 {
-			if self.buffered_for_samples >= MAX_BUFFER_TIME {
+			if self.buffered_for_samples >= self.format.max_buffer_time() {
 				self.buffering_samples = 0;
 				self.buffered_for_samples = 0;
 				trace!(self.logger, "Buffered for too long";
@@ -338,16 +630,9 @@ impl AudioQueue {
 				return Ok((&[], false));
 			}
 		}
-		// Need to refill buffer
-		if self.decoded_pos < self.decoded_buffer.len() {
-			if self.decoded_pos > 0 {
-				self.decoded_buffer.drain(..self.decoded_pos);
-				self.decoded_pos = 0;
-			}
-		} else {
-			self.decoded_buffer.clear();
-			self.decoded_pos = 0;
-		}
+		// Remove the samples which were returned last time
+		self.decoded_buffer.consume(self.decoded_pos);
+		self.decoded_pos = 0;
 
 		while self.decoded_buffer.len() < len {
 			trace!(self.logger, "get_next_data";
@@ -360,7 +645,7 @@ impl AudioQueue {
 			if let Some(packet) = self.packet_buffer.pop_front() {
 				if packet.packet.data().data().data().len() <= 1 {
 					// End of stream
-					return Ok((&self.decoded_buffer, true));
+					return Ok((self.decoded_buffer.as_slice(), true));
 				}
 
 				self.packet_buffer_samples -= packet.samples;
@@ -376,6 +661,7 @@ impl AudioQueue {
 					// Packet loss
 					debug!(self.logger, "Audio packet loss"; "need" => cur_id,
 						"have" => packet.id);
+					self.stats.packets_lost += 1;
 					if packet.id == self.next_id {
 						// Can use forward-error-correction
 						self.decode_packet(Some(&packet), true)?;
@@ -386,9 +672,11 @@ impl AudioQueue {
 					self.packet_buffer.push_front(packet);
 				} else {
 					self.decode_packet(Some(&packet), false)?;
+					self.played.push(packet.packet.into_buffer());
 				}
 			} else {
 				debug!(self.logger, "No packets in queue");
+				self.stats.underruns += 1;
 				// Packet loss or end of stream
 				self.decode_packet(None, false)?;
 			}
@@ -397,10 +685,13 @@ impl AudioQueue {
 				break;
 			}
 
+			let frame = &self.frame[..self.frame_len];
+
 			// Check if we should speed-up playback
 			let min = self.last_buffer_size_min.get_min();
 			let dev = self.get_deviation();
-			if min > (MAX_BUFFER_SIZE / USUAL_FRAME_SIZE) as u8 {
+			let frame_size = self.format.usual_frame_size();
+			if min > (self.format.max_buffer_size() / frame_size) as u8 {
 				debug!(self.logger, "Truncating buffer"; "min" => min);
 				// Throw out all but min samples
 				let mut keep_samples = 0;
@@ -410,50 +701,220 @@ impl AudioQueue {
 					.rev()
 					.take_while(|p| {
 						keep_samples += p.samples;
-						keep_samples < usize::from(min) + USUAL_FRAME_SIZE
+						keep_samples < usize::from(min) + frame_size
 					})
 					.count();
 				let len = self.packet_buffer.len() - keep;
-				self.packet_buffer.drain(..len);
+				self.played.extend(self.packet_buffer.drain(..len).map(|p| p.packet.into_buffer()));
 				self.packet_buffer_samples = self.packet_buffer.iter().map(|p| p.samples).sum();
 				if let Some(p) = self.packet_buffer.front() {
 					self.next_id = p.id;
 				}
+				self.decoded_buffer.push(frame);
 			} else if min > dev {
 				// Speed-up
 				debug!(self.logger, "Speed-up buffer"; "min" => min,
 					"cur_packet_count" => self.packet_buffer.len(),
 					"last_packet_samples" => self.last_packet_samples,
 					"dev" => dev);
-				let start = self.decoded_buffer.len() - self.last_packet_samples * CHANNEL_NUM;
-				for i in 0..(self.last_packet_samples / SPEED_CHANGE_STEPS) {
-					let i = start + i * (SPEED_CHANGE_STEPS - 1) * CHANNEL_NUM;
-					self.decoded_buffer.drain(i..(i + CHANNEL_NUM));
-				}
+				self.decoded_buffer.push_speed_up(frame, self.format.channel_num());
+			} else {
+				self.decoded_buffer.push(frame);
 			}
 		}
 
-		self.decoded_pos = len;
-		Ok((&self.decoded_buffer[..len], false))
+		self.decoded_pos = len.min(self.decoded_buffer.len());
+		Ok((&self.decoded_buffer.as_slice()[..self.decoded_pos], false))
 	}
 }
 
 impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
+	/// Create a handler which outputs 48 kHz stereo audio.
 	pub fn new(logger: Logger) -> Self {
-		Self { logger, queues: Default::default(), avg_buffer_samples: 0 }
+		Self::with_format(logger, DEFAULT_SAMPLE_RATE, DEFAULT_CHANNELS)
+	}
+
+	/// Create a handler which outputs audio with this sample rate and these
+	/// channels.
+	///
+	/// Opus decodes directly to all supported sample rates. `Channels::Auto`
+	/// outputs stereo audio. The buffers passed to [`fill_buffer`] contain
+	/// interleaved samples for all channels.
+	///
+	/// [`fill_buffer`]: #method.fill_buffer
+	pub fn with_format(logger: Logger, sample_rate: SampleRate, channels: Channels) -> Self {
+		let channels = if channels == Channels::Mono { channels } else { Channels::Stereo };
+		let format = Format { sample_rate, channels };
+		Self {
+			logger,
+			format,
+			queues: Default::default(),
+			volumes: Default::default(),
+			master_volume: 1.0,
+			pans: Default::default(),
+			muted: Default::default(),
+			talker_events: Vec::new(),
+			unsupported: Default::default(),
+			avg_buffer_samples: 0,
+			fade_samples: format.duration_to_samples(DEFAULT_FADE),
+			last_fill: None,
+			i16_mix: Vec::new(),
+			buffer_pool: Vec::with_capacity(MAX_POOLED_BUFFERS),
+		}
+	}
+
+	/// Fade streams in and out over this duration.
+	///
+	/// A duration of zero disables fading.
+	///
+	/// # Default
+	/// [`DEFAULT_FADE`](constant.DEFAULT_FADE.html)
+	pub fn set_fade(&mut self, fade: Duration) {
+		self.fade_samples = self.format.duration_to_samples(fade);
+	}
+
+	pub fn get_sample_rate(&self) -> SampleRate { self.format.sample_rate }
+	pub fn get_channels(&self) -> Channels { self.format.channels }
+
+	/// Set the volume for a client, it is kept when the client stops talking.
+	///
+	/// The volume is a factor for the samples, values above `1.0` amplify the
+	/// audio. The mixed output is clamped to `[-1, 1]`.
+	///
+	/// # Default
+	/// `1.0`
+	pub fn set_volume(&mut self, id: Id, volume: f32) {
+		if let Some(queue) = self.queues.get_mut(&id) {
+			queue.volume = volume;
+		}
+		self.volumes.insert(id, volume);
+	}
+
+	pub fn get_volume(&self, id: &Id) -> f32 { self.volumes.get(id).copied().unwrap_or(1.0) }
+
+	/// Set the volume for all clients, it is multiplied with the volume of each
+	/// client.
+	///
+	/// # Default
+	/// `1.0`
+	pub fn set_master_volume(&mut self, volume: f32) { self.master_volume = volume; }
+	pub fn get_master_volume(&self) -> f32 { self.master_volume }
+
+	/// Set the position of a client in the stereo output, from `-1` (left) to
+	/// `1` (right).
+	///
+	/// Uses constant-power panning, a centered client is as loud as before.
+	/// Panning has no effect for mono output.
+	///
+	/// # Default
+	/// `0.0`
+	pub fn set_pan(&mut self, id: Id, pan: f32) {
+		let pan = pan.max(-1.0).min(1.0);
+		if let Some(queue) = self.queues.get_mut(&id) {
+			queue.pan = pan;
+		}
+		self.pans.insert(id, pan);
+	}
+
+	pub fn get_pan(&self, id: &Id) -> f32 { self.pans.get(id).copied().unwrap_or_default() }
+
+	/// Spread the current talkers evenly from left to right, sorted by their
+	/// id.
+	pub fn auto_pan_by_client_order(&mut self)
+	where Id: Ord {
+		let mut ids = self.queues.keys().cloned().collect::<Vec<_>>();
+		ids.sort();
+		let count = ids.len();
+		for (i, id) in ids.into_iter().enumerate() {
+			// Keep some distance to the edges, a single talker is centered
+			let pan = (i + 1) as f32 / (count + 1) as f32 * 2.0 - 1.0;
+			self.set_pan(id, pan);
+		}
+	}
+
+	/// Mute a client locally, its packets are dropped without decoding them.
+	///
+	/// Muted clients which are talking still have a queue, so they are part of
+	/// [`get_queues`]. When a client is unmuted while talking, playback starts
+	/// with the next packet.
+	///
+	/// [`get_queues`]: #method.get_queues
+	pub fn set_muted(&mut self, id: Id, muted: bool) {
+		if muted {
+			if let Some(queue) = self.queues.get_mut(&id) {
+				queue.mute();
+				recycle(&mut self.buffer_pool, &mut queue.played);
+			}
+			self.muted.insert(id);
+		} else {
+			self.muted.remove(&id);
+		}
 	}
 
+	pub fn is_muted(&self, id: &Id) -> bool { self.muted.contains(id) }
+
 	/// Delete all queues
-	pub fn reset(&mut self) { self.queues.clear(); }
+	pub fn reset(&mut self) {
+		self.unsupported.clear();
+		for (id, queue) in self.queues.drain() {
+			if !queue.ended {
+				self.talker_events.push(TalkerEvent::Stopped { id });
+			}
+		}
+	}
+
+	/// Get the clients which started or stopped talking since the last call.
+	///
+	/// Events are collected until they are fetched, so this should be called
+	/// regularly, e.g. after filling a buffer.
+	pub fn drain_talker_events(&mut self) -> Vec<TalkerEvent<Id>> {
+		std::mem::take(&mut self.talker_events)
+	}
 
 	pub fn get_queues(&self) -> &HashMap<Id, AudioQueue> { &self.queues }
+
+	/// The statistics of all current queues.
+	pub fn stats(&self) -> HashMap<Id, AudioQueueStats> {
+		self.queues.iter().map(|(id, q)| (id.clone(), q.stats())).collect()
+	}
+
 	pub fn get_mut_queues(&mut self) -> &mut HashMap<Id, AudioQueue> { &mut self.queues }
 
+	/// Get the buffer of a played packet.
+	///
+	/// The buffer can be handed back to the connection with
+	/// [`Connection::hand_back_buffer`], which receives the next packet into
+	/// it instead of allocating a new buffer.
+	///
+	/// [`Connection::hand_back_buffer`]: ../struct.Connection.html#method.hand_back_buffer
+	pub fn take_buffer(&mut self) -> Option<Vec<u8>> { self.buffer_pool.pop() }
+
 	/// `buf` is not cleared before filling it.
 	///
 	/// Returns the clients that are not talking anymore.
 	pub fn fill_buffer(&mut self, buf: &mut [f32]) -> Vec<Id> {
+		self.fill_buffer_at(buf, Instant::now())
+	}
+
+	/// Same as [`fill_buffer`], but takes the current time of the playback
+	/// clock.
+	///
+	/// Streams that were started with [`handle_packet_at`] are placed at the
+	/// offset in `buf`, which corresponds to the time between the previous call
+	/// and the arrival of their first packet.
+	///
+	/// [`fill_buffer`]: #method.fill_buffer
+	/// [`handle_packet_at`]: #method.handle_packet_at
+	pub fn fill_buffer_at(&mut self, buf: &mut [f32], now: Instant) -> Vec<Id> {
 		trace!(self.logger, "Filling audio buffer"; "len" => buf.len());
+		let channels = self.format.channel_num();
+		// Forget clients with an unsupported codec, which sent no end packet
+		let max_samples = MAX_PACKET_LOSSES * self.format.usual_frame_size();
+		for samples in self.unsupported.values_mut() {
+			*samples += buf.len() / channels;
+		}
+		self.unsupported.retain(|_, samples| *samples < max_samples);
+
 		let mut to_remove = Vec::new();
 		for (id, queue) in self.queues.iter_mut() {
 			if queue.packet_loss_num >= MAX_PACKET_LOSSES {
@@ -462,65 +923,337 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 				to_remove.push(id.clone());
 				continue;
 			}
+			if queue.muted {
+				// Nothing is decoded, so count missing packets here
+				queue.muted_samples += buf.len() / channels;
+				queue.packet_loss_num = queue.muted_samples / self.format.usual_frame_size();
+				continue;
+			}
+
+			let offset = queue.start_offset.unwrap_or_default() * channels;
+			if offset >= buf.len() {
+				// Starts in a later buffer
+				queue.start_offset = Some((offset - buf.len()) / channels);
+				continue;
+			}
 
-			let vol = queue.volume;
-			match queue.get_next_data(buf.len()) {
+			let vol = queue.volume * self.master_volume;
+			let gains = if channels == 2 {
+				let [left, right] = pan_gains(queue.pan);
+				[vol * left, vol * right]
+			} else {
+				[vol, vol]
+			};
+			let mut faded_in = queue.faded_in;
+			let whispering = queue.whispering;
+			match queue.get_next_data(buf.len() - offset) {
 				Err(e) => {
 					warn!(self.logger, "Failed to decode audio packet";
 						"error" => %e);
 				}
 				Ok((r, is_end)) => {
-					for i in 0..r.len() {
-						buf[i] += r[i] * vol;
+					let started = !r.is_empty();
+					let fade = self.fade_samples;
+					mix(&mut buf[offset..], r, channels, gains, fade, &mut faded_in, is_end);
+					if started {
+						queue.start_offset = None;
+						queue.faded_in = faded_in;
 					}
 					if is_end {
 						to_remove.push(id.clone());
 					}
 				}
 			}
+			recycle(&mut self.buffer_pool, &mut queue.played);
+			if queue.whispering != whispering {
+				let whispering = queue.whispering;
+				self.talker_events.push(TalkerEvent::Started { id: id.clone(), whispering });
+			}
 		}
 
 		for id in &to_remove {
-			self.queues.remove(&id);
+			if let Some(queue) = self.queues.remove(&id) {
+				if !queue.ended {
+					self.talker_events.push(TalkerEvent::Stopped { id: id.clone() });
+				}
+			}
+		}
+		if !self.queues.is_empty() || !to_remove.is_empty() {
+			// Amplified streams may exceed the valid range
+			for b in buf.iter_mut() {
+				*b = b.max(-1.0).min(1.0);
+			}
 		}
+		self.last_fill = Some((now, buf.len() / channels));
 		to_remove
 	}
 
+	/// Same as [`fill_buffer`], but for 16 bit samples.
+	///
+	/// The audio is mixed like for [`fill_buffer`] and added with saturation
+	/// to the existing content of `buf`.
+	///
+	/// [`fill_buffer`]: #method.fill_buffer
+	pub fn fill_buffer_i16(&mut self, buf: &mut [i16]) -> Vec<Id> {
+		self.fill_buffer_i16_at(buf, Instant::now())
+	}
+
+	/// Same as [`fill_buffer_i16`], but takes the current time of the playback
+	/// clock like [`fill_buffer_at`].
+	///
+	/// [`fill_buffer_i16`]: #method.fill_buffer_i16
+	/// [`fill_buffer_at`]: #method.fill_buffer_at
+	pub fn fill_buffer_i16_at(&mut self, buf: &mut [i16], now: Instant) -> Vec<Id> {
+		let mut mix = std::mem::take(&mut self.i16_mix);
+		mix.clear();
+		mix.resize(buf.len(), 0.0);
+		let res = self.fill_buffer_at(&mut mix, now);
+		for (b, m) in buf.iter_mut().zip(&mix) {
+			*b = b.saturating_add(f32_to_i16(*m));
+		}
+		self.i16_mix = mix;
+		res
+	}
+
 	/// Add a packet to the audio queue.
 	///
 	/// If a new client started talking, returns the id of this client.
 	pub fn handle_packet(&mut self, id: Id, packet: InAudioBuf) -> Result<Option<Id>> {
+		self.handle_packet_inner(id, packet, None)
+	}
+
+	/// Same as [`handle_packet`], but takes the arrival time of the packet.
+	///
+	/// If this packet starts a new stream, the stream starts playing at the
+	/// corresponding offset in the output buffer. For details, see
+	/// [`fill_buffer_at`].
+	///
+	/// [`handle_packet`]: #method.handle_packet
+	/// [`fill_buffer_at`]: #method.fill_buffer_at
+	pub fn handle_packet_at(
+		&mut self, id: Id, packet: InAudioBuf, arrival: Instant,
+	) -> Result<Option<Id>> {
+		self.handle_packet_inner(id, packet, Some(arrival))
+	}
+
+	fn handle_packet_inner(
+		&mut self, id: Id, packet: InAudioBuf, arrival: Option<Instant>,
+	) -> Result<Option<Id>> {
 		let empty = packet.data().data().data().len() <= 1;
 		let codec = packet.data().data().codec();
 		if codec != CodecType::OpusMusic && codec != CodecType::OpusVoice {
-			return Err(Error::UnsupportedCodec(codec));
+			if empty {
+				self.unsupported.remove(&id);
+			} else if let Some(samples) = self.unsupported.get_mut(&id) {
+				*samples = 0;
+			} else {
+				debug!(self.logger, "Ignoring talker with unsupported codec";
+					"client" => ?id, "codec" => ?codec);
+				self.talker_events.push(TalkerEvent::UnsupportedCodec { id: id.clone(), codec });
+				self.unsupported.insert(id, 0);
+			}
+			return Ok(None);
 		}
+		self.unsupported.remove(&id);
 
+		let muted = self.muted.contains(&id);
+		let mut restart = false;
 		if let Some(queue) = self.queues.get_mut(&id) {
-			queue.add_packet(packet)?;
-			Ok(None)
-		} else {
-			if empty {
+			let whispering = queue.whispering;
+			if !queue.muted || muted || empty {
+				if queue.muted {
+					queue.add_muted_packet(&packet);
+				} else {
+					queue.add_packet(packet)?;
+				}
+				if empty {
+					self.talker_events.push(TalkerEvent::Stopped { id });
+				} else if queue.whispering != whispering {
+					let whispering = queue.whispering;
+					self.talker_events.push(TalkerEvent::Started { id, whispering });
+				}
 				return Ok(None);
 			}
+			// Unmuted while talking, start again with a new decoder
+			self.queues.remove(&id);
+			restart = true;
+		} else if empty {
+			return Ok(None);
+		}
 
-			trace!(self.logger, "Adding talker");
-			let mut queue =
-				AudioQueue::new(self.logger.new(o!("client" => format!("{:?}", id))), packet)?;
-			if !self.queues.is_empty() {
-				// Update avg_buffer_samples
-				self.avg_buffer_samples = USUAL_FRAME_SIZE
-					+ self
-						.queues
-						.values()
-						.map(|q| usize::from(q.last_buffer_size_min.get_min()))
-						.sum::<usize>() / self.queues.len();
-			}
-			queue.buffering_samples = self.avg_buffer_samples;
-			self.queues.insert(id.clone(), queue);
-			Ok(Some(id))
+		trace!(self.logger, "Adding talker"; "muted" => muted);
+		let logger = self.logger.new(o!("client" => format!("{:?}", id)));
+		let mut queue = AudioQueue::new(logger, self.format, packet)?;
+		if muted {
+			queue.mute();
+		}
+		if !self.queues.is_empty() {
+			// Update avg_buffer_samples
+			self.avg_buffer_samples = self.format.usual_frame_size()
+				+ self
+					.queues
+					.values()
+					.map(|q| usize::from(q.last_buffer_size_min.get_min()))
+					.sum::<usize>() / self.queues.len();
+		}
+		queue.buffering_samples = self.avg_buffer_samples;
+		queue.volume = self.get_volume(&id);
+		queue.pan = self.get_pan(&id);
+		if let (Some(arrival), Some((last_fill, len))) = (arrival, self.last_fill) {
+			// Start at most one buffer later
+			let offset =
+				self.format.duration_to_samples(arrival.saturating_duration_since(last_fill));
+			queue.start_offset = Some(offset.min(len));
+		}
+		if !restart {
+			let whispering = queue.whispering;
+			self.talker_events.push(TalkerEvent::Started { id: id.clone(), whispering });
+		}
+		self.queues.insert(id.clone(), queue);
+		Ok(if restart { None } else { Some(id) })
+	}
+}
+
+impl AudioSender {
+	/// Create a sender for audio with this sample rate and these channels.
+	///
+	/// Mono audio is sent with the `OpusVoice` codec, stereo audio with
+	/// `OpusMusic`.
+	pub fn new(
+		sample_rate: SampleRate, channels: Channels, sink: mpsc::Sender<OutPacket>,
+	) -> Result<Self> {
+		let (codec, application) = if channels == Channels::Mono {
+			(CodecType::OpusVoice, Application::Voip)
+		} else {
+			(CodecType::OpusMusic, Application::Audio)
+		};
+		let encoder =
+			Encoder::new(sample_rate, channels, application).map_err(Error::CreateEncoder)?;
+		Ok(Self {
+			encoder,
+			codec,
+			next_id: 0,
+			talking: false,
+			sink,
+			opus_output: [0; MAX_OPUS_FRAME_SIZE],
+		})
+	}
+
+	pub fn get_encoder(&self) -> &Encoder { &self.encoder }
+
+	/// Set the bitrate of the encoder in bits per second.
+	pub fn set_bitrate(&mut self, bitrate: i32) -> Result<()> {
+		self.encoder.set_bitrate(Bitrate::BitsPerSecond(bitrate)).map_err(Error::EncoderSetting)
+	}
+
+	/// Use a variable bitrate.
+	///
+	/// # Default
+	/// `true`
+	pub fn set_vbr(&mut self, vbr: bool) -> Result<()> {
+		self.encoder.set_vbr(vbr).map_err(Error::EncoderSetting)
+	}
+
+	/// Add redundant data to packets, so a lost packet can be restored from
+	/// the next one.
+	///
+	/// # Default
+	/// `false`
+	pub fn set_inband_fec(&mut self, fec: bool) -> Result<()> {
+		self.encoder.set_inband_fec(fec).map_err(Error::EncoderSetting)
+	}
+
+	/// Encode a frame of interleaved samples and send it.
+	///
+	/// A frame has to be 2.5, 5, 10, 20, 40 or 60 ms long.
+	///
+	/// Returns `false` if the packet was dropped because the channel is full.
+	pub fn send_frame(&mut self, frame: &[f32]) -> Result<bool> {
+		let len =
+			self.encoder.encode_float(frame, &mut self.opus_output[..]).map_err(Error::Encode)?;
+		self.talking = true;
+		self.send(len)
+	}
+
+	/// Send the end of the stream, so other clients know that we stopped
+	/// talking.
+	///
+	/// Does nothing if no frame was sent since the last end.
+	pub fn stop_talking(&mut self) -> Result<bool> {
+		if !self.talking {
+			return Ok(true);
 		}
+		self.talking = false;
+		self.send(0)
 	}
+
+	/// Send the first `len` bytes of the encoded output.
+	fn send(&mut self, len: usize) -> Result<bool> {
+		let packet = OutAudio::new(&AudioData::C2S {
+			id: self.next_id,
+			codec: self.codec,
+			data: &self.opus_output[..len],
+		});
+		self.next_id = self.next_id.wrapping_add(1);
+		match self.sink.try_send(packet) {
+			Ok(()) => Ok(true),
+			Err(mpsc::error::TrySendError::Full(_)) => Ok(false),
+			Err(mpsc::error::TrySendError::Closed(_)) => Err(Error::SinkClosed),
+		}
+	}
+}
+
+/// Move the buffers of played packets into the pool, as long as it is not full.
+fn recycle(pool: &mut Vec<Vec<u8>>, played: &mut Vec<Vec<u8>>) {
+	let free = MAX_POOLED_BUFFERS - pool.len();
+	pool.extend(played.drain(..).take(free));
+}
+
+/// The gains of the left and right channel for a stereo position.
+///
+/// The power of both channels together stays the same for all positions.
+fn pan_gains(pan: f32) -> [f32; 2] {
+	if pan == 0.0 {
+		// Exact for centered streams
+		return [1.0, 1.0];
+	}
+	let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+	[angle.cos() * std::f32::consts::SQRT_2, angle.sin() * std::f32::consts::SQRT_2]
+}
+
+/// Convert a sample in the range [-1, 1] to 16 bit, values outside the range
+/// are clamped.
+fn f32_to_i16(sample: f32) -> i16 {
+	(sample * f32::from(i16::MAX)).round().max(f32::from(i16::MIN)).min(f32::from(i16::MAX))
+		as i16
+}
+
+/// Add the interleaved samples in `data` to `buf`.
+///
+/// `gains` contains the volume for each channel. The first `fade` samples of a
+/// stream are faded in, `faded_in` is the number of samples of this stream that
+/// were played before. If `fade_out` is set, the end of `data` is faded out.
+fn mix(
+	buf: &mut [f32], data: &[f32], channels: usize, gains: [f32; 2], fade: usize,
+	faded_in: &mut usize, fade_out: bool,
+) {
+	let len = data.len() / channels;
+	let fade_out_len = if fade_out { fade.min(len) } else { 0 };
+	let frames = buf.chunks_exact_mut(channels).zip(data.chunks_exact(channels));
+	for (i, (b, d)) in frames.enumerate() {
+		let mut gain = 1.0;
+		if *faded_in + i < fade {
+			gain *= (*faded_in + i) as f32 / fade as f32;
+		}
+		let remaining = len - 1 - i;
+		if remaining < fade_out_len {
+			gain *= remaining as f32 / fade_out_len as f32;
+		}
+		for ((b, d), g) in b.iter_mut().zip(d).zip(&gains) {
+			*b += d * gain * g;
+		}
+	}
+	*faded_in = (*faded_in + len).min(fade);
 }
 
 #[cfg(test)]
@@ -534,6 +1267,11 @@ mod test {
 
 	use super::*;
 
+	/// The number of channels of the default format.
+	const CHANNEL_NUM: usize = 2;
+	/// The usual amount of samples in a frame with the default format.
+	const USUAL_FRAME_SIZE: usize = 48_000 / 50;
+
 	enum SimulateAction {
 		CreateEncoder,
 		/// Create packet with id.
@@ -554,6 +1292,20 @@ mod test {
 		slog::Logger::root(drain, o!())
 	}
 
+	/// Encode a sine wave into a packet.
+	fn sine_packet(encoder: &mut Encoder, id: u16) -> Result<InAudioBuf> {
+		let mut opus_output = [0; 1275];
+		let data = (0..USUAL_FRAME_SIZE).map(|i| (i as f32 / 10.0).sin() / 2.0).collect::<Vec<_>>();
+		let len = encoder.encode_float(&data, &mut opus_output[..])?;
+		let packet = OutAudio::new(&AudioData::S2C {
+			id,
+			codec: CodecType::OpusMusic,
+			from: 0,
+			data: &opus_output[..len],
+		});
+		Ok(InAudioBuf::try_new(Direction::S2C, packet.into_vec()).unwrap())
+	}
+
 	fn check_packet(data: &[u8]) -> Result<()> {
 		let logger = create_logger();
 		let mut handler = AudioHandler::<ClientId>::new(logger);
@@ -746,7 +1498,7 @@ mod test {
 			audiopus::Channels::Mono,
 			audiopus::Application::Voip,
 		)?;
-		let mut decoder = Decoder::new(SAMPLE_RATE, CHANNELS)?;
+		let mut decoder = Decoder::new(DEFAULT_SAMPLE_RATE, DEFAULT_CHANNELS)?;
 		let data = vec![0.1234 as f32; USUAL_FRAME_SIZE];
 		let len = encoder.encode_float(&data, &mut opus_output[..])?;
 		let mut decoded_buffer = vec![0.0; USUAL_FRAME_SIZE * 2];
@@ -767,6 +1519,494 @@ mod test {
 		Ok(())
 	}*/
 
+	#[test]
+	fn fade_ramp() {
+		let data = vec![1.0; 10 * CHANNEL_NUM];
+		let mut buf = vec![0.0; 10 * CHANNEL_NUM];
+		let mut faded_in = 0;
+		let (first, second) = buf.split_at_mut(4 * CHANNEL_NUM);
+		mix(first, &data[..4 * CHANNEL_NUM], CHANNEL_NUM, [1.0; 2], 8, &mut faded_in, false);
+		assert_eq!(faded_in, 4);
+		// The fade-in continues in the next buffer
+		mix(second, &data[..6 * CHANNEL_NUM], CHANNEL_NUM, [1.0; 2], 8, &mut faded_in, false);
+		assert_eq!(faded_in, 8);
+		let expected = [0.0, 0.125, 0.25, 0.375, 0.5, 0.625, 0.75, 0.875, 1.0, 1.0];
+		for (i, e) in expected.iter().enumerate() {
+			assert_eq!(buf[i * CHANNEL_NUM], *e);
+			assert_eq!(buf[i * CHANNEL_NUM + 1], *e);
+		}
+
+		// Fade-out at the end of a stream
+		let mut buf = vec![0.0; 6 * CHANNEL_NUM];
+		mix(&mut buf, &data[..6 * CHANNEL_NUM], CHANNEL_NUM, [0.5; 2], 4, &mut faded_in, true);
+		let expected = [0.5, 0.5, 0.375, 0.25, 0.125, 0.0];
+		for (i, e) in expected.iter().enumerate() {
+			assert_eq!(buf[i * CHANNEL_NUM], *e);
+		}
+
+		// Fading can be disabled
+		let mut buf = vec![0.0; 4 * CHANNEL_NUM];
+		let mut faded_in = 0;
+		mix(&mut buf, &data[..4 * CHANNEL_NUM], CHANNEL_NUM, [1.0; 2], 0, &mut faded_in, true);
+		assert!(buf.iter().all(|b| *b == 1.0));
+	}
+
+	#[test]
+	fn pan() {
+		let data = vec![1.0; 4 * CHANNEL_NUM];
+		let mut buf = vec![0.0; 4 * CHANNEL_NUM];
+		mix(&mut buf, &data, CHANNEL_NUM, pan_gains(0.5), 0, &mut 0, false);
+		// tan(3/8 π)
+		assert!((buf[1] / buf[0] - 2.414_213_5).abs() < 1e-4, "{:?}", buf);
+		// The power stays the same
+		assert!((buf[0].powi(2) + buf[1].powi(2) - 2.0).abs() < 1e-4);
+		assert_eq!(pan_gains(0.0), [1.0, 1.0]);
+	}
+
+	#[test]
+	fn hard_pan() -> Result<()> {
+		let mut encoder = Encoder::new(
+			audiopus::SampleRate::Hz48000,
+			audiopus::Channels::Mono,
+			audiopus::Application::Audio,
+		)?;
+		let packets = (0..5).map(|i| sine_packet(&mut encoder, i)).collect::<Result<Vec<_>>>()?;
+		let play = |channels, pan| -> Result<Vec<f32>> {
+			let logger = create_logger();
+			let mut handler =
+				AudioHandler::<ClientId>::with_format(logger, SampleRate::Hz48000, channels);
+			let num = if channels == Channels::Mono { 1 } else { 2 };
+			handler.set_pan(ClientId(0), pan);
+			let mut out = Vec::new();
+			for p in &packets {
+				let p = InAudioBuf::try_new(Direction::S2C, p.raw_data().to_vec()).unwrap();
+				handler.handle_packet(ClientId(0), p)?;
+				let mut buf = vec![0.0; USUAL_FRAME_SIZE * num];
+				handler.fill_buffer(&mut buf);
+				out.extend(buf);
+			}
+			Ok(out)
+		};
+
+		let left = play(Channels::Stereo, -1.0)?;
+		assert!(left.chunks(2).all(|c| c[1] == 0.0));
+		assert!(left.chunks(2).any(|c| c[0] != 0.0));
+		let right = play(Channels::Stereo, 1.0)?;
+		assert!(right.chunks(2).all(|c| c[0].abs() < 1e-6));
+		// Panning does nothing for mono output
+		assert_eq!(play(Channels::Mono, -1.0)?, play(Channels::Mono, 0.0)?);
+		Ok(())
+	}
+
+	#[test]
+	fn join_mid_buffer() -> Result<()> {
+		let logger = create_logger();
+		let mut handler = AudioHandler::<ClientId>::new(logger);
+		let mut encoder = Encoder::new(
+			audiopus::SampleRate::Hz48000,
+			audiopus::Channels::Mono,
+			audiopus::Application::Audio,
+		)?;
+		let mut opus_output = [0; 1275];
+		let frames = USUAL_FRAME_SIZE;
+		let start = Instant::now();
+
+		let mut buf = vec![0.0; frames * CHANNEL_NUM];
+		handler.fill_buffer_at(&mut buf, start);
+
+		// The packet arrives 5 ms after the last buffer was filled
+		let data = (0..frames).map(|i| (i as f32 / 10.0).sin() / 2.0).collect::<Vec<_>>();
+		let len = encoder.encode_float(&data, &mut opus_output[..])?;
+		let packet = OutAudio::new(&AudioData::S2C {
+			id: 0,
+			codec: CodecType::OpusMusic,
+			from: 0,
+			data: &opus_output[..len],
+		});
+		let input = InAudioBuf::try_new(Direction::S2C, packet.into_vec()).unwrap();
+		let arrival = start + Duration::from_millis(5);
+		handler.handle_packet_at(ClientId(0), input, arrival)?;
+
+		let mut buf = vec![0.25; frames * CHANNEL_NUM];
+		handler.fill_buffer_at(&mut buf, start + Duration::from_millis(20));
+		let offset = 48 * 5 * CHANNEL_NUM;
+		// Samples before the start and the first sample of the fade are
+		// untouched.
+		assert!(buf[..offset + CHANNEL_NUM].iter().all(|b| *b == 0.25), "{:?}", &buf[..offset]);
+		assert!(buf[offset..].iter().any(|b| *b != 0.25));
+		assert_eq!(handler.queues[&ClientId(0)].start_offset, None);
+		Ok(())
+	}
+
+	#[test]
+	fn volume() -> Result<()> {
+		let logger = create_logger();
+		let mut handler = AudioHandler::<ClientId>::new(logger);
+		let id = ClientId(0);
+		handler.set_volume(id, 4.0);
+		assert_eq!(handler.get_volume(&id), 4.0);
+		assert_eq!(handler.get_volume(&ClientId(1)), 1.0);
+
+		let mut encoder = Encoder::new(
+			audiopus::SampleRate::Hz48000,
+			audiopus::Channels::Mono,
+			audiopus::Application::Audio,
+		)?;
+		let mut buf = vec![0.0; USUAL_FRAME_SIZE * CHANNEL_NUM];
+		for i in 0..5 {
+			handler.handle_packet(id, sine_packet(&mut encoder, i)?)?;
+			// The volume applies to the new queue
+			assert_eq!(handler.queues[&id].volume, 4.0);
+			buf.iter_mut().for_each(|b| *b = 0.0);
+			handler.fill_buffer(&mut buf);
+		}
+		// The amplified stream is clamped
+		assert!(buf.iter().all(|b| b.abs() <= 1.0));
+		assert!(buf.iter().any(|b| b.abs() == 1.0));
+
+		handler.set_master_volume(0.0);
+		buf.iter_mut().for_each(|b| *b = 0.0);
+		handler.fill_buffer(&mut buf);
+		assert!(buf.iter().all(|b| *b == 0.0));
+		Ok(())
+	}
+
+	#[test]
+	fn muted() -> Result<()> {
+		let logger = create_logger();
+		let mut handler = AudioHandler::<ClientId>::new(logger);
+		let mut encoder = Encoder::new(
+			audiopus::SampleRate::Hz48000,
+			audiopus::Channels::Mono,
+			audiopus::Application::Audio,
+		)?;
+		let id = ClientId(0);
+		handler.set_muted(id, true);
+		let mut buf = vec![0.0; USUAL_FRAME_SIZE * CHANNEL_NUM];
+		assert_eq!(handler.handle_packet(id, sine_packet(&mut encoder, 0)?)?, Some(id));
+		for i in 1..10 {
+			handler.handle_packet(id, sine_packet(&mut encoder, i)?)?;
+			handler.fill_buffer(&mut buf);
+			// The client is talking but does not play anything
+			assert!(handler.queues[&id].is_muted());
+			assert!(handler.queues[&id].packet_buffer.is_empty());
+			assert!(buf.iter().all(|b| *b == 0.0));
+		}
+
+		// Unmuting starts a new queue with the next packet
+		handler.set_muted(id, false);
+		for i in 10..15 {
+			assert_eq!(handler.handle_packet(id, sine_packet(&mut encoder, i)?)?, None);
+			handler.fill_buffer(&mut buf);
+		}
+		assert!(!handler.queues[&id].is_muted());
+		assert!(buf.iter().any(|b| *b != 0.0));
+
+		// Muting while talking drops the buffered audio, the end of the stream
+		// is still noticed
+		handler.set_muted(id, true);
+		handler.handle_packet(id, sine_packet(&mut encoder, 15)?)?;
+		let packet = OutAudio::new(&AudioData::S2C {
+			id: 16,
+			codec: CodecType::OpusMusic,
+			from: 0,
+			data: &[],
+		});
+		handler.handle_packet(id, InAudioBuf::try_new(Direction::S2C, packet.into_vec()).unwrap())?;
+		let mut buf = vec![0.0; USUAL_FRAME_SIZE * CHANNEL_NUM];
+		assert_eq!(handler.fill_buffer(&mut buf), vec![id]);
+		assert!(buf.iter().all(|b| *b == 0.0));
+		assert!(handler.queues.is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn mono_16khz() -> Result<()> {
+		let logger = create_logger();
+		let mut handler =
+			AudioHandler::<ClientId>::with_format(logger, SampleRate::Hz16000, Channels::Mono);
+		let mut encoder = Encoder::new(
+			audiopus::SampleRate::Hz48000,
+			audiopus::Channels::Mono,
+			audiopus::Application::Audio,
+		)?;
+		let id = ClientId(0);
+		// 20 ms of mono audio
+		let mut buf = vec![0.0; 320];
+		for i in 0..5 {
+			handler.handle_packet(id, sine_packet(&mut encoder, i)?)?;
+			buf.iter_mut().for_each(|b| *b = 0.0);
+			handler.fill_buffer(&mut buf);
+			assert_eq!(handler.queues[&id].last_packet_samples, 320);
+		}
+		assert!(buf.iter().any(|b| *b != 0.0));
+		Ok(())
+	}
+
+	#[test]
+	fn queue_stats() -> Result<()> {
+		let mut a = vec![SimulateAction::CreateEncoder];
+		for i in &[0, 1, 3, 4] {
+			a.push(SimulateAction::ReceivePacket(*i, true));
+		}
+		a.push(SimulateAction::Check(Box::new(|h| {
+			let stats = h.stats()[&ClientId(0)];
+			assert_eq!(stats.packets_received, 4);
+			assert_eq!(stats.buffer_samples, 4 * USUAL_FRAME_SIZE);
+		})));
+		a.push(SimulateAction::FillBuffer(USUAL_FRAME_SIZE, Some(0)));
+		a.push(SimulateAction::FillBuffer(USUAL_FRAME_SIZE, Some(1)));
+		// Packet 2 is restored from packet 3
+		a.push(SimulateAction::FillBuffer(USUAL_FRAME_SIZE, None));
+		a.push(SimulateAction::FillBuffer(USUAL_FRAME_SIZE, Some(3)));
+		a.push(SimulateAction::ReceivePacket(2, false));
+		a.push(SimulateAction::FillBuffer(USUAL_FRAME_SIZE, Some(4)));
+		a.push(SimulateAction::FillBuffer(USUAL_FRAME_SIZE, None));
+		a.push(SimulateAction::Check(Box::new(|h| {
+			let stats = h.stats()[&ClientId(0)];
+			assert_eq!(stats, AudioQueueStats {
+				packets_received: 4,
+				packets_lost: 1,
+				packets_late: 1,
+				fec_decodes: 1,
+				buffer_samples: 0,
+				underruns: 1,
+			});
+		})));
+		simulate(a)
+	}
+
+	#[test]
+	fn talker_events() -> Result<()> {
+		let logger = create_logger();
+		let mut handler = AudioHandler::<ClientId>::new(logger);
+		let mut encoder = Encoder::new(
+			audiopus::SampleRate::Hz48000,
+			audiopus::Channels::Mono,
+			audiopus::Application::Audio,
+		)?;
+		let id = ClientId(0);
+		let mut buf = vec![0.0; USUAL_FRAME_SIZE * CHANNEL_NUM];
+		handler.handle_packet(id, sine_packet(&mut encoder, 0)?)?;
+		assert_eq!(handler.drain_talker_events(), vec![TalkerEvent::Started {
+			id,
+			whispering: false
+		}]);
+		assert!(handler.drain_talker_events().is_empty());
+		handler.fill_buffer(&mut buf);
+
+		// Switch to whispering
+		let mut opus_output = [0; 1275];
+		let data = (0..USUAL_FRAME_SIZE).map(|i| (i as f32 / 10.0).sin() / 2.0).collect::<Vec<_>>();
+		let len = encoder.encode_float(&data, &mut opus_output[..])?;
+		let packet = OutAudio::new(&AudioData::S2CWhisper {
+			id: 1,
+			codec: CodecType::OpusMusic,
+			from: 0,
+			data: &opus_output[..len],
+		});
+		handler.handle_packet(id, InAudioBuf::try_new(Direction::S2C, packet.into_vec()).unwrap())?;
+		handler.fill_buffer(&mut buf);
+		assert_eq!(handler.drain_talker_events(), vec![TalkerEvent::Started {
+			id,
+			whispering: true
+		}]);
+
+		// The end of the stream is reported when it arrives
+		let packet = OutAudio::new(&AudioData::S2C {
+			id: 2,
+			codec: CodecType::OpusMusic,
+			from: 0,
+			data: &[],
+		});
+		handler.handle_packet(id, InAudioBuf::try_new(Direction::S2C, packet.into_vec()).unwrap())?;
+		assert_eq!(handler.drain_talker_events(), vec![TalkerEvent::Stopped { id }]);
+		handler.fill_buffer(&mut buf);
+		handler.fill_buffer(&mut buf);
+		assert!(handler.queues.is_empty());
+		assert!(handler.drain_talker_events().is_empty());
+
+		// A stream without end packet stops after some lost packets
+		let id = ClientId(1);
+		handler.handle_packet(id, sine_packet(&mut encoder, 0)?)?;
+		for _ in 0..=MAX_PACKET_LOSSES + 1 {
+			handler.fill_buffer(&mut buf);
+		}
+		assert_eq!(handler.drain_talker_events(), vec![
+			TalkerEvent::Started { id, whispering: false },
+			TalkerEvent::Stopped { id },
+		]);
+		Ok(())
+	}
+
+	/// The 16 bit output matches the float output.
+	#[test]
+	fn fill_buffer_i16() -> Result<()> {
+		let mut float_handler = AudioHandler::<ClientId>::new(create_logger());
+		let mut int_handler = AudioHandler::<ClientId>::new(create_logger());
+		let mut encoder = Encoder::new(
+			audiopus::SampleRate::Hz48000,
+			audiopus::Channels::Mono,
+			audiopus::Application::Audio,
+		)?;
+		let id = ClientId(0);
+		let mut float_buf = vec![0.0; USUAL_FRAME_SIZE * CHANNEL_NUM];
+		let mut int_buf = vec![0; USUAL_FRAME_SIZE * CHANNEL_NUM];
+		for i in 0..5 {
+			let packet = sine_packet(&mut encoder, i)?;
+			let copy = InAudioBuf::try_new(Direction::S2C, packet.raw_data().to_vec()).unwrap();
+			float_handler.handle_packet(id, copy)?;
+			int_handler.handle_packet(id, packet)?;
+			float_buf.iter_mut().for_each(|s| *s = 0.0);
+			int_buf.iter_mut().for_each(|s| *s = 0);
+			float_handler.fill_buffer(&mut float_buf);
+			int_handler.fill_buffer_i16(&mut int_buf);
+			for (f, i) in float_buf.iter().zip(&int_buf) {
+				assert!((f * 32767.0 - f32::from(*i)).abs() <= 1.0, "{} != {}", f, i);
+			}
+		}
+		assert!(int_buf.iter().any(|s| *s != 0));
+
+		// Adding to existing samples saturates
+		let mut buf = vec![i16::MAX; USUAL_FRAME_SIZE * CHANNEL_NUM];
+		int_handler.handle_packet(id, sine_packet(&mut encoder, 5)?)?;
+		int_handler.fill_buffer_i16(&mut buf);
+		assert!(buf.iter().all(|s| *s >= 0));
+		Ok(())
+	}
+
+	#[test]
+	fn sample_ring() {
+		let mut ring = SampleRing::new(10);
+		let mut expected = VecDeque::new();
+		let mut next = 0.0;
+		// Wrap around multiple times and grow the capacity in between
+		for (push, consume) in [(4, 0), (5, 6), (7, 8), (9, 3), (20, 20), (3, 10)].iter() {
+			let samples = (0..*push).map(|i| next + i as f32).collect::<Vec<_>>();
+			next += *push as f32;
+			ring.push(&samples);
+			expected.extend(samples);
+			assert_eq!(ring.as_slice(), expected.iter().copied().collect::<Vec<_>>().as_slice());
+			ring.consume(*consume);
+			expected.drain(..*consume);
+			assert_eq!(ring.as_slice(), expected.iter().copied().collect::<Vec<_>>().as_slice());
+		}
+		assert_eq!(ring.capacity(), 40);
+	}
+
+	/// Skipping samples while pushing them removes the same samples as
+	/// removing them from a decoded buffer.
+	#[test]
+	fn speed_up_samples() {
+		let channels = 2;
+		for frames in [0, 99, 100, 960, 2880].iter() {
+			let samples = (0..frames * channels).map(|i| i as f32).collect::<Vec<_>>();
+			let mut expected = samples.clone();
+			for i in 0..(frames / SPEED_CHANGE_STEPS) {
+				let i = i * (SPEED_CHANGE_STEPS - 1) * channels;
+				expected.drain(i..(i + channels));
+			}
+
+			let mut ring = SampleRing::new(8);
+			ring.push(&[-1.0; 2]);
+			ring.consume(2);
+			ring.push_speed_up(&samples, channels);
+			assert_eq!(ring.as_slice(), expected.as_slice());
+		}
+	}
+
+	#[test]
+	fn codec_mapping() {
+		let codecs = [
+			(Codec::SpeexNarrowband, CodecType::SpeexNarrowband),
+			(Codec::SpeexWideband, CodecType::SpeexWideband),
+			(Codec::SpeexUltrawideband, CodecType::SpeexUltrawideband),
+			(Codec::CeltMono, CodecType::CeltMono),
+			(Codec::OpusVoice, CodecType::OpusVoice),
+			(Codec::OpusMusic, CodecType::OpusMusic),
+		];
+		for (codec, codec_type) in codecs.iter() {
+			assert_eq!(super::codec_type(*codec), Some(*codec_type));
+			assert_eq!(channel_codec(*codec_type), Some(*codec));
+		}
+	}
+
+	#[test]
+	fn unsupported_codec() -> Result<()> {
+		let logger = create_logger();
+		let mut handler = AudioHandler::<ClientId>::new(logger);
+		let id = ClientId(0);
+		let speex_packet = |id, data: &[u8]| {
+			let packet = OutAudio::new(&AudioData::S2C {
+				id,
+				codec: CodecType::SpeexWideband,
+				from: 0,
+				data,
+			});
+			InAudioBuf::try_new(Direction::S2C, packet.into_vec()).unwrap()
+		};
+		let mut buf = vec![0.0; USUAL_FRAME_SIZE * CHANNEL_NUM];
+
+		for i in 0..5 {
+			assert_eq!(handler.handle_packet(id, speex_packet(i, &[1, 2, 3]))?, None);
+			handler.fill_buffer(&mut buf);
+		}
+		assert!(handler.queues.is_empty());
+		assert!(buf.iter().all(|s| *s == 0.0));
+		assert_eq!(handler.drain_talker_events(), vec![TalkerEvent::UnsupportedCodec {
+			id,
+			codec: CodecType::SpeexWideband,
+		}]);
+
+		// Reported again after the client stopped talking
+		handler.handle_packet(id, speex_packet(5, &[]))?;
+		handler.handle_packet(id, speex_packet(0, &[1, 2, 3]))?;
+		assert_eq!(handler.drain_talker_events().len(), 1);
+
+		// Or after a while without packets
+		for _ in 0..MAX_PACKET_LOSSES {
+			handler.fill_buffer(&mut buf);
+		}
+		handler.handle_packet(id, speex_packet(1, &[1, 2, 3]))?;
+		assert_eq!(handler.drain_talker_events().len(), 1);
+		Ok(())
+	}
+
+	#[test]
+	fn sender() -> Result<()> {
+		let (send, mut recv) = mpsc::channel(10);
+		let mut sender = AudioSender::new(SampleRate::Hz48000, Channels::Mono, send)?;
+		sender.set_bitrate(32_000)?;
+		let data = (0..USUAL_FRAME_SIZE).map(|i| (i as f32 / 10.0).sin() / 2.0).collect::<Vec<_>>();
+		for _ in 0..3 {
+			assert!(sender.send_frame(&data)?);
+		}
+		assert!(sender.stop_talking()?);
+		// Nothing to stop
+		assert!(sender.stop_talking()?);
+
+		let mut packets = Vec::new();
+		while let Ok(p) = recv.try_recv() {
+			packets.push(InAudioBuf::try_new(Direction::C2S, p.into_vec()).unwrap());
+		}
+		assert_eq!(packets.len(), 4);
+		for (i, p) in packets.iter().enumerate() {
+			match p.data().data() {
+				AudioData::C2S { id, codec, data } => {
+					assert_eq!(*id, i as u16);
+					assert_eq!(*codec, CodecType::OpusVoice);
+					// The last packet ends the stream
+					assert_eq!(data.is_empty(), i == 3);
+				}
+				d => panic!("Expected a C2S packet, got {:?}", d),
+			}
+		}
+
+		drop(recv);
+		assert!(matches!(sender.send_frame(&data), Err(Error::SinkClosed)));
+		Ok(())
+	}
+
 	#[test]
 	fn packets_wrapping2() -> Result<()> {
 		let mut a = vec![SimulateAction::CreateEncoder];
@@ -855,6 +2095,21 @@ mod test {
 		simulate(a)
 	}
 
+	#[test]
+	fn reuse_buffers() -> Result<()> {
+		let mut a = vec![SimulateAction::CreateEncoder];
+		for i in 0..10 {
+			a.push(SimulateAction::ReceivePacket(i, true));
+			a.push(SimulateAction::FillBuffer(USUAL_FRAME_SIZE, Some(i)));
+		}
+		a.push(SimulateAction::Check(Box::new(|h| {
+			// The buffers of all played packets are kept
+			assert_eq!(h.buffer_pool.len(), 10);
+			assert!(h.buffer_pool.iter().all(|b| !b.is_empty()));
+		})));
+		simulate(a)
+	}
+
 	#[test]
 	fn packet_loss() -> Result<()> {
 		let mut a = vec![SimulateAction::CreateEncoder];
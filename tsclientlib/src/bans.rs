@@ -0,0 +1,400 @@
+//! Fetch, export and import ban lists.
+//!
+//! A ban list can be requested from the server with [`Connection::list_bans`].
+//! The list can be exported to a file and imported again on another server
+//! with [`Connection::import_bans`].
+//!
+//! [`Connection::list_bans`]: ../struct.Connection.html#method.list_bans
+//! [`Connection::import_bans`]: ../struct.Connection.html#method.import_bans
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::OffsetDateTime;
+use tsproto_packets::packets::OutCommand;
+
+use crate::{new_command, parse_list, ParseEntry};
+
+/// The current version of the exported format.
+const FORMAT_VERSION: u32 = 1;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("Failed to access ban list file: {0}")]
+	Io(#[source] std::io::Error),
+	#[error("Failed to parse ban list file: {0}")]
+	Parse(#[source] serde_json::Error),
+	#[error("Unsupported ban list version {0}")]
+	UnsupportedVersion(u32),
+}
+
+/// A single ban of a server.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Ban {
+	/// The id of the ban on the server it was fetched from.
+	#[serde(skip)]
+	pub id: Option<u32>,
+	/// A regex for banned ip addresses.
+	pub ip: Option<String>,
+	/// A regex for banned nicknames.
+	pub name: Option<String>,
+	/// The banned uid.
+	pub uid: Option<String>,
+	pub reason: String,
+	/// When the ban was created as unix timestamp.
+	pub created: i64,
+	/// The duration of the ban in seconds, `None` is a permanent ban.
+	pub duration: Option<u64>,
+}
+
+/// A list of bans which can be exported and imported.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BanList {
+	version: u32,
+	pub bans: Vec<Ban>,
+}
+
+/// Options for [`BanList::import`].
+///
+/// [`BanList::import`]: struct.BanList.html#method.import
+#[derive(Clone, Debug)]
+pub struct ImportOptions {
+	dry_run: bool,
+	deduplicate: bool,
+	skip_expired: bool,
+	cursor: usize,
+}
+
+/// The result of importing a ban list.
+#[derive(Default)]
+pub struct ImportReport {
+	/// The bans which should be added, as commands with the index of their
+	/// ban in the list.
+	///
+	/// The commands are sent with [`Connection::import_bans`]. This is empty
+	/// for a dry run.
+	///
+	/// [`Connection::import_bans`]: ../struct.Connection.html#method.import_bans
+	pub commands: Vec<(usize, OutCommand)>,
+	/// The bans which will be added.
+	pub added: Vec<Ban>,
+	/// Bans which already exist on the server.
+	pub duplicate: Vec<Ban>,
+	/// Bans which are already expired.
+	pub expired: Vec<Ban>,
+	/// Bans which have neither an ip, name or uid set.
+	pub invalid: Vec<Ban>,
+	/// The index of the next ban after the imported bans.
+	///
+	/// If the connection drops while adding bans, the import can be resumed
+	/// by passing the index of the first ban that was not acknowledged to
+	/// [`ImportOptions::cursor`]. [`Connection::import_bans`] returns the
+	/// index of the ban for every sent command.
+	///
+	/// [`ImportOptions::cursor`]: struct.ImportOptions.html#method.cursor
+	/// [`Connection::import_bans`]: ../struct.Connection.html#method.import_bans
+	pub cursor: usize,
+}
+
+pub(crate) fn list() -> OutCommand { new_command("banlist") }
+
+/// Parse the bans from a `notifybanlist`.
+pub(crate) fn parse_ban_list(content: &[u8]) -> Vec<Ban> {
+	parse_list(content, |args| {
+		let mut ban = Ban::default();
+		for (name, value) in args {
+			let opt_value = if value.is_empty() { None } else { Some(value.to_string()) };
+			match name {
+				b"banid" => ban.id = value.parse().ok(),
+				b"ip" => ban.ip = opt_value,
+				b"name" => ban.name = opt_value,
+				b"uid" => ban.uid = opt_value,
+				b"reason" => ban.reason = value.into_owned(),
+				b"created" => ban.created = value.parse().unwrap_or_default(),
+				b"duration" => ban.duration = value.parse().ok().filter(|d| *d != 0),
+				_ => {}
+			}
+		}
+		Some(ban)
+	})
+}
+
+impl ParseEntry for Ban {
+	fn parse(content: &[u8]) -> Vec<Self> { parse_ban_list(content) }
+}
+
+impl Ban {
+	/// If this ban matches the same clients and has the same reason.
+	pub fn is_duplicate_of(&self, other: &Ban) -> bool {
+		self.ip == other.ip
+			&& self.name == other.name
+			&& self.uid == other.uid
+			&& self.reason == other.reason
+	}
+
+	/// The remaining duration of this ban in seconds.
+	///
+	/// Returns `Some(None)` for permanent bans and `None` if the ban is
+	/// expired.
+	pub fn remaining(&self, now: OffsetDateTime) -> Option<Option<u64>> {
+		if let Some(duration) = self.duration {
+			let end = self.created + duration as i64;
+			let now = now.timestamp();
+			if end <= now { None } else { Some(Some((end - now) as u64)) }
+		} else {
+			Some(None)
+		}
+	}
+
+	/// Create a `banadd` command for this ban, which lasts for `duration`
+	/// seconds.
+	pub fn add(&self, duration: Option<u64>) -> OutCommand {
+		let mut cmd = new_command("banadd");
+		if let Some(ip) = &self.ip {
+			cmd.write_arg("ip", ip);
+		}
+		if let Some(name) = &self.name {
+			cmd.write_arg("name", name);
+		}
+		if let Some(uid) = &self.uid {
+			cmd.write_arg("uid", uid);
+		}
+		if let Some(duration) = duration {
+			cmd.write_arg("time", &duration);
+		}
+		cmd.write_arg("banreason", &self.reason);
+		cmd
+	}
+}
+
+impl BanList {
+	pub fn new(bans: Vec<Ban>) -> Self { Self { version: FORMAT_VERSION, bans } }
+
+	/// Write the ban list as json into a file.
+	pub fn export<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+		let file = File::create(path).map_err(Error::Io)?;
+		let mut writer = BufWriter::new(file);
+		// Serializing a ban list only fails if writing fails
+		serde_json::to_writer_pretty(&mut writer, self).map_err(|e| Error::Io(e.into()))?;
+		writer.flush().map_err(Error::Io)
+	}
+
+	/// Read a ban list that was written by [`export`].
+	///
+	/// [`export`]: #method.export
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+		let file = File::open(path).map_err(Error::Io)?;
+		let res: Self = serde_json::from_reader(BufReader::new(file))
+			.map_err(|e| if e.is_io() { Error::Io(e.into()) } else { Error::Parse(e) })?;
+		if res.version != FORMAT_VERSION {
+			return Err(Error::UnsupportedVersion(res.version));
+		}
+		Ok(res)
+	}
+
+	/// Compute which bans of this list should be added to a server.
+	///
+	/// `existing` are the bans which exist already on the server. The
+	/// commands in the returned report are sent to the server with
+	/// [`Connection::import_bans`].
+	///
+	/// [`Connection::import_bans`]: ../struct.Connection.html#method.import_bans
+	pub fn import(
+		&self, existing: &[Ban], options: &ImportOptions, now: OffsetDateTime,
+	) -> ImportReport {
+		let mut report = ImportReport::default();
+		for (i, ban) in self.bans.iter().enumerate().skip(options.cursor) {
+			report.cursor += 1;
+			if ban.ip.is_none() && ban.name.is_none() && ban.uid.is_none() {
+				report.invalid.push(ban.clone());
+				continue;
+			}
+			if options.deduplicate && existing.iter().any(|b| b.is_duplicate_of(ban)) {
+				report.duplicate.push(ban.clone());
+				continue;
+			}
+			let duration = if options.skip_expired {
+				match ban.remaining(now) {
+					Some(r) => r,
+					None => {
+						report.expired.push(ban.clone());
+						continue;
+					}
+				}
+			} else {
+				ban.duration
+			};
+
+			if !options.dry_run {
+				report.commands.push((i, ban.add(duration)));
+			}
+			report.added.push(ban.clone());
+		}
+		report.cursor += options.cursor;
+		report
+	}
+}
+
+impl Default for ImportOptions {
+	fn default() -> Self {
+		Self { dry_run: false, deduplicate: true, skip_expired: true, cursor: 0 }
+	}
+}
+
+impl ImportOptions {
+	pub fn new() -> Self { Default::default() }
+
+	/// Only compute the report, do not create any commands.
+	///
+	/// # Default
+	/// `false`
+	#[inline]
+	pub fn dry_run(mut self, dry_run: bool) -> Self {
+		self.dry_run = dry_run;
+		self
+	}
+
+	/// Skip bans that exist already on the server with the same reason.
+	///
+	/// # Default
+	/// `true`
+	#[inline]
+	pub fn deduplicate(mut self, deduplicate: bool) -> Self {
+		self.deduplicate = deduplicate;
+		self
+	}
+
+	/// Skip expired bans and only ban for the remaining time.
+	///
+	/// If this is disabled, the original ban duration is used.
+	///
+	/// # Default
+	/// `true`
+	#[inline]
+	pub fn skip_expired(mut self, skip_expired: bool) -> Self {
+		self.skip_expired = skip_expired;
+		self
+	}
+
+	/// Start importing at this index of the ban list.
+	///
+	/// # Default
+	/// `0`
+	#[inline]
+	pub fn cursor(mut self, cursor: usize) -> Self {
+		self.cursor = cursor;
+		self
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn ban(uid: &str, reason: &str, created: i64, duration: Option<u64>) -> Ban {
+		Ban {
+			uid: Some(uid.into()),
+			reason: reason.into(),
+			created,
+			duration,
+			..Default::default()
+		}
+	}
+
+	fn now() -> OffsetDateTime { OffsetDateTime::from_unix_timestamp(1000) }
+
+	#[test]
+	fn import_deduplicate() {
+		let list = BanList::new(vec![ban("a", "spam", 0, None), ban("b", "spam", 0, None)]);
+		let existing = vec![ban("a", "spam", 500, None), ban("b", "other", 0, None)];
+		let report = list.import(&existing, &ImportOptions::new(), now());
+		assert_eq!(report.duplicate, vec![ban("a", "spam", 0, None)]);
+		assert_eq!(report.added, vec![ban("b", "spam", 0, None)]);
+		assert_eq!(report.commands.len(), 1);
+	}
+
+	#[test]
+	fn import_dry_run() {
+		let list = BanList::new(vec![
+			ban("a", "spam", 0, None),
+			ban("b", "spam", 0, Some(10)),
+			Ban { reason: "invalid".into(), ..Default::default() },
+		]);
+		let report = list.import(&[], &ImportOptions::new().dry_run(true), now());
+		assert!(report.commands.is_empty());
+		assert_eq!(report.added.len(), 1);
+		assert_eq!(report.expired.len(), 1);
+		assert_eq!(report.invalid.len(), 1);
+		assert_eq!(report.cursor, 3);
+	}
+
+	#[test]
+	fn import_relative_duration() {
+		let list = BanList::new(vec![ban("a", "spam", 900, Some(300))]);
+		let report = list.import(&[], &ImportOptions::new(), now());
+		assert_eq!(
+			(report.commands[0].1).0.content(),
+			b"banadd uid=a time=200 banreason=spam".as_ref()
+		);
+	}
+
+	#[test]
+	fn import_resume() {
+		let list = BanList::new(vec![
+			ban("a", "spam", 0, None),
+			ban("b", "spam", 0, None),
+			ban("c", "spam", 0, None),
+		]);
+		let first = list.import(&[], &ImportOptions::new(), now());
+		// The connection dropped after adding the first ban
+		let report = list.import(&[], &ImportOptions::new().cursor(1), now());
+		assert_eq!(first.cursor, 3);
+		assert_eq!(report.added, vec![ban("b", "spam", 0, None), ban("c", "spam", 0, None)]);
+		assert_eq!(report.commands.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [1, 2]);
+		assert_eq!(report.cursor, 3);
+	}
+
+	#[test]
+	fn file_errors() {
+		let dir = std::env::temp_dir().join(format!("tsclientlib-bans-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("bans.json");
+		let list = BanList::new(vec![ban("a", "spam", 0, None)]);
+		list.export(&path).unwrap();
+		assert_eq!(BanList::load(&path).unwrap(), list);
+
+		std::fs::write(&path, b"no json").unwrap();
+		assert!(matches!(BanList::load(&path), Err(Error::Parse(_))));
+		// A directory cannot be read or written as file
+		assert!(matches!(BanList::load(&dir), Err(Error::Io(_))));
+		assert!(matches!(list.export(&dir), Err(Error::Io(_))));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn parse_bans() {
+		let bans = parse_ban_list(
+			br#"notifybanlist banid=1 ip name=Bad uid reason=spam created=100 duration=0|banid=2 ip=1.2.3.4 name uid reason created=200 duration=60"#,
+		);
+		assert_eq!(bans, vec![
+			Ban {
+				id: Some(1),
+				name: Some("Bad".into()),
+				reason: "spam".into(),
+				created: 100,
+				..Default::default()
+			},
+			Ban {
+				id: Some(2),
+				ip: Some("1.2.3.4".into()),
+				created: 200,
+				duration: Some(60),
+				..Default::default()
+			},
+		]);
+	}
+}
@@ -0,0 +1,104 @@
+//! Information about how this library was built.
+use std::fmt;
+
+use git_testament::CommitKind;
+
+/// Versions and configuration of this library and its dependencies.
+///
+/// The `Display` implementation renders a summary which can be pasted into a
+/// bug report.
+#[derive(Debug)]
+pub struct BuildInfo {
+	pub version: &'static str,
+	pub tsproto_version: &'static str,
+	pub tsproto_packets_version: &'static str,
+	pub tsproto_types_version: &'static str,
+	pub bookkeeping_version: &'static str,
+	/// The commit of the tsdeclarations, which were used to generate the
+	/// messages and the bookkeeping.
+	pub declarations_version: &'static str,
+	/// The target triple, e.g. `x86_64-unknown-linux-gnu`.
+	pub target: &'static str,
+	/// `debug` or `release`.
+	pub profile: &'static str,
+	/// The maximum size of an udp packet that we can receive.
+	pub max_udp_packet_length: usize,
+	features: &'static str,
+}
+
+static BUILD_INFO: BuildInfo = BuildInfo {
+	version: crate::VERSION,
+	tsproto_version: tsproto::VERSION,
+	tsproto_packets_version: tsproto_packets::VERSION,
+	tsproto_types_version: tsproto_types::VERSION,
+	bookkeeping_version: ts_bookkeeping::VERSION,
+	declarations_version: ts_bookkeeping::DECLARATIONS_VERSION,
+	target: env!("TSCLIENTLIB_TARGET"),
+	profile: env!("TSCLIENTLIB_PROFILE"),
+	max_udp_packet_length: tsproto::MAX_UDP_PACKET_LENGTH,
+	features: env!("TSCLIENTLIB_FEATURES"),
+};
+
+/// Get the versions and configuration this library was built with.
+pub fn build_info() -> &'static BuildInfo { &BUILD_INFO }
+
+impl BuildInfo {
+	/// The git commit of tsclientlib, if it was built from a repository.
+	pub fn commit(&self) -> Option<&'static str> {
+		match crate::TESTAMENT.commit {
+			CommitKind::NoTags(commit, _) | CommitKind::FromTag(_, commit, _, _) => Some(commit),
+			CommitKind::NoRepository(..) | CommitKind::NoCommit(..) => None,
+		}
+	}
+
+	/// The enabled cargo features of tsclientlib.
+	pub fn features(&self) -> impl Iterator<Item = &'static str> {
+		self.features.split(',').filter(|f| !f.is_empty())
+	}
+}
+
+impl fmt::Display for BuildInfo {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let commit = self.commit().unwrap_or("unknown commit");
+		writeln!(f, "tsclientlib {} ({})", self.version, commit)?;
+		writeln!(
+			f,
+			"tsproto {}, tsproto-packets {}, tsproto-types {}, ts-bookkeeping {}",
+			self.tsproto_version,
+			self.tsproto_packets_version,
+			self.tsproto_types_version,
+			self.bookkeeping_version
+		)?;
+		writeln!(f, "declarations: {}", self.declarations_version)?;
+		writeln!(f, "target: {} ({})", self.target, self.profile)?;
+		writeln!(f, "features: {}", self.features().collect::<Vec<_>>().join(", "))?;
+		write!(f, "max udp packet length: {}", self.max_udp_packet_length)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn populated() {
+		let info = build_info();
+		assert!(!info.version.is_empty());
+		assert!(!info.tsproto_version.is_empty());
+		assert!(!info.bookkeeping_version.is_empty());
+		assert!(!info.declarations_version.is_empty());
+		assert!(!info.target.is_empty());
+		assert_eq!(info.features().any(|f| f == "audio"), cfg!(feature = "audio"));
+		assert_eq!(info.features().any(|f| f == "unstable"), cfg!(feature = "unstable"));
+	}
+
+	#[test]
+	fn display() {
+		let info = build_info();
+		let s = info.to_string();
+		assert!(s.contains(&format!("tsclientlib {}", info.version)));
+		assert!(s.contains(&format!("tsproto {}", info.tsproto_version)));
+		assert!(s.contains(info.declarations_version));
+		assert!(s.contains(info.target));
+	}
+}
@@ -0,0 +1,233 @@
+//! Capture the decrypted packets of a connection to a file.
+//!
+//! A capture is started with [`ConnectOptions::capture_to`] and read back
+//! with [`read_file`].
+//!
+//! # Format
+//! The file starts with the magic bytes `TSCP` and a version byte, currently
+//! `1`. Afterwards, every packet is stored as a record of
+//! - the time in microseconds since the unix epoch (`u64`)
+//! - the direction, `0` for server to client and `1` for client to server
+//! - the packet type (`u8`)
+//! - the length of the packet (`u32`)
+//! - the packet, header and content
+//!
+//! All numbers are stored in big endian.
+//!
+//! [`ConnectOptions::capture_to`]: ../struct.ConnectOptions.html#method.capture_to
+//! [`read_file`]: fn.read_file.html
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use num_traits::{FromPrimitive, ToPrimitive};
+use slog::{warn, Logger};
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+use tsproto::connection::{Connection, Event};
+use tsproto::observe::ObservedPacket;
+use tsproto_packets::packets::{Direction, InPacket, PacketType};
+
+const MAGIC: &[u8; 4] = b"TSCP";
+const VERSION: u8 = 1;
+/// The size of a record without the packet.
+const RECORD_HEADER_LEN: usize = 14;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("Failed to read capture: {0}")]
+	Io(#[source] io::Error),
+	#[error("The file is not a packet capture")]
+	InvalidHeader,
+	#[error("Unsupported capture version {0}")]
+	UnsupportedVersion(u8),
+	#[error("Invalid direction {0} in capture")]
+	InvalidDirection(u8),
+	#[error("Invalid packet type {0} in capture")]
+	InvalidPacketType(u8),
+}
+
+/// A packet that was read from a capture.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CapturedPacket {
+	/// When the packet was received or sent.
+	pub time: OffsetDateTime,
+	pub direction: Direction,
+	pub packet_type: PacketType,
+	/// The header and the decrypted content of the packet.
+	pub data: Vec<u8>,
+}
+
+/// Reads the packets of a capture one after another.
+pub struct CaptureReader<R> {
+	reader: R,
+}
+
+impl CapturedPacket {
+	/// Parse the captured data as packet.
+	pub fn packet(&self) -> std::result::Result<InPacket, tsproto_packets::Error> {
+		InPacket::try_new(self.direction, &self.data)
+	}
+
+	fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		let micros = self.time.timestamp() as u64 * 1_000_000 + u64::from(self.time.microsecond());
+		let mut header = [0; RECORD_HEADER_LEN];
+		header[..8].copy_from_slice(&micros.to_be_bytes());
+		header[8] = if self.direction == Direction::S2C { 0 } else { 1 };
+		header[9] = self.packet_type.to_u8().unwrap();
+		header[10..].copy_from_slice(&(self.data.len() as u32).to_be_bytes());
+		w.write_all(&header)?;
+		w.write_all(&self.data)
+	}
+}
+
+impl<R: Read> CaptureReader<R> {
+	/// Check the file header and start reading.
+	pub fn new(mut reader: R) -> Result<Self> {
+		let mut header = [0; 5];
+		reader.read_exact(&mut header).map_err(|_| Error::InvalidHeader)?;
+		if &header[..4] != MAGIC {
+			return Err(Error::InvalidHeader);
+		}
+		if header[4] != VERSION {
+			return Err(Error::UnsupportedVersion(header[4]));
+		}
+		Ok(Self { reader })
+	}
+
+	/// Returns `None` at the end of the capture.
+	fn read_packet(&mut self) -> Result<Option<CapturedPacket>> {
+		let mut header = [0; RECORD_HEADER_LEN];
+		// Distinguish the end of the file from a truncated record
+		let read = self.reader.read(&mut header).map_err(Error::Io)?;
+		if read == 0 {
+			return Ok(None);
+		}
+		self.reader.read_exact(&mut header[read..]).map_err(Error::Io)?;
+
+		let mut micros = [0; 8];
+		micros.copy_from_slice(&header[..8]);
+		let micros = u64::from_be_bytes(micros);
+		let time = OffsetDateTime::from_unix_timestamp((micros / 1_000_000) as i64)
+			+ Duration::microseconds((micros % 1_000_000) as i64);
+		let direction = match header[8] {
+			0 => Direction::S2C,
+			1 => Direction::C2S,
+			d => return Err(Error::InvalidDirection(d)),
+		};
+		let packet_type =
+			PacketType::from_u8(header[9]).ok_or(Error::InvalidPacketType(header[9]))?;
+		let mut len = [0; 4];
+		len.copy_from_slice(&header[10..]);
+		let mut data = vec![0; u32::from_be_bytes(len) as usize];
+		self.reader.read_exact(&mut data).map_err(Error::Io)?;
+
+		Ok(Some(CapturedPacket { time, direction, packet_type, data }))
+	}
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+	type Item = Result<CapturedPacket>;
+	fn next(&mut self) -> Option<Self::Item> { self.read_packet().transpose() }
+}
+
+/// Read the packets of a capture file.
+pub fn read_file<P: AsRef<Path>>(path: P) -> Result<CaptureReader<BufReader<File>>> {
+	let file = File::open(path).map_err(Error::Io)?;
+	CaptureReader::new(BufReader::new(file))
+}
+
+/// Write all packets of the connection to the file at `path`.
+///
+/// The file is written by a separate thread, so a slow disk does not stall
+/// the connection. Packets are appended if the file exists already, e.g. when
+/// the connection is reestablished.
+pub(crate) fn add_capture(logger: Logger, path: PathBuf, con: &mut Connection) {
+	let (send, recv) = mpsc::channel::<CapturedPacket>();
+	std::thread::spawn(move || {
+		if let Err(e) = write_capture(&path, recv) {
+			warn!(logger, "Failed to write packet capture"; "path" => %path.display(),
+				"error" => %e);
+		}
+	});
+
+	let listener = Box::new(move |event: &Event| {
+		let ObservedPacket { direction, packet_type, data, .. } =
+			match ObservedPacket::from_event(event, |_| true) {
+				Some(packet) => packet,
+				None => return true,
+			};
+		let time = OffsetDateTime::now_utc();
+		// Fails if the writer stopped, it logged the error already
+		send.send(CapturedPacket { time, direction, packet_type, data }).is_ok()
+	});
+	con.event_listeners.push(listener);
+}
+
+fn write_capture(path: &Path, recv: mpsc::Receiver<CapturedPacket>) -> io::Result<()> {
+	let file = OpenOptions::new().create(true).append(true).open(path)?;
+	let empty = file.metadata()?.len() == 0;
+	let mut file = BufWriter::new(file);
+	if empty {
+		file.write_all(MAGIC)?;
+		file.write_all(&[VERSION])?;
+	}
+
+	// Ends when the connection is dropped
+	while let Ok(packet) = recv.recv() {
+		packet.write(&mut file)?;
+		for packet in recv.try_iter() {
+			packet.write(&mut file)?;
+		}
+		file.flush()?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn packet(direction: Direction, packet_type: PacketType, data: &[u8]) -> CapturedPacket {
+		CapturedPacket {
+			time: OffsetDateTime::from_unix_timestamp(1_600_000_000) + Duration::microseconds(42),
+			direction,
+			packet_type,
+			data: data.to_vec(),
+		}
+	}
+
+	#[test]
+	fn roundtrip() {
+		let packets = vec![
+			packet(Direction::C2S, PacketType::Command, b"header clientinit"),
+			packet(Direction::S2C, PacketType::Voice, &[1, 2, 3]),
+			packet(Direction::S2C, PacketType::Ack, &[]),
+		];
+		let mut file = Vec::new();
+		file.extend_from_slice(MAGIC);
+		file.push(VERSION);
+		for p in &packets {
+			p.write(&mut file).unwrap();
+		}
+
+		let read = CaptureReader::new(&file[..]).unwrap().collect::<Result<Vec<_>>>().unwrap();
+		assert_eq!(read, packets);
+
+		// Truncated record
+		let mut reader = CaptureReader::new(&file[..file.len() - 2]).unwrap();
+		assert!(reader.next().unwrap().is_ok());
+		assert!(reader.next().unwrap().is_ok());
+		assert!(matches!(reader.next(), Some(Err(Error::Io(_)))));
+	}
+
+	#[test]
+	fn invalid_header() {
+		assert!(matches!(CaptureReader::new(&b"TSC"[..]), Err(Error::InvalidHeader)));
+		assert!(matches!(CaptureReader::new(&b"PCAP\x01"[..]), Err(Error::InvalidHeader)));
+		assert!(matches!(CaptureReader::new(&b"TSCP\x02"[..]), Err(Error::UnsupportedVersion(2))));
+	}
+}
@@ -0,0 +1,135 @@
+//! Complaints about clients, which are stored on the server.
+use time::OffsetDateTime;
+use tsproto_packets::packets::OutCommand;
+use tsproto_types::ClientDbId;
+
+use crate::{new_command, parse_list, ParseEntry};
+
+/// A complaint of one client about another client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Complaint {
+	/// The client this complaint is about.
+	pub target: ClientDbId,
+	pub target_name: String,
+	/// The client which filed this complaint.
+	pub source: ClientDbId,
+	pub source_name: String,
+	pub message: String,
+	pub timestamp: OffsetDateTime,
+}
+
+pub(crate) fn add(target: ClientDbId, message: &str) -> OutCommand {
+	let mut cmd = new_command("complainadd");
+	cmd.write_arg("tcldbid", &target.0);
+	cmd.write_arg("message", &message);
+	cmd
+}
+
+pub(crate) fn list(target: Option<ClientDbId>) -> OutCommand {
+	let mut cmd = new_command("complainlist");
+	if let Some(target) = target {
+		cmd.write_arg("tcldbid", &target.0);
+	}
+	cmd
+}
+
+pub(crate) fn delete(target: ClientDbId, source: ClientDbId) -> OutCommand {
+	let mut cmd = new_command("complaindel");
+	cmd.write_arg("tcldbid", &target.0);
+	cmd.write_arg("fcldbid", &source.0);
+	cmd
+}
+
+/// Parse the complaints from a `notifycomplainlist`.
+pub(crate) fn parse(content: &[u8]) -> Vec<Complaint> {
+	parse_list(content, |args| {
+		let mut target = None;
+		let mut target_name = String::new();
+		let mut source = None;
+		let mut source_name = String::new();
+		let mut message = String::new();
+		let mut timestamp = OffsetDateTime::unix_epoch();
+		for (name, value) in args {
+			match name {
+				b"tcldbid" => target = value.parse().ok().map(ClientDbId),
+				b"tname" => target_name = value.into_owned(),
+				b"fcldbid" => source = value.parse().ok().map(ClientDbId),
+				b"fname" => source_name = value.into_owned(),
+				b"message" => message = value.into_owned(),
+				b"timestamp" => {
+					timestamp =
+						OffsetDateTime::from_unix_timestamp(value.parse().unwrap_or_default())
+				}
+				_ => {}
+			}
+		}
+		Some(Complaint {
+			target: target?,
+			target_name,
+			source: source?,
+			source_name,
+			message,
+			timestamp,
+		})
+	})
+}
+
+impl ParseEntry for Complaint {
+	fn parse(content: &[u8]) -> Vec<Self> { parse(content) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn parse_list() {
+		let complaints = parse(
+			br#"notifycomplainlist tcldbid=5 tname=Bad fcldbid=2 fname=Good message=Spamming timestamp=1500000000|tcldbid=5 tname=Bad fcldbid=3 fname=Other message=Annoying timestamp=1500000001"#,
+		);
+		assert_eq!(complaints, vec![
+			Complaint {
+				target: ClientDbId(5),
+				target_name: "Bad".into(),
+				source: ClientDbId(2),
+				source_name: "Good".into(),
+				message: "Spamming".into(),
+				timestamp: OffsetDateTime::from_unix_timestamp(1500000000),
+			},
+			Complaint {
+				target: ClientDbId(5),
+				target_name: "Bad".into(),
+				source: ClientDbId(3),
+				source_name: "Other".into(),
+				message: "Annoying".into(),
+				timestamp: OffsetDateTime::from_unix_timestamp(1500000001),
+			},
+		]);
+	}
+
+	#[test]
+	fn parse_missing_values() {
+		let complaints = parse(
+			br#"notifycomplainlist tcldbid=5 tname=Bad fcldbid=2 fname=Good message=Spamming timestamp=1500000000|tcldbid=6 fcldbid=3"#,
+		);
+		assert_eq!(complaints.len(), 2);
+		assert_eq!(complaints[1], Complaint {
+			target: ClientDbId(6),
+			target_name: String::new(),
+			source: ClientDbId(3),
+			source_name: String::new(),
+			message: String::new(),
+			timestamp: OffsetDateTime::unix_epoch(),
+		});
+
+		// An entry without a source does not take the source of the entry before
+		let complaints = parse(br#"notifycomplainlist tcldbid=5 fcldbid=2|tcldbid=6"#);
+		assert_eq!(complaints.len(), 1);
+	}
+
+	#[test]
+	fn list_all() {
+		assert_eq!(list(None).0.content(), b"complainlist".as_ref());
+		assert_eq!(list(Some(ClientDbId(5))).0.content(), b"complainlist tcldbid=5".as_ref());
+	}
+}
@@ -0,0 +1,78 @@
+//! List the files in the file browser of a channel.
+use time::OffsetDateTime;
+use tsproto_packets::packets::OutCommand;
+use tsproto_types::ChannelId;
+
+use crate::{new_command, parse_list, ParseEntry};
+
+/// A file or directory in a channel.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileEntry {
+	pub name: String,
+	/// The size in bytes, `0` for directories.
+	pub size: u64,
+	/// When the file was changed the last time.
+	pub modified: OffsetDateTime,
+	pub is_directory: bool,
+}
+
+pub(crate) fn list(channel: ChannelId, channel_password: Option<&str>, path: &str) -> OutCommand {
+	let mut cmd = new_command("ftgetfilelist");
+	cmd.write_arg("cid", &channel.0);
+	cmd.write_arg("cpw", &channel_password.unwrap_or_default());
+	cmd.write_arg("path", &path);
+	cmd
+}
+
+/// Parse the files from a `notifyfilelist`.
+pub(crate) fn parse(content: &[u8]) -> Vec<FileEntry> {
+	parse_list(content, |args| {
+		let mut file_name = None;
+		let mut size = 0;
+		let mut modified = OffsetDateTime::unix_epoch();
+		let mut is_directory = false;
+		for (name, value) in args {
+			match name {
+				b"name" => file_name = Some(value.into_owned()),
+				b"size" => size = value.parse().unwrap_or_default(),
+				b"datetime" => {
+					modified =
+						OffsetDateTime::from_unix_timestamp(value.parse().unwrap_or_default())
+				}
+				b"type" => is_directory = value == "0",
+				_ => {}
+			}
+		}
+		Some(FileEntry { name: file_name?, size, modified, is_directory })
+	})
+}
+
+impl ParseEntry for FileEntry {
+	fn parse(content: &[u8]) -> Vec<Self> { parse(content) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn parse_list() {
+		let files = parse(
+			br#"notifyfilelist cid=2 path=\/ return_code=1 name=Music size=0 datetime=1500000000 type=0|name=Song\s1.mp3 size=4096 datetime=1500000060 type=1"#,
+		);
+		assert_eq!(files, vec![
+			FileEntry {
+				name: "Music".into(),
+				size: 0,
+				modified: OffsetDateTime::from_unix_timestamp(1500000000),
+				is_directory: true,
+			},
+			FileEntry {
+				name: "Song 1.mp3".into(),
+				size: 4096,
+				modified: OffsetDateTime::from_unix_timestamp(1500000060),
+				is_directory: false,
+			},
+		]);
+	}
+}
@@ -0,0 +1,85 @@
+//! Search channels and clients on the server.
+//!
+//! The bookkeeping only contains the clients in subscribed channels. On big
+//! servers, where not all channels are subscribed, a client can only be found
+//! by asking the server with [`Connection::find_clients`].
+//!
+//! [`Connection::find_clients`]: ../struct.Connection.html#method.find_clients
+use std::str::FromStr;
+
+use tsproto_packets::packets::OutCommand;
+use tsproto_types::{ChannelId, ClientId};
+
+use crate::{new_command, parse_list, ParseEntry};
+
+pub(crate) fn channels(pattern: &str) -> OutCommand {
+	let mut cmd = new_command("channelfind");
+	cmd.write_arg("pattern", &pattern);
+	cmd
+}
+
+pub(crate) fn clients(pattern: &str) -> OutCommand {
+	let mut cmd = new_command("clientfind");
+	cmd.write_arg("pattern", &pattern);
+	cmd
+}
+
+/// Parse the ids and names of a `notifychannelfind`.
+pub(crate) fn parse_channels(content: &[u8]) -> Vec<(ChannelId, String)> {
+	parse(content, b"cid", b"channel_name", ChannelId)
+}
+
+/// Parse the ids and names of a `notifyclientfind`.
+pub(crate) fn parse_clients(content: &[u8]) -> Vec<(ClientId, String)> {
+	parse(content, b"clid", b"client_nickname", ClientId)
+}
+
+impl ParseEntry for (ChannelId, String) {
+	fn parse(content: &[u8]) -> Vec<Self> { parse_channels(content) }
+}
+
+impl ParseEntry for (ClientId, String) {
+	fn parse(content: &[u8]) -> Vec<Self> { parse_clients(content) }
+}
+
+fn parse<I: FromStr, T>(
+	content: &[u8], id_name: &[u8], name_name: &[u8], to_id: impl Fn(I) -> T,
+) -> Vec<(T, String)> {
+	parse_list(content, |args| {
+		let mut id = None;
+		let mut name = String::new();
+		for (arg, value) in args {
+			if arg == id_name {
+				id = value.parse().ok();
+			} else if arg == name_name {
+				name = value.into_owned();
+			}
+		}
+		Some((to_id(id?), name))
+	})
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn parse_results() {
+		let channels = parse_channels(
+			br#"notifychannelfind cid=2 channel_name=Games return_code=1|cid=5 channel_name=Board\sgames"#,
+		);
+		assert_eq!(channels, vec![
+			(ChannelId(2), "Games".into()),
+			(ChannelId(5), "Board games".into()),
+		]);
+		let clients =
+			parse_clients(br#"notifyclientfind clid=3 client_nickname=Alice|clid=9 client_nickname"#);
+		assert_eq!(clients, vec![(ClientId(3), "Alice".into()), (ClientId(9), String::new())]);
+	}
+
+	#[test]
+	fn commands() {
+		assert_eq!(channels("Gam").0.content(), b"channelfind pattern=Gam".as_ref());
+		assert_eq!(clients("Ali ce").0.content(), br"clientfind pattern=Ali\sce".as_ref());
+	}
+}
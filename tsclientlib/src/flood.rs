@@ -0,0 +1,267 @@
+//! Limit the rate of sent commands, so the server does not block us for
+//! flooding.
+//!
+//! The server adds points for every command of a client and blocks its
+//! commands for a while when they reach a threshold. Commands are queued
+//! when they exceed the [`RateLimit`] and sent when the limit allows it
+//! again. If the server reports flooding anyway, the queue pauses for
+//! [`FLOOD_PAUSE`] and the command is sent once more.
+//!
+//! Commands which the library sends on its own, e.g. to restore our state
+//! after reconnecting, do not wait in the queue.
+//!
+//! [`RateLimit`]: struct.RateLimit.html
+//! [`FLOOD_PAUSE`]: constant.FLOOD_PAUSE.html
+use std::collections::{HashMap, VecDeque};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use tokio::time::{self, Delay};
+use tsproto_packets::packets::OutPacket;
+
+use crate::MessageHandle;
+
+/// Pause sending commands this long when the server reports flooding.
+///
+/// The server does not tell how long it blocks our commands.
+pub const FLOOD_PAUSE: Duration = Duration::from_secs(5);
+
+/// The number of commands which can be sent in an interval.
+///
+/// Up to `commands` can be sent at once, afterwards new commands are sent
+/// evenly spread over the `interval`.
+///
+/// # Default
+/// 30 commands per 30 seconds, which stays below the default anti-flood
+/// settings of a server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+	pub commands: u32,
+	pub interval: Duration,
+}
+
+/// A command which waits until it can be sent.
+struct QueuedCommand {
+	handle: MessageHandle,
+	packet: OutPacket,
+	/// `false` if the command was already sent again after the server
+	/// reported flooding.
+	retry: bool,
+}
+
+/// The queue for commands, which enforces the rate limit.
+pub(crate) struct CommandQueue {
+	limit: Option<RateLimit>,
+	/// The number of commands that can be sent now.
+	budget: u32,
+	/// When the budget was increased the last time.
+	last_refill: Instant,
+	/// Do not send commands until this time because the server reported
+	/// flooding.
+	paused_until: Option<Instant>,
+	queue: VecDeque<QueuedCommand>,
+	/// Sent commands which are sent again if the server reports flooding.
+	sent: HashMap<MessageHandle, OutPacket>,
+	timer: Option<Delay>,
+	/// Woken when a command is added to the empty queue.
+	waker: Option<Waker>,
+}
+
+impl Default for RateLimit {
+	fn default() -> Self { Self { commands: 30, interval: Duration::from_secs(30) } }
+}
+
+impl RateLimit {
+	/// The time until the budget increases by one command.
+	fn refill_interval(&self) -> Duration { self.interval / self.commands.max(1) }
+}
+
+impl CommandQueue {
+	/// No limit is applied if `limit` is `None`, but commands are still sent
+	/// again if the server reports flooding.
+	pub(crate) fn new(limit: Option<RateLimit>) -> Self {
+		let now = Instant::now();
+		Self {
+			limit,
+			budget: limit.map(|l| l.commands).unwrap_or_default(),
+			last_refill: now,
+			paused_until: None,
+			queue: Default::default(),
+			sent: Default::default(),
+			timer: None,
+			waker: None,
+		}
+	}
+
+	/// The number of commands which wait until they can be sent.
+	pub(crate) fn len(&self) -> usize { self.queue.len() }
+
+	/// Commands are not sent until this time because the server reported that
+	/// we are flooding.
+	pub(crate) fn paused_until(&self) -> Option<Instant> {
+		self.paused_until.filter(|t| *t > Instant::now())
+	}
+
+	/// Add a command to the end of the queue.
+	pub(crate) fn push(&mut self, handle: MessageHandle, packet: OutPacket) {
+		self.queue.push_back(QueuedCommand { handle, packet, retry: true });
+		self.wake();
+	}
+
+	/// Count a command that is sent without going through the queue.
+	pub(crate) fn bypass(&mut self, now: Instant) {
+		self.refill(now);
+		self.budget = self.budget.saturating_sub(1);
+	}
+
+	/// The next command that can be sent now.
+	pub(crate) fn next_ready(&mut self, now: Instant) -> Option<(MessageHandle, OutPacket)> {
+		if self.queue.is_empty() || self.paused_until.map(|t| t > now).unwrap_or_default() {
+			return None;
+		}
+		self.paused_until = None;
+		self.refill(now);
+		if self.limit.is_some() {
+			if self.budget == 0 {
+				return None;
+			}
+			self.budget -= 1;
+		}
+
+		let cmd = self.queue.pop_front().unwrap();
+		if cmd.retry {
+			self.sent.insert(cmd.handle, cmd.packet.clone());
+		}
+		Some((cmd.handle, cmd.packet))
+	}
+
+	/// Wait until the next command in the queue can be sent.
+	pub(crate) fn poll_ready(&mut self, cx: &mut Context) -> Poll<()> {
+		if self.queue.is_empty() {
+			self.waker = Some(cx.waker().clone());
+			return Poll::Pending;
+		}
+		let next = match (self.paused_until, self.limit) {
+			(Some(t), _) => t,
+			(None, Some(limit)) if self.budget == 0 => self.last_refill + limit.refill_interval(),
+			_ => return Poll::Ready(()),
+		};
+		match &mut self.timer {
+			Some(timer) => timer.reset(next.into()),
+			None => self.timer = Some(time::delay_until(next.into())),
+		}
+		self.timer.as_mut().unwrap().poll_unpin(cx)
+	}
+
+	/// The server answered a command.
+	pub(crate) fn answered(&mut self, handle: MessageHandle) { self.sent.remove(&handle); }
+
+	/// The server answered a command with a flooding error.
+	///
+	/// Pauses the queue and returns `true` if the command will be sent again.
+	pub(crate) fn flooding(&mut self, handle: MessageHandle, wait: Duration, now: Instant) -> bool {
+		self.paused_until = Some(now + wait);
+		if let Some(packet) = self.sent.remove(&handle) {
+			self.queue.push_front(QueuedCommand { handle, packet, retry: false });
+			self.wake();
+			true
+		} else {
+			false
+		}
+	}
+
+	fn wake(&mut self) {
+		if let Some(waker) = self.waker.take() {
+			waker.wake();
+		}
+	}
+
+	fn refill(&mut self, now: Instant) {
+		if let Some(limit) = self.limit {
+			let interval = limit.refill_interval();
+			while self.budget < limit.commands && now >= self.last_refill + interval {
+				self.budget += 1;
+				self.last_refill += interval;
+			}
+			if self.budget >= limit.commands {
+				self.last_refill = now;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use futures::task::{waker, ArcWake};
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::Arc;
+	use tsproto_packets::packets::{Direction, Flags, OutCommand, PacketType};
+
+	#[derive(Default)]
+	struct CountWakes(AtomicUsize);
+
+	impl ArcWake for CountWakes {
+		fn wake_by_ref(arc_self: &Arc<Self>) { arc_self.0.fetch_add(1, Ordering::SeqCst); }
+	}
+
+	fn command(handle: u16) -> (MessageHandle, OutPacket) {
+		let cmd = OutCommand::new(Direction::C2S, Flags::empty(), PacketType::Command, "cmd");
+		(MessageHandle(handle), cmd.into_packet())
+	}
+
+	#[test]
+	fn rate_limit() {
+		let limit = RateLimit { commands: 2, interval: Duration::from_secs(2) };
+		let mut queue = CommandQueue::new(Some(limit));
+		for i in 0..4 {
+			let (handle, packet) = command(i);
+			queue.push(handle, packet);
+		}
+
+		let now = Instant::now();
+		assert_eq!(queue.next_ready(now).unwrap().0, MessageHandle(0));
+		assert_eq!(queue.next_ready(now).unwrap().0, MessageHandle(1));
+		assert!(queue.next_ready(now).is_none());
+		assert_eq!(queue.len(), 2);
+
+		// One more command per second
+		let later = now + Duration::from_millis(1500);
+		assert_eq!(queue.next_ready(later).unwrap().0, MessageHandle(2));
+		assert!(queue.next_ready(later).is_none());
+	}
+
+	#[test]
+	fn wake_on_push() {
+		let wakes = Arc::new(CountWakes::default());
+		let waker = waker(wakes.clone());
+		let mut cx = Context::from_waker(&waker);
+		let mut queue = CommandQueue::new(None);
+		assert!(queue.poll_ready(&mut cx).is_pending());
+
+		let (handle, packet) = command(0);
+		queue.push(handle, packet);
+		assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+		assert!(queue.poll_ready(&mut cx).is_ready());
+	}
+
+	#[test]
+	fn retry_once_after_flooding() {
+		let mut queue = CommandQueue::new(None);
+		let (handle, packet) = command(0);
+		queue.push(handle, packet);
+
+		let now = Instant::now();
+		assert_eq!(queue.next_ready(now).unwrap().0, handle);
+		assert!(queue.flooding(handle, Duration::from_secs(3), now));
+		assert_eq!(queue.len(), 1);
+		assert!(queue.next_ready(now).is_none());
+
+		let later = now + Duration::from_secs(3);
+		assert_eq!(queue.next_ready(later).unwrap().0, handle);
+		// Only retried once
+		assert!(!queue.flooding(handle, Duration::from_secs(3), later));
+		assert_eq!(queue.len(), 0);
+	}
+}
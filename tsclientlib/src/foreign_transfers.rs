@@ -0,0 +1,115 @@
+//! File transfers of other clients.
+//!
+//! The server sends the list of running file transfers as answer to
+//! [`Connection::request_file_transfers`], if we have the permission to see
+//! them. The latest transfers are kept in [`Server::recent_file_transfers`].
+//!
+//! [`Connection::request_file_transfers`]: ../struct.Connection.html#method.request_file_transfers
+//! [`Server::recent_file_transfers`]: ../data/struct.Server.html#method.recent_file_transfers
+use tsproto_packets::packets::OutCommand;
+use tsproto_types::{ChannelId, ClientId};
+
+use crate::{new_command, parse_list};
+
+pub use ts_bookkeeping::data::{ForeignFileTransfer, TransferDirection, MAX_RECENT_FILE_TRANSFERS};
+
+pub(crate) fn list() -> OutCommand { new_command("ftlist") }
+
+/// Parse the transfers from a `notifyftlist`.
+pub(crate) fn parse(content: &[u8]) -> Vec<ForeignFileTransfer> {
+	parse_list(content, |args| {
+		let mut id = None;
+		let mut client = None;
+		let mut channel = None;
+		let mut dir = String::new();
+		let mut file_name = String::new();
+		let mut size = 0;
+		let mut direction = TransferDirection::Download;
+		for (name, value) in args {
+			match name {
+				b"serverftfid" => id = value.parse().ok(),
+				b"clid" => client = value.parse().ok().map(ClientId),
+				b"path" => {
+					// The path on the server ends with `channel_<id>`
+					channel = value
+						.rsplit('/')
+						.next()
+						.filter(|p| p.starts_with("channel_"))
+						.and_then(|p| p["channel_".len()..].parse().ok())
+						.map(ChannelId);
+					dir = value.into_owned();
+				}
+				b"name" => file_name = value.into_owned(),
+				b"size" => size = value.parse().unwrap_or_default(),
+				b"sender" => {
+					direction = if value == "1" {
+						TransferDirection::Download
+					} else {
+						TransferDirection::Upload
+					}
+				}
+				_ => {}
+			}
+		}
+		// Files in channels have a path relative to the channel
+		let path = if channel.is_some() {
+			file_name
+		} else {
+			format!("{}/{}", dir.trim_end_matches('/'), file_name.trim_start_matches('/'))
+		};
+		Some(ForeignFileTransfer { id: id?, client: client?, channel, path, size, direction })
+	})
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn transfer(id: u16) -> ForeignFileTransfer {
+		ForeignFileTransfer {
+			id,
+			client: ClientId(5),
+			channel: Some(ChannelId(2)),
+			path: "/file".into(),
+			size: 100,
+			direction: TransferDirection::Upload,
+		}
+	}
+
+	#[test]
+	fn parse_list() {
+		let transfers = parse(
+			br#"notifyftlist clid=5 cldbid=3 path=files\/virtualserver_1\/channel_2 name=\/file size=100 sizedone=50 clientftfid=1 serverftfid=7 sender=0 status=1 current_speed=0 average_speed=0 runtime=0|clid=6 cldbid=4 path=files\/virtualserver_1\/internal\/avatar name=\/avatar_abc size=20 sizedone=0 clientftfid=1 serverftfid=8 sender=1 status=1 current_speed=0 average_speed=0 runtime=0"#,
+		);
+		assert_eq!(transfers, vec![
+			ForeignFileTransfer { id: 7, ..transfer(0) },
+			ForeignFileTransfer {
+				id: 8,
+				client: ClientId(6),
+				channel: None,
+				path: "files/virtualserver_1/internal/avatar/avatar_abc".into(),
+				size: 20,
+				direction: TransferDirection::Download,
+			},
+		]);
+	}
+
+	#[test]
+	fn parse_missing_values() {
+		// The second transfer has no path, name, size and sender
+		let transfers = parse(
+			br#"notifyftlist clid=5 path=files\/virtualserver_1\/channel_2 name=\/file size=100 serverftfid=7 sender=0|clid=6 serverftfid=8"#,
+		);
+		assert_eq!(transfers, vec![
+			ForeignFileTransfer { id: 7, ..transfer(0) },
+			ForeignFileTransfer {
+				id: 8,
+				client: ClientId(6),
+				channel: None,
+				path: "/".into(),
+				size: 0,
+				direction: TransferDirection::Download,
+			},
+		]);
+	}
+}
@@ -7,43 +7,74 @@
 //! enabled. Beware that functionality behind this feature may change on any minor release.
 //!
 //! The base class of this library is the [`Connection`]. One instance of this
-//! struct manages a single connection to a server.
+//! struct manages a single connection to a server. Multiple connections can be
+//! polled together with the [`ConnectionManager`].
 //!
 //! [`Connection`]: struct.Connection.html
+//! [`ConnectionManager`]: manager/struct.ConnectionManager.html
 //! [Qint]: https://github.com/ReSpeak/Qint
 // Needed for futures on windows.
 #![recursion_limit = "128"]
 
 use std::borrow::Cow;
+use std::cmp;
 use std::collections::VecDeque;
 use std::iter;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use ::time::OffsetDateTime;
 use futures::prelude::*;
+use rand::Rng;
 use slog::{debug, info, o, warn, Drain, Logger};
 use thiserror::Error;
 use tokio::io::AsyncWriteExt as _;
 use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 use tokio::time;
+use ts_bookkeeping::data::exts::{M2BClientEditExt, M2BClientUpdateExt};
 use ts_bookkeeping::messages::c2s;
 use ts_bookkeeping::messages::s2c::InMessage;
 use ts_bookkeeping::messages::OutMessageTrait;
 use tsproto::client;
 use tsproto::connection::StreamItem as ProtoStreamItem;
+pub use tsproto::connection::TrafficStats;
+pub use tsproto::log::{LogConfig, Verbosity};
+pub use tsproto::resend::ResendConfig;
 use tsproto::resend::ResenderState;
 #[cfg(feature = "audio")]
 use tsproto_packets::packets::InAudioBuf;
-use tsproto_packets::packets::{InCommandBuf, OutCommand};
+use tsproto_packets::commands::{CommandItem, CommandParser};
+use tsproto_packets::packets::{Direction, Flags, InCommandBuf, OutCommand, OutPacket, PacketType};
 
 #[cfg(feature = "audio")]
 pub mod audio;
+pub mod bans;
+mod build_info;
+pub mod capture;
+pub mod complaints;
+pub mod file_list;
+pub mod find;
+pub mod flood;
+pub mod foreign_transfers;
+pub mod manager;
+pub mod offline_messages;
+pub mod operation;
+pub mod policy;
 pub mod prelude;
+pub mod privilege_keys;
+#[cfg(feature = "socks5")]
+pub mod proxy;
 pub mod resolver;
+pub mod restore;
+pub mod server_log;
+pub mod status_display;
 pub mod sync;
+pub mod temp_passwords;
 
 // The build environment of tsclientlib.
 git_testament::git_testament!(TESTAMENT);
@@ -55,13 +86,92 @@ mod tests;
 pub use ts_bookkeeping::*;
 pub use tsproto::Identity;
 
-/// Wait this time for initserver, in seconds.
-const INITSERVER_TIMEOUT: u64 = 5;
+pub use build_info::{build_info, BuildInfo};
+
+/// The version of this crate.
+///
+/// Shadows the `VERSION` of `ts_bookkeeping`, which is available as
+/// [`BuildInfo::bookkeeping_version`].
+///
+/// [`BuildInfo::bookkeeping_version`]: struct.BuildInfo.html#structfield.bookkeeping_version
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Identical background errors are reported at most once in this interval.
+pub const BACKGROUND_ERROR_INTERVAL: Duration = Duration::from_secs(10);
+/// Wait this time before reconnecting again if the server did not say how
+/// long to wait.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// Try this many other names if our name is already in use.
+const NAME_RETRIES: u32 = 10;
+/// The maximum length of a nickname in characters.
+const MAX_NAME_LEN: usize = 30;
+/// The level of a new identity if no identity was supplied.
+const NEW_IDENTITY_LEVEL: u8 = 8;
+/// Give up on a requested list if the server did not finish it in this time.
+const LIST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The maximum size of a command in bytes, with escaped arguments.
+///
+/// Long commands are split into multiple packets, but receivers drop commands
+/// which are bigger than this after putting the packets together.
+pub const MAX_COMMAND_LEN: usize = 40960;
 
 type Result<T> = std::result::Result<T, Error>;
+/// Results in the connection, the bookkeeping and the name we got.
+type ConnectFuture =
+	future::BoxFuture<'static, Result<(client::Client, data::Connection, String)>>;
+/// Replaces connecting to a real server in tests.
+#[cfg(test)]
+type Connector = Box<dyn FnMut() -> ConnectFuture + Send>;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct MessageHandle(pub u16);
+
+/// An entry of a list, which the server sends as answer to a command.
+///
+/// Lists can be requested with [`Connection::send_list_command`].
+///
+/// [`Connection::send_list_command`]: struct.Connection.html#method.send_list_command
+pub trait ParseEntry: Sized + Send + 'static {
+	/// Parse the entries of a single notification.
+	fn parse(content: &[u8]) -> Vec<Self>;
+}
+
+/// The error that the server returned as the answer to a command.
+///
+/// Use [`TsError::is_permission_error`] and [`TsError::is_ban`] to check for
+/// categories of errors.
+///
+/// [`TsError::is_permission_error`]: enum.TsError.html#method.is_permission_error
+/// [`TsError::is_ban`]: enum.TsError.html#method.is_ban
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("{id}: {message}")]
+pub struct CommandError {
+	pub id: TsError,
+	/// The error message of the server.
+	pub message: String,
+	/// Additional information, e.g. the needed identity level.
+	pub extra_message: Option<String>,
+	/// The permission that was missing if the command failed because of
+	/// insufficient permissions.
+	pub failed_permission: Option<Permission>,
+	/// The command that failed.
+	pub return_code: Option<MessageHandle>,
+}
+
+impl CommandError {
+	/// The time until a ban ends.
+	///
+	/// The server tells banned clients when they can try again in the extra
+	/// message of the error, e.g. `You may retry in 600 seconds.`. It does not
+	/// tell how long it blocks our commands after flooding.
+	pub fn retry_after(&self) -> Option<Duration> {
+		if !self.id.is_ban() {
+			return None;
+		}
+		let msg = self.extra_message.as_ref()?.trim();
+		let secs = msg.strip_prefix("You may retry in ")?.strip_suffix(" seconds.")?;
+		secs.parse().ok().map(Duration::from_secs)
+	}
+}
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct FileTransferHandle(pub u16);
 
@@ -69,7 +179,21 @@ pub struct FileTransferHandle(pub u16);
 pub enum Error {
 	/// A command return an error.
 	#[error(transparent)]
-	CommandError(#[from] tsproto_types::errors::Error),
+	CommandError(#[from] CommandError),
+	/// Our socket could not be bound to the local address or device from the
+	/// [`ConnectOptions`].
+	///
+	/// This is only temporary if the device or address does not exist,
+	/// reconnecting does not help against e.g. missing permissions.
+	///
+	/// [`ConnectOptions`]: struct.ConnectOptions.html
+	#[error("Failed to bind our socket to {address}: {source}")]
+	Bind { address: SocketAddr, source: std::io::Error },
+	/// The connection was created with disabled bookkeeping.
+	#[error("Bookkeeping is disabled for this connection")]
+	BookkeepingDisabled,
+	#[error(transparent)]
+	Bookkeeping(#[from] ts_bookkeeping::Error),
 	#[error("Failed to connect: {0}")]
 	Connect(#[source] tsproto::client::Error),
 	#[error("Failed to connect to server at {address:?}: {errors:?}")]
@@ -77,10 +201,19 @@ pub enum Error {
 	/// The connection was destroyed.
 	#[error("Connection does not exist anymore")]
 	ConnectionGone,
+	#[error("Connection failed: {0}")]
+	ConnectionLost(#[source] tsproto::client::Error),
 	#[error("Server refused connection: {0}")]
-	ConnectTs(#[source] tsproto_types::errors::Error),
+	ConnectTs(#[source] CommandError),
 	#[error("File transfer failed: {0}")]
 	FileTransferIo(#[source] std::io::Error),
+	/// A command is bigger than [`MAX_COMMAND_LEN`].
+	///
+	/// [`MAX_COMMAND_LEN`]: constant.MAX_COMMAND_LEN.html
+	#[error("The command is too long ({0} bytes)")]
+	CommandTooLong(usize),
+	#[error("Failed to download host banner: {0}")]
+	HostBanner(#[source] reqwest::Error),
 	#[error("Failed to create identity: {0}")]
 	IdentityCreate(#[source] tsproto::Error),
 	#[error("The server needs an identity of level {0}, please increase your identity level")]
@@ -97,27 +230,130 @@ pub enum Error {
 	InitserverParamsMissing,
 	#[error("Failed to parse initserver: {0}")]
 	InitserverParse(#[source] ts_bookkeeping::messages::ParseError),
+	/// The handshake succeeded, but the server did not send initserver in
+	/// time.
 	#[error("Timeout while waiting for initserver")]
 	InitserverTimeout,
 	#[error("Failed to receive initserver: {0}")]
 	InitserverWait(#[source] tsproto::client::Error),
+	/// The signature of the version in the options is invalid.
+	#[error(transparent)]
+	InvalidVersion(#[from] tsproto_types::versions::InvalidSignatureLength),
 	#[error("Io error: {0}")]
 	Io(#[source] tokio::io::Error),
+	#[error("Failed to parse message: {0}")]
+	MessageParse(#[source] ts_bookkeeping::messages::ParseError),
 	/// The connection is currently not connected to a server but is in the process of connecting.
 	#[error("Currently not connected")]
 	NotConnected,
+	#[error("Failed to compute our own uid: {0}")]
+	OwnUid(#[source] tsproto_types::crypto::Error),
+	/// The action policy of the connection did not allow the action.
+	#[error("Action {0:?} is not allowed by the policy")]
+	PolicyDenied(policy::Action),
+	#[error("Connection got an error: {0}")]
+	Protocol(#[source] tsproto::Error),
+	/// Associating with the SOCKS5 proxy failed.
+	#[cfg(feature = "socks5")]
+	#[error("Failed to use the proxy: {0}")]
+	Proxy(#[source] proxy::ProxyError),
 	#[error("Failed to resolve address: {0}")]
 	ResolveAddress(#[source] resolver::Error),
 	#[error("Failed to send clientinit: {0}")]
 	SendClientinit(#[source] tsproto::client::Error),
 	#[error("Failed to send packet: {0}")]
 	SendPacket(#[source] tsproto::client::Error),
+	#[error("Failed to compute the uid of the server: {0}")]
+	ServerUid(#[source] tsproto_types::crypto::Error),
+	/// The public key of the server does not match the uid set with
+	/// [`ConnectOptions::expected_server_uid`].
+	///
+	/// [`ConnectOptions::expected_server_uid`]: struct.ConnectOptions.html#method.expected_server_uid
+	#[error(
+		"The server has the uid {}, but {} was expected",
+		base64::encode(&.actual.0),
+		base64::encode(&.expected.0)
+	)]
+	ServerUidMismatch { expected: Uid, actual: Uid },
+	/// No answer was received in time.
+	#[error("Request timed out")]
+	Timeout,
+}
+
+impl Error {
+	/// If trying again later can succeed.
+	///
+	/// E.g. a lost connection or a failed address resolution are temporary,
+	/// a wrong server password or a permanent ban are not.
+	pub fn is_temporary(&self) -> bool {
+		match self {
+			Error::CommandError(e) | Error::ConnectTs(e) => e.retry_after().is_some(),
+			Error::Connect(e) => !matches!(e, client::Error::OutdatedServer),
+			Error::ConnectionFailed { errors, .. } => errors.iter().any(Error::is_temporary),
+			Error::Bind { source, .. } => is_missing_interface(source),
+			Error::ConnectionLost(_)
+			| Error::FileTransferIo(_)
+			| Error::HostBanner(_)
+			| Error::InitserverTimeout
+			| Error::InitserverWait(_)
+			| Error::Io(_)
+			| Error::NotConnected
+			| Error::Protocol(_)
+			| Error::ResolveAddress(_)
+			| Error::SendClientinit(_)
+			| Error::SendPacket(_)
+			| Error::Timeout => true,
+			#[cfg(feature = "socks5")]
+			Error::Proxy(_) => true,
+			Error::Bookkeeping(_)
+			| Error::BookkeepingDisabled
+			| Error::CommandTooLong(_)
+			| Error::ConnectionGone
+			| Error::IdentityCreate(_)
+			| Error::IdentityLevel(_)
+			| Error::IdentityLevelCorrupted { .. }
+			| Error::IdentityLevelIncreaseFailed(_)
+			| Error::IdentityLevelIncreaseFailedThread
+			| Error::InitserverParamsMissing
+			| Error::InitserverParse(_)
+			| Error::InvalidVersion(_)
+			| Error::MessageParse(_)
+			| Error::OwnUid(_)
+			| Error::PolicyDenied(_)
+			| Error::ServerUid(_)
+			| Error::ServerUidMismatch { .. } => false,
+		}
+	}
+
+	/// How long to wait before trying again.
+	///
+	/// This is only known if the server sent it, e.g. when we are banned for
+	/// some time.
+	pub fn retry_after(&self) -> Option<Duration> {
+		match self {
+			Error::CommandError(e) | Error::ConnectTs(e) => e.retry_after(),
+			Error::ConnectionFailed { errors, .. } => {
+				errors.iter().filter_map(Error::retry_after).min()
+			}
+			_ => None,
+		}
+	}
 }
 
 pub trait OutCommandExt {
 	/// Adds a `return_code` to the command and returns if the corresponding
 	/// answer is received. If an error occurs, the future will return an error.
 	fn send(self, con: &mut Connection) -> Result<MessageHandle>;
+
+	/// Like [`send`], but the command is sent immediately, even if the
+	/// [`RateLimit`] of the connection is exhausted.
+	///
+	/// This can be used for urgent commands. It still counts towards the rate
+	/// limit, so later commands have to wait longer.
+	///
+	/// [`send`]: #tymethod.send
+	/// [`RateLimit`]: flood/struct.RateLimit.html
+	fn send_unthrottled(self, con: &mut Connection) -> Result<MessageHandle>;
 }
 
 /// The result of a download request.
@@ -149,6 +385,219 @@ pub struct FileUploadResult {
 	pub stream: TcpStream,
 }
 
+/// Statistics about the quality of the connection to the server.
+///
+/// These values are measured locally, they can be retrieved with
+/// [`Connection::get_network_stats`]. The values the server measured are
+/// stored in the `connection_data` of the server and can be requested with
+/// [`Server::request_connection_info`].
+///
+/// [`Connection::get_network_stats`]: struct.Connection.html#method.get_network_stats
+/// [`Server::request_connection_info`]: data/struct.Server.html#method.request_connection_info
+#[derive(Clone, Debug)]
+pub struct NetworkStats {
+	/// The smoothed round trip time.
+	pub rtt: time::Duration,
+	/// The deviation of the smoothed round trip time.
+	pub rtt_dev: time::Duration,
+	/// The round trip time of the last ping packet.
+	///
+	/// Pings are only sent while the connection is idle, so this is unset if
+	/// no ping was answered yet.
+	pub last_ping: Option<time::Duration>,
+	/// Sent, received and resent packets and bytes per packet type.
+	pub traffic: TrafficStats,
+	/// The number of packets that were acknowledged by the server.
+	pub acked_packets: u64,
+}
+
+impl NetworkStats {
+	/// The estimated packet loss in the range from `0` to `1`.
+	///
+	/// This is the ratio of resent packets to all packets that were sent
+	/// reliably.
+	pub fn packet_loss(&self) -> f32 {
+		let resent = self.traffic.packets_resent;
+		let total = resent + self.acked_packets;
+		if total == 0 { 0.0 } else { resent as f32 / total as f32 }
+	}
+}
+
+/// A message that was sent by a plugin of another client.
+///
+/// Plugin messages can be sent with [`data::Connection::send_plugin_message`].
+///
+/// [`data::Connection::send_plugin_message`]: data/struct.Connection.html#method.send_plugin_message
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PluginMessage {
+	/// The name of the plugin which should receive this message.
+	pub name: String,
+	pub data: String,
+	pub invoker: Invoker,
+}
+
+impl PluginMessage {
+	/// Parse all messages from a `notifyplugincmd`.
+	fn parse(content: &[u8]) -> Vec<Self> {
+		let (_, args) = CommandParser::new(content);
+		let mut res = Vec::new();
+		let mut name = None;
+		let mut data = None;
+		let mut invoker_id = None;
+		let mut invoker_name = String::new();
+		let mut invoker_uid = None;
+		for item in args.chain(iter::once(CommandItem::NextCommand)) {
+			match item {
+				CommandItem::Argument(arg) => {
+					let value = match arg.value().get_str() {
+						Ok(r) => r,
+						Err(_) => continue,
+					};
+					match arg.name() {
+						b"name" => name = Some(value.into_owned()),
+						b"data" => data = Some(value.into_owned()),
+						b"invokerid" => invoker_id = value.parse().ok().map(ClientId),
+						b"invokername" => invoker_name = value.into_owned(),
+						b"invokeruid" => invoker_uid = base64::decode(value.as_ref()).ok().map(Uid),
+						_ => {}
+					}
+				}
+				CommandItem::NextCommand => {
+					if let (Some(name), Some(id)) = (&name, invoker_id) {
+						res.push(Self {
+							name: name.clone(),
+							data: data.clone().unwrap_or_default(),
+							invoker: Invoker {
+								name: invoker_name.clone(),
+								id,
+								uid: invoker_uid.clone(),
+							},
+						});
+					}
+				}
+			}
+		}
+		res
+	}
+}
+
+/// The reason why sending audio is currently muted.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SendingMutedReason {
+	/// We do not have enough talk power in our current channel.
+	NoTalkPower,
+	/// Sending was muted with [`Connection::set_talk_override`].
+	///
+	/// [`Connection::set_talk_override`]: struct.Connection.html#method.set_talk_override
+	Override,
+}
+
+/// What to do if our name is already used by another client on the server.
+///
+/// The name is shortened if it gets too long with the appended suffix.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum NameCollisionStrategy {
+	/// Connecting fails with the error of the server.
+	Fail,
+	/// Try again with a number appended to the name: `Name1`, `Name2`, …
+	AppendNumber,
+	/// Try again with a random number appended to the name.
+	AppendRandom,
+}
+
+/// Why the connection to the server ended.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum DisconnectReason {
+	/// We disconnected with [`Connection::disconnect`].
+	///
+	/// [`Connection::disconnect`]: struct.Connection.html#method.disconnect
+	Client,
+	/// Our client was kicked from the server.
+	Kicked { invoker: Option<Invoker>, message: Option<String> },
+	/// Our client was banned from the server.
+	///
+	/// The duration is `None` for permanent bans, the server sends them with a
+	/// ban time of 0.
+	Banned {
+		invoker: Option<Invoker>,
+		message: Option<String>,
+		duration: Option<::time::Duration>,
+	},
+	/// The server shut down.
+	ServerShutdown { message: Option<String> },
+	/// The server did not tell us why the connection ended, e.g. because it
+	/// timed out.
+	ConnectionLost,
+}
+
+/// The part of a connection where a [`BackgroundError`] happened.
+///
+/// [`BackgroundError`]: struct.BackgroundError.html
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ErrorComponent {
+	/// The underlying udp connection.
+	Connection,
+	/// Parsing a command from the server.
+	Parser,
+	/// Applying a command from the server to the bookkeeping.
+	Bookkeeping,
+}
+
+/// An error that happened while handling the connection in the background.
+///
+/// These errors are not the result of a user action, so they are returned as
+/// [`StreamItem::BackgroundError`].
+///
+/// [`StreamItem::BackgroundError`]: enum.StreamItem.html#variant.BackgroundError
+#[derive(Debug)]
+pub struct BackgroundError {
+	pub component: ErrorComponent,
+	pub error: Error,
+	/// If the connection could not continue after this error.
+	///
+	/// A fatal error is followed by a [`StreamItem::DisconnectedTemporarily`]
+	/// and the connection is rebuilt.
+	///
+	/// [`StreamItem::DisconnectedTemporarily`]: enum.StreamItem.html#variant.DisconnectedTemporarily
+	pub fatal: bool,
+	/// How often the same error happened since it was reported the last time.
+	///
+	/// Identical errors are only reported once per
+	/// [`BACKGROUND_ERROR_INTERVAL`].
+	///
+	/// [`BACKGROUND_ERROR_INTERVAL`]: constant.BACKGROUND_ERROR_INTERVAL.html
+	pub repeated: u32,
+}
+
+/// Changes several properties of our own client in a single command.
+///
+/// Created by [`Connection::edit_own_client`]. Properties which are not set
+/// keep their current value. The bookkeeping is updated when the server
+/// confirms the change.
+///
+/// [`Connection::edit_own_client`]: struct.Connection.html#method.edit_own_client
+#[must_use = "the changes are only sent by calling `send`"]
+pub struct OwnClientEdit<'a> {
+	con: &'a mut Connection,
+	name: Option<String>,
+	away: Option<Option<String>>,
+	input_muted: Option<bool>,
+	output_muted: Option<bool>,
+	input_hardware: Option<bool>,
+	output_hardware: Option<bool>,
+	channel_commander: Option<bool>,
+}
+
+/// Remembers the last non-fatal background error to suppress repetitions.
+#[derive(Debug, Default)]
+struct ErrorLimiter {
+	last: Option<(ErrorComponent, String, Instant)>,
+	suppressed: u32,
+	/// The last suppressed repetition of the last error.
+	last_suppressed: Option<Error>,
+}
+
 /// An event that gets returned by the connection.
 ///
 /// A stream of these events is returned by [`Connection::events`].
@@ -161,6 +610,10 @@ pub enum StreamItem {
 	/// If a connection to the server was established this will contain an added
 	/// event of a server.
 	ConEvents(Vec<events::Event>),
+	/// The incoming events of a notification, classified by what happened.
+	///
+	/// These are sent after the corresponding [`ConEvents`](#variant.ConEvents).
+	TypedEvents(Vec<events::TypedEvent>),
 	/// Received an audio packet.
 	///
 	/// Audio packets can be handled by the [`AudioHandler`], which builds a
@@ -171,8 +624,19 @@ pub enum StreamItem {
 	Audio(InAudioBuf),
 	/// The needed level.
 	IdentityLevelIncreasing(u8),
+	/// The identity reached a better level while increasing it, but not yet
+	/// the needed level.
+	IdentityLevelProgress {
+		/// The best level that was found so far.
+		level: u8,
+		/// The hash cash counter that is tried next.
+		counter: u64,
+	},
 	/// This event may occur without an `IdentityLevelIncreasing` event before
 	/// if a new identity is created because no identity was supplied.
+	///
+	/// The improved identity can be retrieved with
+	/// [`Connection::get_identity`](struct.Connection.html#method.get_identity).
 	IdentityLevelIncreased,
 	/// The connection timed out or the server shut down. The connection will be
 	/// rebuilt automatically.
@@ -180,11 +644,13 @@ pub enum StreamItem {
 	/// The result of sending a message.
 	///
 	/// The [`MessageHandle`] is the return value of
-	/// [`Connection::send_command`].
+	/// [`Connection::send_command`]. The results of list requests are
+	/// returned by the future of [`Connection::send_list_command`] instead.
 	///
 	/// [`MessageHandle`]: struct.MessageHandle.html
 	/// [`Connection::send_command`]: struct.Connection.html#method.send_command
-	MessageResult(MessageHandle, std::result::Result<(), TsError>),
+	/// [`Connection::send_list_command`]: struct.Connection.html#method.send_list_command
+	MessageResult(MessageHandle, std::result::Result<(), CommandError>),
 	/// A file download succeeded. This event contains the `TcpStream` where the
 	/// file can be downloaded.
 	///
@@ -215,42 +681,125 @@ pub enum StreamItem {
 	/// [`Connection::download_file`]: struct.Connection.html#method.download_file
 	/// [`Connection::upload_file`]: struct.Connection.html#method.upload_file
 	FileTransferFailed(FileTransferHandle, Error),
+	/// Sending audio is muted, audio packets are dropped from now on.
+	///
+	/// Encoding audio can be paused until a [`SendingUnmuted`] event is
+	/// received.
+	///
+	/// [`SendingUnmuted`]: #variant.SendingUnmuted
+	SendingMuted(SendingMutedReason),
+	/// Sending audio is possible again.
+	SendingUnmuted,
+	/// Received a message from a plugin of another client.
+	PluginMessage(PluginMessage),
+	/// A file transfer of another client started.
+	///
+	/// Transfers of other clients are requested with
+	/// [`Connection::request_file_transfers`].
+	///
+	/// [`Connection::request_file_transfers`]: struct.Connection.html#method.request_file_transfers
+	ForeignFileTransfer(foreign_transfers::ForeignFileTransfer),
+	/// An error happened in the background, e.g. a message from the server
+	/// could not be parsed.
+	BackgroundError(BackgroundError),
+	/// An action was denied or confirmed by the [`ActionPolicy`].
+	///
+	/// [`ActionPolicy`]: policy/struct.ActionPolicy.html
+	PolicyDecision(policy::AuditEntry),
+	/// A part of our client state could not be restored after reconnecting.
+	///
+	/// See [`ConnectOptions::restore_state`].
+	///
+	/// [`ConnectOptions::restore_state`]: struct.ConnectOptions.html#method.restore_state
+	RestoreFailed(restore::RestoreFailure),
 }
 
 /// The `Connection` is the main interaction point with this library.
 ///
 /// It represents a connection to a TeamSpeak server. It will reconnect
-/// automatically when the connection times out (though timeout is not yet
-/// implemented). It will not reconnect which the client is kicked or banned
-/// from the server.
+/// automatically when the connection times out or the server shuts down. It
+/// will not reconnect when the client is kicked or banned from the server,
+/// see [`DisconnectReason::is_removal`]. Failed reconnects are retried as long as the error is
+/// temporary, see [`Error::is_temporary`].
+///
+/// [`Error::is_temporary`]: enum.Error.html#method.is_temporary
+/// [`DisconnectReason::is_removal`]: enum.DisconnectReason.html#method.is_removal
 pub struct Connection {
 	state: ConnectionState,
 	logger: Logger,
-	options: ConnectOptions,
+	/// Shared with the connect future, so reconnecting does not need to clone
+	/// the options.
+	options: Arc<ConnectOptions>,
 	stream_items: VecDeque<Result<StreamItem>>,
+	/// Set if we should not send audio currently.
+	sending_muted: Option<SendingMutedReason>,
+	/// Overwrites if we can talk, set by the user.
+	talk_override: Option<bool>,
+	/// Set when the server removed our client or when we disconnect.
+	disconnect_reason: Option<DisconnectReason>,
+	/// Set while the connection is rebuilt after it was lost.
+	reconnecting: bool,
+	/// Changes of our own client, which are applied again after reconnecting.
+	desired_state: restore::DesiredState,
+	#[cfg(test)]
+	connector: Option<Connector>,
 }
 
 struct ConnectedConnection {
 	client: client::Client,
+	own_client: ClientId,
+	/// The name which the server accepted.
+	name: String,
+	/// When the handshake with the server completed.
+	connected_since: OffsetDateTime,
 	cur_return_code: u16,
 	cur_file_transfer_id: u16,
+	/// Requested lists, where we collect the answers until the request is
+	/// finished.
+	pending_lists: Vec<PendingList>,
+	error_limiter: ErrorLimiter,
+	/// File transfers of other clients which we saw lately.
 	/// If a file stream can be opened, it gets put in here until the tcp
 	/// connection is ready and the key is sent.
 	///
 	/// Afterwards we can directly return a `TcpStream` in the event stream.
 	file_transfers: Vec<future::BoxFuture<'static, StreamItem>>,
+	/// Commands which wait until they can be sent without flooding.
+	commands: flood::CommandQueue,
+	/// The url of the last downloaded host banner and when it was downloaded.
+	///
+	/// Shared with the download future, which sets it when the download
+	/// succeeded.
+	host_banner_download: Arc<Mutex<Option<(String, Instant)>>>,
+	/// Restored when the channel list is complete after reconnecting.
+	restore: Option<restore::DesiredState>,
+	/// The channel and password of the last move of our client that we sent.
+	move_password: Option<(ChannelId, String)>,
+}
+
+struct PendingList {
+	handle: MessageHandle,
+	/// The content of all received answers.
+	parts: Vec<Vec<u8>>,
+	/// A `futures` channel, it can be checked if the receiver was dropped.
+	send: futures::channel::oneshot::Sender<Result<Vec<Vec<u8>>>>,
 }
 
 enum ConnectionState {
-	Connecting(future::BoxFuture<'static, Result<(client::Client, data::Connection)>>),
+	/// No identity was supplied, the level of the new identity is computed.
+	CreatingIdentity(future::BoxFuture<'static, Result<Identity>>),
+	Connecting(ConnectFuture),
 	IdentityLevelIncreasing {
 		/// We get the improved identity here.
 		recv: oneshot::Receiver<std::result::Result<Identity, tsproto::Error>>,
+		/// The best level and the current counter whenever the level improves.
+		progress: mpsc::UnboundedReceiver<(u8, u64)>,
 		state: Arc<Mutex<IdentityIncreaseLevelState>>,
 	},
 	Connected {
 		con: ConnectedConnection,
-		book: data::Connection,
+		/// `None` if bookkeeping is disabled in the `ConnectOptions`.
+		book: Option<data::Connection>,
 	},
 }
 
@@ -334,6 +883,8 @@ impl Connection {
 	///
 	/// [`ConnectOptions`]: struct.ConnectOptions.html
 	pub fn new(mut options: ConnectOptions) -> Result<Self> {
+		options.version.validate()?;
+		check_meta_data_len(&options.meta_data)?;
 		let logger = options.logger.take().unwrap_or_else(|| {
 			let decorator = slog_term::TermDecorator::new().build();
 			let drain = slog_term::CompactFormat::new(decorator).build().fuse();
@@ -351,36 +902,43 @@ impl Connection {
 			"version" => git_testament::render_testament!(TESTAMENT),
 			"profile" => profile,
 			"tsproto-version" => git_testament::render_testament!(tsproto::get_testament()),
+			"declarations-version" => ts_bookkeeping::DECLARATIONS_VERSION,
+			"target" => build_info().target,
+			"features" => build_info().features().collect::<Vec<_>>().join(","),
 		);
 
 		let logger = logger.new(o!("addr" => options.address.to_string()));
 
-		let mut stream_items = VecDeque::new();
-		options.identity = Some(
-			options
-				.identity
-				.take()
-				.map(Ok)
-				.unwrap_or_else(|| {
-					// Create new ECDH key
-					let id = Identity::create();
-					if id.is_ok() {
-						// Send event
-						stream_items.push_back(Ok(StreamItem::IdentityLevelIncreased));
-					}
-					id
-				})
-				.map_err(Error::IdentityCreate)?,
-		);
+		let create_identity = options.identity.is_none();
+		if create_identity {
+			// Create new ECDH key, the level is computed before connecting
+			options.identity = Some(Identity::create_with_level(0).map_err(Error::IdentityCreate)?);
+		}
 
-		// Try all addresses
-		let fut = Self::connect(logger.clone(), options.clone());
+		let options = Arc::new(options);
+		let state = if create_identity {
+			let identity = options.identity.clone().unwrap();
+			ConnectionState::CreatingIdentity(Box::pin(Self::create_identity(
+				logger.clone(),
+				identity,
+			)))
+		} else {
+			// Try all addresses
+			ConnectionState::Connecting(Box::pin(Self::connect(logger.clone(), options.clone())))
+		};
 
 		Ok(Self {
-			state: ConnectionState::Connecting(Box::pin(fut)),
+			state,
 			logger,
 			options,
-			stream_items,
+			stream_items: Default::default(),
+			sending_muted: None,
+			talk_override: None,
+			disconnect_reason: None,
+			reconnecting: false,
+			desired_state: Default::default(),
+			#[cfg(test)]
+			connector: None,
 		})
 	}
 
@@ -390,6 +948,40 @@ impl Connection {
 	/// level needs to be improved.
 	pub fn get_options(&self) -> &ConnectOptions { &self.options }
 
+	/// The identity of this connection.
+	///
+	/// If the identity level was increased while connecting, this contains the
+	/// new counter and should be stored instead of the original identity.
+	pub fn get_identity(&self) -> &Identity {
+		// The key is created in `new` if no identity was supplied
+		self.options.identity.as_ref().unwrap()
+	}
+
+	/// The uid of our own client, computed from the key of the identity.
+	///
+	/// This is known before connecting, so it can be compared with the uids
+	/// of invokers even before the server sent our own client.
+	pub fn own_uid(&self) -> Result<Uid> {
+		let key = self.get_identity().key().to_pub();
+		key.get_uid_no_base64().map(Uid).map_err(Error::OwnUid)
+	}
+
+	/// The name that the server accepted for our client.
+	///
+	/// This differs from the name in the options if it was already in use,
+	/// see [`ConnectOptions::name_collision_strategy`].
+	///
+	/// Fails if the connection is currently not connected to the server.
+	///
+	/// [`ConnectOptions::name_collision_strategy`]: struct.ConnectOptions.html#method.name_collision_strategy
+	pub fn get_name(&self) -> Result<&str> {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			Ok(&con.name)
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
 	/// Get a stream of events. The event stream needs to be polled, otherwise
 	/// nothing will happen in a connection, not even sending packets will work.
 	///
@@ -398,25 +990,80 @@ impl Connection {
 		EventStream(self)
 	}
 
+	/// Compute the hash cash level of a new identity on the blocking thread
+	/// pool, so it does not stall other connections on the same executor
+	/// thread.
+	async fn create_identity(logger: Logger, mut identity: Identity) -> Result<Identity> {
+		tokio::task::spawn_blocking(move || {
+			let mut time_reporter = slog_perf::TimeReporter::new_with_level(
+				"Compute identity level",
+				logger,
+				slog::Level::Info,
+			);
+			time_reporter.start("");
+			let res = identity.upgrade_level(NEW_IDENTITY_LEVEL);
+			time_reporter.finish();
+			res.map(|()| identity)
+		})
+		.await
+		.map_err(|_| Error::IdentityLevelIncreaseFailedThread)?
+		.map_err(Error::IdentityCreate)
+	}
+
+	/// Start connecting to the server again.
+	fn reconnect_future(&mut self) -> ConnectFuture {
+		#[cfg(test)]
+		{
+			if let Some(connector) = &mut self.connector {
+				return connector();
+			}
+		}
+		Box::pin(Self::connect(self.logger.clone(), self.options.clone()))
+	}
+
 	async fn connect(
-		logger: Logger, options: ConnectOptions,
-	) -> Result<(client::Client, data::Connection)> {
-		let resolved = match &options.address {
-			ServerAddress::SocketAddr(a) => stream::once(future::ok(*a)).left_stream(),
-			ServerAddress::Other(s) => resolver::resolve(logger.clone(), s.into()).right_stream(),
-		};
-		pin_utils::pin_mut!(resolved);
-		let mut resolved: Pin<_> = resolved;
+		logger: Logger, options: Arc<ConnectOptions>,
+	) -> Result<(client::Client, data::Connection, String)> {
+		Self::check_bind(&options)?;
+
+		#[cfg(feature = "socks5")]
+		{
+			let name = match &options.proxy {
+				Some(p) => p.name_to_resolve(&options.address).map_err(Error::Proxy)?,
+				None => None,
+			};
+			if let Some((_, port)) = name {
+				// The proxy resolves the name, the socket knows where to send
+				let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+				return Self::connect_to(&logger, &options, addr).await;
+			}
+		}
+
+		let mut resolved =
+			resolver::resolve_server_address(logger.clone(), &options.address, &options.dns_config);
+		// Try the other servers if one server cannot be resolved
+		let multiple = matches!(options.address, ServerAddress::Multiple(_));
 
 		let mut errors = Vec::new();
 		while let Some(addr) = resolved.next().await {
-			let addr = addr.map_err(Error::ResolveAddress)?;
+			let addr = match addr {
+				Ok(a) => a,
+				Err(e) if multiple => {
+					info!(logger, "Resolving failed, trying next address"; "error" => %e);
+					errors.push(Error::ResolveAddress(e));
+					continue;
+				}
+				Err(e) => return Err(Error::ResolveAddress(e)),
+			};
 			match Self::connect_to(&logger, &options, addr).await {
 				Ok(res) => return Ok(res),
 				Err(e @ Error::IdentityLevel(_)) | Err(e @ Error::ConnectTs(_)) => {
 					// Either increase identity level or the server refused us
 					return Err(e);
 				}
+				Err(e @ Error::ServerUidMismatch { .. }) | Err(e @ Error::Bind { .. }) => {
+					return Err(e);
+				}
 				Err(e) => {
 					info!(logger, "Connecting failed, trying next address";
 						"error" => %e);
@@ -424,98 +1071,163 @@ impl Connection {
 				}
 			}
 		}
+		// The cached addresses do not work, resolve again next time
+		resolver::remove_address_from_cache(&options.address);
 		Err(Error::ConnectionFailed { address: options.address.to_string(), errors })
 	}
 
+	/// Check if our socket can be bound, before the server address is
+	/// resolved.
+	fn check_bind(options: &ConnectOptions) -> Result<()> {
+		if options.local_address.is_none() && options.bind_device.is_none() {
+			return Ok(());
+		}
+		let address = match options.local_address {
+			// The scope is taken from the server address when connecting
+			Some(SocketAddr::V6(a)) if needs_scope_id(&a) => "[::]:0".parse().unwrap(),
+			Some(a) => a,
+			None => "0.0.0.0:0".parse().unwrap(),
+		};
+		bind_socket(address, options.get_bind_device())
+			.map(drop)
+			.map_err(|source| Error::Bind { address, source })
+	}
+
+	/// Bind the udp socket, which goes through the proxy if one is set.
+	async fn create_socket(
+		options: &ConnectOptions, addr: SocketAddr,
+	) -> Result<Box<dyn tsproto::connection::Socket + Send>> {
+		// With a proxy, we send our datagrams to the proxy
+		#[cfg(feature = "socks5")]
+		let peer = options.proxy.as_ref().map(|p| p.get_address()).unwrap_or(addr);
+		#[cfg(not(feature = "socks5"))]
+		let peer = addr;
+		let address = local_address_for(options.local_address, peer);
+		let socket = bind_socket(address, options.get_bind_device())
+			.and_then(UdpSocket::from_std)
+			.map_err(|source| Error::Bind { address, source })?;
+
+		#[cfg(feature = "socks5")]
+		{
+			if let Some(config) = &options.proxy {
+				let socket = proxy::ProxySocket::associate(config, socket, addr, &options.address)
+					.await
+					.map_err(Error::Proxy)?;
+				return Ok(Box::new(socket));
+			}
+		}
+		Ok(Box::new(socket))
+	}
+
 	async fn connect_to(
 		logger: &Logger, options: &ConnectOptions, addr: SocketAddr,
-	) -> Result<(client::Client, data::Connection)> {
+	) -> Result<(client::Client, data::Connection, String)> {
 		let counter = options.identity.as_ref().unwrap().counter();
-		let socket = Box::new(
-			UdpSocket::bind(options.local_address.unwrap_or_else(|| {
-				if addr.is_ipv4() {
-					"0.0.0.0:0".parse().unwrap()
-				} else {
-					"[::]:0".parse().unwrap()
-				}
-			}))
-			.await
-			.map_err(Error::Io)?,
-		);
+		let socket = Self::create_socket(options, addr).await?;
 		let mut client = client::Client::new(
 			logger.clone(),
 			addr,
 			socket,
 			options.identity.as_ref().unwrap().key().clone(),
 		);
+		// Before the handshake, so the connecting timeout applies
+		client.resender.set_config(options.resend_config.clone());
 
 		// Logging
-		let verbosity = if options.log_packets {
-			3
-		} else if options.log_udp_packets {
-			2
-		} else if options.log_commands {
-			1
-		} else {
-			0
-		};
-		if verbosity > 0 {
-			tsproto::log::add_logger(logger.clone(), verbosity, &mut *client);
+		let log_config = options.get_log_config();
+		if log_config.is_enabled() {
+			tsproto::log::add_logger_with_config(logger.clone(), log_config, &mut *client);
+		}
+		if let Some(path) = &options.capture_to {
+			capture::add_capture(logger.clone(), path.clone(), &mut *client);
 		}
 
 		// Create a connection
 		debug!(logger, "Connecting"; "address" => %addr);
 		client.connect().await.map_err(Error::Connect)?;
+		// Check the server before sending our data
+		if let Some(expected) = &options.expected_server_uid {
+			let actual = Self::get_server_uid(&client)?;
+			if actual != *expected {
+				return Err(Error::ServerUidMismatch { expected: expected.clone(), actual });
+			}
+		}
 
 		// Create clientinit packet
 		let client_version = options.version.get_version_string();
 		let client_platform = options.version.get_platform();
 		let client_version_sign = base64::encode(options.version.get_signature());
+		let badges = options.badges.as_ref().map(ToString::to_string);
 
-		let packet = c2s::OutClientInitMessage::new(&mut iter::once(c2s::OutClientInitPart {
-			name: &options.name,
-			client_version: &client_version,
-			client_platform: &client_platform,
-			input_hardware_enabled: true,
-			output_hardware_enabled: true,
-			default_channel: options.channel.as_ref().map(AsRef::as_ref).unwrap_or_default(),
-			default_channel_password: options
-				.channel_password
-				.as_ref()
-				.map(AsRef::as_ref)
-				.unwrap_or_default(),
-			password: options.password.as_ref().map(AsRef::as_ref).unwrap_or_default(),
-			metadata: "",
-			client_version_sign: &client_version_sign,
-			client_key_offset: counter,
-			phonetic_name: "",
-			default_token: "",
-			hardware_id: &options.hardware_id,
-			badges: None,
-			signed_badges: None,
-			integrations: None,
-			active_integrations_info: None,
-			my_team_speak_avatar: None,
-			my_team_speak_id: None,
-			security_hash: None,
-		}));
-
-		client.send_packet(packet.into_packet()).map_err(Error::SendClientinit)?;
+		let mut attempt = 0;
+		let mut name = Cow::Borrowed(options.name.as_ref());
+		loop {
+			let packet = c2s::OutClientInitMessage::new(&mut iter::once(c2s::OutClientInitPart {
+				name: &name,
+				client_version: &client_version,
+				client_platform: &client_platform,
+				input_hardware_enabled: options.input_hardware,
+				output_hardware_enabled: options.output_hardware,
+				default_channel: options.channel.as_ref().map(AsRef::as_ref).unwrap_or_default(),
+				default_channel_password: options
+					.channel_password
+					.as_ref()
+					.map(AsRef::as_ref)
+					.unwrap_or_default(),
+				password: options.password.as_ref().map(AsRef::as_ref).unwrap_or_default(),
+				metadata: &options.meta_data,
+				client_version_sign: &client_version_sign,
+				client_key_offset: counter,
+				phonetic_name: "",
+				default_token: "",
+				hardware_id: &options.hardware_id,
+				badges: badges.as_deref(),
+				signed_badges: None,
+				integrations: None,
+				active_integrations_info: None,
+				my_team_speak_avatar: None,
+				my_team_speak_id: None,
+				security_hash: None,
+			}));
 
-		match time::timeout(
-			time::Duration::from_secs(INITSERVER_TIMEOUT),
-			Self::wait_initserver(logger, client),
-		)
-		.await
-		{
-			Ok(r) => r,
-			Err(_) => Err(Error::InitserverTimeout),
+			client.send_packet(packet.into_packet()).map_err(Error::SendClientinit)?;
+
+			// Dropping the client on errors closes the socket
+			let timeout = options.resend_config.connecting_timeout;
+			let res = Self::wait_initserver_timeout(logger, &mut client, timeout).await;
+			match res {
+				Err(Error::ConnectTs(e)) if e.id == TsError::ClientNicknameInuse => {
+					// Try again on the same connection with another name
+					attempt += 1;
+					let strategy = options.name_collision_strategy;
+					if let Some(n) = strategy.get_name(&options.name, attempt) {
+						info!(logger, "Name is already in use, trying another one";
+							"name" => %n);
+						name = n;
+					} else {
+						return Err(Error::ConnectTs(e));
+					}
+				}
+				r => return r.map(|book| (client, book, name.into_owned())),
+			}
 		}
 	}
 
+	/// Wait for initserver, but at most `timeout`.
+	///
+	/// Some servers or proxies complete the handshake but never send
+	/// initserver, so we cannot wait forever.
+	async fn wait_initserver_timeout(
+		logger: &Logger, client: &mut client::Client, timeout: Duration,
+	) -> Result<data::Connection> {
+		time::timeout(timeout, Self::wait_initserver(logger, client))
+			.await
+			.unwrap_or_else(|_| Err(Error::InitserverTimeout))
+	}
+
 	async fn wait_initserver(
-		logger: &Logger, mut client: client::Client,
-	) -> Result<(client::Client, data::Connection)> {
+		logger: &Logger, client: &mut client::Client,
+	) -> Result<data::Connection> {
 		// Wait until we received the initserver packet.
 		loop {
 			let cmd = client
@@ -536,7 +1248,13 @@ impl Connection {
 							return Err(Error::IdentityLevel(needed));
 						}
 					}
-					return Err(Error::ConnectTs(e.id));
+					return Err(Error::ConnectTs(CommandError {
+						id: e.id,
+						message: e.message.to_string(),
+						extra_message: e.extra_message.as_ref().map(|m| m.to_string()),
+						failed_permission: e.missing_permission_id,
+						return_code: None,
+					}));
 				}
 				Ok(InMessage::InitServer(initserver)) => {
 					let public_key = {
@@ -552,7 +1270,7 @@ impl Connection {
 					// Create connection
 					let data = data::Connection::new(public_key, &initserver);
 
-					return Ok((client, data));
+					return Ok(data);
 				}
 				Ok(msg) => {
 					// TODO Save instead of drop
@@ -568,7 +1286,7 @@ impl Connection {
 	/// If this returns `None`, the level was increased and we should try
 	/// connecting again.
 	fn increase_identity_level(&mut self, needed: u8) -> Result<()> {
-		if needed > 20 {
+		if needed > self.options.max_level_upgrade {
 			return Err(Error::IdentityLevel(needed));
 		}
 
@@ -581,28 +1299,123 @@ impl Connection {
 		// Increase identity level
 		let state = Arc::new(Mutex::new(IdentityIncreaseLevelState::Computing));
 		let (send, recv) = oneshot::channel();
+		let (progress_send, progress) = mpsc::unbounded_channel();
+		let thread_state = state.clone();
 		// TODO Time estimate
 		std::thread::spawn(move || {
 			let mut identity = identity;
-			// TODO Check if canceled in between
-			let r = identity.upgrade_level(needed);
+			let mut best = level;
+			let r = loop {
+				if let IdentityIncreaseLevelState::Canceled =
+					*thread_state.lock().unwrap_or_else(|e| e.into_inner())
+				{
+					return;
+				}
+				match identity
+					.improve_level_steps(needed, tsproto::HASH_CASH_CHUNK_SIZE)
+					.and_then(|done| Ok((done, identity.level()?)))
+				{
+					Ok((false, level)) => {
+						if level > best {
+							best = level;
+							let _ = progress_send.send((level, identity.max_counter()));
+						}
+					}
+					Ok((true, _)) => break Ok(()),
+					Err(e) => break Err(e),
+				}
+			};
 			let _ = send.send(r.map(|()| identity));
 		});
 
-		self.state = ConnectionState::IdentityLevelIncreasing { recv, state };
+		self.state = ConnectionState::IdentityLevelIncreasing { recv, progress, state };
 		Ok(())
 	}
 
 	/// Adds a `return_code` to the command and returns if the corresponding
 	/// answer is received. If an error occurs, the future will return an error.
 	fn send_command(&mut self, packet: OutCommand) -> Result<MessageHandle> {
+		self.send_checked_command(packet, false, true)
+	}
+
+	/// Send a command which the library needs on its own.
+	///
+	/// It bypasses the rate limit, so it does not wait behind the commands of
+	/// the user.
+	fn send_internal_command(&mut self, packet: OutCommand) -> Result<MessageHandle> {
+		self.send_checked_command(packet, false, false)
+	}
+
+	/// Send a command that was created by the user.
+	#[cfg(feature = "unstable")]
+	fn send_raw_command(&mut self, packet: OutCommand) -> Result<MessageHandle> {
+		self.send_checked_command(packet, true, true)
+	}
+
+	/// Check the command against the action policy and send it.
+	///
+	/// If `throttled` is `false`, the command bypasses the rate limit.
+	fn send_checked_command(
+		&mut self, packet: OutCommand, raw: bool, throttled: bool,
+	) -> Result<MessageHandle> {
 		if let ConnectionState::Connected { con, .. } = &mut self.state {
-			con.send_command(packet)
+			if let Some(entry) = self.options.action_policy.check(packet.0.content(), raw) {
+				info!(self.logger, "Checked action"; "action" => ?entry.action,
+					"decision" => ?entry.decision);
+				let allowed = entry.decision == policy::Decision::Confirmed;
+				let action = entry.action.clone();
+				self.stream_items.push_back(Ok(StreamItem::PolicyDecision(entry)));
+				if !allowed {
+					return Err(Error::PolicyDenied(action));
+				}
+			}
+			if let Some(password) =
+				restore::DesiredState::sent_password(con.own_client, packet.0.content())
+			{
+				con.move_password = Some(password);
+			}
+			if throttled { con.send_command(packet) } else { con.send_unthrottled_command(packet) }
 		} else {
 			Err(Error::NotConnected)
 		}
 	}
 
+	/// Send a command that requests a list and collect the answers.
+	///
+	/// The server can split a list into multiple notifications. They are
+	/// recognized by the return code of the command, so other notifications
+	/// can arrive in between. The returned future resolves to the entries of
+	/// all notifications when the server answered the command. An empty list
+	/// is no error.
+	///
+	/// The future fails with [`Error::Timeout`] if the server does not finish
+	/// the list within 30 seconds. It has to be polled while the connection
+	/// is polled, the connection receives the answers.
+	///
+	/// [`Error::Timeout`]: enum.Error.html#variant.Timeout
+	pub fn send_list_command<T: ParseEntry>(
+		&mut self, packet: OutCommand,
+	) -> future::BoxFuture<'static, Result<Vec<T>>> {
+		let handle = match self.send_command(packet) {
+			Ok(r) => r,
+			Err(e) => return future::err(e).boxed(),
+		};
+		let (send, recv) = futures::channel::oneshot::channel();
+		if let ConnectionState::Connected { con, .. } = &mut self.state {
+			// Forget lists whose future was dropped
+			con.pending_lists.retain(|l| !l.send.is_canceled());
+			con.pending_lists.push(PendingList { handle, parts: Vec::new(), send });
+		}
+		async move {
+			let parts = time::timeout(LIST_TIMEOUT, recv)
+				.await
+				.map_err(|_| Error::Timeout)?
+				.map_err(|_| Error::ConnectionGone)??;
+			Ok(parts.iter().flat_map(|p| T::parse(p)).collect())
+		}
+		.boxed()
+	}
+
 	/// Cancels the computation to increase the identity level.
 	///
 	/// This function initiates the cancellation and immediately returns. It
@@ -611,7 +1424,8 @@ impl Connection {
 	/// Does nothing if the identity level is currently not increased.
 	pub fn cancel_identity_level_increase(&mut self) {
 		if let ConnectionState::IdentityLevelIncreasing { state, .. } = &mut self.state {
-			*state.lock().unwrap() = IdentityIncreaseLevelState::Canceled;
+			// The state is a plain value, so it is still valid if the lock is poisoned
+			*state.lock().unwrap_or_else(|e| e.into_inner()) = IdentityIncreaseLevelState::Canceled;
 		}
 	}
 
@@ -629,7 +1443,12 @@ impl Connection {
 
 	/// Get access to the raw connection.
 	///
+	/// Packets which are sent directly through the raw connection are not
+	/// checked by the [`ActionPolicy`].
+	///
 	/// Fails if the connection is currently not connected to the server.
+	///
+	/// [`ActionPolicy`]: policy/struct.ActionPolicy.html
 	#[cfg(feature = "unstable")]
 	pub fn get_tsproto_client_mut(&mut self) -> Result<&mut client::Client> {
 		if let ConnectionState::Connected { con, .. } = &mut self.state {
@@ -639,6 +1458,28 @@ impl Connection {
 		}
 	}
 
+	/// The uid of the server, which is computed from its public key.
+	///
+	/// The uid can be stored after the first connection and passed to
+	/// [`ConnectOptions::expected_server_uid`] for later connections to make
+	/// sure that we connect to the same server.
+	///
+	/// Fails if the connection is currently not connected to the server.
+	///
+	/// [`ConnectOptions::expected_server_uid`]: struct.ConnectOptions.html#method.expected_server_uid
+	pub fn server_uid(&self) -> Result<Uid> {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			Self::get_server_uid(&con.client)
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
+	fn get_server_uid(client: &client::Client) -> Result<Uid> {
+		let params = client.params.as_ref().ok_or(Error::InitserverParamsMissing)?;
+		params.public_key.get_uid_no_base64().map(Uid).map_err(Error::ServerUid)
+	}
+
 	/// Returns the public key of the server, fails if disconnected.
 	#[cfg(feature = "unstable")]
 	pub fn get_server_key(&self) -> Result<tsproto_types::crypto::EccKeyPubP256> {
@@ -653,27 +1494,657 @@ impl Connection {
 
 	/// Get the current state of clients and channels of this connection.
 	///
-	/// Fails if the connection is currently not connected to the server.
+	/// The bookkeeping is owned by the connection and only changed while the
+	/// event stream is polled, so this never blocks. To access the state from
+	/// other threads, e.g. an audio callback, take a
+	/// [`snapshot`](data/struct.Connection.html#method.snapshot) and send it.
+	///
+	/// Fails if the connection is currently not connected to the server or if
+	/// bookkeeping is disabled.
 	pub fn get_state(&self) -> Result<&data::Connection> {
 		if let ConnectionState::Connected { book, .. } = &self.state {
-			Ok(book)
+			book.as_ref().ok_or(Error::BookkeepingDisabled)
 		} else {
 			Err(Error::NotConnected)
 		}
 	}
 
-	/// Disconnect from the server.
+	/// Move our own client into a channel.
 	///
-	/// # Arguments
-	/// - `options`: Either `None` or `DisconnectOptions`.
+	/// The channel is looked up in the current bookkeeping. If no `password`
+	/// is given and the channel is the one from [`ConnectOptions::channel`],
+	/// the [`ConnectOptions::channel_password`] is used.
 	///
-	/// # Examples
+	/// Returns the id of the channel that we move into.
 	///
-	/// Use default options:
+	/// [`ConnectOptions::channel`]: struct.ConnectOptions.html#method.channel
+	/// [`ConnectOptions::channel_password`]: struct.ConnectOptions.html#method.channel_password
+	pub fn join_channel(
+		&mut self, spec: ChannelSpec, password: Option<&str>,
+	) -> Result<(ChannelId, MessageHandle)> {
+		let (id, cmd) = self.join_channel_command(spec, password)?;
+		Ok((id, self.send_command(cmd)?))
+	}
+
+	fn join_channel_command(
+		&self, spec: ChannelSpec, password: Option<&str>,
+	) -> Result<(ChannelId, OutCommand)> {
+		let book = self.get_state()?;
+		let id = book.find_channel(spec)?;
+		let password = password.or_else(|| {
+			let channel: &str = self.options.channel.as_ref()?;
+			// The channel can be given as `/<id>`
+			let channel = match channel.strip_prefix('/').and_then(|c| c.parse().ok()) {
+				Some(c) => ChannelSpec::Id(ChannelId(c)),
+				None => ChannelSpec::Path(channel),
+			};
+			if book.find_channel(channel).ok() == Some(id) {
+				self.options.channel_password.as_ref().map(AsRef::as_ref)
+			} else {
+				None
+			}
+		});
+		let own_client = book.clients.get(&book.own_client).ok_or_else(|| {
+			ts_bookkeeping::Error::NotFound("Client", book.own_client.to_string())
+		})?;
+		Ok((id, own_client.move_to(id, password)))
+	}
+
+	/// If the connection is currently connected to a server.
+	pub fn is_connected(&self) -> bool {
+		matches!(self.state, ConnectionState::Connected { .. })
+	}
+
+	/// The number of commands that were sent but not yet acknowledged by the
+	/// server.
+	pub fn send_queue_len(&self) -> Result<usize> {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			Ok(con.client.send_queue_len())
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
+	/// The number of commands that wait until they can be sent without
+	/// exceeding the [`RateLimit`].
 	///
-	/// ```no_run
-	/// # use futures::prelude::*;
-	/// # use tsclientlib::{Connection, ConnectOptions, DisconnectOptions, StreamItem};
+	/// [`RateLimit`]: flood/struct.RateLimit.html
+	pub fn command_queue_len(&self) -> Result<usize> {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			Ok(con.commands.len())
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
+	/// If the server reported that we are flooding, no commands are sent until
+	/// the returned time.
+	pub fn command_queue_paused_until(&self) -> Result<Option<Instant>> {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			Ok(con.commands.paused_until())
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
+	/// Returns `Ready` if more commands can be sent without congesting the
+	/// connection.
+	///
+	/// Sending commands always works, they are buffered without limit. Clients
+	/// that send many commands should wait until the connection is ready
+	/// before sending the next one. The task is woken up when the server
+	/// acknowledged enough commands, in the meantime the event stream has to be
+	/// polled.
+	///
+	/// Returns an error if the connection is currently not connected.
+	pub fn poll_send_ready(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+		if let ConnectionState::Connected { con, .. } = &mut self.state {
+			con.client.poll_send_ready(cx).map(Ok)
+		} else {
+			Poll::Ready(Err(Error::NotConnected))
+		}
+	}
+
+	/// The address of the server that we are connected to.
+	///
+	/// If the server address resolved to multiple addresses, this is the one
+	/// that accepted the connection. The address can change when the
+	/// connection is reestablished after it was lost.
+	///
+	/// Fails if the connection is currently not connected to the server.
+	pub fn remote_address(&self) -> Result<SocketAddr> {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			Ok(con.client.address)
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
+	/// The local address of the udp socket, including the port that was
+	/// chosen by the operating system.
+	///
+	/// Fails if the connection is currently not connected to the server.
+	pub fn local_address(&self) -> Result<SocketAddr> {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			con.client.udp_socket.local_addr().map_err(Error::Io)
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
+	/// When the current connection to the server was established.
+	///
+	/// This is reset when the connection is reestablished after it was lost.
+	///
+	/// Fails if the connection is currently not connected to the server.
+	pub fn connected_since(&self) -> Result<OffsetDateTime> {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			Ok(con.connected_since)
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
+	/// Get the current ping, packet loss and traffic of the connection.
+	///
+	/// Fails if the connection is currently not connected to the server.
+	pub fn get_network_stats(&self) -> Result<NetworkStats> {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			let resender = &con.client.resender;
+			Ok(NetworkStats {
+				rtt: resender.get_srtt(),
+				rtt_dev: resender.get_srtt_dev(),
+				last_ping: resender.get_last_ping(),
+				traffic: con.client.stats,
+				acked_packets: resender.get_acked_packets(),
+			})
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
+	/// Ask the server for the statistics of the connection which it measured.
+	///
+	/// The answer is stored in the `connection_data` of the server in the
+	/// bookkeeping, [`SyncConnectionHandle::server_connection_info`] waits
+	/// for it.
+	///
+	/// [`SyncConnectionHandle::server_connection_info`]: sync/struct.SyncConnectionHandle.html#method.server_connection_info
+	pub fn server_connection_info(&mut self) -> Result<MessageHandle> {
+		let cmd = self.get_state()?.server.request_connection_info();
+		self.send_command(cmd)
+	}
+
+	/// Get the number of sent, received, resent and dropped packets.
+	///
+	/// This is cheap to call, all counters are copied out of the connection.
+	///
+	/// Fails if the connection is currently not connected to the server.
+	pub fn get_traffic_stats(&self) -> Result<TrafficStats> {
+		if let ConnectionState::Connected { con, .. } = &self.state {
+			Ok(con.client.stats)
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
+	/// Send an audio packet to the server.
+	///
+	/// If we are not allowed to talk in the current channel, the packet is
+	/// dropped and `false` is returned. The server would drop the packet
+	/// anyway.
+	///
+	/// Fails if the connection is currently not connected to the server.
+	pub fn send_audio(&mut self, packet: OutPacket) -> Result<bool> {
+		if let ConnectionState::Connected { con, .. } = &mut self.state {
+			if self.sending_muted.is_some() {
+				return Ok(false);
+			}
+			con.client.send_packet(packet).map_err(Error::SendPacket)?;
+			Ok(true)
+		} else {
+			Err(Error::NotConnected)
+		}
+	}
+
+	/// Receive the next packet into this buffer instead of allocating a new
+	/// one.
+	///
+	/// The buffer of a played audio packet can be taken from
+	/// [`AudioHandler::take_buffer`]. Nothing happens if the connection is
+	/// currently not connected to the server.
+	///
+	/// [`AudioHandler::take_buffer`]: audio/struct.AudioHandler.html#method.take_buffer
+	pub fn hand_back_buffer(&mut self, buffer: Vec<u8>) {
+		if let ConnectionState::Connected { con, .. } = &mut self.state {
+			con.client.hand_back_buffer(buffer);
+		}
+	}
+
+	/// If audio packets are currently sent to the server.
+	///
+	/// This is `false` if we do not have the permission to talk in our channel
+	/// or sending was muted with [`set_talk_override`].
+	///
+	/// [`set_talk_override`]: #method.set_talk_override
+	pub fn can_send_audio(&self) -> bool { self.sending_muted.is_none() }
+
+	/// Overwrite if audio should be sent.
+	///
+	/// `Some(true)` always sends audio, `Some(false)` never sends audio and
+	/// `None` decides based on the talk power in the current channel.
+	pub fn set_talk_override(&mut self, talk_override: Option<bool>) {
+		self.talk_override = talk_override;
+		let book = self.get_state().ok();
+		let muted = Self::get_sending_muted(book, talk_override);
+		Self::update_sending_muted(&mut self.sending_muted, muted, &mut self.stream_items);
+	}
+
+	fn get_sending_muted(
+		book: Option<&data::Connection>, talk_override: Option<bool>,
+	) -> Option<SendingMutedReason> {
+		match talk_override {
+			Some(true) => None,
+			Some(false) => Some(SendingMutedReason::Override),
+			None if book.map(|b| b.can_talk_here()).unwrap_or(true) => None,
+			None => Some(SendingMutedReason::NoTalkPower),
+		}
+	}
+
+	fn update_sending_muted(
+		sending_muted: &mut Option<SendingMutedReason>, muted: Option<SendingMutedReason>,
+		stream_items: &mut VecDeque<Result<StreamItem>>,
+	)
+	{
+		if *sending_muted != muted {
+			*sending_muted = muted;
+			stream_items.push_back(Ok(match muted {
+				Some(reason) => StreamItem::SendingMuted(reason),
+				None => StreamItem::SendingUnmuted,
+			}));
+		}
+	}
+
+	/// Change several properties of our own client at once.
+	///
+	/// The single setters like [`set_input_muted`] send one command per
+	/// property, this sends all changes in one `clientupdate`.
+	///
+	/// # Example
+	///
+	/// ```no_run
+	/// # fn f(con: &mut tsclientlib::Connection) -> tsclientlib::Result<()> {
+	/// con.edit_own_client().away(Some("Lunch")).input_muted(true).output_muted(true).send()?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	///
+	/// [`set_input_muted`]: #method.set_input_muted
+	pub fn edit_own_client(&mut self) -> OwnClientEdit {
+		OwnClientEdit {
+			con: self,
+			name: None,
+			away: None,
+			input_muted: None,
+			output_muted: None,
+			input_hardware: None,
+			output_hardware: None,
+			channel_commander: None,
+		}
+	}
+
+	/// Show our microphone as muted to other clients.
+	///
+	/// This only sets the flag on the server, it does not stop sending audio.
+	/// The bookkeeping is updated when the server confirms the change.
+	pub fn set_input_muted(&mut self, muted: bool) -> Result<MessageHandle> {
+		let cmd = self.get_state()?.client_update().set_input_muted(muted).to_packet();
+		self.send_command(cmd)
+	}
+
+	/// Show our speakers as muted to other clients.
+	pub fn set_output_muted(&mut self, muted: bool) -> Result<MessageHandle> {
+		let cmd = self.get_state()?.client_update().set_output_muted(muted).to_packet();
+		self.send_command(cmd)
+	}
+
+	/// Talk as channel commander.
+	///
+	/// Needs the permission to be a channel commander.
+	pub fn set_channel_commander(&mut self, commander: bool) -> Result<MessageHandle> {
+		let cmd =
+			self.get_state()?.client_update().set_is_channel_commander(commander).to_packet();
+		self.send_command(cmd)
+	}
+
+	/// Make a client a priority speaker, other clients get quieter while it
+	/// talks.
+	pub fn set_priority_speaker(
+		&mut self, client: ClientId, priority_speaker: bool,
+	) -> Result<MessageHandle> {
+		let book = self.get_state()?;
+		let client = book
+			.clients
+			.get(&client)
+			.ok_or_else(|| ts_bookkeeping::Error::NotFound("Client", client.to_string()))?;
+		let cmd = client.edit().set_is_priority_speaker(priority_speaker).to_packet();
+		self.send_command(cmd)
+	}
+
+	/// Set our metadata, which other clients can read.
+	///
+	/// Fails if the escaped data does not fit into a command, see
+	/// [`MAX_COMMAND_LEN`].
+	///
+	/// [`MAX_COMMAND_LEN`]: constant.MAX_COMMAND_LEN.html
+	pub fn set_meta_data(&mut self, meta_data: &str) -> Result<MessageHandle> {
+		let cmd = self.get_state()?.client_update().set_meta_data(meta_data).to_packet();
+		check_command_len(&cmd)?;
+		self.send_command(cmd)
+	}
+
+	/// Set if we have a microphone, see [`ConnectOptions::input_hardware`].
+	///
+	/// [`ConnectOptions::input_hardware`]: struct.ConnectOptions.html#method.input_hardware
+	pub fn set_input_hardware(&mut self, enabled: bool) -> Result<MessageHandle> {
+		let cmd = self.get_state()?.client_update().set_input_hardware_enabled(enabled).to_packet();
+		self.send_command(cmd)
+	}
+
+	/// Set if we have speakers, see [`ConnectOptions::output_hardware`].
+	///
+	/// [`ConnectOptions::output_hardware`]: struct.ConnectOptions.html#method.output_hardware
+	pub fn set_output_hardware(&mut self, enabled: bool) -> Result<MessageHandle> {
+		let cmd =
+			self.get_state()?.client_update().set_output_hardware_enabled(enabled).to_packet();
+		self.send_command(cmd)
+	}
+
+	/// Store a message on the server for a client that may currently be
+	/// offline.
+	pub fn send_offline_message(
+		&mut self, target: &Uid, subject: &str, message: &str,
+	) -> Result<MessageHandle> {
+		self.send_command(offline_messages::add(target, subject, message))
+	}
+
+	/// Request the list of our offline messages.
+	///
+	/// The content of the messages is not part of the list, it can be
+	/// requested with [`get_offline_message`].
+	///
+	/// [`get_offline_message`]: #method.get_offline_message
+	pub fn list_offline_messages(
+		&mut self,
+	) -> future::BoxFuture<'static, Result<Vec<offline_messages::OfflineMessage>>> {
+		self.send_list_command(offline_messages::list())
+	}
+
+	/// Request an offline message including its content.
+	pub fn get_offline_message(
+		&mut self, id: u32,
+	) -> future::BoxFuture<'static, Result<Option<offline_messages::OfflineMessage>>> {
+		self.send_list_command(offline_messages::get(id))
+			.map_ok(|msgs: Vec<_>| msgs.into_iter().next())
+			.boxed()
+	}
+
+	pub fn delete_offline_message(&mut self, id: u32) -> Result<MessageHandle> {
+		self.send_command(offline_messages::delete(id))
+	}
+
+	/// Mark an offline message as read or unread.
+	pub fn mark_read(&mut self, id: u32, is_read: bool) -> Result<MessageHandle> {
+		self.send_command(offline_messages::set_read(id, is_read))
+	}
+
+	/// File a complaint about a client.
+	pub fn add_complaint(&mut self, target: ClientDbId, message: &str) -> Result<MessageHandle> {
+		self.send_command(complaints::add(target, message))
+	}
+
+	/// Request the list of complaints, optionally only about a single client.
+	///
+	/// If there are no complaints, the list is empty.
+	pub fn list_complaints(
+		&mut self, target: Option<ClientDbId>,
+	) -> future::BoxFuture<'static, Result<Vec<complaints::Complaint>>> {
+		self.send_list_command(complaints::list(target))
+	}
+
+	/// Delete the complaint of `source` about `target`.
+	pub fn delete_complaint(
+		&mut self, target: ClientDbId, source: ClientDbId,
+	) -> Result<MessageHandle> {
+		self.send_command(complaints::delete(target, source))
+	}
+
+	/// Use a privilege key to get the server or channel group it grants.
+	///
+	/// If the key is invalid or was already used, the returned
+	/// [`StreamItem::MessageResult`] contains an error, which can be converted
+	/// into a [`privilege_keys::Error`].
+	///
+	/// The server does not always notify us about the new group before it
+	/// answers, so this also requests the current state of our own client.
+	///
+	/// [`StreamItem::MessageResult`]: enum.StreamItem.html#variant.MessageResult
+	/// [`privilege_keys::Error`]: privilege_keys/enum.Error.html
+	pub fn use_privilege_key(&mut self, token: &str) -> Result<MessageHandle> {
+		let handle = self.send_command(privilege_keys::use_key(token))?;
+		let refresh = if let ConnectionState::Connected { book: Some(book), .. } = &self.state {
+			book.own_client().map(|c| c.refresh())
+		} else {
+			None
+		};
+		if let Some(cmd) = refresh {
+			self.send_internal_command(cmd)?;
+		}
+		Ok(handle)
+	}
+
+	/// Create a new privilege key.
+	///
+	/// For server groups, `id1` is the group id and `id2` is `0`. For channel
+	/// groups, `id1` is the group id and `id2` the channel id.
+	pub fn create_privilege_key(
+		&mut self, token_type: TokenType, id1: u64, id2: u64, description: &str,
+	) -> Result<MessageHandle> {
+		self.send_command(privilege_keys::add(token_type, id1, id2, description))
+	}
+
+	/// Request the list of privilege keys.
+	pub fn list_privilege_keys(
+		&mut self,
+	) -> future::BoxFuture<'static, Result<Vec<privilege_keys::PrivilegeKey>>> {
+		self.send_list_command(privilege_keys::list())
+	}
+
+	/// Request the running file transfers of other clients.
+	///
+	/// New transfers are returned as [`StreamItem::ForeignFileTransfer`] and
+	/// kept in [`Server::recent_file_transfers`].
+	///
+	/// [`StreamItem::ForeignFileTransfer`]: enum.StreamItem.html#variant.ForeignFileTransfer
+	/// [`Server::recent_file_transfers`]: data/struct.Server.html#method.recent_file_transfers
+	pub fn request_file_transfers(&mut self) -> Result<MessageHandle> {
+		self.send_command(foreign_transfers::list())
+	}
+
+	/// Add a password which can be used to join the server for `duration`.
+	///
+	/// Clients which join with this password are moved into `target_channel`,
+	/// `channel_password` is the password of this channel.
+	pub fn add_temporary_password(
+		&mut self, password: &str, description: &str, duration: Duration,
+		target_channel: Option<ChannelId>, channel_password: Option<&str>,
+	) -> Result<MessageHandle>
+	{
+		self.send_command(temp_passwords::add(
+			password,
+			description,
+			duration,
+			target_channel,
+			channel_password,
+		))
+	}
+
+	/// Request the list of temporary passwords.
+	pub fn list_temporary_passwords(
+		&mut self,
+	) -> future::BoxFuture<'static, Result<Vec<temp_passwords::TempPassword>>> {
+		self.send_list_command(temp_passwords::list())
+	}
+
+	/// Delete the temporary password `password`, so it can no longer be used
+	/// to join the server.
+	pub fn delete_temporary_password(&mut self, password: &str) -> Result<MessageHandle> {
+		self.send_command(temp_passwords::delete(password))
+	}
+
+	/// Request the list of bans.
+	///
+	/// The bans of all answers are collected into one list.
+	pub fn list_bans(&mut self) -> future::BoxFuture<'static, Result<Vec<bans::Ban>>> {
+		self.send_list_command(bans::list())
+	}
+
+	/// Add the bans of an import to the server.
+	///
+	/// The commands are sent with the [`RateLimit`] of the connection, so a
+	/// big import does not get us blocked for flooding. Returns the index of
+	/// the ban in the imported list with the handle of its command. If the
+	/// connection drops during an import, it can be resumed at the first ban
+	/// without a successful [`StreamItem::MessageResult`], see
+	/// [`bans::ImportReport::cursor`].
+	///
+	/// [`RateLimit`]: flood/struct.RateLimit.html
+	/// [`StreamItem::MessageResult`]: enum.StreamItem.html#variant.MessageResult
+	/// [`bans::ImportReport::cursor`]: bans/struct.ImportReport.html#structfield.cursor
+	pub fn import_bans(
+		&mut self, commands: Vec<(usize, OutCommand)>,
+	) -> Result<Vec<(usize, MessageHandle)>> {
+		commands.into_iter().map(|(i, cmd)| Ok((i, self.send_command(cmd)?))).collect()
+	}
+
+	/// Request the files in the directory `path` of a channel.
+	///
+	/// The path of the root directory is `/`.
+	pub fn list_files(
+		&mut self, channel: ChannelId, channel_password: Option<&str>, path: &str,
+	) -> future::BoxFuture<'static, Result<Vec<file_list::FileEntry>>> {
+		self.send_list_command(file_list::list(channel, channel_password, path))
+	}
+
+	/// Request `lines` entries of the server log.
+	///
+	/// With `reverse`, the newest entries are returned first. The `last_pos`
+	/// of the returned log can be passed as `begin_pos` to get the next
+	/// entries.
+	pub fn get_server_log(
+		&mut self, lines: u32, reverse: bool, begin_pos: Option<u64>,
+	) -> future::BoxFuture<'static, Result<server_log::ServerLog>> {
+		self.send_list_command(server_log::view(lines, reverse, begin_pos))
+			.map_ok(|parts: Vec<server_log::ServerLog>| {
+				let mut parts = parts.into_iter();
+				let mut log = parts.next().unwrap_or_default();
+				for part in parts {
+					log.entries.extend(part.entries);
+				}
+				log
+			})
+			.boxed()
+	}
+
+	/// Search the channels on the server whose name contains `pattern`.
+	///
+	/// The bookkeeping always contains all channels, so
+	/// [`data::Connection::find_channel`] finds the same channels without
+	/// asking the server.
+	///
+	/// [`data::Connection::find_channel`]: data/struct.Connection.html#method.find_channel
+	pub fn find_channels(
+		&mut self, pattern: &str,
+	) -> future::BoxFuture<'static, Result<Vec<(ChannelId, String)>>> {
+		self.send_list_command(find::channels(pattern))
+	}
+
+	/// Search the clients on the server whose nickname contains `pattern`.
+	///
+	/// In contrast to [`data::Connection::find_clients_by_name`], this also
+	/// finds clients in channels which are not subscribed. If all channels
+	/// are subscribed, the bookkeeping contains every client and the local
+	/// search should be preferred, see
+	/// [`data::Connection::is_fully_subscribed`].
+	///
+	/// [`data::Connection::find_clients_by_name`]: data/struct.Connection.html#method.find_clients_by_name
+	/// [`data::Connection::is_fully_subscribed`]: data/struct.Connection.html#method.is_fully_subscribed
+	pub fn find_clients(
+		&mut self, pattern: &str,
+	) -> future::BoxFuture<'static, Result<Vec<(ClientId, String)>>> {
+		self.send_list_command(find::clients(pattern))
+	}
+
+	/// Write a message into the server log.
+	pub fn add_log_entry(&mut self, level: LogLevel, message: &str) -> Result<MessageHandle> {
+		self.send_command(server_log::add(level, message))
+	}
+
+	/// Download the banner image of the server.
+	///
+	/// Returns `None` if the server has no banner image or if the banner was
+	/// already downloaded and its refresh interval did not pass yet. The
+	/// returned future does not borrow the connection, so the connection can
+	/// be polled while the image is downloaded. Only successful downloads
+	/// count for the refresh interval.
+	///
+	/// A [`TypedEvent::HostBannerChanged`] signals that the banner should be
+	/// downloaded again.
+	///
+	/// [`TypedEvent::HostBannerChanged`]: events/enum.TypedEvent.html#variant.HostBannerChanged
+	pub fn download_host_banner(
+		&mut self,
+	) -> Result<Option<impl Future<Output = Result<Vec<u8>>> + Send + 'static>> {
+		let (con, book) = if let ConnectionState::Connected { con, book } = &mut self.state {
+			(con, book.as_ref().ok_or(Error::BookkeepingDisabled)?)
+		} else {
+			return Err(Error::NotConnected);
+		};
+		let url = if let Some(url) = book.server.host_banner_gfx_url() {
+			url
+		} else {
+			return Ok(None);
+		};
+		let interval = book.server.host_banner_interval();
+		let interval = interval.map(|i| Duration::from_secs(i.whole_seconds() as u64));
+		let last = con.host_banner_download.lock().unwrap().clone();
+		if !host_banner_due(last.as_ref(), url, interval, Instant::now()) {
+			return Ok(None);
+		}
+
+		let url = url.to_string();
+		let last = con.host_banner_download.clone();
+		Ok(Some(async move {
+			let res = reqwest::get(&url).await.map_err(Error::HostBanner)?;
+			let res = res.error_for_status().map_err(Error::HostBanner)?;
+			let data = res.bytes().await.map_err(Error::HostBanner)?.to_vec();
+			*last.lock().unwrap() = Some((url, Instant::now()));
+			Ok(data)
+		}))
+	}
+
+	/// Disconnect from the server.
+	///
+	/// # Arguments
+	/// - `options`: Either `None` or `DisconnectOptions`.
+	///
+	/// # Examples
+	///
+	/// Use default options:
+	///
+	/// ```no_run
+	/// # use futures::prelude::*;
+	/// # use tsclientlib::{Connection, ConnectOptions, DisconnectOptions, StreamItem};
 	///
 	/// # #[tokio::main]
 	/// # async fn main() {
@@ -718,9 +2189,66 @@ impl Connection {
 	/// # }
 	/// ```
 	pub fn disconnect(&mut self, options: DisconnectOptions) -> Result<()> {
-		if let ConnectionState::Connected { con, book } = &mut self.state {
-			let packet = book.disconnect(options);
+		if let ConnectionState::Connected { con, .. } = &mut self.state {
+			let packet = c2s::OutDisconnectMessage::new(&mut iter::once(c2s::OutDisconnectPart {
+				reason: options.reason,
+				reason_message: options.message.as_ref().map(|m| m.as_str()),
+			}));
 			con.client.send_packet(packet.into_packet()).map_err(Error::SendPacket)?;
+			self.disconnect_reason.get_or_insert(DisconnectReason::Client);
+		}
+		Ok(())
+	}
+
+	/// Why the connection ended or is ending.
+	///
+	/// This is set when the server removes our own client, e.g. because we
+	/// were kicked, and after calling [`disconnect`]. It is `None` while the
+	/// connection is running normally.
+	///
+	/// [`disconnect`]: #method.disconnect
+	pub fn disconnect_reason(&self) -> Option<&DisconnectReason> { self.disconnect_reason.as_ref() }
+
+	/// The changes of our own client which are restored after reconnecting.
+	#[inline]
+	pub fn desired_state(&self) -> &restore::DesiredState { &self.desired_state }
+
+	/// Disconnect and wait until the connection and its background work
+	/// stopped.
+	///
+	/// Dropping a connection closes its socket right away, but the server
+	/// does not know that we left and a running identity level increase only
+	/// stops its thread after a while. This sends the disconnect, polls the
+	/// connection until the server acknowledged it and waits for the identity
+	/// thread to finish.
+	///
+	/// A connection which is not yet connected is dropped without waiting.
+	///
+	/// # Example
+	///
+	/// ```no_run
+	/// # use tsclientlib::{Connection, ConnectOptions, DisconnectOptions};
+	///
+	/// # #[tokio::main]
+	/// # async fn main() {
+	/// let con = Connection::new(ConnectOptions::new("localhost")).unwrap();
+	/// // Use the connection
+	/// con.shutdown(DisconnectOptions::new()).await.unwrap();
+	/// # }
+	/// ```
+	pub async fn shutdown(mut self, options: DisconnectOptions) -> Result<()> {
+		self.cancel_identity_level_increase();
+		match &mut self.state {
+			ConnectionState::CreatingIdentity(_) | ConnectionState::Connecting(_) => {}
+			ConnectionState::IdentityLevelIncreasing { recv, .. } => {
+				// The sender is dropped when the thread stops
+				let _ = recv.await;
+			}
+			ConnectionState::Connected { .. } => {
+				self.disconnect(options)?;
+				let mut events = self.events();
+				while events.next().await.is_some() {}
+			}
 		}
 		Ok(())
 	}
@@ -783,6 +2311,17 @@ impl Connection {
 			return Poll::Ready(Some(item));
 		}
 		match &mut self.state {
+			ConnectionState::CreatingIdentity(fut) => match fut.poll_unpin(cx) {
+				Poll::Pending => Poll::Pending,
+				Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+				Poll::Ready(Ok(identity)) => {
+					// No connect future exists yet, so this does not clone
+					Arc::make_mut(&mut self.options).identity = Some(identity);
+					let fut = self.reconnect_future();
+					self.state = ConnectionState::Connecting(fut);
+					Poll::Ready(Some(Ok(StreamItem::IdentityLevelIncreased)))
+				}
+			},
 			ConnectionState::Connecting(fut) => match fut.poll_unpin(cx) {
 				Poll::Pending => Poll::Pending,
 				Poll::Ready(Err(Error::IdentityLevel(level))) => {
@@ -791,15 +2330,85 @@ impl Connection {
 					}
 					Poll::Ready(Some(Ok(StreamItem::IdentityLevelIncreasing(level))))
 				}
-				Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
-				Poll::Ready(Ok((client, book))) => {
-					let con = ConnectedConnection {
+				Poll::Ready(Err(e)) => {
+					if !self.reconnecting || !e.is_temporary() {
+						self.reconnecting = false;
+						return Poll::Ready(Some(Err(e)));
+					}
+					// Try again later
+					let delay = e.retry_after().unwrap_or(RECONNECT_DELAY);
+					warn!(self.logger, "Reconnecting failed, trying again";
+						"error" => %e, "delay" => ?delay);
+					let fut = self.reconnect_future();
+					self.state = ConnectionState::Connecting(Box::pin(async move {
+						time::delay_for(delay).await;
+						fut.await
+					}));
+					Poll::Ready(Some(Ok(StreamItem::BackgroundError(BackgroundError {
+						component: ErrorComponent::Connection,
+						error: e,
+						fatal: false,
+						repeated: 0,
+					}))))
+				}
+				Poll::Ready(Ok((client, book, name))) => {
+					let restore = if self.reconnecting
+						&& self.options.restore_state
+						&& !self.desired_state.is_empty()
+					{
+						Some(self.desired_state.clone())
+					} else {
+						None
+					};
+					let mut con = ConnectedConnection {
 						client,
+						own_client: book.own_client,
+						name,
+						connected_since: OffsetDateTime::now_utc(),
 						cur_return_code: 0,
 						cur_file_transfer_id: 0,
+						pending_lists: Default::default(),
+						error_limiter: Default::default(),
 						file_transfers: Default::default(),
+						commands: flood::CommandQueue::new(self.options.rate_limit),
+						host_banner_download: Default::default(),
+						restore: None,
+						move_password: None,
 					};
+					if let Some(state) = restore {
+						if self.options.bookkeeping {
+							// Wait until we know the channels
+							con.restore = Some(state);
+						} else {
+							con.restore_state(&self.logger, None, &state, &mut self.stream_items);
+						}
+					}
+					let book = if self.options.bookkeeping { Some(book) } else { None };
 					self.state = ConnectionState::Connected { con, book };
+					self.disconnect_reason = None;
+					self.reconnecting = false;
+					if self.options.bookkeeping {
+						// Fill the server groups, they are not part of initserver
+						let cmd = new_command("servergrouplist");
+						if let Err(e) = self.send_internal_command(cmd) {
+							warn!(self.logger, "Failed to request server groups"; "error" => %e);
+						}
+					}
+					// The muted flags are not part of clientinit
+					let (input_muted, output_muted) =
+						(self.options.input_muted, self.options.output_muted);
+					if input_muted || output_muted {
+						let cmd = self.get_state().map(|state| {
+							state
+								.client_update()
+								.set_input_muted(input_muted)
+								.set_output_muted(output_muted)
+								.to_packet()
+						});
+						if let Err(e) = cmd.and_then(|cmd| self.send_internal_command(cmd)) {
+							warn!(self.logger, "Failed to set muted flags"; "error" => %e);
+						}
+					}
 					Poll::Ready(Some(Ok(StreamItem::ConEvents(vec![
 						events::Event::PropertyAdded {
 							id: events::PropertyId::Server,
@@ -809,25 +2418,57 @@ impl Connection {
 					]))))
 				}
 			},
-			ConnectionState::IdentityLevelIncreasing { recv, .. } => match recv.poll_unpin(cx) {
-				Poll::Pending => Poll::Pending,
-				Poll::Ready(Err(_)) => {
-					Poll::Ready(Some(Err(Error::IdentityLevelIncreaseFailedThread)))
+			ConnectionState::IdentityLevelIncreasing { recv, progress, .. } => {
+				if let Poll::Ready(Some((level, counter))) = progress.poll_recv(cx) {
+					let item = StreamItem::IdentityLevelProgress { level, counter };
+					return Poll::Ready(Some(Ok(item)));
 				}
-				Poll::Ready(Ok(Err(e))) => {
-					Poll::Ready(Some(Err(Error::IdentityLevelIncreaseFailed(e))))
-				}
-				Poll::Ready(Ok(Ok(identity))) => {
-					self.options.identity = Some(identity);
-					let fut = Self::connect(self.logger.clone(), self.options.clone());
-					self.state = ConnectionState::Connecting(Box::pin(fut));
-					Poll::Ready(Some(Ok(StreamItem::IdentityLevelIncreased)))
+				match recv.poll_unpin(cx) {
+					Poll::Pending => Poll::Pending,
+					Poll::Ready(Err(_)) => {
+						Poll::Ready(Some(Err(Error::IdentityLevelIncreaseFailedThread)))
+					}
+					Poll::Ready(Ok(Err(e))) => {
+						Poll::Ready(Some(Err(Error::IdentityLevelIncreaseFailed(e))))
+					}
+					Poll::Ready(Ok(Ok(identity))) => {
+						// The last connect future is finished, so this does not clone
+						Arc::make_mut(&mut self.options).identity = Some(identity);
+						let fut = self.reconnect_future();
+						self.state = ConnectionState::Connecting(fut);
+						Poll::Ready(Some(Ok(StreamItem::IdentityLevelIncreased)))
+					}
 				}
-			},
+			}
 			ConnectionState::Connected { con, book } => match loop {
+				if let Err(e) = con.poll_commands(cx) {
+					con.error_limiter.push(
+						&self.logger,
+						&mut self.stream_items,
+						ErrorComponent::Connection,
+						e,
+						Instant::now(),
+					);
+					if let Some(item) = self.stream_items.pop_front() {
+						break Poll::Ready(Some(item));
+					}
+				}
 				match con.client.poll_next_unpin(cx) {
 					Poll::Pending => break Poll::Pending,
-					Poll::Ready(None) => break Poll::Ready(None),
+					Poll::Ready(None) => {
+						// The server can come back, e.g. after a restart
+						if matches!(
+							self.disconnect_reason,
+							Some(DisconnectReason::ServerShutdown { .. })
+						) {
+							info!(self.logger, "Server shut down, reconnecting");
+							let fut = self.reconnect_future();
+							self.state = ConnectionState::Connecting(fut);
+							self.reconnecting = true;
+							return Poll::Ready(Some(Ok(StreamItem::DisconnectedTemporarily)));
+						}
+						break Poll::Ready(None);
+					}
 					Poll::Ready(Some(Err(e))) => {
 						// Check if we were disconnecting
 						let state = con.client.resender.get_state();
@@ -836,18 +2477,45 @@ impl Connection {
 						{
 							break Poll::Ready(None);
 						}
+						// Do not reconnect if we were removed from the server
+						if matches!(&self.disconnect_reason, Some(r) if r.is_removal()) {
+							info!(self.logger, "Connection closed after removal"; "error" => %e);
+							break Poll::Ready(None);
+						}
 
-						warn!(self.logger, "Connection failed, reconnecting"; "error" => %e);
+						let error = Error::ConnectionLost(e);
+						if !error.is_temporary() {
+							info!(self.logger, "Connection failed"; "error" => %error);
+							break Poll::Ready(None);
+						}
+
+						warn!(self.logger, "Connection failed, reconnecting"; "error" => %error);
 						// Reconnect
-						// TODO Depending on reason
-						let fut = Self::connect(self.logger.clone(), self.options.clone());
-						self.state = ConnectionState::Connecting(Box::pin(fut));
-						return Poll::Ready(Some(Ok(StreamItem::DisconnectedTemporarily)));
+						let fut = self.reconnect_future();
+						self.state = ConnectionState::Connecting(fut);
+						self.reconnecting = true;
+						self.stream_items.push_back(Ok(StreamItem::DisconnectedTemporarily));
+						return Poll::Ready(Some(Ok(StreamItem::BackgroundError(
+							BackgroundError {
+								component: ErrorComponent::Connection,
+								error,
+								fatal: true,
+								repeated: 0,
+							},
+						))));
 					}
 					Poll::Ready(Some(Ok(item))) => match item {
 						ProtoStreamItem::Error(e) => {
-							warn!(self.logger, "Connection got a non-fatal error";
-									"error" => %e);
+							con.error_limiter.push(
+								&self.logger,
+								&mut self.stream_items,
+								ErrorComponent::Connection,
+								Error::Protocol(e),
+								Instant::now(),
+							);
+							if let Some(item) = self.stream_items.pop_front() {
+								break Poll::Ready(Some(item));
+							}
 						}
 						ProtoStreamItem::Audio(audio) => {
 							#[cfg(feature = "audio")]
@@ -860,7 +2528,20 @@ impl Connection {
 							}
 						}
 						ProtoStreamItem::Command(cmd) => {
-							con.handle_command(&self.logger, book, &mut self.stream_items, cmd);
+							con.handle_command(
+								&self.logger,
+								book.as_mut(),
+								&mut self.stream_items,
+								&mut self.disconnect_reason,
+								&mut self.desired_state,
+								cmd,
+							);
+							let muted = Self::get_sending_muted(book.as_ref(), self.talk_override);
+							Self::update_sending_muted(
+								&mut self.sending_muted,
+								muted,
+								&mut self.stream_items,
+							);
 							if let Some(item) = self.stream_items.pop_front() {
 								break Poll::Ready(Some(item));
 							}
@@ -890,10 +2571,243 @@ impl Connection {
 	}
 }
 
+/// A new command from the client to the server.
+pub(crate) fn new_command(name: &str) -> OutCommand {
+	OutCommand::new(Direction::C2S, Flags::empty(), PacketType::Command, name)
+}
+
+/// The return code of a notification, which answers a command of us.
+fn get_return_code(content: &[u8]) -> Option<MessageHandle> {
+	let (_, args) = CommandParser::new(content);
+	args.filter_map(|item| match item {
+		CommandItem::Argument(arg) if arg.name() == b"return_code" => {
+			arg.value().get_str().ok()?.parse().ok().map(MessageHandle)
+		}
+		_ => None,
+	})
+	.next()
+}
+
+/// Parse the entries of a list notification like `notifybanlist`.
+///
+/// `parse_entry` gets the names and values of the arguments of every entry.
+/// Arguments which are not valid UTF-8 are skipped.
+pub(crate) fn parse_list<'a, T>(
+	content: &'a [u8], mut parse_entry: impl FnMut(Vec<(&'a [u8], Cow<'a, str>)>) -> Option<T>,
+) -> Vec<T> {
+	let (_, args) = CommandParser::new(content);
+	let mut res = Vec::new();
+	let mut entry = Vec::new();
+	for item in args.chain(iter::once(CommandItem::NextCommand)) {
+		match item {
+			CommandItem::Argument(arg) => {
+				if let Ok(value) = arg.value().get_str() {
+					entry.push((arg.name(), value));
+				}
+			}
+			CommandItem::NextCommand => res.extend(parse_entry(std::mem::take(&mut entry))),
+		}
+	}
+	res
+}
+
+/// If the host banner at `url` should be downloaded.
+///
+/// `last` is the url and time of the last successful download. An `interval`
+/// of `None` means that the banner does not change.
+fn host_banner_due(
+	last: Option<&(String, Instant)>, url: &str, interval: Option<Duration>, now: Instant,
+) -> bool {
+	match last {
+		Some((last_url, last_time)) if last_url == url => {
+			interval.map(|i| now >= *last_time + i).unwrap_or(false)
+		}
+		_ => true,
+	}
+}
+
+/// Check the length of the metadata before connecting, when there is no
+/// bookkeeping to build the `clientupdate` yet.
+fn check_meta_data_len(meta_data: &str) -> Result<()> {
+	let mut cmd = new_command("clientupdate");
+	cmd.write_arg("client_meta_data", &meta_data);
+	check_command_len(&cmd)
+}
+
+fn check_command_len(cmd: &OutCommand) -> Result<()> {
+	let len = cmd.0.content().len();
+	if len > MAX_COMMAND_LEN { Err(Error::CommandTooLong(len)) } else { Ok(()) }
+}
+
+/// If `address` is link-local and has no scope id.
+///
+/// Such an address cannot be bound without knowing the interface.
+fn needs_scope_id(address: &SocketAddrV6) -> bool {
+	address.scope_id() == 0 && address.ip().segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// The address to bind our socket to when sending to `peer`.
+///
+/// Without a local address, any address of the family of `peer` is used. A
+/// link-local address without scope id gets the scope of `peer`.
+fn local_address_for(local_address: Option<SocketAddr>, peer: SocketAddr) -> SocketAddr {
+	match (local_address, peer) {
+		(Some(SocketAddr::V6(mut local)), SocketAddr::V6(peer)) if needs_scope_id(&local) => {
+			local.set_scope_id(peer.scope_id());
+			local.into()
+		}
+		(Some(local), _) => local,
+		(None, SocketAddr::V4(_)) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+		(None, SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+	}
+}
+
+/// Create a udp socket, which only uses the network interface `device` if
+/// it is set.
+fn bind_socket(
+	address: SocketAddr, device: Option<&str>,
+) -> std::io::Result<std::net::UdpSocket> {
+	let socket = std::net::UdpSocket::bind(address)?;
+	socket.set_nonblocking(true)?;
+	if let Some(device) = device {
+		bind_to_device(&socket, device)?;
+	}
+	Ok(socket)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &std::net::UdpSocket, device: &str) -> std::io::Result<()> {
+	use std::os::unix::io::AsRawFd;
+
+	// The name needs to fit into a buffer of IFNAMSIZ with a trailing 0
+	if device.len() >= libc::IFNAMSIZ || device.contains('\0') {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid device name"));
+	}
+	// Safe because the pointer and length describe the name
+	let res = unsafe {
+		libc::setsockopt(
+			socket.as_raw_fd(),
+			libc::SOL_SOCKET,
+			libc::SO_BINDTODEVICE,
+			device.as_ptr() as *const libc::c_void,
+			device.len() as libc::socklen_t,
+		)
+	};
+	if res == 0 { Ok(()) } else { Err(std::io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_: &std::net::UdpSocket, _: &str) -> std::io::Result<()> {
+	Err(std::io::Error::new(
+		std::io::ErrorKind::Other,
+		"Binding to a device is only supported on Linux",
+	))
+}
+
+/// If binding failed because the interface or address does not exist.
+///
+/// They can come back, e.g. for a vpn, other errors like missing permissions
+/// stay.
+fn is_missing_interface(e: &std::io::Error) -> bool {
+	#[cfg(target_os = "linux")]
+	{
+		if e.raw_os_error() == Some(libc::ENODEV) {
+			return true;
+		}
+	}
+	e.kind() == std::io::ErrorKind::AddrNotAvailable
+}
+
 impl<T: OutMessageTrait> OutCommandExt for T {
 	fn send(self, con: &mut Connection) -> Result<MessageHandle> {
 		con.send_command(self.to_packet())
 	}
+
+	fn send_unthrottled(self, con: &mut Connection) -> Result<MessageHandle> {
+		con.send_checked_command(self.to_packet(), false, false)
+	}
+}
+
+impl<'a> OwnClientEdit<'a> {
+	/// Change our nickname.
+	pub fn name(mut self, name: &str) -> Self {
+		self.name = Some(name.into());
+		self
+	}
+
+	/// Set us away with a message or come back with `None`.
+	pub fn away(mut self, message: Option<&str>) -> Self {
+		self.away = Some(message.map(Into::into));
+		self
+	}
+
+	/// See [`Connection::set_input_muted`].
+	///
+	/// [`Connection::set_input_muted`]: struct.Connection.html#method.set_input_muted
+	pub fn input_muted(mut self, muted: bool) -> Self {
+		self.input_muted = Some(muted);
+		self
+	}
+
+	/// See [`Connection::set_output_muted`].
+	///
+	/// [`Connection::set_output_muted`]: struct.Connection.html#method.set_output_muted
+	pub fn output_muted(mut self, muted: bool) -> Self {
+		self.output_muted = Some(muted);
+		self
+	}
+
+	/// See [`Connection::set_input_hardware`].
+	///
+	/// [`Connection::set_input_hardware`]: struct.Connection.html#method.set_input_hardware
+	pub fn input_hardware(mut self, enabled: bool) -> Self {
+		self.input_hardware = Some(enabled);
+		self
+	}
+
+	/// See [`Connection::set_output_hardware`].
+	///
+	/// [`Connection::set_output_hardware`]: struct.Connection.html#method.set_output_hardware
+	pub fn output_hardware(mut self, enabled: bool) -> Self {
+		self.output_hardware = Some(enabled);
+		self
+	}
+
+	/// See [`Connection::set_channel_commander`].
+	///
+	/// [`Connection::set_channel_commander`]: struct.Connection.html#method.set_channel_commander
+	pub fn channel_commander(mut self, commander: bool) -> Self {
+		self.channel_commander = Some(commander);
+		self
+	}
+
+	/// Send all changes in one command.
+	pub fn send(self) -> Result<MessageHandle> {
+		let mut update = self.con.get_state()?.client_update();
+		if let Some(name) = &self.name {
+			update = update.set_name(name);
+		}
+		if let Some(away) = &self.away {
+			update = update.set_away(away.as_deref());
+		}
+		if let Some(muted) = self.input_muted {
+			update = update.set_input_muted(muted);
+		}
+		if let Some(muted) = self.output_muted {
+			update = update.set_output_muted(muted);
+		}
+		if let Some(enabled) = self.input_hardware {
+			update = update.set_input_hardware_enabled(enabled);
+		}
+		if let Some(enabled) = self.output_hardware {
+			update = update.set_output_hardware_enabled(enabled);
+		}
+		if let Some(commander) = self.channel_commander {
+			update = update.set_is_channel_commander(commander);
+		}
+		let cmd = update.to_packet();
+		self.con.send_command(cmd)
+	}
 }
 
 impl Drop for Connection {
@@ -907,12 +2821,157 @@ impl<'a> Stream for EventStream<'a> {
 	}
 }
 
+impl ErrorLimiter {
+	/// Report a non-fatal background error.
+	///
+	/// The error is dropped if it is identical to the last error and the last
+	/// report was less than [`BACKGROUND_ERROR_INTERVAL`] ago.
+	///
+	/// If a different error arrives while the last error was suppressed, the
+	/// last suppressed repetition is reported first, so the count is not lost.
+	///
+	/// [`BACKGROUND_ERROR_INTERVAL`]: constant.BACKGROUND_ERROR_INTERVAL.html
+	fn push(
+		&mut self, logger: &Logger, stream_items: &mut VecDeque<Result<StreamItem>>,
+		component: ErrorComponent, error: Error, now: Instant,
+	)
+	{
+		let msg = error.to_string();
+		if let Some((last_component, last_msg, last_time)) = &self.last {
+			if *last_component == component && *last_msg == msg {
+				if now.duration_since(*last_time) < BACKGROUND_ERROR_INTERVAL {
+					self.suppressed += 1;
+					self.last_suppressed = Some(error);
+					return;
+				}
+			} else if let Some(last_error) = self.last_suppressed.take() {
+				// The reported repetition is not counted
+				let repeated = self.suppressed - 1;
+				Self::report(logger, stream_items, *last_component, last_error, repeated);
+			}
+		}
+
+		Self::report(logger, stream_items, component, error, self.suppressed);
+		self.last = Some((component, msg, now));
+		self.suppressed = 0;
+		self.last_suppressed = None;
+	}
+
+	fn report(
+		logger: &Logger, stream_items: &mut VecDeque<Result<StreamItem>>,
+		component: ErrorComponent, error: Error, repeated: u32,
+	)
+	{
+		warn!(logger, "Background error"; "component" => ?component, "error" => %error,
+			"repeated" => repeated);
+		stream_items.push_back(Ok(StreamItem::BackgroundError(BackgroundError {
+			component,
+			error,
+			fatal: false,
+			repeated,
+		})));
+	}
+}
+
+impl DisconnectReason {
+	/// The reason if the message removes our own client from the server.
+	fn from_message(own_client: ClientId, msg: &InMessage) -> Option<Self> {
+		let msg = if let InMessage::ClientLeftView(msg) = msg {
+			msg.iter().find(|m| m.client_id == own_client)?
+		} else {
+			return None;
+		};
+		let message = msg.reason_message.as_ref().map(|m| m.to_string());
+		Some(match msg.reason {
+			Reason::KickServer => Self::Kicked { invoker: msg.get_invoker(), message },
+			Reason::KickServerBan => {
+				// A ban time of 0 is a permanent ban
+				let duration = msg.ban_time.filter(|d| !d.is_zero());
+				Self::Banned { invoker: msg.get_invoker(), message, duration }
+			}
+			Reason::Serverstop | Reason::ClientdisconnectServerShutdown => {
+				Self::ServerShutdown { message }
+			}
+			Reason::Clientdisconnect => Self::Client,
+			_ => return None,
+		})
+	}
+
+	/// If the server removed us on purpose, so we should not reconnect.
+	pub fn is_removal(&self) -> bool {
+		matches!(self, DisconnectReason::Kicked { .. } | DisconnectReason::Banned { .. })
+	}
+}
+
+impl NameCollisionStrategy {
+	/// The name to use for the `attempt`th try, starting at `0`.
+	///
+	/// Returns `None` if no more names should be tried.
+	fn get_name(self, name: &str, attempt: u32) -> Option<Cow<str>> {
+		if attempt == 0 {
+			return Some(name.into());
+		}
+		if attempt > NAME_RETRIES {
+			return None;
+		}
+		let suffix = match self {
+			NameCollisionStrategy::Fail => return None,
+			NameCollisionStrategy::AppendNumber => attempt.to_string(),
+			NameCollisionStrategy::AppendRandom => {
+				rand::thread_rng().gen_range(0, 10_000).to_string()
+			}
+		};
+		let prefix = name.chars().take(MAX_NAME_LEN.saturating_sub(suffix.len()));
+		Some(prefix.chain(suffix.chars()).collect::<String>().into())
+	}
+}
+
 impl ConnectedConnection {
 	fn handle_command(
-		&mut self, logger: &Logger, book: &mut data::Connection,
-		stream_items: &mut VecDeque<Result<StreamItem>>, cmd: InCommandBuf,
+		&mut self, logger: &Logger, mut book: Option<&mut data::Connection>,
+		stream_items: &mut VecDeque<Result<StreamItem>>,
+		disconnect_reason: &mut Option<DisconnectReason>,
+		desired_state: &mut restore::DesiredState, cmd: InCommandBuf,
 	)
 	{
+		// Plugin messages are not part of the bookkeeping
+		if cmd.data().packet().content().starts_with(b"notifyplugincmd ") {
+			let msgs = PluginMessage::parse(cmd.data().packet().content());
+			stream_items.extend(msgs.into_iter().map(|m| Ok(StreamItem::PluginMessage(m))));
+			self.client.hand_back_buffer(cmd.into_buffer());
+			return;
+		}
+		let (name, _) = CommandParser::new(cmd.data().packet().content());
+		// The entries are already collected, the list ends with the error
+		if name == b"notifyfilelistfinished" && !self.pending_lists.is_empty() {
+			self.client.hand_back_buffer(cmd.into_buffer());
+			return;
+		}
+		// Answers to a list carry the return code of the request
+		if !self.pending_lists.is_empty() {
+			if let Some(handle) = get_return_code(cmd.data().packet().content()) {
+				if let Some(list) = self.pending_lists.iter_mut().find(|l| l.handle == handle) {
+					list.parts.push(cmd.data().packet().content().to_vec());
+					self.client.hand_back_buffer(cmd.into_buffer());
+					return;
+				}
+			}
+		}
+		if cmd.data().packet().content().starts_with(b"notifyftlist ") {
+			for t in foreign_transfers::parse(cmd.data().packet().content()) {
+				// Without bookkeeping, every announcement is reported
+				let new = book.as_mut().map(|b| b.server.add_file_transfer(t.clone()));
+				if new.unwrap_or(true) {
+					stream_items.push_back(Ok(StreamItem::ForeignFileTransfer(t)));
+				}
+			}
+			self.client.hand_back_buffer(cmd.into_buffer());
+			return;
+		}
+
+		let content = cmd.data().packet().content();
+		desired_state.update(self.own_client, content, &mut self.move_password);
+
 		let msg = match InMessage::new(
 			logger,
 			&cmd.data().packet().header(),
@@ -920,18 +2979,57 @@ impl ConnectedConnection {
 		) {
 			Ok(r) => r,
 			Err(e) => {
-				warn!(logger, "Failed to parse message"; "error" => %e);
+				self.error_limiter.push(
+					logger,
+					stream_items,
+					ErrorComponent::Parser,
+					Error::MessageParse(e),
+					Instant::now(),
+				);
 				return;
 			}
 		};
 
+		if let Some(reason) = DisconnectReason::from_message(self.own_client, &msg) {
+			*disconnect_reason = Some(reason);
+		}
+
 		// Handle error messages
 		if let InMessage::CommandError(e) = &msg {
 			for e in e.iter() {
 				if let Some(ret_code) = e.return_code.as_ref().and_then(|r| r.parse().ok()) {
-					let res = if e.id == TsError::Ok { Ok(()) } else { Err(e.id) };
-					stream_items
-						.push_back(Ok(StreamItem::MessageResult(MessageHandle(ret_code), res)));
+					let handle = MessageHandle(ret_code);
+					let error = CommandError {
+						id: e.id,
+						message: e.message.to_string(),
+						extra_message: e.extra_message.as_ref().map(|m| m.to_string()),
+						failed_permission: e.missing_permission_id,
+						return_code: Some(handle),
+					};
+					if e.id == TsError::ClientIsFlooding {
+						info!(logger, "Server reported flooding, pausing commands";
+							"wait" => ?flood::FLOOD_PAUSE);
+						if self.commands.flooding(handle, flood::FLOOD_PAUSE, Instant::now()) {
+							// Sent again later
+							continue;
+						}
+					} else {
+						self.commands.answered(handle);
+					}
+					if let Some(i) = self.pending_lists.iter().position(|l| l.handle == handle) {
+						let list = self.pending_lists.remove(i);
+						// An empty list is signaled with an error
+						let res = if e.id == TsError::Ok || e.id == TsError::DatabaseEmptyResult {
+							Ok(list.parts)
+						} else {
+							Err(error.into())
+						};
+						// The future may have been dropped already
+						let _ = list.send.send(res);
+						continue;
+					}
+					let res = if e.id == TsError::Ok { Ok(()) } else { Err(error) };
+					stream_items.push_back(Ok(StreamItem::MessageResult(handle, res)));
 				}
 			}
 		} else if let InMessage::FileDownload(msg) = &msg {
@@ -992,29 +3090,104 @@ impl ConnectedConnection {
 				stream_items
 					.push_back(Ok(StreamItem::FileTransferFailed(ft_id, msg.status.into())));
 			}
-		} else {
+		} else if let Some(book) = book {
 			let events = match book.handle_command(logger, &msg) {
 				Ok(r) => r,
 				Err(e) => {
-					warn!(logger, "Failed to handle message"; "error" => %e);
+					self.error_limiter.push(
+						logger,
+						stream_items,
+						ErrorComponent::Bookkeeping,
+						Error::Bookkeeping(e),
+						Instant::now(),
+					);
 					return;
 				}
 			};
 			self.client.hand_back_buffer(cmd.into_buffer());
-			if !events.is_empty() {
-				stream_items.push_back(Ok(StreamItem::ConEvents(events)));
+			let channels_known =
+				events.iter().any(|e| matches!(e, events::Event::ChannelListFinished));
+			if !events.is_empty() {
+				let typed = events.iter().map(events::Event::to_typed).collect();
+				stream_items.push_back(Ok(StreamItem::ConEvents(events)));
+				stream_items.push_back(Ok(StreamItem::TypedEvents(typed)));
+			}
+			if channels_known {
+				if let Some(state) = self.restore.take() {
+					self.restore_state(logger, Some(&*book), &state, stream_items);
+				}
+			}
+		} else {
+			// Bookkeeping is disabled, drop notifications
+			self.client.hand_back_buffer(cmd.into_buffer());
+		}
+	}
+
+	/// Send the commands to restore the state of our client after
+	/// reconnecting.
+	fn restore_state(
+		&mut self, logger: &Logger, book: Option<&data::Connection>,
+		state: &restore::DesiredState, stream_items: &mut VecDeque<Result<StreamItem>>,
+	)
+	{
+		let (cmds, failures) = state.commands(self.own_client, book);
+		// Keep the password when the server notifies us about the move
+		if let (Some(channel), Some(password)) = (state.channel, &state.channel_password) {
+			self.move_password = Some((channel, password.clone()));
+		}
+		info!(logger, "Restoring client state"; "commands" => cmds.len(),
+			"failures" => failures.len());
+		// Only our own client is changed, so the action policy has nothing to
+		// check.
+		for cmd in cmds {
+			if let Err(e) = self.send_unthrottled_command(cmd) {
+				warn!(logger, "Failed to restore client state"; "error" => %e);
 			}
 		}
+		stream_items.extend(failures.into_iter().map(|f| Ok(StreamItem::RestoreFailed(f))));
+	}
+
+	/// Queue a command and send it when the rate limit allows it.
+	fn send_command(&mut self, packet: OutCommand) -> Result<MessageHandle> {
+		let (handle, packet) = self.add_return_code(packet);
+		self.commands.push(handle, packet);
+		self.send_ready_commands()?;
+		Ok(handle)
 	}
 
-	fn send_command(&mut self, mut packet: OutCommand) -> Result<MessageHandle> {
+	/// Send a command immediately, without waiting for the rate limit.
+	fn send_unthrottled_command(&mut self, packet: OutCommand) -> Result<MessageHandle> {
+		let (handle, packet) = self.add_return_code(packet);
+		self.commands.bypass(Instant::now());
+		self.client.send_packet(packet).map(|_| handle).map_err(Error::SendPacket)
+	}
+
+	fn add_return_code(&mut self, mut packet: OutCommand) -> (MessageHandle, OutPacket) {
 		let code = self.cur_return_code;
 		self.cur_return_code += 1;
 		packet.write_arg("return_code", &code);
-		self.client
-			.send_packet(packet.into_packet())
-			.map(|_| MessageHandle(code))
-			.map_err(Error::SendPacket)
+		(MessageHandle(code), packet.into_packet())
+	}
+
+	fn send_ready_commands(&mut self) -> Result<()> {
+		let now = Instant::now();
+		while let Some((_, packet)) = self.commands.next_ready(now) {
+			self.client.send_packet(packet).map_err(Error::SendPacket)?;
+		}
+		Ok(())
+	}
+
+	/// Send queued commands when the rate limit or a pause after flooding
+	/// ends.
+	fn poll_commands(&mut self, cx: &mut Context) -> Result<()> {
+		if self.commands.poll_ready(cx).is_ready() {
+			self.send_ready_commands()?;
+			// Register the timer for the next command
+			if self.commands.poll_ready(cx).is_ready() {
+				cx.waker().wake_by_ref();
+			}
+		}
+		Ok(())
 	}
 
 	fn download_file(
@@ -1076,17 +3249,37 @@ impl ConnectedConnection {
 pub struct ConnectOptions {
 	address: ServerAddress,
 	local_address: Option<SocketAddr>,
+	bind_device: Option<Cow<'static, str>>,
 	identity: Option<Identity>,
 	name: Cow<'static, str>,
+	name_collision_strategy: NameCollisionStrategy,
 	version: Version,
 	hardware_id: Cow<'static, str>,
+	badges: Option<Badges>,
+	input_muted: bool,
+	output_muted: bool,
+	input_hardware: bool,
+	output_hardware: bool,
+	meta_data: Cow<'static, str>,
 	channel: Option<Cow<'static, str>>,
 	channel_password: Option<Cow<'static, str>>,
 	password: Option<Cow<'static, str>>,
 	logger: Option<Logger>,
 	log_commands: bool,
-	log_packets: bool,
+	log_incoming_packets: bool,
+	log_outgoing_packets: bool,
 	log_udp_packets: bool,
+	capture_to: Option<PathBuf>,
+	bookkeeping: bool,
+	dns_config: resolver::DnsConfig,
+	action_policy: policy::ActionPolicy,
+	resend_config: ResendConfig,
+	max_level_upgrade: u8,
+	rate_limit: Option<flood::RateLimit>,
+	expected_server_uid: Option<Uid>,
+	restore_state: bool,
+	#[cfg(feature = "socks5")]
+	proxy: Option<proxy::ProxyConfig>,
 }
 
 impl ConnectOptions {
@@ -1098,26 +3291,75 @@ impl ConnectOptions {
 	/// will automatically be resolved from all formats supported by TeamSpeak.
 	/// For details, see [`resolver::resolve`].
 	///
+	/// The address can also be a `ts3server://` invite link. The password,
+	/// nickname and channel of the link are used as defaults for the options,
+	/// setting them explicitly overwrites the values from the link.
+	///
+	/// # Example
+	///
+	/// ```
+	/// # use tsclientlib::ConnectOptions;
+	/// let opts = ConnectOptions::new("ts3server://localhost?port=9988&nickname=Bot&password=pw")
+	///     .name("Other name");
+	/// assert_eq!(opts.get_address().to_string(), "localhost:9988");
+	/// assert_eq!(opts.get_name(), "Other name");
+	/// assert_eq!(opts.get_password(), Some("pw"));
+	/// ```
+	///
 	/// [`SocketAddr`]: ../../std/net/enum.SocketAddr.html
 	/// [`ServerAddress`]: enum.ServerAddress.html
 	/// [`resolver::resolve`]: resolver/method.resolve.html
-	#[inline]
 	pub fn new<A: Into<ServerAddress>>(address: A) -> Self {
-		Self {
-			address: address.into(),
+		let address = address.into();
+		let link = match &address {
+			ServerAddress::Other(s) => resolver::parse_invite_link(s),
+			_ => None,
+		};
+		let mut res = Self {
+			address,
 			local_address: None,
+			bind_device: None,
 			identity: None,
 			name: "TeamSpeakUser".into(),
+			name_collision_strategy: NameCollisionStrategy::Fail,
 			version: Version::Windows_3_X_X__1,
 			hardware_id: "923f136fb1e22ae6ce95e60255529c00,d13231b1bc33edfecfb9169cc7a63bcc".into(),
+			badges: None,
+			input_muted: false,
+			output_muted: false,
+			input_hardware: true,
+			output_hardware: true,
+			meta_data: "".into(),
 			channel: None,
 			channel_password: None,
 			password: None,
 			logger: None,
 			log_commands: false,
-			log_packets: false,
+			log_incoming_packets: false,
+			log_outgoing_packets: false,
 			log_udp_packets: false,
+			capture_to: None,
+			bookkeeping: true,
+			dns_config: Default::default(),
+			action_policy: Default::default(),
+			resend_config: Default::default(),
+			max_level_upgrade: 20,
+			rate_limit: Some(Default::default()),
+			expected_server_uid: None,
+			restore_state: true,
+			#[cfg(feature = "socks5")]
+			proxy: None,
+		};
+		if let Some(link) = link {
+			res.address = ServerAddress::Other(link.address);
+			if let Some(name) = link.nickname {
+				res.name = name.into();
+			}
+			res.password = link.password.map(Into::into);
+			res.channel = link.channel.map(Into::into);
+			res.channel_password = link.channel_password.map(Into::into);
 		}
+		res
 	}
 
 	/// The address for the socket of our client
@@ -1131,6 +3373,37 @@ impl ConnectOptions {
 		self
 	}
 
+	/// Only send and receive through this network interface, e.g. `eth1`.
+	///
+	/// This uses `SO_BINDTODEVICE` and is only supported on Linux, where it
+	/// usually needs the `CAP_NET_RAW` capability. On other platforms,
+	/// connecting fails with [`Error::Bind`].
+	///
+	/// # Default
+	/// The interface is chosen by the routing table.
+	///
+	/// [`Error::Bind`]: enum.Error.html#variant.Bind
+	#[inline]
+	pub fn bind_device<S: Into<Cow<'static, str>>>(mut self, device: S) -> Self {
+		self.bind_device = Some(device.into());
+		self
+	}
+
+	/// Send the udp traffic through a SOCKS5 proxy.
+	///
+	/// If the proxy cannot be used, connecting fails with [`Error::Proxy`].
+	///
+	/// # Default
+	/// No proxy is used.
+	///
+	/// [`Error::Proxy`]: enum.Error.html#variant.Proxy
+	#[cfg(feature = "socks5")]
+	#[inline]
+	pub fn proxy(mut self, proxy: proxy::ProxyConfig) -> Self {
+		self.proxy = Some(proxy);
+		self
+	}
+
 	/// Set the identity of the user.
 	///
 	/// # Default
@@ -1151,8 +3424,30 @@ impl ConnectOptions {
 		self
 	}
 
+	/// What to do if the name is already in use on the server.
+	///
+	/// Other names are tried on the same connection, without a new handshake.
+	/// The name that was used in the end can be retrieved with
+	/// [`Connection::get_name`].
+	///
+	/// # Default
+	/// [`NameCollisionStrategy::Fail`]
+	///
+	/// [`Connection::get_name`]: struct.Connection.html#method.get_name
+	/// [`NameCollisionStrategy::Fail`]: enum.NameCollisionStrategy.html#variant.Fail
+	#[inline]
+	pub fn name_collision_strategy(mut self, strategy: NameCollisionStrategy) -> Self {
+		self.name_collision_strategy = strategy;
+		self
+	}
+
 	/// The displayed version of the client.
 	///
+	/// Other versions can be set with [`Version::custom`], connecting fails
+	/// if their signature is invalid.
+	///
+	/// [`Version::custom`]: enum.Version.html#method.custom
+	///
 	/// # Default
 	/// `3.2.1 on Linux`
 	#[inline]
@@ -1171,6 +3466,85 @@ impl ConnectOptions {
 		self
 	}
 
+	/// The badges which our client shows to others.
+	///
+	/// They can be changed later with [`data::Connection::set_badges`].
+	///
+	/// # Default
+	/// No badges are sent.
+	///
+	/// [`data::Connection::set_badges`]: data/struct.Connection.html#method.set_badges
+	#[inline]
+	pub fn badges(mut self, badges: Badges) -> Self {
+		self.badges = Some(badges);
+		self
+	}
+
+	/// Show our microphone as muted after connecting.
+	///
+	/// Some servers require clients in their AFK channel to be muted.
+	///
+	/// The muted flags are sent after connecting, they are only sent if the
+	/// [`bookkeeping`] is enabled.
+	///
+	/// # Default
+	/// `false`
+	///
+	/// [`bookkeeping`]: #method.bookkeeping
+	#[inline]
+	pub fn input_muted(mut self, input_muted: bool) -> Self {
+		self.input_muted = input_muted;
+		self
+	}
+
+	/// Show our speakers as muted after connecting.
+	///
+	/// # Default
+	/// `false`
+	#[inline]
+	pub fn output_muted(mut self, output_muted: bool) -> Self {
+		self.output_muted = output_muted;
+		self
+	}
+
+	/// If we have a microphone. Without one, other clients see a crossed out
+	/// microphone.
+	///
+	/// # Default
+	/// `true`
+	#[inline]
+	pub fn input_hardware(mut self, input_hardware: bool) -> Self {
+		self.input_hardware = input_hardware;
+		self
+	}
+
+	/// If we have speakers.
+	///
+	/// # Default
+	/// `true`
+	#[inline]
+	pub fn output_hardware(mut self, output_hardware: bool) -> Self {
+		self.output_hardware = output_hardware;
+		self
+	}
+
+	/// Free-form data which other clients can read, e.g. to exchange state
+	/// between bots.
+	///
+	/// The data is sent escaped in `clientinit`. Connecting fails if it is
+	/// bigger than [`MAX_COMMAND_LEN`] after escaping, the whole `clientinit`
+	/// should stay below that.
+	///
+	/// # Default
+	/// Empty
+	///
+	/// [`MAX_COMMAND_LEN`]: constant.MAX_COMMAND_LEN.html
+	#[inline]
+	pub fn meta_data<S: Into<Cow<'static, str>>>(mut self, meta_data: S) -> Self {
+		self.meta_data = meta_data.into();
+		self
+	}
+
 	/// Connect to a specific channel.
 	///
 	/// # Example
@@ -1185,6 +3559,12 @@ impl ConnectOptions {
 	/// let opts = ConnectOptions::new("localhost")
 	///     .channel("Default Channel/Nested");
 	/// ```
+	///
+	/// A `/` inside a channel name is escaped with a backslash.
+	/// ```
+	/// # use tsclientlib::ConnectOptions;
+	/// let opts = ConnectOptions::new("localhost").channel(r"Music/AC\/DC");
+	/// ```
 	#[inline]
 	pub fn channel<S: Into<Cow<'static, str>>>(mut self, path: S) -> Self {
 		self.channel = Some(path.into());
@@ -1234,7 +3614,9 @@ impl ConnectOptions {
 		self
 	}
 
-	/// If the content of all commands should be written to the logger.
+	/// If the text of all commands should be written to the logger.
+	///
+	/// Commands are logged at info level, other packets are not logged.
 	///
 	/// # Default
 	/// `false`
@@ -1247,11 +3629,38 @@ impl ConnectOptions {
 	/// If the content of all packets in high-level form should be written to
 	/// the logger.
 	///
+	/// This is a shorthand for [`log_incoming_packets`] and
+	/// [`log_outgoing_packets`].
+	///
+	/// # Default
+	/// `false`
+	///
+	/// [`log_incoming_packets`]: #method.log_incoming_packets
+	/// [`log_outgoing_packets`]: #method.log_outgoing_packets
+	#[inline]
+	pub fn log_packets(self, log_packets: bool) -> Self {
+		self.log_incoming_packets(log_packets).log_outgoing_packets(log_packets)
+	}
+
+	/// If the content of all received packets in high-level form should be
+	/// written to the logger.
+	///
+	/// # Default
+	/// `false`
+	#[inline]
+	pub fn log_incoming_packets(mut self, log_incoming_packets: bool) -> Self {
+		self.log_incoming_packets = log_incoming_packets;
+		self
+	}
+
+	/// If the content of all sent packets in high-level form should be written
+	/// to the logger.
+	///
 	/// # Default
 	/// `false`
 	#[inline]
-	pub fn log_packets(mut self, log_packets: bool) -> Self {
-		self.log_packets = log_packets;
+	pub fn log_outgoing_packets(mut self, log_outgoing_packets: bool) -> Self {
+		self.log_outgoing_packets = log_outgoing_packets;
 		self
 	}
 
@@ -1266,6 +3675,215 @@ impl ConnectOptions {
 		self
 	}
 
+	/// Write all decrypted packets to a file, which can be read with
+	/// [`capture::read_file`].
+	///
+	/// The packets are appended if the file exists already.
+	///
+	/// # Default
+	/// No packets are captured.
+	///
+	/// [`capture::read_file`]: capture/fn.read_file.html
+	#[inline]
+	pub fn capture_to<P: Into<PathBuf>>(mut self, path: P) -> Self {
+		self.capture_to = Some(path.into());
+		self
+	}
+
+	/// If the state of the server should be tracked.
+	///
+	/// Without bookkeeping, notifications from the server are dropped and
+	/// [`Connection::get_state`] returns an error. Sending commands and audio
+	/// still works. This saves resources for clients which do not need to
+	/// know anything about the server, like load generators.
+	///
+	/// # Default
+	/// `true`
+	///
+	/// [`Connection::get_state`]: struct.Connection.html#method.get_state
+	#[inline]
+	pub fn bookkeeping(mut self, bookkeeping: bool) -> Self {
+		self.bookkeeping = bookkeeping;
+		self
+	}
+
+	/// If cached addresses should be used when resolving the server address.
+	///
+	/// Disabling the cache always resolves the address again, e.g. when the
+	/// server moved.
+	///
+	/// # Default
+	/// `true`
+	#[inline]
+	pub fn dns_cache(mut self, dns_cache: bool) -> Self {
+		self.dns_config = self.dns_config.cache(dns_cache);
+		self
+	}
+
+	/// Use these dns servers to resolve the server address instead of the
+	/// servers from the system configuration.
+	///
+	/// # Default
+	/// The system configuration is used.
+	#[inline]
+	pub fn dns_servers(mut self, servers: Vec<SocketAddr>) -> Self {
+		self.dns_config = self.dns_config.servers(servers);
+		self
+	}
+
+	/// Which kind of ip addresses are used to connect to the server.
+	///
+	/// # Default
+	/// All addresses are tried in the order in which they are resolved.
+	#[inline]
+	pub fn address_family(mut self, address_family: resolver::AddressFamily) -> Self {
+		self.dns_config = self.dns_config.address_family(address_family);
+		self
+	}
+
+	/// The settings for resolving the server address, including the dns
+	/// servers, the timeout and the number of attempts per lookup.
+	///
+	/// # Default
+	/// The system configuration with the address cache enabled.
+	#[inline]
+	pub fn dns_config(mut self, dns_config: resolver::DnsConfig) -> Self {
+		self.dns_config = dns_config;
+		self
+	}
+
+	/// Check destructive actions like deleting channels or banning clients
+	/// before sending them.
+	///
+	/// For details, see the [`policy`](policy/index.html) module.
+	///
+	/// # Default
+	/// All actions are allowed.
+	#[inline]
+	pub fn action_policy(mut self, action_policy: policy::ActionPolicy) -> Self {
+		self.action_policy = action_policy;
+		self
+	}
+
+	/// Give up connecting if the server does not answer for this duration.
+	///
+	/// This sets the `connecting_timeout` of the [`resend_config`], so it
+	/// applies to the handshake and to the wait for the `initserver` after
+	/// sending `clientinit`, each one separately. The timeout of an
+	/// established connection can be changed with [`resend_config`].
+	///
+	/// # Default
+	/// 5 seconds
+	///
+	/// [`resend_config`]: #method.resend_config
+	#[inline]
+	pub fn timeout(mut self, timeout: time::Duration) -> Self {
+		self.resend_config.connecting_timeout = timeout;
+		self
+	}
+
+	/// Timeouts and start values for the round trip time used for resending
+	/// packets.
+	///
+	/// The configuration replaces the timeout set with [`timeout`] and vice
+	/// versa, so [`timeout`] has to be called afterwards to combine both.
+	///
+	/// # Example
+	/// Allow lossy connections to be unresponsive for a longer time.
+	/// ```
+	/// # use std::time::Duration;
+	/// # use tsclientlib::{ConnectOptions, ResendConfig};
+	/// let opts = ConnectOptions::new("localhost").resend_config(ResendConfig {
+	///     normal_timeout: Duration::from_secs(60),
+	///     ..Default::default()
+	/// });
+	/// ```
+	///
+	/// # Default
+	/// The default of [`ResendConfig`].
+	///
+	/// [`timeout`]: #method.timeout
+	/// [`ResendConfig`]: struct.ResendConfig.html
+	#[inline]
+	pub fn resend_config(mut self, resend_config: ResendConfig) -> Self {
+		self.resend_config = resend_config;
+		self
+	}
+
+	/// Increase the identity level automatically up to `max_level`, if the
+	/// server requires a higher level than the identity has.
+	///
+	/// The progress is reported with [`StreamItem::IdentityLevelProgress`]
+	/// events. Every level doubles the expected time to compute it, a level of
+	/// 30 can take hours. If the server requires a higher level, connecting
+	/// fails with [`Error::IdentityLevel`]. Set it to `0` to never increase
+	/// the level.
+	///
+	/// # Default
+	/// `20`
+	///
+	/// [`StreamItem::IdentityLevelProgress`]: enum.StreamItem.html#variant.IdentityLevelProgress
+	/// [`Error::IdentityLevel`]: enum.Error.html#variant.IdentityLevel
+	#[inline]
+	pub fn allow_level_upgrade(mut self, max_level: u8) -> Self {
+		self.max_level_upgrade = max_level;
+		self
+	}
+
+	/// Limit how fast commands are sent to the server.
+	///
+	/// Commands which exceed the limit are queued and sent later, so the
+	/// server does not block us for flooding. If the server reports flooding
+	/// anyway, sending commands pauses and the rejected command is sent again
+	/// once. Set it to `None` to send all commands immediately.
+	///
+	/// Commands which the library sends on its own, e.g. to restore our state
+	/// after reconnecting, are sent immediately but count towards the limit.
+	///
+	/// # Default
+	/// 30 commands per 30 seconds, see [`RateLimit`].
+	///
+	/// [`RateLimit`]: flood/struct.RateLimit.html
+	#[inline]
+	pub fn rate_limit(mut self, rate_limit: Option<flood::RateLimit>) -> Self {
+		self.rate_limit = rate_limit;
+		self
+	}
+
+	/// Only connect if the server has this uid.
+	///
+	/// The uid is computed from the public key of the server after the
+	/// handshake. If it does not match, the connection is aborted with
+	/// [`Error::ServerUidMismatch`] before our client data is sent. The uid of
+	/// a server can be obtained with [`Connection::server_uid`].
+	///
+	/// # Default
+	/// All servers are accepted.
+	///
+	/// [`Error::ServerUidMismatch`]: enum.Error.html#variant.ServerUidMismatch
+	/// [`Connection::server_uid`]: struct.Connection.html#method.server_uid
+	#[inline]
+	pub fn expected_server_uid(mut self, uid: Uid) -> Self {
+		self.expected_server_uid = Some(uid);
+		self
+	}
+
+	/// Move back into our channel and set our away and muted flags again
+	/// after the connection was rebuilt.
+	///
+	/// Only changes of our client since connecting are restored, see the
+	/// [`restore`] module.
+	///
+	/// # Default
+	/// `true`
+	///
+	/// [`restore`]: restore/index.html
+	#[inline]
+	pub fn restore_state(mut self, restore_state: bool) -> Self {
+		self.restore_state = restore_state;
+		self
+	}
+
 	/// Set a custom logger for the connection.
 	///
 	/// # Default
@@ -1281,14 +3899,35 @@ impl ConnectOptions {
 	#[inline]
 	pub fn get_local_address(&self) -> Option<&SocketAddr> { self.local_address.as_ref() }
 	#[inline]
+	pub fn get_bind_device(&self) -> Option<&str> { self.bind_device.as_deref() }
+	#[cfg(feature = "socks5")]
+	#[inline]
+	pub fn get_proxy(&self) -> Option<&proxy::ProxyConfig> { self.proxy.as_ref() }
+	#[inline]
 	pub fn get_identity(&self) -> Option<&Identity> { self.identity.as_ref() }
 	#[inline]
 	pub fn get_name(&self) -> &str { &self.name }
 	#[inline]
+	pub fn get_name_collision_strategy(&self) -> NameCollisionStrategy {
+		self.name_collision_strategy
+	}
+	#[inline]
 	pub fn get_version(&self) -> &Version { &self.version }
 	#[inline]
 	pub fn get_hardware_id(&self) -> &str { &self.hardware_id }
 	#[inline]
+	pub fn get_badges(&self) -> Option<&Badges> { self.badges.as_ref() }
+	#[inline]
+	pub fn get_input_muted(&self) -> bool { self.input_muted }
+	#[inline]
+	pub fn get_output_muted(&self) -> bool { self.output_muted }
+	#[inline]
+	pub fn get_input_hardware(&self) -> bool { self.input_hardware }
+	#[inline]
+	pub fn get_output_hardware(&self) -> bool { self.output_hardware }
+	#[inline]
+	pub fn get_meta_data(&self) -> &str { &self.meta_data }
+	#[inline]
 	pub fn get_channel(&self) -> Option<&str> { self.channel.as_ref().map(AsRef::as_ref) }
 	#[inline]
 	pub fn get_channel_password(&self) -> Option<&str> {
@@ -1301,7 +3940,50 @@ impl ConnectOptions {
 	#[inline]
 	pub fn get_log_commands(&self) -> bool { self.log_commands }
 	#[inline]
-	pub fn get_log_packets(&self) -> bool { self.log_packets }
+	pub fn get_log_packets(&self) -> bool {
+		self.log_incoming_packets && self.log_outgoing_packets
+	}
+	#[inline]
+	pub fn get_log_incoming_packets(&self) -> bool { self.log_incoming_packets }
+	#[inline]
+	pub fn get_log_outgoing_packets(&self) -> bool { self.log_outgoing_packets }
 	#[inline]
 	pub fn get_log_udp_packets(&self) -> bool { self.log_udp_packets }
+	#[inline]
+	pub fn get_capture_to(&self) -> Option<&Path> { self.capture_to.as_deref() }
+	/// The combination of all logging options.
+	pub fn get_log_config(&self) -> LogConfig {
+		let base = if self.log_udp_packets {
+			Verbosity::UdpPackets
+		} else if self.log_commands {
+			Verbosity::Commands
+		} else {
+			Verbosity::Off
+		};
+		let packets = |enabled| if enabled { cmp::max(base, Verbosity::Packets) } else { base };
+		LogConfig {
+			incoming: packets(self.log_incoming_packets),
+			outgoing: packets(self.log_outgoing_packets),
+		}
+	}
+	#[inline]
+	pub fn get_bookkeeping(&self) -> bool { self.bookkeeping }
+	#[inline]
+	pub fn get_dns_cache(&self) -> bool { self.dns_config.get_cache() }
+	#[inline]
+	pub fn get_dns_config(&self) -> &resolver::DnsConfig { &self.dns_config }
+	#[inline]
+	pub fn get_action_policy(&self) -> &policy::ActionPolicy { &self.action_policy }
+	#[inline]
+	pub fn get_timeout(&self) -> time::Duration { self.resend_config.connecting_timeout }
+	#[inline]
+	pub fn get_resend_config(&self) -> &ResendConfig { &self.resend_config }
+	#[inline]
+	pub fn get_allow_level_upgrade(&self) -> u8 { self.max_level_upgrade }
+	#[inline]
+	pub fn get_rate_limit(&self) -> Option<flood::RateLimit> { self.rate_limit }
+	#[inline]
+	pub fn get_expected_server_uid(&self) -> Option<&Uid> { self.expected_server_uid.as_ref() }
+	#[inline]
+	pub fn get_restore_state(&self) -> bool { self.restore_state }
 }
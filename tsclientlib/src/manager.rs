@@ -0,0 +1,225 @@
+//! Run many connections through a single stream.
+//!
+//! Bots which sit on several servers can add all their connections to a
+//! [`ConnectionManager`] and poll it as one stream of events. Every event
+//! comes with the [`ConnectionId`] of the connection that produced it.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use futures::prelude::*;
+//! # use tsclientlib::ConnectOptions;
+//! # use tsclientlib::manager::ConnectionManager;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let mut manager = ConnectionManager::new();
+//! let first = manager.add_connection(ConnectOptions::new("localhost")).unwrap();
+//! let second = manager.add_connection(ConnectOptions::new("example.com")).unwrap();
+//! while let Some((id, event)) = manager.next().await {
+//!     println!("Connection {}: {:?}", id, event);
+//! }
+//! # }
+//! ```
+//!
+//! [`ConnectionManager`]: struct.ConnectionManager.html
+//! [`ConnectionId`]: struct.ConnectionId.html
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use futures::prelude::*;
+use slog::info;
+
+use crate::{ConnectOptions, Connection, DisconnectOptions, Result, StreamItem};
+
+/// Identifies a connection in a [`ConnectionManager`].
+///
+/// Ids are not reused after a connection was removed.
+///
+/// [`ConnectionManager`]: struct.ConnectionManager.html
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ConnectionId(pub u64);
+
+struct ManagedConnection {
+	id: ConnectionId,
+	con: Connection,
+	/// Set when the connection should be removed after it disconnected.
+	removing: bool,
+}
+
+/// Owns multiple connections and multiplexes their events.
+///
+/// The manager is a stream of `(ConnectionId, Result<StreamItem>)`. The
+/// connections are polled in turns, so a busy connection cannot starve the
+/// others. Connections which end are removed.
+///
+/// The stream does not end when there are no connections, new connections
+/// can be added at any time.
+#[derive(Default)]
+pub struct ConnectionManager {
+	connections: Vec<ManagedConnection>,
+	next_id: u64,
+	/// The connection which is polled first the next time.
+	poll_index: usize,
+	/// Woken when a connection is added.
+	waker: Option<Waker>,
+}
+
+impl fmt::Display for ConnectionId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.0.fmt(f) }
+}
+
+impl ConnectionManager {
+	#[inline]
+	pub fn new() -> Self { Self::default() }
+
+	/// Create a new connection and add it to the manager.
+	///
+	/// The connection starts connecting when the manager is polled.
+	pub fn add_connection(&mut self, options: ConnectOptions) -> Result<ConnectionId> {
+		Ok(self.insert_connection(Connection::new(options)?))
+	}
+
+	/// Add an existing connection to the manager.
+	pub fn insert_connection(&mut self, con: Connection) -> ConnectionId {
+		let id = ConnectionId(self.next_id);
+		self.next_id += 1;
+		self.connections.push(ManagedConnection { id, con, removing: false });
+		if let Some(waker) = self.waker.take() {
+			waker.wake();
+		}
+		id
+	}
+
+	/// Disconnect a connection and remove it from the manager.
+	///
+	/// A connected connection is kept until the server acknowledged the
+	/// disconnect, its remaining events are still returned by the stream.
+	/// Other connections are removed immediately.
+	///
+	/// Does nothing if there is no connection with this id.
+	pub fn remove_connection(
+		&mut self, id: ConnectionId, options: DisconnectOptions,
+	) -> Result<()> {
+		if let Some(i) = self.position(id) {
+			let managed = &mut self.connections[i];
+			if managed.con.is_connected() {
+				managed.con.disconnect(options)?;
+				managed.removing = true;
+			} else {
+				self.remove_index(i);
+			}
+		}
+		Ok(())
+	}
+
+	#[inline]
+	pub fn get_connection(&self, id: ConnectionId) -> Option<&Connection> {
+		self.position(id).map(|i| &self.connections[i].con)
+	}
+
+	#[inline]
+	pub fn get_mut_connection(&mut self, id: ConnectionId) -> Option<&mut Connection> {
+		self.position(id).map(move |i| &mut self.connections[i].con)
+	}
+
+	/// All connections, in the order in which they were added.
+	pub fn connections(&self) -> impl Iterator<Item = (ConnectionId, &Connection)> {
+		self.connections.iter().map(|c| (c.id, &c.con))
+	}
+
+	/// The number of connections, including connections which are
+	/// disconnecting.
+	#[inline]
+	pub fn len(&self) -> usize { self.connections.len() }
+
+	#[inline]
+	pub fn is_empty(&self) -> bool { self.connections.is_empty() }
+
+	fn position(&self, id: ConnectionId) -> Option<usize> {
+		self.connections.iter().position(|c| c.id == id)
+	}
+
+	fn remove_index(&mut self, i: usize) -> ManagedConnection {
+		// Keep polling at the same connection
+		if i < self.poll_index {
+			self.poll_index -= 1;
+		}
+		self.connections.remove(i)
+	}
+}
+
+impl Stream for ConnectionManager {
+	type Item = (ConnectionId, Result<StreamItem>);
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		let mut polled = 0;
+		while polled < self.connections.len() {
+			if self.poll_index >= self.connections.len() {
+				self.poll_index = 0;
+			}
+			let i = self.poll_index;
+			let managed = &mut self.connections[i];
+			match managed.con.poll_next(cx) {
+				Poll::Ready(Some(item)) => {
+					let id = managed.id;
+					// Start with the next connection next time
+					self.poll_index = i + 1;
+					return Poll::Ready(Some((id, item)));
+				}
+				Poll::Ready(None) => {
+					let managed = self.remove_index(i);
+					if !managed.removing {
+						info!(managed.con.logger, "Connection ended, removing it from the manager";
+							"id" => managed.id.0);
+					}
+				}
+				Poll::Pending => {
+					self.poll_index = i + 1;
+					polled += 1;
+				}
+			}
+		}
+
+		self.waker = Some(cx.waker().clone());
+		Poll::Pending
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::tests::get_logger;
+
+	fn options() -> ConnectOptions { ConnectOptions::new("127.0.0.1:1").logger(get_logger()) }
+
+	#[test]
+	fn add_and_remove() {
+		let mut manager = ConnectionManager::new();
+		let first = manager.add_connection(options()).unwrap();
+		let second = manager.add_connection(options()).unwrap();
+		assert_ne!(first, second);
+		assert_eq!(manager.len(), 2);
+		assert_eq!(manager.connections().map(|(id, _)| id).collect::<Vec<_>>(), [first, second]);
+
+		// Not connected yet, so it is removed immediately
+		manager.remove_connection(first, DisconnectOptions::new()).unwrap();
+		assert!(manager.get_connection(first).is_none());
+		assert!(manager.get_connection(second).is_some());
+
+		// Ids are not reused
+		let third = manager.add_connection(options()).unwrap();
+		assert_ne!(third, first);
+		assert_eq!(manager.len(), 2);
+	}
+
+	#[test]
+	fn remove_keeps_poll_order() {
+		let mut manager = ConnectionManager::new();
+		let ids = (0..3).map(|_| manager.add_connection(options()).unwrap()).collect::<Vec<_>>();
+		manager.poll_index = 2;
+		manager.remove_connection(ids[0], DisconnectOptions::new()).unwrap();
+		// Still points to the third connection
+		assert_eq!(manager.connections[manager.poll_index].id, ids[2]);
+	}
+}
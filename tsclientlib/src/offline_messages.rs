@@ -0,0 +1,126 @@
+//! Offline messages, which can be sent to clients that are not online.
+//!
+//! The messages are stored on the server and can be listed by the receiver the
+//! next time they connect.
+use time::OffsetDateTime;
+use tsproto_packets::packets::OutCommand;
+use tsproto_types::Uid;
+
+use crate::{new_command, parse_list, ParseEntry};
+
+/// An offline message that is stored on the server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OfflineMessage {
+	pub id: u32,
+	/// The uid of the client that sent this message.
+	pub sender: Option<Uid>,
+	pub subject: String,
+	/// The content of the message.
+	///
+	/// This is only set when a single message is requested with
+	/// [`Connection::get_offline_message`].
+	///
+	/// [`Connection::get_offline_message`]: ../struct.Connection.html#method.get_offline_message
+	pub message: Option<String>,
+	pub timestamp: OffsetDateTime,
+	pub is_read: bool,
+}
+
+pub(crate) fn add(target: &Uid, subject: &str, message: &str) -> OutCommand {
+	let mut cmd = new_command("messageadd");
+	cmd.write_arg("cluid", target);
+	cmd.write_arg("subject", &subject);
+	cmd.write_arg("message", &message);
+	cmd
+}
+
+pub(crate) fn list() -> OutCommand { new_command("messagelist") }
+
+pub(crate) fn get(id: u32) -> OutCommand {
+	let mut cmd = new_command("messageget");
+	cmd.write_arg("msgid", &id);
+	cmd
+}
+
+pub(crate) fn delete(id: u32) -> OutCommand {
+	let mut cmd = new_command("messagedel");
+	cmd.write_arg("msgid", &id);
+	cmd
+}
+
+pub(crate) fn set_read(id: u32, is_read: bool) -> OutCommand {
+	let mut cmd = new_command("messageupdateflag");
+	cmd.write_arg("msgid", &id);
+	cmd.write_arg("flag", &(is_read as u8));
+	cmd
+}
+
+/// Parse the messages from a `notifymessagelist` or `notifymessage`.
+pub(crate) fn parse(content: &[u8]) -> Vec<OfflineMessage> {
+	parse_list(content, |args| {
+		let mut id = None;
+		let mut sender = None;
+		let mut subject = String::new();
+		let mut message = None;
+		let mut timestamp = OffsetDateTime::unix_epoch();
+		let mut is_read = false;
+		for (name, value) in args {
+			match name {
+				b"msgid" => id = value.parse().ok(),
+				b"cluid" => sender = base64::decode(value.as_ref()).ok().map(Uid),
+				b"subject" => subject = value.into_owned(),
+				b"message" => message = Some(value.into_owned()),
+				b"timestamp" => {
+					timestamp =
+						OffsetDateTime::from_unix_timestamp(value.parse().unwrap_or_default())
+				}
+				b"flag_read" => is_read = value == "1",
+				_ => {}
+			}
+		}
+		Some(OfflineMessage { id: id?, sender, subject, message, timestamp, is_read })
+	})
+}
+
+impl ParseEntry for OfflineMessage {
+	fn parse(content: &[u8]) -> Vec<Self> { parse(content) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn parse_list() {
+		let msgs = parse(
+			br#"notifymessagelist msgid=1 cluid=T3RoZXI= subject=Hello timestamp=1500000000 flag_read=0|msgid=2 cluid=T3RoZXI= subject=Again timestamp=1500000001 flag_read=1"#,
+		);
+		assert_eq!(msgs.len(), 2);
+		assert_eq!(msgs[0].id, 1);
+		assert_eq!(msgs[0].sender, Some(Uid(b"Other".to_vec())));
+		assert_eq!(msgs[0].subject, "Hello");
+		assert_eq!(msgs[0].message, None);
+		assert!(!msgs[0].is_read);
+		assert_eq!(msgs[1].subject, "Again");
+		assert_eq!(msgs[1].timestamp.timestamp(), 1500000001);
+		assert!(msgs[1].is_read);
+	}
+
+	#[test]
+	fn parse_message() {
+		let msgs = parse(
+			br#"notifymessage msgid=3 cluid=T3RoZXI= subject=Hi message=Some\stext timestamp=1500000000"#,
+		);
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0].message.as_deref(), Some("Some text"));
+	}
+
+	#[test]
+	fn add_message() {
+		let cmd = add(&Uid(b"Other".to_vec()), "Hi", "Some text");
+		assert_eq!(
+			cmd.0.content(),
+			br#"messageadd cluid=T3RoZXI= subject=Hi message=Some\stext"#.as_ref()
+		);
+	}
+}
@@ -0,0 +1,298 @@
+//! Compound actions which are rolled back when one of their steps fails.
+//!
+//! An [`Operation`] runs a list of steps one after another. Each step can
+//! register a compensating action, e.g. a step that creates a channel can
+//! delete the channel again. If a step fails or the operation times out, the
+//! compensations of all finished steps run in reverse order.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use tsclientlib::operation::Operation;
+//! # use tsclientlib::sync::SyncConnectionHandle;
+//! # async fn f(handle: SyncConnectionHandle) -> Result<(), tsclientlib::operation::Error> {
+//! Operation::new(&handle)
+//!     .step_with_rollback(
+//!         "create channel",
+//!         |_handle| async { /* Create the channel */ Ok(5u64) },
+//!         |_handle, _id| async { /* Delete the channel again */ Ok(()) },
+//!     )
+//!     .step("move client", |_handle| async { Ok(()) })
+//!     .run()
+//!     .await
+//! # }
+//! ```
+//!
+//! [`Operation`]: struct.Operation.html
+use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use slog::{debug, o, warn, Logger};
+use thiserror::Error;
+
+use crate::sync::SyncConnectionHandle;
+
+/// The time that an operation can take if no timeout is set.
+pub const DEFAULT_OPERATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Action<T> = Box<dyn FnOnce(SyncConnectionHandle) -> BoxFuture<'static, T> + Send>;
+type Compensation = Action<crate::Result<()>>;
+type StepAction = Action<crate::Result<Option<Compensation>>>;
+
+struct Step {
+	name: Cow<'static, str>,
+	run: StepAction,
+}
+
+/// The reason why a step of an operation failed.
+#[derive(Debug, Error)]
+pub enum StepError {
+	#[error(transparent)]
+	Failed(crate::Error),
+	/// The timeout of the operation elapsed while this step was running.
+	#[error("Operation timed out")]
+	Timeout,
+}
+
+/// A failed operation.
+///
+/// All finished steps were rolled back, errors which happened during the
+/// rollback are collected in `rollback_errors`.
+#[derive(Debug, Error)]
+pub struct Error {
+	/// The index of the failed step.
+	pub step: usize,
+	/// The name of the failed step.
+	pub name: Cow<'static, str>,
+	#[source]
+	pub cause: StepError,
+	/// The names of the steps where the compensation failed and the errors.
+	pub rollback_errors: Vec<(Cow<'static, str>, crate::Error)>,
+}
+
+/// A list of steps that are executed as one unit.
+///
+/// For details, see the [module documentation](index.html).
+pub struct Operation {
+	handle: SyncConnectionHandle,
+	logger: Logger,
+	timeout: Duration,
+	steps: Vec<Step>,
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Step {} ({}) failed: {}", self.step, self.name, self.cause)?;
+		if !self.rollback_errors.is_empty() {
+			write!(f, " (rollback failed for")?;
+			for (i, (name, e)) in self.rollback_errors.iter().enumerate() {
+				let sep = if i == 0 { " " } else { ", " };
+				write!(f, "{}{}: {}", sep, name, e)?;
+			}
+			write!(f, ")")?;
+		}
+		Ok(())
+	}
+}
+
+impl Operation {
+	pub fn new(handle: &SyncConnectionHandle) -> Self {
+		Self {
+			handle: handle.clone(),
+			logger: Logger::root(slog::Discard, o!()),
+			timeout: DEFAULT_OPERATION_TIMEOUT,
+			steps: Vec::new(),
+		}
+	}
+
+	/// Log the progress of the operation to this logger.
+	///
+	/// # Default
+	/// Nothing is logged.
+	#[inline]
+	pub fn logger(mut self, logger: Logger) -> Self {
+		self.logger = logger;
+		self
+	}
+
+	/// The time which all steps together can take.
+	///
+	/// The rollback is not included in this time.
+	///
+	/// # Default
+	/// [`DEFAULT_OPERATION_TIMEOUT`](constant.DEFAULT_OPERATION_TIMEOUT.html)
+	#[inline]
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Add a step which does not need to be undone if a later step fails.
+	pub fn step<N, F, Fut>(self, name: N, f: F) -> Self
+	where
+		N: Into<Cow<'static, str>>,
+		F: FnOnce(SyncConnectionHandle) -> Fut + Send + 'static,
+		Fut: Future<Output = crate::Result<()>> + Send + 'static,
+	{
+		self.push(name, Box::new(move |handle| f(handle).map_ok(|()| None).boxed()))
+	}
+
+	/// Add a step with a compensating action.
+	///
+	/// The result of the step is passed to the compensation, e.g. the id of a
+	/// created channel, so it can be deleted again.
+	pub fn step_with_rollback<N, T, F, Fut, R, RFut>(self, name: N, f: F, rollback: R) -> Self
+	where
+		N: Into<Cow<'static, str>>,
+		T: Send + 'static,
+		F: FnOnce(SyncConnectionHandle) -> Fut + Send + 'static,
+		Fut: Future<Output = crate::Result<T>> + Send + 'static,
+		R: FnOnce(SyncConnectionHandle, T) -> RFut + Send + 'static,
+		RFut: Future<Output = crate::Result<()>> + Send + 'static,
+	{
+		self.push(
+			name,
+			Box::new(move |handle| {
+				f(handle)
+					.map_ok(move |res| {
+						let compensation: Compensation =
+							Box::new(move |handle| rollback(handle, res).boxed());
+						Some(compensation)
+					})
+					.boxed()
+			}),
+		)
+	}
+
+	fn push<N: Into<Cow<'static, str>>>(mut self, name: N, run: StepAction) -> Self {
+		self.steps.push(Step { name: name.into(), run });
+		self
+	}
+
+	/// Run all steps.
+	///
+	/// If a step fails, the compensations of the previous steps are run in
+	/// reverse order and the error of the step is returned.
+	pub async fn run(self) -> Result<(), Error> {
+		let deadline = tokio::time::Instant::now() + self.timeout;
+		let mut done: Vec<(Cow<'static, str>, Compensation)> = Vec::new();
+		for (i, step) in self.steps.into_iter().enumerate() {
+			debug!(self.logger, "Running step"; "step" => i, "name" => %step.name);
+			let res = tokio::time::timeout_at(deadline, (step.run)(self.handle.clone())).await;
+			let cause = match res {
+				Ok(Ok(Some(compensation))) => {
+					done.push((step.name, compensation));
+					continue;
+				}
+				Ok(Ok(None)) => continue,
+				Ok(Err(e)) => StepError::Failed(e),
+				Err(_) => StepError::Timeout,
+			};
+
+			warn!(self.logger, "Step failed, rolling back"; "step" => i, "name" => %step.name,
+				"error" => %cause);
+			let mut rollback_errors = Vec::new();
+			for (name, compensation) in done.into_iter().rev() {
+				debug!(self.logger, "Rolling back step"; "name" => %name);
+				if let Err(e) = compensation(self.handle.clone()).await {
+					warn!(self.logger, "Rollback failed"; "name" => %name, "error" => %e);
+					rollback_errors.push((name, e));
+				}
+			}
+			return Err(Error { step: i, name: step.name, cause, rollback_errors });
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+
+	type Log = Arc<Mutex<Vec<String>>>;
+
+	/// Build an operation with three steps, where the step `fail` fails and
+	/// the compensation of step `fail_rollback` fails.
+	fn operation(log: &Log, fail: Option<usize>, fail_rollback: Option<usize>) -> Operation {
+		let mut op = Operation::new(&SyncConnectionHandle::detached());
+		for i in 0..3 {
+			let log = log.clone();
+			let log2 = log.clone();
+			op = op.step_with_rollback(
+				format!("step {}", i),
+				move |_| async move {
+					if fail == Some(i) {
+						return Err(crate::Error::NotConnected);
+					}
+					log.lock().unwrap().push(format!("run {}", i));
+					Ok(i)
+				},
+				move |_, res| async move {
+					assert_eq!(res, i);
+					log2.lock().unwrap().push(format!("undo {}", i));
+					if fail_rollback == Some(i) {
+						return Err(crate::Error::ConnectionGone);
+					}
+					Ok(())
+				},
+			);
+		}
+		op
+	}
+
+	#[tokio::test]
+	async fn success() {
+		let log = Log::default();
+		operation(&log, None, None).run().await.unwrap();
+		assert_eq!(*log.lock().unwrap(), ["run 0", "run 1", "run 2"]);
+	}
+
+	#[tokio::test]
+	async fn failure_at_each_step() {
+		for fail in 0..3 {
+			let log = Log::default();
+			let e = operation(&log, Some(fail), None).run().await.unwrap_err();
+			assert_eq!(e.step, fail);
+			assert_eq!(e.name, format!("step {}", fail));
+			assert!(matches!(e.cause, StepError::Failed(crate::Error::NotConnected)));
+			assert!(e.rollback_errors.is_empty());
+
+			let mut expected: Vec<_> = (0..fail).map(|i| format!("run {}", i)).collect();
+			expected.extend((0..fail).rev().map(|i| format!("undo {}", i)));
+			assert_eq!(*log.lock().unwrap(), expected);
+		}
+	}
+
+	#[tokio::test]
+	async fn rollback_failure() {
+		let log = Log::default();
+		let e = operation(&log, Some(2), Some(1)).run().await.unwrap_err();
+		// The rollback continues after a failed compensation
+		assert_eq!(*log.lock().unwrap(), ["run 0", "run 1", "undo 1", "undo 0"]);
+		assert_eq!(e.rollback_errors.len(), 1);
+		assert_eq!(e.rollback_errors[0].0, "step 1");
+		assert!(matches!(e.rollback_errors[0].1, crate::Error::ConnectionGone));
+		assert!(e.to_string().contains("rollback failed for step 1"));
+	}
+
+	#[tokio::test]
+	async fn timeout() {
+		let log = Log::default();
+		let e = operation(&log, None, None)
+			.timeout(Duration::from_millis(50))
+			.step("hang", |_| future::pending())
+			.run()
+			.await
+			.unwrap_err();
+		assert_eq!(e.step, 3);
+		assert!(matches!(e.cause, StepError::Timeout));
+		assert_eq!(*log.lock().unwrap(), [
+			"run 0", "run 1", "run 2", "undo 2", "undo 1", "undo 0"
+		]);
+	}
+}
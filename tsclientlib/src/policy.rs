@@ -0,0 +1,375 @@
+//! Restrict destructive actions, e.g. for bots which run plugins of other
+//! people.
+//!
+//! An [`ActionPolicy`] is set with [`ConnectOptions::action_policy`]. Every
+//! command is checked against the policy before it is sent, no matter if it
+//! is sent with [`OutCommandExt`], a method of the [`Connection`] or a
+//! [`SyncConnectionHandle`]. Denied and confirmed actions are reported as
+//! [`StreamItem::PolicyDecision`].
+//!
+//! # Example
+//! Deny deleting channels and ask before banning someone for longer than a
+//! day.
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use tsclientlib::policy::{Action, ActionKind, ActionPolicy, Category};
+//! let policy = ActionPolicy::new()
+//!     .set(ActionKind::DeleteChannel, Category::Denied)
+//!     .set_with(ActionKind::Ban, |action| match action {
+//!         Action::Ban { duration: Some(d) } if *d <= Duration::from_secs(60 * 60 * 24) => {
+//!             Category::Allowed
+//!         }
+//!         _ => Category::RequireConfirmation,
+//!     })
+//!     .confirm(|action| {
+//!         println!("Allow {:?}?", action);
+//!         false
+//!     });
+//! ```
+//!
+//! [`ActionPolicy`]: struct.ActionPolicy.html
+//! [`ConnectOptions::action_policy`]: ../struct.ConnectOptions.html#method.action_policy
+//! [`StreamItem::PolicyDecision`]: ../enum.StreamItem.html#variant.PolicyDecision
+//! [`OutCommandExt`]: ../trait.OutCommandExt.html
+//! [`Connection`]: ../struct.Connection.html
+//! [`SyncConnectionHandle`]: ../sync/struct.SyncConnectionHandle.html
+use std::collections::HashMap;
+use std::fmt;
+use std::iter;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tsproto_packets::commands::{CommandItem, CommandParser};
+use tsproto_types::{ChannelId, ClientId};
+
+type Classifier = Arc<dyn Fn(&Action) -> Category + Send + Sync>;
+type Confirmation = Arc<dyn Fn(&Action) -> bool + Send + Sync>;
+
+/// A destructive action, which can be restricted by an [`ActionPolicy`].
+///
+/// [`ActionPolicy`]: struct.ActionPolicy.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+	DeleteChannel { channel: ChannelId, force: bool },
+	/// Kick clients from the server or from their channel.
+	Kick { clients: Vec<ClientId>, from_server: bool },
+	/// Ban a client or an ip, name or uid.
+	///
+	/// The duration is `None` for permanent bans.
+	Ban { duration: Option<Duration> },
+	/// Remove a single ban.
+	RemoveBan,
+	RemoveAllBans,
+	DeleteServerGroup,
+	DeleteChannelGroup,
+	DeleteFile { channel: ChannelId },
+	/// Remove a client from the server database.
+	DeleteDatabaseClient,
+}
+
+/// The type of an [`Action`], without its scope.
+///
+/// [`Action`]: enum.Action.html
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ActionKind {
+	DeleteChannel,
+	Kick,
+	Ban,
+	RemoveBan,
+	RemoveAllBans,
+	DeleteServerGroup,
+	DeleteChannelGroup,
+	DeleteFile,
+	DeleteDatabaseClient,
+}
+
+/// How an action is treated by the policy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Category {
+	Allowed,
+	/// Ask the confirmation callback before executing the action.
+	///
+	/// If no callback is set, the action is denied.
+	RequireConfirmation,
+	Denied,
+}
+
+/// The outcome of an action which is not allowed by default.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Decision {
+	/// The action was denied by the policy.
+	Denied,
+	/// The confirmation callback allowed the action.
+	Confirmed,
+	/// The confirmation callback denied the action.
+	Rejected,
+}
+
+/// An action that was denied or needed a confirmation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuditEntry {
+	pub action: Action,
+	pub decision: Decision,
+}
+
+/// Classifies destructive actions.
+///
+/// For details, see the [module documentation](index.html).
+#[derive(Clone, Default)]
+pub struct ActionPolicy {
+	rules: HashMap<ActionKind, Classifier>,
+	confirm: Option<Confirmation>,
+	bypass_raw: bool,
+}
+
+impl Action {
+	pub fn kind(&self) -> ActionKind {
+		match self {
+			Action::DeleteChannel { .. } => ActionKind::DeleteChannel,
+			Action::Kick { .. } => ActionKind::Kick,
+			Action::Ban { .. } => ActionKind::Ban,
+			Action::RemoveBan => ActionKind::RemoveBan,
+			Action::RemoveAllBans => ActionKind::RemoveAllBans,
+			Action::DeleteServerGroup => ActionKind::DeleteServerGroup,
+			Action::DeleteChannelGroup => ActionKind::DeleteChannelGroup,
+			Action::DeleteFile { .. } => ActionKind::DeleteFile,
+			Action::DeleteDatabaseClient => ActionKind::DeleteDatabaseClient,
+		}
+	}
+
+	/// Get the destructive action of a command, if it is one.
+	pub(crate) fn from_command(content: &[u8]) -> Option<Self> {
+		let (name, args) = CommandParser::new(content);
+		let mut channel = ChannelId(0);
+		let mut force = false;
+		let mut clients = Vec::new();
+		let mut from_server = false;
+		let mut duration = None;
+		for item in args.chain(iter::once(CommandItem::NextCommand)) {
+			if let CommandItem::Argument(arg) = item {
+				let value = match arg.value().get_str() {
+					Ok(r) => r,
+					Err(_) => continue,
+				};
+				match arg.name() {
+					b"cid" => channel = ChannelId(value.parse().unwrap_or_default()),
+					b"force" => force = value == "1",
+					b"clid" => clients.extend(value.parse().ok().map(ClientId)),
+					// Kick reason 5 is a server kick
+					b"reasonid" => from_server = value == "5",
+					b"time" => {
+						duration =
+							value.parse().ok().filter(|t| *t != 0).map(Duration::from_secs)
+					}
+					_ => {}
+				}
+			}
+		}
+
+		Some(match name {
+			b"channeldelete" => Action::DeleteChannel { channel, force },
+			b"clientkick" => Action::Kick { clients, from_server },
+			b"banclient" | b"banadd" => Action::Ban { duration },
+			b"bandel" => Action::RemoveBan,
+			b"bandelall" => Action::RemoveAllBans,
+			b"servergroupdel" => Action::DeleteServerGroup,
+			b"channelgroupdel" => Action::DeleteChannelGroup,
+			b"ftdeletefile" => Action::DeleteFile { channel },
+			b"clientdbdelete" => Action::DeleteDatabaseClient,
+			_ => return None,
+		})
+	}
+}
+
+impl ActionPolicy {
+	/// A policy which allows everything.
+	pub fn new() -> Self { Self::default() }
+
+	/// Put all actions of this kind into a category.
+	///
+	/// # Default
+	/// All actions are allowed.
+	#[inline]
+	pub fn set(self, kind: ActionKind, category: Category) -> Self {
+		self.set_with(kind, move |_| category)
+	}
+
+	/// Decide the category of actions of this kind based on their scope.
+	///
+	/// # Default
+	/// All actions are allowed.
+	#[inline]
+	pub fn set_with<F: Fn(&Action) -> Category + Send + Sync + 'static>(
+		mut self, kind: ActionKind, f: F,
+	) -> Self {
+		self.rules.insert(kind, Arc::new(f));
+		self
+	}
+
+	/// Ask this function before executing actions that require a
+	/// confirmation.
+	///
+	/// The function is called while sending the command, which often happens
+	/// while the connection is polled, so it must not block. A blocking
+	/// function stalls the whole connection. To ask a human, reject the action and
+	/// send the command again after the human allowed it, e.g. by remembering
+	/// confirmed actions in the function. It returns `true` if the action
+	/// should be executed.
+	///
+	/// # Default
+	/// No callback is set, actions that need a confirmation are denied.
+	#[inline]
+	pub fn confirm<F: Fn(&Action) -> bool + Send + Sync + 'static>(mut self, f: F) -> Self {
+		self.confirm = Some(Arc::new(f));
+		self
+	}
+
+	/// Do not check commands that are sent with
+	/// [`SyncConnectionHandle::send_command`].
+	///
+	/// # Default
+	/// `false`, raw commands are checked like all other commands.
+	///
+	/// [`SyncConnectionHandle::send_command`]: ../sync/struct.SyncConnectionHandle.html#method.send_command
+	#[inline]
+	pub fn bypass_raw(mut self, bypass_raw: bool) -> Self {
+		self.bypass_raw = bypass_raw;
+		self
+	}
+
+	#[inline]
+	pub fn get_bypass_raw(&self) -> bool { self.bypass_raw }
+
+	pub fn classify(&self, action: &Action) -> Category {
+		self.rules.get(&action.kind()).map(|f| f(action)).unwrap_or(Category::Allowed)
+	}
+
+	/// Check a command before it is sent.
+	///
+	/// Returns `None` if the command is allowed without confirmation.
+	pub(crate) fn check(&self, content: &[u8], raw: bool) -> Option<AuditEntry> {
+		if raw && self.bypass_raw {
+			return None;
+		}
+		let action = Action::from_command(content)?;
+		let decision = match self.classify(&action) {
+			Category::Allowed => return None,
+			Category::Denied => Decision::Denied,
+			Category::RequireConfirmation => {
+				if self.confirm.as_ref().map(|f| f(&action)).unwrap_or_default() {
+					Decision::Confirmed
+				} else {
+					Decision::Rejected
+				}
+			}
+		};
+		Some(AuditEntry { action, decision })
+	}
+}
+
+impl fmt::Debug for ActionPolicy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut rules = self.rules.keys().collect::<Vec<_>>();
+		rules.sort_by_key(|k| format!("{:?}", k));
+		f.debug_struct("ActionPolicy")
+			.field("rules", &rules)
+			.field("confirm", &self.confirm.is_some())
+			.field("bypass_raw", &self.bypass_raw)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::*;
+
+	const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+	fn policy() -> ActionPolicy {
+		ActionPolicy::new()
+			.set(ActionKind::DeleteChannel, Category::Denied)
+			.set_with(ActionKind::Ban, |action| match action {
+				Action::Ban { duration: Some(d) } if *d <= DAY => Category::Allowed,
+				_ => Category::RequireConfirmation,
+			})
+	}
+
+	#[test]
+	fn parse_commands() {
+		assert_eq!(
+			Action::from_command(b"channeldelete cid=5 force=1"),
+			Some(Action::DeleteChannel { channel: ChannelId(5), force: true })
+		);
+		assert_eq!(
+			Action::from_command(b"clientkick reasonid=5 reasonmsg=Bye clid=3|clid=4"),
+			Some(Action::Kick { clients: vec![ClientId(3), ClientId(4)], from_server: true })
+		);
+		assert_eq!(
+			Action::from_command(b"banclient clid=3 time=0 banreason"),
+			Some(Action::Ban { duration: None })
+		);
+		assert_eq!(
+			Action::from_command(b"banadd ip=1.2.3.4 time=60"),
+			Some(Action::Ban { duration: Some(Duration::from_secs(60)) })
+		);
+		assert_eq!(Action::from_command(b"sendtextmessage targetmode=3 msg=Hi"), None);
+	}
+
+	#[test]
+	fn categories() {
+		let policy = policy();
+		// Allowed
+		assert_eq!(policy.check(b"banclient clid=3 time=3600", false), None);
+		assert_eq!(policy.check(b"clientkick reasonid=4 clid=3", false), None);
+		// Denied
+		assert_eq!(
+			policy.check(b"channeldelete cid=5 force=0", false),
+			Some(AuditEntry {
+				action: Action::DeleteChannel { channel: ChannelId(5), force: false },
+				decision: Decision::Denied,
+			})
+		);
+		// Needs a confirmation, but there is no callback
+		let long_ban = format!("banclient clid=3 time={}", DAY.as_secs() + 1);
+		assert_eq!(
+			policy.check(long_ban.as_bytes(), false).map(|e| e.decision),
+			Some(Decision::Rejected)
+		);
+	}
+
+	#[test]
+	fn confirmation() {
+		let asked = Arc::new(AtomicUsize::new(0));
+		let asked2 = asked.clone();
+		let policy = policy().confirm(move |action| {
+			asked2.fetch_add(1, Ordering::SeqCst);
+			*action == Action::Ban { duration: None }
+		});
+		assert_eq!(
+			policy.check(b"banclient clid=3 time=0", false).map(|e| e.decision),
+			Some(Decision::Confirmed)
+		);
+		let long_ban = format!("banclient clid=3 time={}", DAY.as_secs() * 2);
+		assert_eq!(
+			policy.check(long_ban.as_bytes(), false).map(|e| e.decision),
+			Some(Decision::Rejected)
+		);
+		assert_eq!(asked.load(Ordering::SeqCst), 2);
+		// Denied actions do not ask
+		policy.check(b"channeldelete cid=5 force=0", false);
+		assert_eq!(asked.load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	fn bypass_raw() {
+		let cmd = b"channeldelete cid=5 force=0";
+		assert!(policy().check(cmd, true).is_some());
+		let policy = policy().bypass_raw(true);
+		assert_eq!(policy.check(cmd, true), None);
+		// Only raw commands bypass the policy
+		assert!(policy.check(cmd, false).is_some());
+	}
+}
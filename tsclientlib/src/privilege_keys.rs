@@ -0,0 +1,175 @@
+//! Privilege keys (also called tokens) grant a server or channel group to the
+//! client that uses them.
+use num_traits::{FromPrimitive, ToPrimitive};
+use thiserror::Error;
+use time::OffsetDateTime;
+use tsproto_packets::packets::OutCommand;
+use tsproto_types::errors::Error as TsError;
+use tsproto_types::TokenType;
+
+use crate::{new_command, parse_list, CommandError, ParseEntry};
+
+/// The reason why using a privilege key failed.
+///
+/// Can be created from the error of the [`StreamItem::MessageResult`] that
+/// answers [`Connection::use_privilege_key`].
+///
+/// [`StreamItem::MessageResult`]: ../enum.StreamItem.html#variant.MessageResult
+/// [`Connection::use_privilege_key`]: ../struct.Connection.html#method.use_privilege_key
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum Error {
+	/// The key does not exist on this server.
+	#[error("Invalid privilege key")]
+	Invalid,
+	/// The key exists but it was already used.
+	#[error("Privilege key was already used")]
+	AlreadyUsed,
+	#[error(transparent)]
+	Other(TsError),
+}
+
+/// A privilege key which is stored on the server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PrivilegeKey {
+	pub token: String,
+	/// If the key grants a server group or a channel group.
+	pub token_type: TokenType,
+	/// The id of the granted server group or channel group.
+	pub id1: u64,
+	/// The channel id for channel groups, `0` for server groups.
+	pub id2: u64,
+	pub created: OffsetDateTime,
+	pub description: String,
+}
+
+impl From<TsError> for Error {
+	fn from(e: TsError) -> Self {
+		match e {
+			TsError::TokenInvalidId => Error::Invalid,
+			TsError::TokenTooManyUses => Error::AlreadyUsed,
+			e => Error::Other(e),
+		}
+	}
+}
+
+impl From<CommandError> for Error {
+	fn from(e: CommandError) -> Self { e.id.into() }
+}
+
+pub(crate) fn use_key(token: &str) -> OutCommand {
+	let mut cmd = new_command("privilegekeyuse");
+	cmd.write_arg("token", &token);
+	cmd
+}
+
+pub(crate) fn add(token_type: TokenType, id1: u64, id2: u64, description: &str) -> OutCommand {
+	let mut cmd = new_command("privilegekeyadd");
+	cmd.write_arg("tokentype", &token_type.to_u8().unwrap());
+	cmd.write_arg("tokenid1", &id1);
+	cmd.write_arg("tokenid2", &id2);
+	cmd.write_arg("tokendescription", &description);
+	cmd
+}
+
+pub(crate) fn list() -> OutCommand { new_command("privilegekeylist") }
+
+/// Parse the keys from a `notifyprivilegekeylist`.
+pub(crate) fn parse(content: &[u8]) -> Vec<PrivilegeKey> {
+	parse_list(content, |args| {
+		let mut token = None;
+		let mut token_type = None;
+		let mut id1 = 0;
+		let mut id2 = 0;
+		let mut created = OffsetDateTime::unix_epoch();
+		let mut description = String::new();
+		for (name, value) in args {
+			match name {
+				b"token" => token = Some(value.into_owned()),
+				b"token_type" => token_type = value.parse().ok().and_then(TokenType::from_u8),
+				b"token_id1" => id1 = value.parse().unwrap_or_default(),
+				b"token_id2" => id2 = value.parse().unwrap_or_default(),
+				b"token_created" => {
+					created = OffsetDateTime::from_unix_timestamp(value.parse().unwrap_or_default())
+				}
+				b"token_description" => description = value.into_owned(),
+				_ => {}
+			}
+		}
+		Some(PrivilegeKey {
+			token: token?,
+			token_type: token_type?,
+			id1,
+			id2,
+			created,
+			description,
+		})
+	})
+}
+
+impl ParseEntry for PrivilegeKey {
+	fn parse(content: &[u8]) -> Vec<Self> { parse(content) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn parse_list() {
+		let keys = parse(
+			br#"notifyprivilegekeylist token=abc token_type=0 token_id1=6 token_id2=0 token_created=1500000000 token_description=Admin|token=def token_type=1 token_id1=5 token_id2=2 token_created=1500000001 token_description"#,
+		);
+		assert_eq!(keys, vec![
+			PrivilegeKey {
+				token: "abc".into(),
+				token_type: TokenType::ServerGroup,
+				id1: 6,
+				id2: 0,
+				created: OffsetDateTime::from_unix_timestamp(1500000000),
+				description: "Admin".into(),
+			},
+			PrivilegeKey {
+				token: "def".into(),
+				token_type: TokenType::ChannelGroup,
+				id1: 5,
+				id2: 2,
+				created: OffsetDateTime::from_unix_timestamp(1500000001),
+				description: String::new(),
+			},
+		]);
+	}
+
+	#[test]
+	fn add_key() {
+		assert_eq!(
+			add(TokenType::ChannelGroup, 5, 2, "For you").0.content(),
+			br#"privilegekeyadd tokentype=1 tokenid1=5 tokenid2=2 tokendescription=For\syou"#
+				.as_ref()
+		);
+	}
+
+	#[test]
+	fn distinct_errors() {
+		assert_eq!(Error::from(TsError::TokenInvalidId), Error::Invalid);
+		assert_eq!(Error::from(TsError::TokenTooManyUses), Error::AlreadyUsed);
+		assert_eq!(
+			Error::from(TsError::DatabaseEmptyResult),
+			Error::Other(TsError::DatabaseEmptyResult)
+		);
+	}
+
+	#[test]
+	fn command_error_categories() {
+		let e = CommandError {
+			id: TsError::TokenTooManyUses,
+			message: "token used too often".into(),
+			extra_message: None,
+			failed_permission: None,
+			return_code: Some(crate::MessageHandle(3)),
+		};
+		assert!(!e.id.is_permission_error());
+		assert!(!e.id.is_ban());
+		assert_eq!(Error::from(e), Error::AlreadyUsed);
+		assert!(TsError::PermissionsClientInsufficient.is_permission_error());
+	}
+}
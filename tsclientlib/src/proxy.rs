@@ -0,0 +1,447 @@
+//! Send the udp traffic of a connection through a SOCKS5 proxy.
+//!
+//! The proxy relays our datagrams with `UDP ASSOCIATE` ([RFC 1928]). This
+//! module is only available with the `socks5` feature.
+//!
+//! [RFC 1928]: https://tools.ietf.org/html/rfc1928
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::ready;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+use tsproto::connection::Socket;
+use tsproto::MAX_UDP_PACKET_LENGTH;
+
+use crate::resolver;
+use crate::ServerAddress;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const UDP_ASSOCIATE: u8 = 3;
+
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_NAME: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+/// The longest header of a relayed datagram, with a 255 byte name.
+const MAX_HEADER_LEN: usize = 3 + 2 + 255 + 2;
+/// The default time for connecting to the proxy and getting a udp relay.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ProxyError {
+	#[error("Failed to talk to the proxy: {0}")]
+	Io(#[from] io::Error),
+	#[error("The proxy does not support SOCKS5, it answered with version {0}")]
+	InvalidVersion(u8),
+	#[error("The proxy does not support our authentication method")]
+	NoAuthenticationMethod,
+	#[error("The proxy rejected our username or password")]
+	AuthenticationFailed,
+	#[error("The username, password or server name is longer than 255 bytes")]
+	TooLong,
+	/// The proxy did not allow the udp association, the reply code is from
+	/// [RFC 1928](https://tools.ietf.org/html/rfc1928#section-6).
+	#[error("The proxy refused the udp association (reply {0})")]
+	Refused(u8),
+	#[error("The proxy sent an unsupported address type {0}")]
+	InvalidAddressType(u8),
+	/// The server address has a port which is not a number, it is not resolved
+	/// locally instead.
+	#[error("The server address {0:?} has an invalid port")]
+	InvalidPort(String),
+	#[error("The proxy did not set up the udp relay within {0:?}")]
+	Timeout(Duration),
+}
+
+/// The settings for a SOCKS5 proxy.
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+	address: SocketAddr,
+	credentials: Option<(String, String)>,
+	resolve_via_proxy: bool,
+	timeout: Duration,
+}
+
+/// A udp socket whose datagrams are relayed by a SOCKS5 proxy.
+pub(crate) struct ProxySocket {
+	socket: UdpSocket,
+	/// Where the proxy relays our datagrams.
+	relay: SocketAddr,
+	/// The server name and port, if the proxy resolves it.
+	name: Option<(String, u16)>,
+	/// The association ends when this connection is closed.
+	_control: TcpStream,
+}
+
+impl ProxyConfig {
+	/// Use the SOCKS5 proxy at `address`.
+	pub fn new(address: SocketAddr) -> Self {
+		Self { address, credentials: None, resolve_via_proxy: false, timeout: TIMEOUT }
+	}
+
+	/// Authenticate at the proxy with a username and password.
+	///
+	/// # Default
+	/// No authentication.
+	#[inline]
+	pub fn credentials(mut self, username: String, password: String) -> Self {
+		self.credentials = Some((username, password));
+		self
+	}
+
+	/// Let the proxy resolve the host name of the server.
+	///
+	/// The name is sent to the proxy instead of resolving it locally. Only
+	/// plain host names with an optional port are supported in this case, no
+	/// SRV records or TSDNS. The remote address of the connection is then
+	/// the unspecified address with the port of the server. Connecting to an
+	/// address with an invalid port fails, instead of resolving it locally.
+	///
+	/// # Default
+	/// `false`
+	#[inline]
+	pub fn resolve_via_proxy(mut self, resolve_via_proxy: bool) -> Self {
+		self.resolve_via_proxy = resolve_via_proxy;
+		self
+	}
+
+	/// Give up if the proxy does not set up the udp relay within this
+	/// duration.
+	///
+	/// This includes connecting to the proxy and the authentication.
+	///
+	/// # Default
+	/// 5 seconds
+	#[inline]
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	#[inline]
+	pub fn get_address(&self) -> SocketAddr { self.address }
+	#[inline]
+	pub fn get_username(&self) -> Option<&str> {
+		self.credentials.as_ref().map(|c| c.0.as_str())
+	}
+	#[inline]
+	pub fn get_resolve_via_proxy(&self) -> bool { self.resolve_via_proxy }
+	#[inline]
+	pub fn get_timeout(&self) -> Duration { self.timeout }
+
+	/// The name and port which the proxy should resolve.
+	///
+	/// Returns `None` if the address is resolved locally. An invalid port is
+	/// an error, so the name does not leak to the local dns resolver.
+	pub(crate) fn name_to_resolve(
+		&self, address: &ServerAddress,
+	) -> Result<Option<(String, u16)>, ProxyError> {
+		let address = match address {
+			ServerAddress::Other(s) if self.resolve_via_proxy => s,
+			_ => return Ok(None),
+		};
+		if address.parse::<SocketAddr>().is_ok() || address.parse::<IpAddr>().is_ok() {
+			return Ok(None);
+		}
+		match address.rfind(':') {
+			Some(i) => {
+				let port = address[i + 1..]
+					.parse()
+					.map_err(|_| ProxyError::InvalidPort(address.clone()))?;
+				Ok(Some((address[..i].to_string(), port)))
+			}
+			None => Ok(Some((address.to_string(), resolver::DEFAULT_PORT))),
+		}
+	}
+}
+
+impl ProxySocket {
+	/// Ask the proxy for a udp relay.
+	///
+	/// The `socket` should be bound for the address family of the proxy. If
+	/// `target` is unspecified, the proxy resolves the name of `address`.
+	pub(crate) async fn associate(
+		config: &ProxyConfig, socket: UdpSocket, target: SocketAddr, address: &ServerAddress,
+	) -> Result<Self, ProxyError> {
+		let name =
+			if target.ip().is_unspecified() { config.name_to_resolve(address)? } else { None };
+		if name.as_ref().map(|n| n.0.len() > 255).unwrap_or_default() {
+			return Err(ProxyError::TooLong);
+		}
+
+		let (control, relay) = time::timeout(config.timeout, Self::handshake(config))
+			.await
+			.map_err(|_| ProxyError::Timeout(config.timeout))??;
+		Ok(Self { socket, relay, name, _control: control })
+	}
+
+	/// Authenticate at the proxy and request the udp relay.
+	///
+	/// Returns the control connection and the address of the relay.
+	async fn handshake(config: &ProxyConfig) -> Result<(TcpStream, SocketAddr), ProxyError> {
+		let mut control = TcpStream::connect(config.address).await?;
+		let method =
+			if config.credentials.is_some() { USERNAME_PASSWORD } else { NO_AUTHENTICATION };
+		control.write_all(&[VERSION, 1, method]).await?;
+		let mut reply = [0; 2];
+		control.read_exact(&mut reply).await?;
+		if reply[0] != VERSION {
+			return Err(ProxyError::InvalidVersion(reply[0]));
+		}
+		if reply[1] != method {
+			return Err(ProxyError::NoAuthenticationMethod);
+		}
+
+		if let Some((username, password)) = &config.credentials {
+			if username.len() > 255 || password.len() > 255 {
+				return Err(ProxyError::TooLong);
+			}
+			let mut request = vec![USERNAME_PASSWORD_VERSION, username.len() as u8];
+			request.extend_from_slice(username.as_bytes());
+			request.push(password.len() as u8);
+			request.extend_from_slice(password.as_bytes());
+			control.write_all(&request).await?;
+			control.read_exact(&mut reply).await?;
+			if reply[1] != 0 {
+				return Err(ProxyError::AuthenticationFailed);
+			}
+		}
+
+		// We do not know our public address, so let the proxy accept
+		// datagrams from any address.
+		let mut request = vec![VERSION, UDP_ASSOCIATE, 0];
+		write_address(&mut request, &SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+		control.write_all(&request).await?;
+		let mut reply = [0; 4];
+		control.read_exact(&mut reply).await?;
+		if reply[0] != VERSION {
+			return Err(ProxyError::InvalidVersion(reply[0]));
+		}
+		if reply[1] != 0 {
+			return Err(ProxyError::Refused(reply[1]));
+		}
+		let ip = match reply[3] {
+			ADDRESS_IPV4 => {
+				let mut ip = [0; 4];
+				control.read_exact(&mut ip).await?;
+				IpAddr::from(ip)
+			}
+			ADDRESS_IPV6 => {
+				let mut ip = [0; 16];
+				control.read_exact(&mut ip).await?;
+				IpAddr::from(ip)
+			}
+			t => return Err(ProxyError::InvalidAddressType(t)),
+		};
+		let port = control.read_u16().await?;
+		// An unspecified address means the address of the proxy
+		let ip = if ip.is_unspecified() { config.address.ip() } else { ip };
+		Ok((control, SocketAddr::new(ip, port)))
+	}
+}
+
+impl Socket for ProxySocket {
+	fn poll_recv_from(
+		&self, cx: &mut Context, buf: &mut [u8],
+	) -> Poll<io::Result<(usize, SocketAddr)>> {
+		let mut data = [0; MAX_HEADER_LEN + MAX_UDP_PACKET_LENGTH];
+		loop {
+			let (len, from) = ready!(self.socket.poll_recv_from(cx, &mut data))?;
+			if from != self.relay {
+				continue;
+			}
+			let (source, header_len) = match parse_header(&data[..len]) {
+				Some(r) => r,
+				None => continue,
+			};
+			// The address of a resolved name is unknown to the connection
+			let source = match (&self.name, source) {
+				(Some((_, port)), _) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *port),
+				(None, Some(source)) => source,
+				(None, None) => continue,
+			};
+			let payload = &data[header_len..len];
+			let len = payload.len().min(buf.len());
+			buf[..len].copy_from_slice(&payload[..len]);
+			return Poll::Ready(Ok((len, source)));
+		}
+	}
+
+	fn poll_send_to(
+		&self, cx: &mut Context, buf: &[u8], target: &SocketAddr,
+	) -> Poll<io::Result<usize>> {
+		let mut data = Vec::with_capacity(MAX_HEADER_LEN + buf.len());
+		// Reserved bytes and fragment number
+		data.extend_from_slice(&[0, 0, 0]);
+		match &self.name {
+			Some((name, port)) if target.ip().is_unspecified() => {
+				data.push(ADDRESS_NAME);
+				data.push(name.len() as u8);
+				data.extend_from_slice(name.as_bytes());
+				data.extend_from_slice(&port.to_be_bytes());
+			}
+			_ => write_address(&mut data, target),
+		}
+		data.extend_from_slice(buf);
+		ready!(self.socket.poll_send_to(cx, &data, &self.relay))?;
+		Poll::Ready(Ok(buf.len()))
+	}
+
+	fn local_addr(&self) -> io::Result<SocketAddr> { self.socket.local_addr() }
+}
+
+fn write_address(data: &mut Vec<u8>, address: &SocketAddr) {
+	match address.ip() {
+		IpAddr::V4(ip) => {
+			data.push(ADDRESS_IPV4);
+			data.extend_from_slice(&ip.octets());
+		}
+		IpAddr::V6(ip) => {
+			data.push(ADDRESS_IPV6);
+			data.extend_from_slice(&ip.octets());
+		}
+	}
+	data.extend_from_slice(&address.port().to_be_bytes());
+}
+
+/// Parse the header of a relayed datagram.
+///
+/// Returns the source address, `None` for a name, and the length of the
+/// header. Fragmented datagrams are not supported and dropped.
+fn parse_header(data: &[u8]) -> Option<(Option<SocketAddr>, usize)> {
+	if data.len() < 4 || data[2] != 0 {
+		return None;
+	}
+	let (ip, len) = match data[3] {
+		ADDRESS_IPV4 if data.len() >= 10 => {
+			let mut ip = [0; 4];
+			ip.copy_from_slice(&data[4..8]);
+			(Some(IpAddr::from(ip)), 8)
+		}
+		ADDRESS_IPV6 if data.len() >= 22 => {
+			let mut ip = [0; 16];
+			ip.copy_from_slice(&data[4..20]);
+			(Some(IpAddr::from(ip)), 20)
+		}
+		ADDRESS_NAME if data.len() >= 5 && data.len() >= 7 + data[4] as usize => {
+			(None, 5 + data[4] as usize)
+		}
+		_ => return None,
+	};
+	let port = u16::from_be_bytes([data[len], data[len + 1]]);
+	Some((ip.map(|ip| SocketAddr::new(ip, port)), len + 2))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use tokio::net::TcpListener;
+
+	#[test]
+	fn headers() {
+		let mut data = vec![0, 0, 0];
+		write_address(&mut data, &"127.0.0.1:9987".parse().unwrap());
+		data.extend_from_slice(b"payload");
+		assert_eq!(parse_header(&data), Some((Some("127.0.0.1:9987".parse().unwrap()), 10)));
+
+		let data = b"\0\0\0\x03\x0bexample.com\x26\xfdpayload";
+		assert_eq!(parse_header(data), Some((None, 18)));
+		// Fragments and truncated headers are dropped
+		assert_eq!(parse_header(b"\0\0\x01\x01\x7f\0\0\x01\x26\xfd"), None);
+		assert_eq!(parse_header(b"\0\0\0\x01\x7f\0\0"), None);
+	}
+
+	#[test]
+	fn name_to_resolve() {
+		let config =
+			ProxyConfig::new("127.0.0.1:1080".parse().unwrap()).resolve_via_proxy(true);
+		let name = |a: &str| config.name_to_resolve(&ServerAddress::Other(a.into())).unwrap();
+		assert_eq!(name("example.com"), Some(("example.com".into(), 9987)));
+		assert_eq!(name("example.com:9988"), Some(("example.com".into(), 9988)));
+		assert_eq!(name("127.0.0.1:9988"), None);
+		assert_eq!(name("::1"), None);
+		// Falling back to the local resolver would leak the name
+		let res = config.name_to_resolve(&ServerAddress::Other("example.com:abc".into()));
+		assert!(matches!(res, Err(ProxyError::InvalidPort(a)) if a == "example.com:abc"));
+		let config = config.resolve_via_proxy(false);
+		let address = ServerAddress::Other("example.com:abc".into());
+		assert_eq!(config.name_to_resolve(&address).unwrap(), None);
+	}
+
+	#[tokio::test]
+	async fn handshake_timeout() {
+		// A proxy which accepts the connection but never answers
+		let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let timeout = Duration::from_millis(100);
+		let config = ProxyConfig::new(listener.local_addr().unwrap()).timeout(timeout);
+		tokio::spawn(async move {
+			let (_stream, _) = listener.accept().await.unwrap();
+			futures::future::pending::<()>().await;
+		});
+
+		let target = "127.0.0.1:9987".parse().unwrap();
+		let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let res =
+			ProxySocket::associate(&config, socket, target, &ServerAddress::SocketAddr(target))
+				.await;
+		assert!(matches!(res, Err(ProxyError::Timeout(t)) if t == timeout));
+	}
+
+	#[tokio::test]
+	async fn associate() {
+		let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let config = ProxyConfig::new(listener.local_addr().unwrap())
+			.credentials("user".into(), "pw".into());
+		let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let relay_port = relay.local_addr().unwrap().port();
+
+		let proxy = tokio::spawn(async move {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut buf = [0; 3];
+			stream.read_exact(&mut buf).await.unwrap();
+			assert_eq!(buf, [VERSION, 1, USERNAME_PASSWORD]);
+			stream.write_all(&[VERSION, USERNAME_PASSWORD]).await.unwrap();
+			let mut buf = [0; 9];
+			stream.read_exact(&mut buf).await.unwrap();
+			assert_eq!(&buf, b"\x01\x04user\x02pw");
+			stream.write_all(&[USERNAME_PASSWORD_VERSION, 0]).await.unwrap();
+			let mut buf = [0; 10];
+			stream.read_exact(&mut buf).await.unwrap();
+			assert_eq!(buf[..2], [VERSION, UDP_ASSOCIATE]);
+			// Answer with an unspecified address
+			let mut reply = vec![VERSION, 0, 0];
+			write_address(&mut reply, &SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), relay_port));
+			stream.write_all(&reply).await.unwrap();
+			stream
+		});
+
+		let target = "127.0.0.1:9987".parse().unwrap();
+		let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let socket =
+			ProxySocket::associate(&config, socket, target, &ServerAddress::SocketAddr(target))
+				.await
+				.unwrap();
+		let _control = proxy.await.unwrap();
+		assert_eq!(socket.relay, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), relay_port));
+
+		futures::future::poll_fn(|cx| socket.poll_send_to(cx, b"ping", &target)).await.unwrap();
+		let mut relay = relay;
+		let mut buf = [0; 64];
+		let (len, from) = relay.recv_from(&mut buf).await.unwrap();
+		assert_eq!(&buf[..len], b"\0\0\0\x01\x7f\0\0\x01\x27\x03ping");
+
+		relay.send_to(&buf[..len], &from).await.unwrap();
+		let res = futures::future::poll_fn(|cx| socket.poll_recv_from(cx, &mut buf)).await;
+		assert_eq!(res.unwrap(), (4, target));
+		assert_eq!(&buf[..4], b"ping");
+	}
+}
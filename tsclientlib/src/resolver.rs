@@ -2,26 +2,114 @@
 // Changes with TeamSpeak client 3.1:
 // https://support.teamspeakusa.com/index.php?/Knowledgebase/Article/View/332
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use futures::prelude::*;
 use itertools::Itertools;
+use lazy_static::lazy_static;
 use rand::Rng;
 use slog::{debug, o, warn, Logger};
 use thiserror::Error;
 use tokio::net::{self, TcpStream};
 use tokio::prelude::*;
-use tokio::time::Duration;
-use trust_dns_resolver::config::ResolverConfig;
+use tokio::time::{self, Duration};
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
 use trust_dns_resolver::{Name, TokioAsyncResolver};
 
-const DEFAULT_PORT: u16 = 9987;
+use crate::ServerAddress;
+
+pub(crate) const DEFAULT_PORT: u16 = 9987;
 const DNS_PREFIX_TCP: &str = "_tsdns._tcp.";
 const DNS_PREFIX_UDP: &str = "_ts3._udp.";
 const NICKNAME_LOOKUP_ADDRESS: &str = "https://named.myteamspeak.com/lookup";
+/// The port of a tsdns server, if it is not given by an SRV record.
+const TSDNS_DEFAULT_PORT: u16 = 41144;
 /// Wait this amount of seconds before giving up.
 const TIMEOUT_SECONDS: u64 = 10;
+/// Wait this long for the answer of a single tsdns server.
+const TSDNS_TIMEOUT: Duration = Duration::from_secs(3);
+
+lazy_static! {
+	static ref CACHE: Mutex<Cache> = Mutex::new(Cache {
+		entries: HashMap::new(),
+		min_ttl: Duration::from_secs(60),
+		max_ttl: Duration::from_secs(60 * 60),
+	});
+}
+
+/// Remembers resolved addresses, so reconnecting does not need to resolve the
+/// address again.
+///
+/// The entries are stored per address and the custom dns servers which
+/// resolved it.
+struct Cache {
+	entries: HashMap<(String, Vec<SocketAddr>), CacheEntry>,
+	min_ttl: Duration,
+	max_ttl: Duration,
+}
+
+struct CacheEntry {
+	addrs: Vec<SocketAddr>,
+	valid_until: Instant,
+}
+
+/// Settings for the dns lookups when resolving an address.
+///
+/// The default uses the dns servers of the system configuration.
+#[derive(Clone, Debug)]
+pub struct DnsConfig {
+	servers: Vec<SocketAddr>,
+	timeout: Option<Duration>,
+	attempts: Option<usize>,
+	cache: bool,
+	address_family: AddressFamily,
+}
+
+/// Which kind of ip addresses should be used.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressFamily {
+	/// Use IPv4 addresses first, then IPv6 addresses.
+	PreferIpv4,
+	/// Use IPv6 addresses first, then IPv4 addresses.
+	PreferIpv6,
+	/// Only use IPv4 addresses.
+	Only4,
+	/// Only use IPv6 addresses.
+	Only6,
+	/// Use addresses in the order in which they are resolved.
+	Any,
+}
+
+/// The parameters of a `ts3server://` link, which TeamSpeak uses to invite
+/// users to a server.
+///
+/// A link looks like
+/// `ts3server://ts.example.com?port=9987&password=secret&nickname=Bot&channel=Lobby/Games`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InviteLink {
+	/// The address of the server, including the port if it is given.
+	pub address: String,
+	pub password: Option<String>,
+	pub nickname: Option<String>,
+	/// The path of the channel, e.g. `Lobby/Games`.
+	pub channel: Option<String>,
+	pub channel_password: Option<String>,
+}
+
+/// Shared state of [`filter_family`](fn.filter_family.html).
+#[derive(Default)]
+struct FamilyState {
+	/// Addresses of the less preferred family.
+	deferred: Vec<SocketAddr>,
+	/// If an address of the right family was found.
+	found: bool,
+	/// If an address was dropped because of its family.
+	filtered: bool,
+}
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -33,6 +121,10 @@ pub enum Error {
 		system: trust_dns_resolver::error::ResolveError,
 		fallback: trust_dns_resolver::error::ResolveError,
 	},
+	#[error("Failed to create resolver for custom dns servers: {0}")]
+	CreateCustomResolver(#[source] trust_dns_resolver::error::ResolveError),
+	#[error("Found no address for {0:?}, only addresses of the other family")]
+	NoAddressOfFamily(AddressFamily),
 	#[error("Failed to parse domain {0:?}: {1}")]
 	InvalidDomain(String, #[source] trust_dns_proto::error::ProtoError),
 	#[error("Invalid IPv4 address")]
@@ -55,6 +147,8 @@ pub enum Error {
 	NoSrvEntry,
 	#[error("Failed to resolve hostname: {0}")]
 	ResolveHost(#[source] tokio::io::Error),
+	#[error("Failed to resolve hostname with the custom dns servers: {0}")]
+	ResolveHostCustom(#[source] trust_dns_resolver::error::ResolveError),
 	#[error("Failed to get SRV record")]
 	SrvLookup(#[source] trust_dns_resolver::error::ResolveError),
 	#[error("tsdns did not return an ip address but {0:?}")]
@@ -63,6 +157,8 @@ pub enum Error {
 	TsdnsAddressNotFound,
 	#[error("Failed to parse tsdns response: {0}")]
 	TsdnsParseResponse(#[source] std::str::Utf8Error),
+	#[error("tsdns server did not answer within {0:?}")]
+	TsdnsTimeout(Duration),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -81,11 +177,206 @@ enum ParseIpResult<'a> {
 ///    server, e.g. when the address is `ts3.subdomain.from.com`, the SRV record
 ///    at `_tsdns._tcp.from.com` is requested
 /// 1. Directly resolve the address to an ip address
+/// 1. The tsdns server at `address.tld:41144`, only if the address has no A
+///    or AAAA record
 ///
 /// If a port is given with `:port`, it overwrites the automatically determined
 /// port. IPv6 addresses are put in square brackets when a port is present:
-/// `[::1]:9987`
+/// `[::1]:9987`. Otherwise the port of an SRV record, tsdns answer or server
+/// nickname is used. The default port 9987 is only used if none of them
+/// contain a port. [`ServerAddress::with_port`] can be used to set the port
+/// of an address.
+///
+/// Resolved addresses are cached, the cache is used for the next resolve of
+/// the same address with the same dns servers. Entries expire with the
+/// shortest TTL of the SRV, A and AAAA records, clamped to the range set by
+/// [`set_cache_ttl`]. [`resolve_uncached`] bypasses the cache.
+///
+/// [`set_cache_ttl`]: fn.set_cache_ttl.html
+/// [`resolve_uncached`]: fn.resolve_uncached.html
+/// [`ServerAddress::with_port`]: ../enum.ServerAddress.html#method.with_port
 pub fn resolve(logger: Logger, address: String) -> impl Stream<Item = Result<SocketAddr>> {
+	resolve_with_config(logger, address, &DnsConfig::default())
+}
+
+/// Resolve an address without looking at the cache.
+///
+/// The results are still written into the cache.
+///
+/// For details, see [`resolve`].
+///
+/// [`resolve`]: fn.resolve.html
+pub fn resolve_uncached(
+	logger: Logger, address: String,
+) -> impl Stream<Item = Result<SocketAddr>> {
+	resolve_with_config(logger, address, &DnsConfig::default().cache(false))
+}
+
+/// Resolve an address with custom settings for the dns resolver.
+///
+/// The results are written into the cache, even if [`DnsConfig::cache`] is
+/// disabled.
+///
+/// For details, see [`resolve`].
+///
+/// [`DnsConfig::cache`]: struct.DnsConfig.html#method.cache
+/// [`resolve`]: fn.resolve.html
+pub fn resolve_with_config(
+	logger: Logger, address: String, config: &DnsConfig,
+) -> impl Stream<Item = Result<SocketAddr>> {
+	let address = parse_invite_link(&address).map(|l| l.address).unwrap_or(address);
+	let family = config.address_family;
+	if config.cache {
+		if let Some(addrs) = CACHE.lock().unwrap().get(&address, &config.servers, Instant::now()) {
+			debug!(logger, "Using cached addresses"; "address" => &address, "addrs" => ?addrs);
+			return filter_family(stream::iter(addrs.into_iter().map(Ok)).left_stream(), family);
+		}
+	}
+
+	let valid_until = Arc::new(Mutex::new(None));
+	let key = address.clone();
+	let servers = config.servers.clone();
+	let valid_until2 = valid_until.clone();
+	let mut first = true;
+	let res = resolve_inner(logger, address, config.clone(), valid_until).inspect(move |r| {
+		if let Ok(addr) = r {
+			let valid_until = *valid_until2.lock().unwrap();
+			let now = Instant::now();
+			CACHE.lock().unwrap().add(&key, &servers, *addr, valid_until, now, first);
+			first = false;
+		}
+	});
+	filter_family(res.right_stream(), family)
+}
+
+/// Resolve a [`ServerAddress`].
+///
+/// The servers of a [`ServerAddress::Multiple`] are resolved one after
+/// another and their addresses are returned in this order.
+///
+/// [`ServerAddress`]: ../enum.ServerAddress.html
+/// [`ServerAddress::Multiple`]: ../enum.ServerAddress.html#variant.Multiple
+pub fn resolve_server_address(
+	logger: Logger, address: &ServerAddress, config: &DnsConfig,
+) -> stream::BoxStream<'static, Result<SocketAddr>> {
+	match address {
+		ServerAddress::SocketAddr(a) => stream::once(future::ok(*a)).boxed(),
+		ServerAddress::Other(s) => resolve_with_config(logger, s.clone(), config).boxed(),
+		ServerAddress::Multiple(addrs) => {
+			let streams = addrs
+				.iter()
+				.map(|a| resolve_server_address(logger.clone(), a, config))
+				.collect::<Vec<_>>();
+			stream::iter(streams).flatten().boxed()
+		}
+	}
+}
+
+/// Parse a `ts3server://` link.
+///
+/// Returns `None` if the string is not such a link.
+pub fn parse_invite_link(link: &str) -> Option<InviteLink> {
+	let url = url::Url::parse(link).ok().filter(|u| u.scheme() == "ts3server")?;
+	let host = url.host_str().filter(|h| !h.is_empty())?;
+	let mut port = url.port();
+	let mut res = InviteLink::default();
+	for (key, value) in url.query_pairs() {
+		let value = Some(value.into_owned()).filter(|v| !v.is_empty());
+		match key.as_ref() {
+			"port" => port = value.and_then(|p| p.parse().ok()).or(port),
+			"password" => res.password = value,
+			"nickname" => res.nickname = value,
+			"channel" => res.channel = value,
+			"channelpassword" => res.channel_password = value,
+			_ => {}
+		}
+	}
+	res.address = match port {
+		Some(port) => format!("{}:{}", host, port),
+		None => host.to_string(),
+	};
+	Some(res)
+}
+
+/// Drop and reorder addresses according to the address family.
+///
+/// Addresses of the preferred family are returned directly, the others are
+/// returned when the stream ends.
+fn filter_family<S: Stream<Item = Result<SocketAddr>>>(
+	stream: S, family: AddressFamily,
+) -> impl Stream<Item = Result<SocketAddr>> {
+	let state = Arc::new(Mutex::new(FamilyState::default()));
+	let state2 = state.clone();
+	stream
+		.filter_map(move |r| {
+			let mut state = state.lock().unwrap();
+			future::ready(match r {
+				Ok(addr) => match family.rank(&addr) {
+					Some(0) => {
+						state.found = true;
+						Some(Ok(addr))
+					}
+					Some(_) => {
+						state.deferred.push(addr);
+						None
+					}
+					None => {
+						state.filtered = true;
+						None
+					}
+				},
+				Err(e) => Some(Err(e)),
+			})
+		})
+		.chain(
+			stream::once(future::lazy(move |_| {
+				let state = std::mem::take(&mut *state2.lock().unwrap());
+				let mut res = state.deferred.into_iter().map(Ok).collect::<Vec<_>>();
+				if res.is_empty() && !state.found && state.filtered {
+					res.push(Err(Error::NoAddressOfFamily(family)));
+				}
+				stream::iter(res)
+			}))
+			.flatten(),
+		)
+}
+
+/// Remove all entries from the cache.
+pub fn clear_cache() { CACHE.lock().unwrap().entries.clear(); }
+
+/// Remove an address from the cache, for all dns servers.
+pub(crate) fn remove_from_cache(address: &str) {
+	CACHE.lock().unwrap().entries.retain(|(a, _), _| a != address);
+}
+
+/// Remove all addresses of a server from the cache.
+pub(crate) fn remove_address_from_cache(address: &ServerAddress) {
+	match address {
+		ServerAddress::SocketAddr(_) => {}
+		ServerAddress::Other(s) => match parse_invite_link(s) {
+			Some(link) => remove_from_cache(&link.address),
+			None => remove_from_cache(s),
+		},
+		ServerAddress::Multiple(addrs) => addrs.iter().for_each(remove_address_from_cache),
+	}
+}
+
+/// Set the minimum and maximum time that resolved addresses are cached.
+///
+/// If the TTL of a record is shorter or longer, or if it is unknown, it is
+/// clamped to this range.
+///
+/// # Default
+/// 1 minute to 1 hour.
+pub fn set_cache_ttl(min: Duration, max: Duration) {
+	let mut cache = CACHE.lock().unwrap();
+	cache.min_ttl = min;
+	cache.max_ttl = max;
+}
+
+fn resolve_inner(
+	logger: Logger, address: String, config: DnsConfig, valid_until: Arc<Mutex<Option<Instant>>>,
+) -> impl Stream<Item = Result<SocketAddr>> {
 	let logger = logger.new(o!("module" => "resolver"));
 	debug!(logger, "Starting resolve"; "address" => &address);
 	let addr;
@@ -108,14 +399,7 @@ pub fn resolve(logger: Logger, address: String) -> impl Stream<Item = Result<Soc
 	let res = if !address.contains('.') && address != "localhost" {
 		debug!(logger, "Resolving nickname"; "address" => &address);
 		// Could be a server nickname
-		resolve_nickname(address.clone())
-			.map_ok(move |mut addr| {
-				if let Some(port) = port {
-					addr.set_port(port);
-				}
-				addr
-			})
-			.left_stream()
+		resolve_nickname(address.clone()).map_ok(move |addr| with_port(addr, port)).left_stream()
 	} else {
 		stream::once(future::err(Error::InvalidNickname)).right_stream()
 	};
@@ -124,9 +408,12 @@ pub fn resolve(logger: Logger, address: String) -> impl Stream<Item = Result<Soc
 	// https://github.com/bluejekyll/trust-dns/issues/652
 	let addr2 = addr.clone();
 	let logger2 = logger.clone();
+	let config2 = config.clone();
+	let valid_until2 = valid_until.clone();
+	let valid_until3 = valid_until.clone();
 	let res = res.chain(
 		stream::once(async move {
-			let resolver = create_resolver(&logger2).await?;
+			let resolver = create_resolver(&logger2, &config2).await?;
 
 			// Try to get the address by an SRV record
 			let prefix = Name::from_str(DNS_PREFIX_UDP).expect("Canot parse udp domain prefix");
@@ -134,17 +421,21 @@ pub fn resolve(logger: Logger, address: String) -> impl Stream<Item = Result<Soc
 				Name::from_str(&addr2).map_err(|e| Error::InvalidDomain(addr2.clone(), e))?;
 			name.set_fqdn(true);
 
-			Result::<_>::Ok(resolve_srv(resolver, prefix.append_name(&name)))
+			let custom = !config2.servers.is_empty();
+			let srv = resolve_srv(resolver, custom, prefix.append_name(&name), valid_until2);
+			Result::<_>::Ok(srv.map_ok(move |addr| with_port(addr, port)))
 		})
 		.try_flatten(),
 	);
 
 	// Try to get the address of a tsdns server by an SRV record
 	let addr2 = addr.clone();
+	let address2 = address.clone();
 	let logger2 = logger.clone();
+	let config2 = config.clone();
 	let res = res.chain(
 		stream::once(async move {
-			let resolver = create_resolver(&logger2).await?;
+			let resolver = create_resolver(&logger2, &config2).await?;
 			let prefix = Name::from_str(DNS_PREFIX_TCP).expect("Canot parse udp domain prefix");
 			let mut name =
 				Name::from_str(&addr2).map_err(|e| Error::InvalidDomain(addr2.clone(), e))?;
@@ -152,31 +443,41 @@ pub fn resolve(logger: Logger, address: String) -> impl Stream<Item = Result<Soc
 
 			let name = name.trim_to(2);
 			// Pick the first srv record of the first server that answers
-			Result::<_>::Ok(resolve_srv(resolver, prefix.append_name(&name)).and_then(move |srv| {
-				let address = address.clone();
+			let custom = !config2.servers.is_empty();
+			let srv = resolve_srv(resolver, custom, prefix.append_name(&name), valid_until);
+			Result::<_>::Ok(srv.and_then(move |srv| {
+				let address = address2.clone();
 				async move {
 					// Got tsdns server
-					let mut addr = resolve_tsdns(srv, &address).await?;
-					if let Some(port) = port {
-						// Overwrite port if it was specified
-						addr.set_port(port);
-					}
-					Ok(addr)
+					let addr = resolve_tsdns(srv, &address).await?;
+					Ok(with_port(addr, port))
 				}
 			}))
 		})
 		.try_flatten(),
 	);
 
-	// Interpret as normal address and resolve with system resolver
+	// Interpret as normal address, then try the tsdns server of the parent
+	// domain
 	let res = res.chain(
 		stream::once(async move {
-			let res = net::lookup_host((addr.as_str(), port.unwrap_or(DEFAULT_PORT)))
-				.await
-				.map_err(Error::ResolveHost)?
-				.map(Ok)
-				.collect::<Vec<_>>();
-			Result::<_>::Ok(stream::iter(res))
+			let custom = !config.servers.is_empty();
+			let resolver = match create_resolver(&logger, &config).await {
+				Ok(r) => Some(r),
+				Err(e) if custom => return Err(e),
+				Err(_) => None,
+			};
+			let res = lookup_host_or_tsdns(
+				&logger,
+				resolver.as_ref(),
+				custom,
+				&addr,
+				port,
+				TSDNS_DEFAULT_PORT,
+				&valid_until3,
+			)
+			.await?;
+			Result::<_>::Ok(stream::iter(res.into_iter().map(Ok)))
 		})
 		.try_flatten(),
 	);
@@ -188,19 +489,263 @@ pub fn resolve(logger: Logger, address: String) -> impl Stream<Item = Result<Soc
 		.right_stream()
 }
 
-async fn create_resolver(logger: &Logger) -> Result<TokioAsyncResolver> {
-	match TokioAsyncResolver::tokio_from_system_conf().await {
+/// The port to connect to.
+///
+/// A port which was `explicit`ly given in the address always wins. Otherwise
+/// the port which was `found` while resolving, e.g. in an SRV record, is used.
+/// The default port is the last resort.
+fn select_port(explicit: Option<u16>, found: Option<u16>) -> u16 {
+	explicit.or(found).unwrap_or(DEFAULT_PORT)
+}
+
+/// Overwrite the port of a resolved address if a port was given explicitly.
+fn with_port(mut addr: SocketAddr, explicit: Option<u16>) -> SocketAddr {
+	addr.set_port(select_port(explicit, Some(addr.port())));
+	addr
+}
+
+/// Resolve the addresses of a host name.
+///
+/// The expiry of the A and AAAA records is stored in `valid_until` if it is
+/// earlier. If the `resolver` does not use `custom` dns servers and fails,
+/// e.g. for names from the hosts file, the system resolver is asked. The TTL
+/// of its results is unknown.
+async fn lookup_host(
+	resolver: Option<&TokioAsyncResolver>, custom: bool, host: &str, port: u16,
+	valid_until: &Mutex<Option<Instant>>,
+) -> Result<Vec<SocketAddr>>
+{
+	if let Some(resolver) = resolver {
+		match resolver.lookup_ip(host).await {
+			Ok(lookup) => {
+				shorten_valid_until(valid_until, lookup.valid_until());
+				return Ok(lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect());
+			}
+			Err(e) if custom => return Err(Error::ResolveHostCustom(e)),
+			Err(_) => {}
+		}
+	}
+	Ok(net::lookup_host((host, port)).await.map_err(Error::ResolveHost)?.collect())
+}
+
+/// Resolve the addresses of a host name like [`lookup_host`], ask the tsdns
+/// server of the parent domain if the name has no address.
+///
+/// The tsdns server is asked at `tsdns_port`, its name is resolved with the
+/// same dns servers as the host name.
+///
+/// [`lookup_host`]: fn.lookup_host.html
+async fn lookup_host_or_tsdns(
+	logger: &Logger, resolver: Option<&TokioAsyncResolver>, custom: bool, host: &str,
+	port: Option<u16>, tsdns_port: u16, valid_until: &Mutex<Option<Instant>>,
+) -> Result<Vec<SocketAddr>>
+{
+	match lookup_host(resolver, custom, host, select_port(port, None), valid_until).await {
+		Ok(res) if !res.is_empty() => return Ok(res),
+		Ok(_) => debug!(logger, "Host has no address, trying tsdns"; "host" => host),
+		Err(e) => debug!(logger, "Resolving host failed, trying tsdns"; "error" => %e),
+	}
+
+	let name = Name::from_str(host).map_err(|e| Error::InvalidDomain(host.to_string(), e))?;
+	let server = name.trim_to(2).to_ascii();
+	let mut res = Err(Error::TsdnsAddressNotFound);
+	for server in lookup_host(resolver, custom, &server, tsdns_port, valid_until).await? {
+		res = resolve_tsdns(server, host).await;
+		if res.is_ok() {
+			break;
+		}
+	}
+	res.map(|addr| vec![with_port(addr, port)])
+}
+
+/// Cached addresses expire with the shortest TTL of all used records.
+fn shorten_valid_until(valid_until: &Mutex<Option<Instant>>, lookup_valid: Instant) {
+	let mut valid_until = valid_until.lock().unwrap();
+	if valid_until.map(|v| lookup_valid < v).unwrap_or(true) {
+		*valid_until = Some(lookup_valid);
+	}
+}
+
+async fn create_resolver(logger: &Logger, config: &DnsConfig) -> Result<TokioAsyncResolver> {
+	if !config.servers.is_empty() {
+		let mut servers = NameServerConfigGroup::new();
+		for addr in &config.servers {
+			servers.merge(NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port()));
+		}
+		let resolver_config = ResolverConfig::from_parts(None, Vec::new(), servers);
+		return TokioAsyncResolver::tokio(resolver_config, config.apply(Default::default()))
+			.await
+			.map_err(Error::CreateCustomResolver);
+	}
+
+	let res = match trust_dns_resolver::system_conf::read_system_conf() {
+		Ok((resolver_config, opts)) => {
+			TokioAsyncResolver::tokio(resolver_config, config.apply(opts)).await
+		}
+		Err(e) => Err(e.into()),
+	};
+	match res {
 		Ok(r) => Ok(r),
 		Err(e) => {
 			warn!(logger, "Failed to use system dns resolver config"; "error" => %e);
 			// Fallback
-			Ok(TokioAsyncResolver::tokio(ResolverConfig::cloudflare(), Default::default())
+			let opts = config.apply(Default::default());
+			Ok(TokioAsyncResolver::tokio(ResolverConfig::cloudflare(), opts)
 				.await
 				.map_err(|e2| Error::CreateResolver { system: e, fallback: e2 })?)
 		}
 	}
 }
 
+impl Default for DnsConfig {
+	fn default() -> Self {
+		Self {
+			servers: Vec::new(),
+			timeout: None,
+			attempts: None,
+			cache: true,
+			address_family: AddressFamily::Any,
+		}
+	}
+}
+
+impl DnsConfig {
+	/// Use these dns servers instead of the ones from the system
+	/// configuration.
+	///
+	/// They are asked for SRV records and for the addresses of host names.
+	///
+	/// # Default
+	/// Empty, which uses the system configuration.
+	#[inline]
+	pub fn servers(mut self, servers: Vec<SocketAddr>) -> Self {
+		self.servers = servers;
+		self
+	}
+
+	/// The timeout for a single dns request.
+	///
+	/// # Default
+	/// The timeout of the system configuration.
+	#[inline]
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// How often a dns request is tried before giving up.
+	///
+	/// # Default
+	/// The attempts of the system configuration.
+	#[inline]
+	pub fn attempts(mut self, attempts: usize) -> Self {
+		self.attempts = Some(attempts);
+		self
+	}
+
+	/// If cached addresses should be used.
+	///
+	/// # Default
+	/// `true`
+	#[inline]
+	pub fn cache(mut self, cache: bool) -> Self {
+		self.cache = cache;
+		self
+	}
+
+	/// Which kind of ip addresses should be returned.
+	///
+	/// # Default
+	/// [`AddressFamily::Any`](enum.AddressFamily.html#variant.Any)
+	#[inline]
+	pub fn address_family(mut self, address_family: AddressFamily) -> Self {
+		self.address_family = address_family;
+		self
+	}
+
+	#[inline]
+	pub fn get_servers(&self) -> &[SocketAddr] { &self.servers }
+	#[inline]
+	pub fn get_timeout(&self) -> Option<Duration> { self.timeout }
+	#[inline]
+	pub fn get_attempts(&self) -> Option<usize> { self.attempts }
+	#[inline]
+	pub fn get_cache(&self) -> bool { self.cache }
+	#[inline]
+	pub fn get_address_family(&self) -> AddressFamily { self.address_family }
+
+	/// Overwrite the options which are set in this config.
+	fn apply(&self, mut opts: ResolverOpts) -> ResolverOpts {
+		if let Some(timeout) = self.timeout {
+			opts.timeout = timeout;
+		}
+		if let Some(attempts) = self.attempts {
+			opts.attempts = attempts;
+		}
+		opts
+	}
+}
+
+impl Default for AddressFamily {
+	fn default() -> Self { AddressFamily::Any }
+}
+
+impl AddressFamily {
+	/// The position of this address in the result, `0` comes first and `None`
+	/// is dropped.
+	fn rank(self, addr: &SocketAddr) -> Option<u8> {
+		match (self, addr.is_ipv4()) {
+			(AddressFamily::Any, _)
+			| (AddressFamily::PreferIpv4, true)
+			| (AddressFamily::PreferIpv6, false)
+			| (AddressFamily::Only4, true)
+			| (AddressFamily::Only6, false) => Some(0),
+			(AddressFamily::PreferIpv4, false) | (AddressFamily::PreferIpv6, true) => Some(1),
+			(AddressFamily::Only4, false) | (AddressFamily::Only6, true) => None,
+		}
+	}
+}
+
+impl Cache {
+	fn get(
+		&mut self, address: &str, servers: &[SocketAddr], now: Instant,
+	) -> Option<Vec<SocketAddr>> {
+		let key = (address.to_string(), servers.to_vec());
+		let entry = self.entries.get(&key)?;
+		if entry.valid_until <= now {
+			self.entries.remove(&key);
+			None
+		} else {
+			Some(entry.addrs.clone())
+		}
+	}
+
+	/// Add a resolved address.
+	///
+	/// If `first` is set, this replaces the old entry.
+	fn add(
+		&mut self, address: &str, servers: &[SocketAddr], addr: SocketAddr,
+		valid_until: Option<Instant>, now: Instant, first: bool,
+	)
+	{
+		let key = (address.to_string(), servers.to_vec());
+		let valid_until = valid_until
+			.unwrap_or(now)
+			.max(now + self.min_ttl)
+			.min(now + self.max_ttl);
+		if !first {
+			if let Some(entry) = self.entries.get_mut(&key) {
+				if !entry.addrs.contains(&addr) {
+					entry.addrs.push(addr);
+				}
+				entry.valid_until = entry.valid_until.min(valid_until);
+				return;
+			}
+		}
+		self.entries.insert(key, CacheEntry { addrs: vec![addr], valid_until });
+	}
+}
+
 fn parse_ip(address: &str) -> Result<ParseIpResult> {
 	let mut addr = address;
 	let mut port = None;
@@ -294,7 +839,7 @@ pub fn resolve_nickname(nickname: String) -> impl Stream<Item = Result<SocketAdd
 					match parse_ip(&addr)? {
 						ParseIpResult::Addr(a) => Ok(stream::once(future::ok(a)).left_stream()),
 						ParseIpResult::Other(a, p) => {
-							let addrs = net::lookup_host((a, p.unwrap_or(DEFAULT_PORT)))
+							let addrs = net::lookup_host((a, select_port(p, None)))
 								.await
 								.map_err(Error::ResolveHost)?
 								.collect::<Vec<_>>();
@@ -309,10 +854,25 @@ pub fn resolve_nickname(nickname: String) -> impl Stream<Item = Result<SocketAdd
 }
 
 pub async fn resolve_tsdns<A: net::ToSocketAddrs>(server: A, addr: &str) -> Result<SocketAddr> {
-	let mut stream = TcpStream::connect(server).await.map_err(|e| Error::Io("tsdns", e))?;
-	stream.write_all(addr.as_bytes()).await.map_err(|e| Error::Io("tsdns", e))?;
-	let mut data = Vec::new();
-	stream.read_to_end(&mut data).await.map_err(|e| Error::Io("tsdns", e))?;
+	resolve_tsdns_timeout(server, addr, TSDNS_TIMEOUT).await
+}
+
+/// Ask a tsdns server, gives up if the server does not answer within
+/// `timeout`.
+async fn resolve_tsdns_timeout<A: net::ToSocketAddrs>(
+	server: A, addr: &str, timeout: Duration,
+) -> Result<SocketAddr> {
+	let request = async {
+		let mut stream = TcpStream::connect(server).await?;
+		stream.write_all(addr.as_bytes()).await?;
+		let mut data = Vec::new();
+		stream.read_to_end(&mut data).await?;
+		Ok::<_, std::io::Error>(data)
+	};
+	let data = time::timeout(timeout, request)
+		.await
+		.map_err(|_| Error::TsdnsTimeout(timeout))?
+		.map_err(|e| Error::Io("tsdns", e))?;
 
 	let addr = str::from_utf8(&data).map_err(Error::TsdnsParseResponse)?;
 	if addr.starts_with("404") {
@@ -324,81 +884,66 @@ pub async fn resolve_tsdns<A: net::ToSocketAddrs>(server: A, addr: &str) -> Resu
 	}
 }
 
-fn resolve_srv(resolver: TokioAsyncResolver, addr: Name) -> impl Stream<Item = Result<SocketAddr>> {
-	stream::once(async {
-		let lookup = resolver.srv_lookup(addr).await.map_err(Error::SrvLookup)?;
-		let mut entries = Vec::new();
-		let mut max_prio = if let Some(e) = lookup.iter().next() {
-			e.priority()
-		} else {
-			return Err(Error::NoSrvEntry);
-		};
-
-		// Move all SRV records into entries and only retain the ones with
-		// the lowest priority.
-		for srv in lookup.iter() {
-			if srv.priority() < max_prio {
-				max_prio = srv.priority();
-				entries.clear();
-				entries.push(srv);
-			} else if srv.priority() == max_prio {
-				entries.push(srv);
-			}
-		}
-
-		let prios = lookup.iter().group_by(|e| e.priority());
-		let entries = prios.into_iter().sorted_by_key(|(p, _)| *p);
-
-		// Select by weight
-		let mut sorted_entries = Vec::new();
-		for (_, es) in entries {
-			let mut zero_entries = Vec::new();
-
-			// All non-zero entries
-			let mut entries = es
-				.filter_map(|e| {
-					if e.weight() == 0 {
-						zero_entries.push(e);
-						None
-					} else {
-						Some(e)
-					}
+/// Order SRV records as specified in RFC 2782.
+///
+/// The records are `(priority, weight, target)`. Records with a lower
+/// priority come first, records with the same priority are ordered by a
+/// weighted random selection.
+fn order_srv<T, R: Rng>(rng: &mut R, mut records: Vec<(u16, u16, T)>) -> Vec<T> {
+	// Records with weight 0 are put first, so they have a small chance to be
+	// selected.
+	records.sort_by_key(|(prio, weight, _)| (*prio, *weight != 0));
+	let mut res = Vec::with_capacity(records.len());
+	for (_, group) in &records.into_iter().group_by(|(prio, ..)| *prio) {
+		let mut group = group.map(|(_, weight, r)| (u32::from(weight), r)).collect::<Vec<_>>();
+		while !group.is_empty() {
+			let sum: u32 = group.iter().map(|(w, _)| w).sum();
+			let selected = rng.gen_range(0, sum + 1);
+			let mut running = 0;
+			let i = group
+				.iter()
+				.position(|(w, _)| {
+					running += w;
+					running >= selected
 				})
-				.collect::<Vec<_>>();
+				.unwrap();
+			res.push(group.remove(i).1);
+		}
+	}
+	res
+}
 
-			while !entries.is_empty() {
-				let weight: u32 = entries.iter().map(|e| e.weight() as u32).sum();
-				let mut w = rand::thread_rng().gen_range(0, weight + 1);
-				if w == 0 {
-					// Pick the first entry with weight 0
-					if let Some(i) = entries.iter().position(|e| e.weight() == 0) {
-						sorted_entries.push(entries.remove(i));
-					}
-				}
-				for i in 0..entries.len() {
-					let weight = entries[i].weight() as u32;
-					if w <= weight {
-						sorted_entries.push(entries.remove(i));
-						break;
-					}
-					w -= weight;
-				}
-			}
+/// Resolve an SRV record and the addresses of its targets.
+///
+/// `valid_until` is set to the expiration time of the lookup, if it is earlier
+/// than the current value.
+fn resolve_srv(
+	resolver: TokioAsyncResolver, custom: bool, addr: Name,
+	valid_until: Arc<Mutex<Option<Instant>>>,
+) -> impl Stream<Item = Result<SocketAddr>> {
+	stream::once(async move {
+		let lookup = resolver.srv_lookup(addr).await.map_err(Error::SrvLookup)?;
+		shorten_valid_until(&valid_until, lookup.valid_until());
+		let records = lookup
+			.iter()
+			.map(|e| (e.priority(), e.weight(), (e.target().to_ascii(), e.port())))
+			.collect::<Vec<_>>();
+		if records.is_empty() {
+			return Err(Error::NoSrvEntry);
 		}
 
-		let res = sorted_entries
+		let res = order_srv(&mut rand::thread_rng(), records)
 			.into_iter()
-			.map(|e| Ok((e.target().to_ascii(), e.port())))
+			.map(Ok)
 			.collect::<Vec<Result<(String, u16)>>>();
-		drop(resolver);
 		Ok(stream::iter(res)
-			.and_then(|(e, port)| async move {
-				let res = net::lookup_host((e.as_str(), port))
-					.await
-					.map_err(Error::ResolveHost)?
-					.map(Ok)
-					.collect::<Vec<_>>();
-				Ok(stream::iter(res))
+			.and_then(move |(e, port)| {
+				let resolver = resolver.clone();
+				let valid_until = valid_until.clone();
+				async move {
+					let res = lookup_host(Some(&resolver), custom, &e, port, &valid_until).await?;
+					Ok(stream::iter(res.into_iter().map(Ok)))
+				}
 			})
 			.try_flatten())
 	})
@@ -407,6 +952,13 @@ fn resolve_srv(resolver: TokioAsyncResolver, addr: Name) -> impl Stream<Item = R
 
 #[cfg(test)]
 mod test {
+	use std::net::Ipv4Addr;
+
+	use rand::rngs::StdRng;
+	use rand::SeedableRng;
+	use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+	use trust_dns_proto::rr::{RData, Record, RecordType};
+
 	use super::*;
 	use crate::tests::get_logger;
 
@@ -464,6 +1016,418 @@ mod test {
 		assert!(parse_ip("127.0.0.1:65536").is_err());
 	}
 
+	/// Start a tsdns server which answers a single request with `answer`.
+	async fn mock_tsdns(answer: &'static str) -> SocketAddr {
+		let mut listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut buf = [0; 64];
+			let len = stream.read(&mut buf).await.unwrap();
+			assert_eq!(&buf[..len], b"ts.example.com");
+			stream.write_all(answer.as_bytes()).await.unwrap();
+		});
+		addr
+	}
+
+	#[tokio::test]
+	async fn tsdns() {
+		let server = mock_tsdns("1.2.3.4:9988").await;
+		let res = resolve_tsdns(server, "ts.example.com").await.unwrap();
+		assert_eq!(res, "1.2.3.4:9988".parse().unwrap());
+	}
+
+	#[tokio::test]
+	async fn tsdns_not_found() {
+		let server = mock_tsdns("404").await;
+		let res = resolve_tsdns(server, "ts.example.com").await;
+		assert!(matches!(res, Err(Error::TsdnsAddressNotFound)));
+	}
+
+	#[tokio::test]
+	async fn tsdns_invalid() {
+		let server = mock_tsdns("ts.other.com").await;
+		let res = resolve_tsdns(server, "ts.example.com").await;
+		assert!(matches!(res, Err(Error::TsdnsAddressInvalidResponse(_))));
+	}
+
+	#[tokio::test]
+	async fn tsdns_timeout() {
+		// A server which accepts the connection but never answers
+		let mut listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let server = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			let (_stream, _) = listener.accept().await.unwrap();
+			future::pending::<()>().await;
+		});
+		let timeout = Duration::from_millis(100);
+		let res = resolve_tsdns_timeout(server, "ts.example.com", timeout).await;
+		assert!(matches!(res, Err(Error::TsdnsTimeout(t)) if t == timeout), "{:?}", res);
+	}
+
+	#[tokio::test]
+	async fn tsdns_refused() {
+		// Get a free port and close it again
+		let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let server = listener.local_addr().unwrap();
+		drop(listener);
+		let res = resolve_tsdns(server, "ts.example.com").await;
+		assert!(matches!(res, Err(Error::Io("tsdns", _))));
+	}
+
+	#[test]
+	fn cache() {
+		let mut cache = Cache {
+			entries: HashMap::new(),
+			min_ttl: Duration::from_secs(60),
+			max_ttl: Duration::from_secs(600),
+		};
+		let now = Instant::now();
+		let a = "127.0.0.1:1".parse().unwrap();
+		let b = "127.0.0.1:2".parse().unwrap();
+		// Short TTL gets clamped to the minimum
+		cache.add("host", &[], a, Some(now + Duration::from_secs(1)), now, true);
+		cache.add("host", &[], b, None, now, false);
+		assert_eq!(cache.get("host", &[], now + Duration::from_secs(59)), Some(vec![a, b]));
+		assert_eq!(cache.get("host", &[], now + Duration::from_secs(60)), None);
+		assert_eq!(cache.get("host", &[], now), None);
+
+		// Long TTL gets clamped to the maximum
+		cache.add("host", &[], a, Some(now + Duration::from_secs(6000)), now, true);
+		assert_eq!(cache.get("host", &[], now + Duration::from_secs(599)), Some(vec![a]));
+		assert_eq!(cache.get("host", &[], now + Duration::from_secs(600)), None);
+
+		// TTL of the record
+		cache.add("host", &[], a, Some(now + Duration::from_secs(120)), now, true);
+		assert_eq!(cache.get("host", &[], now + Duration::from_secs(119)), Some(vec![a]));
+		assert_eq!(cache.get("host", &[], now + Duration::from_secs(120)), None);
+
+		// Addresses of other dns servers are separate
+		let dns = ["127.0.0.1:53".parse().unwrap()];
+		cache.add("host", &dns, b, None, now, true);
+		assert_eq!(cache.get("host", &[], now), None);
+		assert_eq!(cache.get("host", &dns, now), Some(vec![b]));
+	}
+
+	#[tokio::test]
+	async fn resolve_cached() {
+		let logger = get_logger();
+		let addr = "127.0.0.1:3".parse().unwrap();
+		CACHE.lock().unwrap().add("cached.invalid", &[], addr, None, Instant::now(), true);
+		let res: Vec<_> =
+			resolve(logger, "cached.invalid".into()).map(|r| r.unwrap()).collect().await;
+		assert_eq!(res, vec![addr]);
+		remove_from_cache("cached.invalid");
+	}
+
+	#[test]
+	fn invite_link() {
+		let link = parse_invite_link(
+			"ts3server://ts.example.com?port=9988&nickname=My%20Bot&password=secret&channel=Lobby/Games&channelpassword=&addbookmark=1",
+		)
+		.unwrap();
+		assert_eq!(link, InviteLink {
+			address: "ts.example.com:9988".into(),
+			password: Some("secret".into()),
+			nickname: Some("My Bot".into()),
+			channel: Some("Lobby/Games".into()),
+			channel_password: None,
+		});
+
+		let link = parse_invite_link("ts3server://[::1]").unwrap();
+		assert_eq!(link.address, "[::1]");
+		assert_eq!(parse_invite_link("ts.example.com"), None);
+		assert_eq!(parse_invite_link("https://ts.example.com"), None);
+	}
+
+	#[tokio::test]
+	async fn resolve_multiple() {
+		let logger = get_logger();
+		let address = ServerAddress::Multiple(vec![
+			"ts3server://127.0.0.1?port=1".into(),
+			"127.0.0.1:2".parse::<SocketAddr>().unwrap().into(),
+			ServerAddress::Multiple(vec!["[::1]:3".into()]),
+		]);
+		let res: Vec<_> = resolve_server_address(logger, &address, &DnsConfig::default())
+			.map(|r| r.unwrap())
+			.collect()
+			.await;
+		let expected: Vec<SocketAddr> = ["127.0.0.1:1", "127.0.0.1:2", "[::1]:3"]
+			.iter()
+			.map(|a| a.parse().unwrap())
+			.collect();
+		assert_eq!(res, expected);
+	}
+
+	#[test]
+	fn port_precedence() {
+		assert_eq!(select_port(Some(1), Some(2)), 1);
+		assert_eq!(select_port(Some(1), None), 1);
+		assert_eq!(select_port(None, Some(2)), 2);
+		assert_eq!(select_port(None, None), DEFAULT_PORT);
+
+		let found: SocketAddr = "1.2.3.4:9000".parse().unwrap();
+		assert_eq!(with_port(found, Some(1)), "1.2.3.4:1".parse().unwrap());
+		assert_eq!(with_port(found, None), found);
+	}
+
+	#[test]
+	fn server_address_with_port() {
+		let set = |a: &str| ServerAddress::from(a).with_port(1).to_string();
+		assert_eq!(set("ts.example.com"), "ts.example.com:1");
+		assert_eq!(set("ts.example.com:9000"), "ts.example.com:1");
+		assert_eq!(set("127.0.0.1:9000"), "127.0.0.1:1");
+		assert_eq!(set("::1"), "[::1]:1");
+		assert_eq!(set("[::1]"), "[::1]:1");
+		assert_eq!(set("[::1]:9000"), "[::1]:1");
+		assert_eq!(set("ts3server://ts.example.com"), "ts3server://ts.example.com?port=1");
+		assert_eq!(
+			set("ts3server://ts.example.com?port=9000"),
+			"ts3server://ts.example.com?port=1"
+		);
+		assert_eq!(
+			set("ts3server://ts.example.com?port=9000&nickname=Bot&port=9001"),
+			"ts3server://ts.example.com?nickname=Bot&port=1"
+		);
+
+		let link = ServerAddress::from("ts3server://ts.example.com:9000?port=9001").with_port(1);
+		assert_eq!(parse_invite_link(&link.to_string()).unwrap().address, "ts.example.com:1");
+
+		let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+		let multiple = ServerAddress::Multiple(vec![addr.into(), "localhost".into()]);
+		assert_eq!(multiple.with_port(1).to_string(), "127.0.0.1:1, localhost:1");
+	}
+
+	#[tokio::test]
+	async fn resolve_with_port() {
+		let logger = get_logger();
+		let address = ServerAddress::Multiple(vec![
+			"127.0.0.1:9000".into(),
+			"ts3server://127.0.0.1?port=9000".into(),
+		])
+		.with_port(1);
+		assert_eq!(address.to_string(), "127.0.0.1:1, ts3server://127.0.0.1?port=1");
+		let res: Vec<_> = resolve_server_address(logger, &address, &DnsConfig::default())
+			.map(|r| r.unwrap())
+			.collect()
+			.await;
+		let expected: SocketAddr = "127.0.0.1:1".parse().unwrap();
+		assert_eq!(res, vec![expected, expected]);
+	}
+
+	#[test]
+	fn srv_priority() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let records = vec![(20, 10, "backup"), (10, 0, "b"), (30, 0, "last"), (10, 5, "a")];
+		for _ in 0..100 {
+			let res = order_srv(&mut rng, records.clone());
+			assert_eq!(res.len(), 4);
+			assert!(res[..2].contains(&"a") && res[..2].contains(&"b"));
+			assert_eq!(&res[2..], &["backup", "last"]);
+		}
+	}
+
+	#[test]
+	fn srv_weight() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let records = vec![(0, 30, "a"), (0, 10, "b"), (0, 0, "c")];
+		let mut first = HashMap::new();
+		for _ in 0..4000 {
+			let res = order_srv(&mut rng, records.clone());
+			*first.entry(res[0]).or_insert(0) += 1;
+		}
+		// The selection is uniform in 0..=40, so the expected counts are 2927,
+		// 976 and 98.
+		let a = first["a"];
+		let b = first["b"];
+		let c = first.get("c").copied().unwrap_or_default();
+		assert!(a > 2750 && a < 3100, "a was first {} times", a);
+		assert!(b > 850 && b < 1100, "b was first {} times", b);
+		assert!(c < 200, "c was first {} times", c);
+	}
+
+	async fn families(family: AddressFamily, addrs: &[&str]) -> Vec<String> {
+		let addrs = addrs.iter().map(|a| Ok(a.parse().unwrap())).collect::<Vec<_>>();
+		filter_family(stream::iter(addrs), family)
+			.map(|r| r.map(|a| a.to_string()).unwrap_or_else(|e| e.to_string()))
+			.collect()
+			.await
+	}
+
+	#[tokio::test]
+	async fn address_family() {
+		let addrs = ["[::1]:1", "127.0.0.1:2", "[::2]:3", "127.0.0.2:4"];
+		assert_eq!(families(AddressFamily::Any, &addrs).await, addrs);
+		assert_eq!(families(AddressFamily::PreferIpv4, &addrs).await, [
+			"127.0.0.1:2",
+			"127.0.0.2:4",
+			"[::1]:1",
+			"[::2]:3"
+		]);
+		assert_eq!(families(AddressFamily::PreferIpv6, &addrs).await, [
+			"[::1]:1",
+			"[::2]:3",
+			"127.0.0.1:2",
+			"127.0.0.2:4"
+		]);
+		assert_eq!(families(AddressFamily::Only4, &addrs).await, ["127.0.0.1:2", "127.0.0.2:4"]);
+		assert_eq!(families(AddressFamily::Only6, &addrs).await, ["[::1]:1", "[::2]:3"]);
+		// Prefer works with only one family
+		assert_eq!(families(AddressFamily::PreferIpv6, &addrs[1..2]).await, ["127.0.0.1:2"]);
+		assert!(families(AddressFamily::Only4, &[]).await.is_empty());
+	}
+
+	#[tokio::test]
+	async fn address_family_missing() {
+		let config = DnsConfig::default().address_family(AddressFamily::Only6);
+		let res: Vec<_> =
+			resolve_with_config(get_logger(), "127.0.0.1".into(), &config).collect().await;
+		assert_eq!(res.len(), 1);
+		assert!(matches!(res[0], Err(Error::NoAddressOfFamily(AddressFamily::Only6))));
+	}
+
+	#[test]
+	fn dns_config_options() {
+		let opts = ResolverOpts::default();
+		let applied = DnsConfig::default().apply(opts);
+		assert_eq!(applied.timeout, opts.timeout);
+		assert_eq!(applied.attempts, opts.attempts);
+
+		let config = DnsConfig::default().timeout(Duration::from_millis(300)).attempts(5);
+		let applied = config.apply(opts);
+		assert_eq!(applied.timeout, Duration::from_millis(300));
+		assert_eq!(applied.attempts, 5);
+	}
+
+	/// Start a dns server which answers A requests for the hosts in `records`
+	/// with their ip and this `ttl` in seconds.
+	///
+	/// All other requests are answered with `NXDomain`.
+	fn stub_dns_server(records: Vec<(&'static str, Ipv4Addr)>, ttl: u32) -> SocketAddr {
+		let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+		let addr = socket.local_addr().unwrap();
+		std::thread::spawn(move || {
+			let mut buf = [0; 512];
+			while let Ok((len, from)) = socket.recv_from(&mut buf) {
+				let query = match Message::from_vec(&buf[..len]) {
+					Ok(q) => q,
+					Err(_) => continue,
+				};
+				let mut answer = Message::new();
+				answer
+					.set_id(query.id())
+					.set_message_type(MessageType::Response)
+					.set_op_code(query.op_code())
+					.set_recursion_desired(query.recursion_desired())
+					.set_recursion_available(true)
+					.add_queries(query.queries().to_vec());
+				for q in query.queries() {
+					let name = q.name().to_ascii();
+					let name = name.trim_end_matches('.');
+					for (host, ip) in &records {
+						if q.query_type() == RecordType::A && name.eq_ignore_ascii_case(host) {
+							let rdata = RData::A(*ip);
+							answer.add_answer(Record::from_rdata(q.name().clone(), ttl, rdata));
+						}
+					}
+				}
+				if answer.answers().is_empty() {
+					answer.set_response_code(ResponseCode::NXDomain);
+				}
+				let _ = socket.send_to(&answer.to_vec().unwrap(), from);
+			}
+		});
+		addr
+	}
+
+	#[tokio::test]
+	async fn custom_dns_servers() {
+		let logger = get_logger();
+		let ip = Ipv4Addr::new(10, 1, 2, 3);
+		let server = stub_dns_server(vec![("ts.example.invalid", ip)], 60);
+		let config = DnsConfig::default()
+			.servers(vec![server])
+			.timeout(Duration::from_millis(100))
+			.attempts(1)
+			.cache(false);
+		// Only the custom server knows this name
+		let res: Vec<_> = resolve_with_config(logger, "ts.example.invalid".into(), &config)
+			.map(|r| r.unwrap())
+			.collect()
+			.await;
+		assert_eq!(res, vec![SocketAddr::new(ip.into(), DEFAULT_PORT)]);
+	}
+
+	#[tokio::test]
+	async fn a_record_before_tsdns() {
+		let logger = get_logger();
+		let tsdns = mock_tsdns("10.0.0.2:9988").await;
+		let ip = Ipv4Addr::new(10, 0, 0, 1);
+		let lookup = |records| {
+			let logger = logger.clone();
+			async move {
+				let config = DnsConfig::default()
+					.servers(vec![stub_dns_server(records, 60)])
+					.timeout(Duration::from_millis(100))
+					.attempts(1);
+				let resolver = create_resolver(&logger, &config).await.unwrap();
+				let valid_until = Mutex::new(None);
+				let port = tsdns.port();
+				lookup_host_or_tsdns(
+					&logger,
+					Some(&resolver),
+					true,
+					"ts.example.com",
+					None,
+					port,
+					&valid_until,
+				)
+				.await
+				.unwrap()
+			}
+		};
+
+		// The tsdns server of example.com is not asked if there is an A record
+		let records = vec![("ts.example.com", ip), ("example.com", Ipv4Addr::LOCALHOST)];
+		assert_eq!(lookup(records).await, vec![SocketAddr::new(ip.into(), DEFAULT_PORT)]);
+		let records = vec![("example.com", Ipv4Addr::LOCALHOST)];
+		assert_eq!(lookup(records).await, vec!["10.0.0.2:9988".parse().unwrap()]);
+	}
+
+	#[tokio::test]
+	async fn cache_with_dns_servers() {
+		let address = "cache.example.invalid";
+		let resolve = |ip: Ipv4Addr, ttl| {
+			let config = DnsConfig::default()
+				.servers(vec![stub_dns_server(vec![(address, ip)], ttl)])
+				.timeout(Duration::from_millis(100))
+				.attempts(1);
+			resolve_with_config(get_logger(), address.into(), &config)
+				.map(|r| r.unwrap())
+				.collect::<Vec<_>>()
+		};
+
+		// The cache entry expires with the TTL of the A record
+		let ip = Ipv4Addr::new(10, 1, 2, 3);
+		let start = Instant::now();
+		assert_eq!(resolve(ip, 120).await, vec![SocketAddr::new(ip.into(), DEFAULT_PORT)]);
+		let valid_until = CACHE
+			.lock()
+			.unwrap()
+			.entries
+			.iter()
+			.find(|((a, _), _)| a == address)
+			.map(|(_, e)| e.valid_until)
+			.unwrap();
+		assert!(valid_until > start + Duration::from_secs(100));
+		assert!(valid_until <= Instant::now() + Duration::from_secs(120));
+
+		// Another dns server does not get the cached address
+		let ip = Ipv4Addr::new(10, 1, 2, 4);
+		assert_eq!(resolve(ip, 120).await, vec![SocketAddr::new(ip.into(), DEFAULT_PORT)]);
+		remove_from_cache(address);
+	}
+
 	#[tokio::test]
 	async fn resolve_localhost() {
 		let logger = get_logger();
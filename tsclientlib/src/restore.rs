@@ -0,0 +1,316 @@
+//! Restore the state of our own client after the connection was rebuilt.
+//!
+//! When the connection is lost, the [`Connection`] reconnects automatically.
+//! The server puts us into the default channel with default flags again. To
+//! not lose e.g. the channel of a music bot, the connection remembers the
+//! changes of our client, which the server notifies us about, and applies
+//! them again after reconnecting. This includes changes by other clients,
+//! e.g. when we were moved into another channel.
+//!
+//! This can be disabled with [`ConnectOptions::restore_state`].
+//!
+//! [`Connection`]: ../struct.Connection.html
+//! [`ConnectOptions::restore_state`]: ../struct.ConnectOptions.html#method.restore_state
+use std::borrow::Cow;
+use std::fmt;
+
+use tsproto_packets::commands::{CommandItem, CommandParser};
+use tsproto_packets::packets::OutCommand;
+use tsproto_types::{ChannelId, ClientId};
+
+use crate::{data, new_command};
+
+/// The state of our own client which we want to keep.
+///
+/// Fields are `None` if they were not changed since connecting.
+#[derive(Clone, Default, Eq, PartialEq)]
+pub struct DesiredState {
+	pub channel: Option<ChannelId>,
+	/// The password for `channel`.
+	pub(crate) channel_password: Option<String>,
+	pub input_muted: Option<bool>,
+	pub output_muted: Option<bool>,
+	pub input_hardware: Option<bool>,
+	pub output_hardware: Option<bool>,
+	/// `Some(None)` if we came back from being away.
+	pub away: Option<Option<String>>,
+	pub name: Option<String>,
+}
+
+/// A part of the [`DesiredState`] could not be restored.
+///
+/// [`DesiredState`]: struct.DesiredState.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum RestoreFailure {
+	/// The channel does not exist anymore.
+	ChannelNotFound(ChannelId),
+}
+
+impl DesiredState {
+	/// If nothing has to be restored.
+	pub fn is_empty(&self) -> bool { *self == Self::default() }
+
+	/// Remember the changes of our client from a notification of the server.
+	///
+	/// Notifications do not contain channel passwords, so `password` is the
+	/// channel and password of the last move that we sent. It is used if we
+	/// were moved into that channel.
+	pub(crate) fn update(
+		&mut self, own_client: ClientId, content: &[u8], password: &mut Option<(ChannelId, String)>,
+	) {
+		let (name, args) = CommandParser::new(content);
+		if name != b"notifyclientmoved" && name != b"notifyclientupdated" {
+			return;
+		}
+		let parts = parse_parts(args);
+		let own_client = own_client.0.to_string();
+		let part = match (0..parts.len())
+			.find(|i| get_arg(&parts, *i, b"clid").as_deref() == Some(own_client.as_str()))
+		{
+			Some(r) => r,
+			None => return,
+		};
+
+		if name == b"notifyclientmoved" {
+			let channel = get_arg(&parts, part, b"ctid").and_then(|c| c.parse().ok());
+			if let Some(channel) = channel.map(ChannelId) {
+				self.channel = Some(channel);
+				self.channel_password =
+					password.take().filter(|(c, _)| *c == channel).map(|(_, p)| p);
+			}
+			return;
+		}
+		for (name, value) in &parts[part] {
+			match *name {
+				b"client_nickname" => self.name = Some(value.to_string()),
+				b"client_input_muted" => self.input_muted = Some(value == "1"),
+				b"client_output_muted" => self.output_muted = Some(value == "1"),
+				b"client_input_hardware" => self.input_hardware = Some(value == "1"),
+				b"client_output_hardware" => self.output_hardware = Some(value == "1"),
+				_ => {}
+			}
+		}
+		let away_message = get_arg(&parts, part, b"client_away_message");
+		if let Some(is_away) = get_arg(&parts, part, b"client_away") {
+			let message = away_message.map(Cow::into_owned).unwrap_or_default();
+			self.away = Some(if is_away == "1" { Some(message) } else { None });
+		} else if let (Some(message), Some(Some(_))) = (away_message, &self.away) {
+			self.away = Some(Some(message.into_owned()));
+		}
+	}
+
+	/// The channel and password of a move of our client that we send.
+	pub(crate) fn sent_password(
+		own_client: ClientId, content: &[u8],
+	) -> Option<(ChannelId, String)> {
+		let (name, args) = CommandParser::new(content);
+		if name != b"clientmove" {
+			return None;
+		}
+		// Only look at the first client of a move
+		let parts = parse_parts(args);
+		if get_arg(&parts, 0, b"clid")?.parse().ok().map(ClientId) != Some(own_client) {
+			return None;
+		}
+		let channel = get_arg(&parts, 0, b"cid")?.parse().ok().map(ChannelId)?;
+		Some((channel, get_arg(&parts, 0, b"cpw")?.into_owned())).filter(|(_, p)| !p.is_empty())
+	}
+
+	/// The commands to apply this state.
+	///
+	/// If the bookkeeping is available, only changed properties are sent and
+	/// missing channels are reported.
+	pub(crate) fn commands(
+		&self, own_client: ClientId, book: Option<&data::Connection>,
+	) -> (Vec<OutCommand>, Vec<RestoreFailure>) {
+		let mut cmds = Vec::new();
+		let mut failures = Vec::new();
+		let client = book.and_then(|b| b.own_client());
+
+		if let Some(channel) = self.channel {
+			if book.map(|b| !b.channels.contains_key(&channel)).unwrap_or_default() {
+				failures.push(RestoreFailure::ChannelNotFound(channel));
+			} else if client.map(|c| c.channel != channel).unwrap_or(true) {
+				let mut cmd = new_command("clientmove");
+				cmd.write_arg("clid", &own_client.0);
+				cmd.write_arg("cid", &channel.0);
+				if let Some(password) = &self.channel_password {
+					cmd.write_arg("cpw", &password.as_str());
+				}
+				cmds.push(cmd);
+			}
+		}
+
+		let mut cmd = new_command("clientupdate");
+		let mut changed = false;
+		if let Some(name) = &self.name {
+			if client.map(|c| c.name != *name).unwrap_or(true) {
+				cmd.write_arg("client_nickname", &name.as_str());
+				changed = true;
+			}
+		}
+		if let Some(muted) = self.input_muted {
+			if client.map(|c| c.input_muted != muted).unwrap_or(true) {
+				cmd.write_arg("client_input_muted", &(muted as u8));
+				changed = true;
+			}
+		}
+		if let Some(muted) = self.output_muted {
+			if client.map(|c| c.output_muted != muted).unwrap_or(true) {
+				cmd.write_arg("client_output_muted", &(muted as u8));
+				changed = true;
+			}
+		}
+		// The hardware flags are sent in clientinit, so they are only
+		// different if they were changed afterwards.
+		if let Some(enabled) = self.input_hardware {
+			cmd.write_arg("client_input_hardware", &(enabled as u8));
+			changed = true;
+		}
+		if let Some(enabled) = self.output_hardware {
+			cmd.write_arg("client_output_hardware", &(enabled as u8));
+			changed = true;
+		}
+		if let Some(away) = &self.away {
+			if client.map(|c| c.away_message != *away).unwrap_or(true) {
+				cmd.write_arg("client_away", &(away.is_some() as u8));
+				if let Some(message) = away {
+					cmd.write_arg("client_away_message", &message.as_str());
+				}
+				changed = true;
+			}
+		}
+		if changed {
+			cmds.push(cmd);
+		}
+		(cmds, failures)
+	}
+}
+
+impl fmt::Debug for DesiredState {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("DesiredState")
+			.field("channel", &self.channel)
+			.field("channel_password", &self.channel_password.as_ref().map(|_| "<redacted>"))
+			.field("input_muted", &self.input_muted)
+			.field("output_muted", &self.output_muted)
+			.field("input_hardware", &self.input_hardware)
+			.field("output_hardware", &self.output_hardware)
+			.field("away", &self.away)
+			.field("name", &self.name)
+			.finish()
+	}
+}
+
+/// The arguments of all parts of a command, which can be separated by `|`.
+fn parse_parts<'a>(
+	args: impl Iterator<Item = CommandItem<'a>>,
+) -> Vec<Vec<(&'a [u8], Cow<'a, str>)>> {
+	let mut parts = vec![Vec::new()];
+	for item in args {
+		match item {
+			CommandItem::Argument(arg) => {
+				if let Ok(value) = arg.value().get_str() {
+					parts.last_mut().unwrap().push((arg.name(), value));
+				}
+			}
+			CommandItem::NextCommand => parts.push(Vec::new()),
+		}
+	}
+	parts
+}
+
+/// The value of an argument in a part, arguments of the first part are shared
+/// by all parts.
+fn get_arg<'a>(
+	parts: &[Vec<(&[u8], Cow<'a, str>)>], part: usize, key: &[u8],
+) -> Option<Cow<'a, str>> {
+	parts[part].iter().chain(&parts[0]).find(|(n, _)| *n == key).map(|(_, v)| v.clone())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn remember_changes() {
+		let mut state = DesiredState::default();
+		let mut password = None;
+		// Moves of other clients are ignored
+		state.update(ClientId(2), b"notifyclientmoved ctid=5 reasonid=0 clid=3", &mut password);
+		assert!(state.is_empty());
+
+		password = DesiredState::sent_password(ClientId(2), b"clientmove clid=2 cid=5 cpw=secret");
+		let moved = b"notifyclientmoved ctid=5 reasonid=0 clid=3|clid=2";
+		state.update(ClientId(2), moved, &mut password);
+		let updated = b"notifyclientupdated clid=2 client_input_muted=1";
+		state.update(ClientId(2), updated, &mut password);
+		let away = b"notifyclientupdated clid=2 client_away=1 client_away_message=Busy";
+		state.update(ClientId(2), away, &mut password);
+		state.update(ClientId(2), b"notifytextmessage targetmode=3 msg=Hi", &mut password);
+		assert_eq!(state, DesiredState {
+			channel: Some(ChannelId(5)),
+			channel_password: Some("secret".into()),
+			input_muted: Some(true),
+			output_muted: None,
+			input_hardware: None,
+			output_hardware: None,
+			away: Some(Some("Busy".into())),
+			name: None,
+		});
+
+		state.update(ClientId(2), b"notifyclientupdated clid=2 client_away=0", &mut password);
+		assert_eq!(state.away, Some(None));
+	}
+
+	#[test]
+	fn channel_password() {
+		let mut state = DesiredState::default();
+		// Only passwords for moves of our own client are kept
+		assert_eq!(
+			DesiredState::sent_password(ClientId(2), b"clientmove clid=3 cid=5 cpw=pw"),
+			None
+		);
+		assert_eq!(DesiredState::sent_password(ClientId(2), b"clientmove clid=2 cid=5 cpw"), None);
+
+		// The password is used for the channel we were moved into
+		let mut password =
+			DesiredState::sent_password(ClientId(2), b"clientmove clid=2 cid=5 cpw=a\\sb");
+		assert_eq!(password, Some((ChannelId(5), "a b".into())));
+		state.update(ClientId(2), b"notifyclientmoved ctid=5 reasonid=0 clid=2", &mut password);
+		assert_eq!(state.channel_password.as_deref(), Some("a b"));
+		assert_eq!(password, None);
+		let (cmds, _) = state.commands(ClientId(2), None);
+		assert_eq!(cmds[0].0.content(), br"clientmove clid=2 cid=5 cpw=a\sb".as_ref());
+
+		// Another move, e.g. by an admin, forgets the password
+		password = DesiredState::sent_password(ClientId(2), b"clientmove clid=2 cid=5 cpw=pw");
+		state.update(ClientId(2), b"notifyclientmoved ctid=6 reasonid=1 clid=2", &mut password);
+		assert_eq!(state.channel, Some(ChannelId(6)));
+		assert_eq!(state.channel_password, None);
+
+		// The password is not printed
+		state.channel_password = Some("secret".into());
+		let debug = format!("{:?}", state);
+		assert!(!debug.contains("secret"), "{}", debug);
+	}
+
+	#[test]
+	fn commands_without_bookkeeping() {
+		let mut state = DesiredState::default();
+		assert_eq!(state.commands(ClientId(2), None).0.len(), 0);
+
+		let mut password = None;
+		state.update(ClientId(2), b"notifyclientmoved ctid=5 reasonid=0 clid=2", &mut password);
+		let updated = b"notifyclientupdated clid=2 client_nickname=Bot client_output_muted=1";
+		state.update(ClientId(2), updated, &mut password);
+		let (cmds, failures) = state.commands(ClientId(2), None);
+		assert!(failures.is_empty());
+		let cmds = cmds.iter().map(|c| c.0.content()).collect::<Vec<_>>();
+		assert_eq!(cmds, [
+			b"clientmove clid=2 cid=5".as_ref(),
+			b"clientupdate client_nickname=Bot client_output_muted=1".as_ref(),
+		]);
+	}
+}
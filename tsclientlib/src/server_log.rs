@@ -0,0 +1,150 @@
+//! Read and write the log of the virtual server.
+//!
+//! The log is requested with [`Connection::get_server_log`].
+//!
+//! [`Connection::get_server_log`]: ../struct.Connection.html#method.get_server_log
+use num_traits::ToPrimitive;
+use time::{Date, OffsetDateTime, PrimitiveDateTime, Time};
+use tsproto_packets::packets::OutCommand;
+use tsproto_types::LogLevel;
+
+use crate::{new_command, parse_list, ParseEntry};
+
+/// A part of the server log.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ServerLog {
+	pub entries: Vec<LogEntry>,
+	/// The position in the log file where the next older entries start.
+	///
+	/// Pass it as `begin_pos` to request the next page.
+	pub last_pos: u64,
+	/// The size of the log file in bytes.
+	pub file_size: u64,
+}
+
+/// A line of the server log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogEntry {
+	pub time: OffsetDateTime,
+	/// `None` for levels which `LogLevel` does not contain, e.g. `CRITICAL` or
+	/// `DEVELOP`.
+	pub level: Option<LogLevel>,
+	/// The part of the server which wrote the entry, e.g. `VirtualServer`.
+	pub channel: String,
+	pub message: String,
+}
+
+pub(crate) fn view(lines: u32, reverse: bool, begin_pos: Option<u64>) -> OutCommand {
+	let mut cmd = new_command("logview");
+	cmd.write_arg("lines", &lines);
+	cmd.write_arg("reverse", &(reverse as u8));
+	cmd.write_arg("instance", &0);
+	if let Some(pos) = begin_pos {
+		cmd.write_arg("begin_pos", &pos);
+	}
+	cmd
+}
+
+pub(crate) fn add(level: LogLevel, message: &str) -> OutCommand {
+	let mut cmd = new_command("logadd");
+	cmd.write_arg("loglevel", &level.to_u32().unwrap_or_default());
+	cmd.write_arg("logmsg", &message);
+	cmd
+}
+
+/// Parse the entries and positions of a `notifyserverlog`.
+pub(crate) fn parse(content: &[u8]) -> ServerLog {
+	let mut last_pos = 0;
+	let mut file_size = 0;
+	let entries = parse_list(content, |args| {
+		let mut entry = None;
+		for (name, value) in args {
+			match name {
+				b"l" => entry = parse_line(&value),
+				b"last_pos" => last_pos = value.parse().unwrap_or_default(),
+				b"file_size" => file_size = value.parse().unwrap_or_default(),
+				_ => {}
+			}
+		}
+		entry
+	});
+	ServerLog { entries, last_pos, file_size }
+}
+
+impl ParseEntry for ServerLog {
+	fn parse(content: &[u8]) -> Vec<Self> { vec![parse(content)] }
+}
+
+/// Parse a line like
+/// `2020-05-01 12:30:00.123456|INFO    |VirtualServer |  1| message`.
+fn parse_line(line: &str) -> Option<LogEntry> {
+	let mut parts = line.splitn(5, '|');
+	let time = parse_time(parts.next()?.trim())?;
+	let level = match parts.next()?.trim() {
+		"ERROR" => Some(LogLevel::Error),
+		"WARNING" => Some(LogLevel::Warning),
+		"DEBUG" => Some(LogLevel::Debug),
+		"INFO" => Some(LogLevel::Info),
+		_ => None,
+	};
+	let channel = parts.next()?.trim().to_string();
+	// The id of the virtual server
+	parts.next()?;
+	let message = parts.next()?.trim_start().to_string();
+	Some(LogEntry { time, level, channel, message })
+}
+
+/// Parse a time in UTC like `2020-05-01 12:30:00.123456`.
+fn parse_time(s: &str) -> Option<OffsetDateTime> {
+	let mut parts = s.splitn(2, ' ');
+	let mut date = parts.next()?.splitn(3, '-').map(str::parse::<u32>);
+	let mut time = parts.next()?.splitn(3, ':');
+	let date = Date::try_from_ymd(
+		date.next()?.ok()? as i32,
+		date.next()?.ok()? as u8,
+		date.next()?.ok()? as u8,
+	)
+	.ok()?;
+	let hour = time.next()?.parse().ok()?;
+	let minute = time.next()?.parse().ok()?;
+	let mut seconds = time.next()?.splitn(2, '.');
+	let second = seconds.next()?.parse().ok()?;
+	let micro = seconds.next().map(|m| m.parse().ok()).unwrap_or(Some(0))?;
+	let time = Time::try_from_hms_micro(hour, minute, second, micro).ok()?;
+	Some(PrimitiveDateTime::new(date, time).assume_utc())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn parse_log() {
+		let log = parse(
+			br#"notifyserverlog last_pos=1024 file_size=4096 l=2020-05-01\s12:30:00.123456\pINFO\s\s\s\s\pVirtualServer\s\p\s\s1\pclient\sconnected\s\p\sid:3|l=broken\sline|l=2020-05-01\s12:30:01\pCRITICAL\pServerLibPriv\p\p\sstopped"#,
+		);
+		assert_eq!(log.last_pos, 1024);
+		assert_eq!(log.file_size, 4096);
+		assert_eq!(log.entries.len(), 2);
+		let entry = &log.entries[0];
+		assert_eq!(entry.time.year(), 2020);
+		assert_eq!(entry.time.microsecond(), 123456);
+		assert_eq!(entry.level, Some(LogLevel::Info));
+		assert_eq!(entry.channel, "VirtualServer");
+		assert_eq!(entry.message, "client connected | id:3");
+		assert_eq!(log.entries[1].level, None);
+		assert_eq!(log.entries[1].message, "stopped");
+	}
+
+	#[test]
+	fn commands() {
+		assert_eq!(
+			view(50, true, None).0.content(),
+			b"logview lines=50 reverse=1 instance=0".as_ref()
+		);
+		assert_eq!(
+			view(50, false, Some(1024)).0.content(),
+			b"logview lines=50 reverse=0 instance=0 begin_pos=1024".as_ref()
+		);
+	}
+}
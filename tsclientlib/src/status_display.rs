@@ -0,0 +1,455 @@
+//! Show a status, e.g. the currently playing song, in the description or the
+//! avatar of the own client.
+//!
+//! A [`StatusDisplay`] regularly asks a provider for the current status and
+//! applies it to the server. It only sends changed values, waits a minimum
+//! time between updates, backs off when updates fail and pauses while the
+//! connection is not connected.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use futures::prelude::*;
+//! # use tsclientlib::status_display::{Status, StatusDisplay};
+//! # async fn f(handle: tsclientlib::sync::SyncConnectionHandle) {
+//! let mut queue_len = 0;
+//! let display = StatusDisplay::new(move || {
+//!     queue_len += 1;
+//!     Status::new(format!("{} songs in the queue", queue_len))
+//! });
+//! tokio::spawn(display.run(handle).for_each(|event| {
+//!     println!("Status display: {:?}", event);
+//!     future::ready(())
+//! }));
+//! # }
+//! ```
+//!
+//! [`StatusDisplay`]: struct.StatusDisplay.html
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use tokio::io::AsyncWriteExt;
+use tsproto_packets::packets::OutCommand;
+use tsproto_types::ChannelId;
+
+use crate::sync::SyncConnectionHandle;
+use crate::{new_command, Error, Result};
+
+/// Wait at most this long after failed updates.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// The status that should be displayed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Status {
+	pub description: String,
+	/// The avatar image, `None` leaves the avatar unchanged.
+	pub avatar: Option<Avatar>,
+}
+
+/// An avatar image.
+#[derive(Clone)]
+pub struct Avatar {
+	/// The md5 hash of the image, which is used by other clients to cache
+	/// avatars.
+	hash: String,
+	data: Arc<[u8]>,
+}
+
+/// A way to display the status.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mechanism {
+	Description,
+	Avatar,
+}
+
+/// An update which should be applied to the server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Update {
+	Description(String),
+	Avatar(Avatar),
+}
+
+#[derive(Debug)]
+pub enum StatusEvent {
+	/// The status was applied successfully.
+	Updated(Mechanism),
+	/// Applying the status failed, it is tried again after `retry_in`.
+	Failed { mechanism: Mechanism, error: Error, retry_in: Duration },
+	/// The connection is not connected, updates are paused.
+	Paused,
+	Resumed,
+}
+
+/// The state of one mechanism.
+struct Slot<T> {
+	/// The value which is shown on the server.
+	applied: Option<T>,
+	/// The latest value of the provider.
+	wanted: Option<T>,
+	min_interval: Duration,
+	/// The time of the last update, successful or not.
+	last_update: Option<Instant>,
+	/// The additional time to wait after failed updates.
+	backoff: Duration,
+}
+
+/// Displays a status in the description or the avatar.
+///
+/// For details, see the [module documentation](index.html).
+pub struct StatusDisplay {
+	provider: Box<dyn FnMut() -> Status + Send>,
+	interval: Duration,
+	last_poll: Option<Instant>,
+	paused: bool,
+	description: Slot<String>,
+	avatar: Slot<Avatar>,
+}
+
+impl Status {
+	pub fn new<S: Into<String>>(description: S) -> Self {
+		Self { description: description.into(), avatar: None }
+	}
+
+	#[inline]
+	pub fn avatar(mut self, avatar: Avatar) -> Self {
+		self.avatar = Some(avatar);
+		self
+	}
+}
+
+impl Avatar {
+	pub fn new<D: Into<Arc<[u8]>>>(data: D) -> Self {
+		let data = data.into();
+		Self { hash: format!("{:x}", md5::compute(&data)), data }
+	}
+
+	pub fn get_hash(&self) -> &str { &self.hash }
+	pub fn get_data(&self) -> &[u8] { &self.data }
+}
+
+impl PartialEq for Avatar {
+	fn eq(&self, other: &Self) -> bool { self.hash == other.hash }
+}
+
+impl Eq for Avatar {}
+
+impl fmt::Debug for Avatar {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Avatar({}, {} bytes)", self.hash, self.data.len())
+	}
+}
+
+impl Update {
+	pub fn mechanism(&self) -> Mechanism {
+		match self {
+			Update::Description(_) => Mechanism::Description,
+			Update::Avatar(_) => Mechanism::Avatar,
+		}
+	}
+}
+
+impl<T: Clone + PartialEq> Slot<T> {
+	fn new(min_interval: Duration) -> Self {
+		Self {
+			applied: None,
+			wanted: None,
+			min_interval,
+			last_update: None,
+			backoff: Duration::from_secs(0),
+		}
+	}
+
+	/// Returns the value that should be applied now.
+	fn due(&self, now: Instant) -> Option<T> {
+		let wanted = self.wanted.as_ref()?;
+		if self.applied.as_ref() == Some(wanted) {
+			return None;
+		}
+		let wait = self.min_interval + self.backoff;
+		if self.last_update.map(|last| now < last + wait).unwrap_or_default() {
+			return None;
+		}
+		Some(wanted.clone())
+	}
+
+	/// Returns the time until the next try if the update failed.
+	fn finish(&mut self, value: T, success: bool) -> Option<Duration> {
+		if success {
+			self.applied = Some(value);
+			self.backoff = Duration::from_secs(0);
+			None
+		} else {
+			// The server may know a different value now
+			self.applied = None;
+			self.backoff = if self.backoff == Duration::from_secs(0) {
+				self.min_interval
+			} else {
+				self.backoff * 2
+			}
+			.min(MAX_BACKOFF);
+			Some(self.min_interval + self.backoff)
+		}
+	}
+}
+
+impl StatusDisplay {
+	/// The provider is called regularly and returns the status that should be
+	/// displayed.
+	pub fn new<F: FnMut() -> Status + Send + 'static>(provider: F) -> Self {
+		Self {
+			provider: Box::new(provider),
+			interval: Duration::from_secs(1),
+			last_poll: None,
+			paused: false,
+			description: Slot::new(Duration::from_secs(5)),
+			avatar: Slot::new(Duration::from_secs(60)),
+		}
+	}
+
+	/// How often the provider is asked for the current status.
+	///
+	/// Changes between updates are coalesced, only the latest status is
+	/// applied.
+	///
+	/// # Default
+	/// 1 second
+	#[inline]
+	pub fn interval(mut self, interval: Duration) -> Self {
+		self.interval = interval;
+		self
+	}
+
+	/// The minimum time between two updates of the description.
+	///
+	/// # Default
+	/// 5 seconds
+	#[inline]
+	pub fn description_interval(mut self, interval: Duration) -> Self {
+		self.description.min_interval = interval;
+		self
+	}
+
+	/// The minimum time between two avatar uploads.
+	///
+	/// # Default
+	/// 1 minute
+	#[inline]
+	pub fn avatar_interval(mut self, interval: Duration) -> Self {
+		self.avatar.min_interval = interval;
+		self
+	}
+
+	/// Stop or resume updating the status.
+	///
+	/// [`run`](#method.run) pauses automatically while the connection is not
+	/// connected.
+	pub fn set_paused(&mut self, paused: bool) { self.paused = paused; }
+	pub fn is_paused(&self) -> bool { self.paused }
+
+	/// Get the next update which should be applied.
+	///
+	/// The result has to be reported with [`finished`] afterwards.
+	///
+	/// [`finished`]: #method.finished
+	pub fn next_update(&mut self, now: Instant) -> Option<Update> {
+		if self.paused {
+			return None;
+		}
+		if self.last_poll.map(|last| now >= last + self.interval).unwrap_or(true) {
+			self.last_poll = Some(now);
+			let status = (self.provider)();
+			self.description.wanted = Some(status.description);
+			if status.avatar.is_some() {
+				self.avatar.wanted = status.avatar;
+			}
+		}
+
+		if let Some(d) = self.description.due(now) {
+			self.description.last_update = Some(now);
+			Some(Update::Description(d))
+		} else if let Some(a) = self.avatar.due(now) {
+			self.avatar.last_update = Some(now);
+			Some(Update::Avatar(a))
+		} else {
+			None
+		}
+	}
+
+	/// Report the result of an update.
+	pub fn finished(&mut self, update: Update, result: Result<()>) -> StatusEvent {
+		let mechanism = update.mechanism();
+		let success = result.is_ok();
+		let retry_in = match update {
+			Update::Description(d) => self.description.finish(d, success),
+			Update::Avatar(a) => self.avatar.finish(a, success),
+		};
+		match (result, retry_in) {
+			(Err(error), Some(retry_in)) => StatusEvent::Failed { mechanism, error, retry_in },
+			_ => StatusEvent::Updated(mechanism),
+		}
+	}
+
+	/// Apply the status to a connection until the connection is gone.
+	///
+	/// The returned stream has to be polled to apply updates.
+	pub fn run(self, handle: SyncConnectionHandle) -> impl Stream<Item = StatusEvent> {
+		let timer = tokio::time::interval(self.interval);
+		stream::unfold((self, handle, timer), |(mut display, mut handle, mut timer)| async move {
+			timer.tick().await;
+			let connected = handle.with_connection(|con| con.is_connected()).await.ok()?;
+			let mut events = Vec::new();
+			if connected == display.paused {
+				display.set_paused(!connected);
+				events.push(if connected { StatusEvent::Resumed } else { StatusEvent::Paused });
+			}
+			if let Some(update) = display.next_update(Instant::now()) {
+				let res = apply(&mut handle, &update).await;
+				events.push(display.finished(update, res));
+			}
+			Some((stream::iter(events), (display, handle, timer)))
+		})
+		.flatten()
+	}
+}
+
+async fn apply(handle: &mut SyncConnectionHandle, update: &Update) -> Result<()> {
+	match update {
+		Update::Description(description) => {
+			let description = description.clone();
+			handle
+				.send_with(move |con| {
+					let mut cmd = new_command("clientedit");
+					cmd.write_arg("clid", &con.get_state()?.own_client.0);
+					cmd.write_arg("client_description", &description);
+					con.send_command(cmd)
+				})
+				.await
+		}
+		Update::Avatar(avatar) => {
+			let size = avatar.data.len() as u64;
+			let mut upload =
+				handle.upload_file(ChannelId(0), "/avatar".into(), None, size, true, false).await?;
+			upload.stream.write_all(&avatar.data).await.map_err(Error::FileTransferIo)?;
+			upload.stream.shutdown().await.map_err(Error::FileTransferIo)?;
+
+			let hash = avatar.hash.clone();
+			handle
+				.send_with(move |con| {
+					let mut cmd = new_command("clientupdate");
+					cmd.write_arg("client_flag_avatar", &hash);
+					con.send_command(cmd)
+				})
+				.await
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::sync::Mutex;
+
+	use super::*;
+
+	fn secs(s: u64) -> Duration { Duration::from_secs(s) }
+
+	/// A display which shows the content of the returned status.
+	fn display() -> (StatusDisplay, Arc<Mutex<Status>>) {
+		let status = Arc::new(Mutex::new(Status::new("a")));
+		let status2 = status.clone();
+		let display = StatusDisplay::new(move || status2.lock().unwrap().clone())
+			.interval(secs(1))
+			.description_interval(secs(5))
+			.avatar_interval(secs(60));
+		(display, status)
+	}
+
+	fn description(s: &str) -> Option<Update> { Some(Update::Description(s.into())) }
+
+	#[test]
+	fn delta_suppression() {
+		let (mut display, status) = display();
+		let start = Instant::now();
+		let update = display.next_update(start).unwrap();
+		assert_eq!(Some(update.clone()), description("a"));
+		display.finished(update, Ok(()));
+
+		// Same status, nothing to do
+		assert_eq!(display.next_update(start + secs(10)), None);
+		assert_eq!(display.next_update(start + secs(20)), None);
+
+		// Changes are coalesced
+		*status.lock().unwrap() = Status::new("b");
+		assert_eq!(display.next_update(start + secs(21)), description("b"));
+		*status.lock().unwrap() = Status::new("a");
+		display.next_update(start + secs(22));
+		*status.lock().unwrap() = Status::new("c");
+		display.next_update(start + secs(23));
+		display.finished(Update::Description("b".into()), Ok(()));
+		assert_eq!(display.next_update(start + secs(26)), description("c"));
+	}
+
+	#[test]
+	fn minimum_intervals() {
+		let (mut display, status) = display();
+		let avatar = Avatar::new(vec![1, 2, 3]);
+		*status.lock().unwrap() = Status::new("a").avatar(avatar.clone());
+		let start = Instant::now();
+		let update = display.next_update(start).unwrap();
+		display.finished(update, Ok(()));
+		let update = display.next_update(start).unwrap();
+		assert_eq!(update, Update::Avatar(avatar));
+		display.finished(update, Ok(()));
+
+		*status.lock().unwrap() = Status::new("b").avatar(Avatar::new(vec![4]));
+		// The description waits 5 seconds
+		assert_eq!(display.next_update(start + secs(4)), None);
+		let update = display.next_update(start + secs(5)).unwrap();
+		assert_eq!(update, Update::Description("b".into()));
+		display.finished(update, Ok(()));
+		// The avatar waits a minute
+		assert_eq!(display.next_update(start + secs(59)), None);
+		let update = display.next_update(start + secs(60)).unwrap();
+		assert_eq!(update.mechanism(), Mechanism::Avatar);
+	}
+
+	#[test]
+	fn failure_backoff() {
+		let (mut display, _) = display();
+		let start = Instant::now();
+		let update = display.next_update(start).unwrap();
+		let event = display.finished(update, Err(Error::NotConnected));
+		assert!(matches!(
+			event,
+			StatusEvent::Failed { mechanism: Mechanism::Description, retry_in, .. }
+				if retry_in == secs(10)
+		));
+		assert_eq!(display.next_update(start + secs(9)), None);
+		let update = display.next_update(start + secs(10)).unwrap();
+		// The backoff doubles
+		let event = display.finished(update, Err(Error::NotConnected));
+		assert!(matches!(event, StatusEvent::Failed { retry_in, .. } if retry_in == secs(15)));
+		assert_eq!(display.next_update(start + secs(24)), None);
+		let update = display.next_update(start + secs(25)).unwrap();
+		display.finished(update, Ok(()));
+		assert_eq!(display.description.backoff, secs(0));
+	}
+
+	#[test]
+	fn pause_resume() {
+		let (mut display, status) = display();
+		let start = Instant::now();
+		display.set_paused(true);
+		assert_eq!(display.next_update(start), None);
+		display.set_paused(false);
+		let update = display.next_update(start + secs(1)).unwrap();
+		display.finished(update, Ok(()));
+
+		display.set_paused(true);
+		*status.lock().unwrap() = Status::new("b");
+		assert_eq!(display.next_update(start + secs(100)), None);
+		display.set_paused(false);
+		assert_eq!(display.next_update(start + secs(101)), description("b"));
+	}
+}
@@ -2,27 +2,76 @@
 //!
 //! It makes it easier to use a connection from multiple threads and use
 //! `async`/`await` syntax for the cost of a little bit performance.
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Duration;
 
+use bitflags::bitflags;
 use futures::prelude::*;
-use slog::{error, info};
-use tokio::sync::{mpsc, oneshot};
-use ts_bookkeeping::ChannelId;
+use slog::{error, info, warn};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time;
+use ts_bookkeeping::data::exts::M2BClientUpdateExt;
+use ts_bookkeeping::messages::OutMessageTrait;
+use ts_bookkeeping::{data, ChannelId, ChannelSpec, ClientDbId, ClientId, MessageTarget};
 #[cfg(feature = "audio")]
-use tsproto_packets::packets::InAudioBuf;
-#[cfg(feature = "unstable")]
+use tsproto_packets::packets::{Direction, InAudioBuf};
 use tsproto_packets::packets::OutCommand;
 
-use crate::{events, DisconnectOptions, Error, Result, StreamItem};
+use crate::{
+	bans, complaints, events, file_list, foreign_transfers, offline_messages, policy,
+	privilege_keys, restore, server_log, temp_passwords, BackgroundError, DisconnectOptions,
+	DisconnectReason, Error, OutCommandExt, PluginMessage, Result, SendingMutedReason, StreamItem,
+};
+
+/// The number of event lists which are buffered for a subscriber, older
+/// events are dropped for slow subscribers.
+const EVENT_BUFFER: usize = 64;
+/// The number of audio packets which are buffered for a subscriber.
+#[cfg(feature = "audio")]
+const AUDIO_BUFFER: usize = 256;
+
+bitflags! {
+	/// Categories of events, used to subscribe only to some events with
+	/// [`SyncConnectionHandle::subscribe_filtered`].
+	///
+	/// [`SyncConnectionHandle::subscribe_filtered`]: struct.SyncConnectionHandle.html#method.subscribe_filtered
+	pub struct EventFilter: u8 {
+		/// Text messages and pokes.
+		const CHAT = 0x01;
+		/// Clients which join, leave or change their properties.
+		const CLIENTS = 0x02;
+		/// Added, removed and changed channels.
+		const CHANNELS = 0x04;
+		/// Properties of the server and our connection.
+		const SERVER = 0x08;
+		/// Server and channel groups and the groups of clients.
+		const PERMISSIONS = 0x10;
+		/// Talk power, talk requests and priority speakers.
+		const TALK_STATUS = 0x20;
+	}
+}
+
+/// Subscribers of [`SyncConnectionHandle::subscribe_filtered`].
+///
+/// [`SyncConnectionHandle::subscribe_filtered`]: struct.SyncConnectionHandle.html#method.subscribe_filtered
+type FilteredSubscribers = Arc<Mutex<Vec<(EventFilter, mpsc::Sender<events::Event>)>>>;
 
 enum SyncConMessage {
 	RunFn(Box<dyn FnOnce(&mut SyncConnection) + Send>),
 	#[cfg(feature = "unstable")]
 	SendCommand(OutCommand, oneshot::Sender<Result<()>>),
 	WaitConnected(oneshot::Sender<Result<()>>),
+	WaitDisconnected(oneshot::Sender<Result<DisconnectReason>>),
+	SendMessage(MessageTarget, String, oneshot::Sender<Result<()>>),
+	ChangeName(String, oneshot::Sender<Result<()>>),
+	SetAway(Option<String>, oneshot::Sender<Result<()>>),
+	MoveToChannel(ChannelId, Option<String>, oneshot::Sender<Result<()>>),
 	Disconnect(DisconnectOptions, oneshot::Sender<Result<()>>),
 	DownloadFile {
 		channel_id: ChannelId,
@@ -42,15 +91,39 @@ enum SyncConMessage {
 	},
 }
 
+impl SyncConMessage {
+	/// If handling this message sends a command which was requested by the
+	/// user.
+	///
+	/// These messages are delayed when the connection is congested. Other
+	/// messages, e.g. for disconnecting, are handled immediately.
+	fn sends_command(&self) -> bool {
+		match self {
+			#[cfg(feature = "unstable")]
+			SyncConMessage::SendCommand(..) => true,
+			SyncConMessage::SendMessage(..)
+			| SyncConMessage::ChangeName(..)
+			| SyncConMessage::SetAway(..)
+			| SyncConMessage::MoveToChannel(..) => true,
+			_ => false,
+		}
+	}
+}
+
 /// This is a subset of [`StreamItem`].
 ///
 /// [`StreamItem`]: ../enum.StreamItem.html
+#[derive(Debug)]
 pub enum SyncStreamItem {
 	/// All the incoming events.
 	///
 	/// If a connection to the server was established this will contain an added
 	/// event of a server.
 	ConEvents(Vec<events::Event>),
+	/// The incoming events of a notification, classified by what happened.
+	///
+	/// These are sent after the corresponding [`ConEvents`](#variant.ConEvents).
+	TypedEvents(Vec<events::TypedEvent>),
 	/// Received an audio packet.
 	///
 	/// Audio packets can be handled by the [`AudioHandler`], which builds a
@@ -61,12 +134,28 @@ pub enum SyncStreamItem {
 	Audio(InAudioBuf),
 	/// The needed level.
 	IdentityLevelIncreasing(u8),
+	/// The identity reached a better level while increasing it.
+	IdentityLevelProgress { level: u8, counter: u64 },
 	/// This event may occur without an `IdentityLevelIncreasing` event before
 	/// if a new identity is created because no identity was supplied.
 	IdentityLevelIncreased,
 	/// The connection timed out or the server shut down. The connection will be
 	/// rebuilt automatically.
 	DisconnectedTemporarily,
+	/// Sending audio is muted, audio packets are dropped from now on.
+	SendingMuted(SendingMutedReason),
+	/// Sending audio is possible again.
+	SendingUnmuted,
+	/// Received a message from a plugin of another client.
+	PluginMessage(PluginMessage),
+	/// A file transfer of another client started.
+	ForeignFileTransfer(foreign_transfers::ForeignFileTransfer),
+	/// An error happened in the background.
+	BackgroundError(BackgroundError),
+	/// An action was denied or confirmed by the action policy.
+	PolicyDecision(policy::AuditEntry),
+	/// A part of our client state could not be restored after reconnecting.
+	RestoreFailed(restore::RestoreFailure),
 }
 
 /// A handle for a [`SyncConnection`] which can be sent across threads.
@@ -78,16 +167,46 @@ pub enum SyncStreamItem {
 #[derive(Clone)]
 pub struct SyncConnectionHandle {
 	send: mpsc::Sender<SyncConMessage>,
+	/// The default timeout for requests.
+	timeout: Option<Duration>,
+	events: broadcast::Sender<Arc<Vec<events::Event>>>,
+	#[cfg(feature = "audio")]
+	audio: broadcast::Sender<Arc<InAudioBuf>>,
+	filtered: FilteredSubscribers,
+	snapshot: watch::Receiver<Option<Arc<data::Connection>>>,
 }
 
 pub struct SyncConnection {
 	con: super::Connection,
 	recv: mpsc::Receiver<SyncConMessage>,
 	send: mpsc::Sender<SyncConMessage>,
+	events: broadcast::Sender<Arc<Vec<events::Event>>>,
+	#[cfg(feature = "audio")]
+	audio: broadcast::Sender<Arc<InAudioBuf>>,
+	filtered: FilteredSubscribers,
+	/// The last published copy of the bookkeeping.
+	snapshot: watch::Sender<Option<Arc<data::Connection>>>,
+	/// Kept to create handles.
+	snapshot_recv: watch::Receiver<Option<Arc<data::Connection>>>,
+	/// If a snapshot is published after each batch of events.
+	///
+	/// Copying the bookkeeping is cheap but not free, so this is only enabled
+	/// when a handle watches the snapshots.
+	publish_snapshots: bool,
 
 	commands: HashMap<super::MessageHandle, oneshot::Sender<Result<()>>>,
+	/// Requested channel descriptions.
+	descriptions: HashMap<super::MessageHandle, (ChannelId, oneshot::Sender<Result<String>>)>,
+	/// Requested connection statistics of the server.
+	connection_infos:
+		HashMap<super::MessageHandle, oneshot::Sender<Result<data::ConnectionServerData>>>,
 	connects: Vec<oneshot::Sender<Result<()>>>,
 	disconnects: Vec<oneshot::Sender<Result<()>>>,
+	/// Waiting until the connection ends.
+	ends: Vec<oneshot::Sender<Result<DisconnectReason>>>,
+	/// Messages which send commands, delayed until the send queue of the
+	/// connection is not full.
+	throttled: VecDeque<SyncConMessage>,
 	downloads:
 		HashMap<super::FileTransferHandle, oneshot::Sender<Result<super::FileDownloadResult>>>,
 	uploads: HashMap<super::FileTransferHandle, oneshot::Sender<Result<super::FileUploadResult>>>,
@@ -96,14 +215,26 @@ pub struct SyncConnection {
 impl From<super::Connection> for SyncConnection {
 	fn from(con: super::Connection) -> Self {
 		let (send, recv) = mpsc::channel(1);
+		let (snapshot, snapshot_recv) = watch::channel(None);
 		Self {
 			con,
 			recv,
 			send,
+			events: broadcast::channel(EVENT_BUFFER).0,
+			#[cfg(feature = "audio")]
+			audio: broadcast::channel(AUDIO_BUFFER).0,
+			filtered: Default::default(),
+			snapshot,
+			snapshot_recv,
+			publish_snapshots: false,
 
 			commands: Default::default(),
+			descriptions: Default::default(),
+			connection_infos: Default::default(),
 			connects: Default::default(),
 			disconnects: Default::default(),
+			ends: Default::default(),
+			throttled: Default::default(),
 			downloads: Default::default(),
 			uploads: Default::default(),
 		}
@@ -124,85 +255,21 @@ impl DerefMut for SyncConnection {
 impl Stream for SyncConnection {
 	type Item = Result<SyncStreamItem>;
 	fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+		self.remove_canceled(ctx);
+		while !self.throttled.is_empty() && self.con.poll_send_ready(ctx).is_ready() {
+			let msg = self.throttled.pop_front().unwrap();
+			self.handle_message(msg);
+		}
 		loop {
 			if let Poll::Ready(msg) = self.recv.poll_next_unpin(ctx) {
 				if let Some(msg) = msg {
-					match msg {
-						SyncConMessage::RunFn(f) => f(&mut *self),
-						#[cfg(feature = "unstable")]
-						SyncConMessage::SendCommand(arg, send) => {
-							let handle = match self.con.send_command(arg) {
-								Ok(r) => r,
-								Err(e) => {
-									let _ = send.send(Err(e));
-									continue;
-								}
-							};
-							self.commands.insert(handle, send);
-						}
-						SyncConMessage::WaitConnected(send) => {
-							if self.con.get_state().is_ok() {
-								let _ = send.send(Ok(()));
-							} else {
-								self.connects.push(send);
-							}
-						}
-						SyncConMessage::Disconnect(arg, send) => {
-							match self.con.disconnect(arg) {
-								Ok(r) => r,
-								Err(e) => {
-									let _ = send.send(Err(e));
-									continue;
-								}
-							}
-							self.disconnects.push(send);
-						}
-						SyncConMessage::DownloadFile {
-							channel_id,
-							path,
-							channel_password,
-							seek_position,
-							send,
-						} => {
-							let handle = match self.con.download_file(
-								channel_id,
-								&path,
-								channel_password.as_deref(),
-								seek_position,
-							) {
-								Ok(r) => r,
-								Err(e) => {
-									let _ = send.send(Err(e));
-									continue;
-								}
-							};
-							self.downloads.insert(handle, send);
-						}
-						SyncConMessage::UploadFile {
-							channel_id,
-							path,
-							channel_password,
-							size,
-							overwrite,
-							resume,
-							send,
-						} => {
-							let handle = match self.con.upload_file(
-								channel_id,
-								&path,
-								channel_password.as_deref(),
-								size,
-								overwrite,
-								resume,
-							) {
-								Ok(r) => r,
-								Err(e) => {
-									let _ = send.send(Err(e));
-									continue;
-								}
-							};
-							self.uploads.insert(handle, send);
-						}
+					let congested =
+						!self.throttled.is_empty() || self.con.poll_send_ready(ctx).is_pending();
+					if msg.sends_command() && congested {
+						// Wait until the send queue has space again
+						self.throttled.push_back(msg);
+					} else {
+						self.handle_message(msg);
 					}
 					continue;
 				} else {
@@ -220,22 +287,64 @@ impl Stream for SyncConnection {
 							self.connects.drain(..).for_each(|send| {
 								let _ = send.send(Ok(()));
 							});
+							if self.events.receiver_count() > 0 {
+								// Fails only if all subscribers are gone
+								let _ = self.events.send(Arc::new(i.clone()));
+							}
+							send_filtered(&self.filtered, &i);
+							if self.publish_snapshots {
+								self.publish_snapshot();
+							}
 							SyncStreamItem::ConEvents(i)
 						}
+						StreamItem::TypedEvents(i) => SyncStreamItem::TypedEvents(i),
 						#[cfg(feature = "audio")]
-						StreamItem::Audio(i) => SyncStreamItem::Audio(i),
+						StreamItem::Audio(i) => {
+							if self.audio.receiver_count() > 0 {
+								// The buffer is not clonable, so copy the packet
+								if let Ok(packet) =
+									InAudioBuf::try_new(Direction::S2C, i.raw_data().to_vec())
+								{
+									let _ = self.audio.send(Arc::new(packet));
+								}
+							}
+							SyncStreamItem::Audio(i)
+						}
 						StreamItem::IdentityLevelIncreasing(i) => {
 							SyncStreamItem::IdentityLevelIncreasing(i)
 						}
+						StreamItem::IdentityLevelProgress { level, counter } => {
+							SyncStreamItem::IdentityLevelProgress { level, counter }
+						}
 						StreamItem::IdentityLevelIncreased => {
 							SyncStreamItem::IdentityLevelIncreased
 						}
 						StreamItem::DisconnectedTemporarily => {
+							self.clear_snapshot();
 							SyncStreamItem::DisconnectedTemporarily
 						}
+						StreamItem::SendingMuted(r) => SyncStreamItem::SendingMuted(r),
+						StreamItem::SendingUnmuted => SyncStreamItem::SendingUnmuted,
+						StreamItem::PluginMessage(m) => SyncStreamItem::PluginMessage(m),
+						StreamItem::ForeignFileTransfer(t) => {
+							SyncStreamItem::ForeignFileTransfer(t)
+						}
+						StreamItem::BackgroundError(e) => SyncStreamItem::BackgroundError(e),
+						StreamItem::PolicyDecision(e) => SyncStreamItem::PolicyDecision(e),
+						StreamItem::RestoreFailed(f) => SyncStreamItem::RestoreFailed(f),
 						StreamItem::MessageResult(handle, res) => {
 							if let Some(send) = self.commands.remove(&handle) {
 								let _ = send.send(res.map_err(|e| e.into()));
+							} else if let Some((channel, send)) = self.descriptions.remove(&handle)
+							{
+								let _ = send.send(res.map_err(|e| e.into()).and_then(|()| {
+									Ok(self.cached_description(channel)?.unwrap_or_default())
+								}));
+							} else if let Some(send) = self.connection_infos.remove(&handle) {
+								let _ = send.send(res.map_err(|e| e.into()).and_then(|()| {
+									let data = self.get_state()?.server.connection_data.clone();
+									Ok(data.ok_or(ts_bookkeeping::Error::None)?)
+								}));
 							} else {
 								info!(self.con.logger, "Got untracked message result");
 							}
@@ -268,11 +377,21 @@ impl Stream for SyncConnection {
 							continue;
 						}
 					})),
-					Some(Err(e)) => Some(Err(e)),
+					Some(Err(e)) => {
+						// Errors are only returned while connecting, afterwards
+						// the connection cannot continue.
+						if !self.con.is_connected() {
+							self.clear_snapshot();
+							self.finish_ends();
+						}
+						Some(Err(e))
+					}
 					None => {
 						self.disconnects.drain(..).for_each(|send| {
 							let _ = send.send(Ok(()));
 						});
+						self.clear_snapshot();
+						self.finish_ends();
 						None
 					}
 				})
@@ -284,14 +403,262 @@ impl Stream for SyncConnection {
 }
 
 impl SyncConnection {
+	/// Drive the connection on the tokio runtime.
+	///
+	/// All interaction with the connection happens through the returned
+	/// handle, e.g. through [`subscribe_events`]. Errors of single items are
+	/// logged. The join handle resolves when the connection ends, e.g. after
+	/// [`disconnect`], with the last error if the connection ended because of
+	/// an error.
+	///
+	/// # Example
+	///
+	/// ```no_run
+	/// # use tsclientlib::{Connection, ConnectOptions};
+	/// # use tsclientlib::sync::SyncConnection;
+	///
+	/// # #[tokio::main]
+	/// # async fn main() {
+	/// let con: SyncConnection = Connection::new(ConnectOptions::new("localhost")).unwrap().into();
+	/// let (mut handle, running) = con.spawn();
+	/// handle.wait_until_connected().await.unwrap();
+	/// // Do something with the connection, then wait until it is closed
+	/// running.await.unwrap().unwrap();
+	/// # }
+	/// ```
+	///
+	/// [`subscribe_events`]: struct.SyncConnectionHandle.html#method.subscribe_events
+	/// [`disconnect`]: struct.SyncConnectionHandle.html#method.disconnect
+	pub fn spawn(mut self) -> (SyncConnectionHandle, JoinHandle<Result<()>>) {
+		let handle = self.get_handle();
+		let logger = self.con.logger.clone();
+		let running = tokio::spawn(async move {
+			let mut last_error = None;
+			while let Some(item) = self.next().await {
+				match item {
+					Ok(_) => last_error = None,
+					Err(e) => {
+						warn!(logger, "Error in connection"; "error" => %e);
+						last_error = Some(e);
+					}
+				}
+			}
+			last_error.map(Err).unwrap_or(Ok(()))
+		});
+		(handle, running)
+	}
+
 	/// Get a handle to the connection that can be sent across threads.
 	#[inline]
 	pub fn get_handle(&self) -> SyncConnectionHandle {
-		SyncConnectionHandle { send: self.send.clone() }
+		SyncConnectionHandle {
+			send: self.send.clone(),
+			timeout: None,
+			events: self.events.clone(),
+			#[cfg(feature = "audio")]
+			audio: self.audio.clone(),
+			filtered: self.filtered.clone(),
+			snapshot: self.snapshot_recv.clone(),
+		}
+	}
+
+	/// Send a copy of the current bookkeeping to the handles.
+	fn publish_snapshot(&mut self) {
+		let snapshot = self.con.get_state().ok().map(|b| Arc::new(b.snapshot()));
+		// Fails only if all receivers are gone, but we keep one
+		let _ = self.snapshot.broadcast(snapshot);
+	}
+
+	/// Publish that there is no bookkeeping while not connected.
+	fn clear_snapshot(&mut self) {
+		if self.snapshot_recv.borrow().is_some() {
+			let _ = self.snapshot.broadcast(None);
+		}
+	}
+}
+
+impl SyncConnection {
+	/// Handle a message from a handle.
+	fn handle_message(&mut self, msg: SyncConMessage) {
+		match msg {
+			SyncConMessage::RunFn(f) => f(self),
+			#[cfg(feature = "unstable")]
+			SyncConMessage::SendCommand(arg, send) => {
+				let handle = match self.con.send_raw_command(arg) {
+					Ok(r) => r,
+					Err(e) => {
+						let _ = send.send(Err(e));
+						return;
+					}
+				};
+				self.commands.insert(handle, send);
+			}
+			SyncConMessage::SendMessage(target, message, send) => {
+				let res =
+					self.con.get_state().map(|b| b.send_message(target, &message));
+				let res = res.and_then(|cmd| self.con.send_command(cmd));
+				self.track_command(res, send);
+			}
+			SyncConMessage::ChangeName(name, send) => {
+				let res =
+					self.con.get_state().map(|b| b.client_update().set_name(&name).to_packet());
+				let res = res.and_then(|cmd| self.con.send_command(cmd));
+				self.track_command(res, send);
+			}
+			SyncConMessage::SetAway(message, send) => {
+				let res = self
+					.con
+					.get_state()
+					.map(|b| b.client_update().set_away(message.as_deref()).to_packet());
+				let res = res.and_then(|cmd| self.con.send_command(cmd));
+				self.track_command(res, send);
+			}
+			SyncConMessage::MoveToChannel(channel, password, send) => {
+				let res = self
+					.con
+					.join_channel(ChannelSpec::Id(channel), password.as_deref())
+					.map(|(_, handle)| handle);
+				self.track_command(res, send);
+			}
+			SyncConMessage::WaitConnected(send) => {
+				if self.con.is_connected() {
+					let _ = send.send(Ok(()));
+				} else {
+					self.connects.push(send);
+				}
+			}
+			SyncConMessage::WaitDisconnected(send) => self.ends.push(send),
+			SyncConMessage::Disconnect(arg, send) => {
+				match self.con.disconnect(arg) {
+					Ok(r) => r,
+					Err(e) => {
+						let _ = send.send(Err(e));
+						return;
+					}
+				}
+				self.disconnects.push(send);
+			}
+			SyncConMessage::DownloadFile {
+				channel_id,
+				path,
+				channel_password,
+				seek_position,
+				send,
+			} => {
+				let handle = match self.con.download_file(
+					channel_id,
+					&path,
+					channel_password.as_deref(),
+					seek_position,
+				) {
+					Ok(r) => r,
+					Err(e) => {
+						let _ = send.send(Err(e));
+						return;
+					}
+				};
+				self.downloads.insert(handle, send);
+			}
+			SyncConMessage::UploadFile {
+				channel_id,
+				path,
+				channel_password,
+				size,
+				overwrite,
+				resume,
+				send,
+			} => {
+				let handle = match self.con.upload_file(
+					channel_id,
+					&path,
+					channel_password.as_deref(),
+					size,
+					overwrite,
+					resume,
+				) {
+					Ok(r) => r,
+					Err(e) => {
+						let _ = send.send(Err(e));
+						return;
+					}
+				};
+				self.uploads.insert(handle, send);
+			}
+		}
+	}
+
+	/// Forget requests whose future was dropped.
+	///
+	/// This also registers `ctx` to get woken up when a future is dropped.
+	fn remove_canceled(&mut self, ctx: &mut Context) {
+		fn retain<T>(senders: &mut Vec<oneshot::Sender<T>>, ctx: &mut Context) {
+			*senders = mem::take(senders)
+				.into_iter()
+				.filter_map(|mut s| if s.poll_closed(ctx).is_pending() { Some(s) } else { None })
+				.collect();
+		}
+
+		self.commands.retain(|_, s| s.poll_closed(ctx).is_pending());
+		self.descriptions.retain(|_, (_, s)| s.poll_closed(ctx).is_pending());
+		self.connection_infos.retain(|_, s| s.poll_closed(ctx).is_pending());
+		self.downloads.retain(|_, s| s.poll_closed(ctx).is_pending());
+		self.uploads.retain(|_, s| s.poll_closed(ctx).is_pending());
+		retain(&mut self.connects, ctx);
+		retain(&mut self.disconnects, ctx);
+		retain(&mut self.ends, ctx);
+	}
+
+	/// Tell everyone who waits for the end of the connection why it ended.
+	fn finish_ends(&mut self) {
+		let reason =
+			self.con.disconnect_reason().cloned().unwrap_or(DisconnectReason::ConnectionLost);
+		self.ends.drain(..).for_each(|send| {
+			let _ = send.send(Ok(reason.clone()));
+		});
+	}
+
+	fn get_channel(&self, channel: ChannelId) -> Result<&data::Channel> {
+		self.con.get_state()?.channels.get(&channel).ok_or_else(|| {
+			ts_bookkeeping::Error::NotFound("Channel", channel.to_string()).into()
+		})
+	}
+
+	/// The description of a channel, if it is stored in the bookkeeping.
+	fn cached_description(&self, channel: ChannelId) -> Result<Option<String>> {
+		Ok(self.get_channel(channel)?.description().map(str::to_string))
+	}
+
+	/// Resolve `send` when the answer for the command is received.
+	fn track_command(
+		&mut self, handle: Result<super::MessageHandle>, send: oneshot::Sender<Result<()>>,
+	) {
+		match handle {
+			Ok(handle) => {
+				self.commands.insert(handle, send);
+			}
+			Err(e) => {
+				let _ = send.send(Err(e));
+			}
+		}
 	}
 }
 
 impl SyncConnectionHandle {
+	/// A handle which does not belong to a connection.
+	#[cfg(test)]
+	pub(crate) fn detached() -> Self {
+		let (send, _) = mpsc::channel(1);
+		Self {
+			send,
+			timeout: None,
+			events: broadcast::channel(1).0,
+			#[cfg(feature = "audio")]
+			audio: broadcast::channel(1).0,
+			filtered: Default::default(),
+			snapshot: watch::channel(None).1,
+		}
+	}
+
 	/// Run a function on the connection.
 	pub async fn with_connection<
 		T: Send + 'static,
@@ -300,35 +667,451 @@ impl SyncConnectionHandle {
 		&mut self, f: F,
 	) -> Result<T> {
 		let (send, recv) = oneshot::channel();
-		self.send
-			.send(SyncConMessage::RunFn(Box::new(move |con| {
-				let _ = send.send(f(con));
-			})))
-			.await
-			.map_err(|_| Error::ConnectionGone)?;
-		Ok(recv.await.map_err(|_| Error::ConnectionGone)?)
+		let msg = SyncConMessage::RunFn(Box::new(move |con| {
+			let _ = send.send(Ok(f(con)));
+		}));
+		self.request(msg, recv, self.timeout).await
+	}
+
+	/// Send a command from the connection and wait for the answer.
+	pub(crate) async fn send_with<
+		F: FnOnce(&mut super::Connection) -> Result<super::MessageHandle> + Send + 'static,
+	>(
+		&mut self, f: F,
+	) -> Result<()> {
+		let (send, recv) = oneshot::channel();
+		let msg = SyncConMessage::RunFn(Box::new(move |con| {
+			let handle = f(&mut con.con);
+			con.track_command(handle, send);
+		}));
+		self.request(msg, recv, self.timeout).await
+	}
+
+	/// Send a list command and wait until the whole list is received.
+	async fn list_with<
+		T: Send + 'static,
+		F: FnOnce(&mut super::Connection) -> future::BoxFuture<'static, Result<T>> + Send + 'static,
+	>(
+		&mut self, f: F,
+	) -> Result<T> {
+		let list = self.with_connection(move |con| f(&mut con.con)).await?;
+		match self.timeout {
+			Some(timeout) => time::timeout(timeout, list).await.map_err(|_| Error::Timeout)?,
+			None => list.await,
+		}
+	}
+
+	/// Get the description of a channel.
+	///
+	/// Descriptions are not sent with the channel list. If the description is
+	/// not stored in the bookkeeping yet, it is requested from the server.
+	///
+	/// Fails with `Error::Timeout` if the server does not answer in time.
+	pub async fn get_channel_description(&mut self, channel: ChannelId) -> Result<String> {
+		let (send, recv) = oneshot::channel();
+		let msg = SyncConMessage::RunFn(Box::new(move |con| {
+			let cmd = match con.cached_description(channel) {
+				Ok(Some(description)) => {
+					let _ = send.send(Ok(description));
+					return;
+				}
+				Ok(None) => con.get_channel(channel).map(|c| c.request_description()),
+				Err(e) => Err(e),
+			};
+			match cmd.and_then(|cmd| cmd.send(&mut con.con)) {
+				Ok(handle) => {
+					con.descriptions.insert(handle, (channel, send));
+				}
+				Err(e) => {
+					let _ = send.send(Err(e));
+				}
+			}
+		}));
+		self.request(msg, recv, self.timeout).await
+	}
+
+	/// Get the statistics of the connection which the server measured, see
+	/// [`Connection::server_connection_info`].
+	///
+	/// Fails with `Error::Timeout` if the server does not answer in time.
+	///
+	/// [`Connection::server_connection_info`]: ../struct.Connection.html#method.server_connection_info
+	pub async fn server_connection_info(&mut self) -> Result<data::ConnectionServerData> {
+		let (send, recv) = oneshot::channel();
+		let msg =
+			SyncConMessage::RunFn(Box::new(move |con| match con.con.server_connection_info() {
+				Ok(handle) => {
+					con.connection_infos.insert(handle, send);
+				}
+				Err(e) => {
+					let _ = send.send(Err(e));
+				}
+			}));
+		self.request(msg, recv, self.timeout).await
+	}
+
+	/// Get the list of bans.
+	///
+	/// Fails with `Error::Timeout` if the list is not finished in time.
+	pub async fn list_bans(&mut self) -> Result<Vec<bans::Ban>> {
+		self.list_with(|con| con.list_bans()).await
+	}
+
+	/// Get the files in the directory `path` of a channel.
+	///
+	/// Fails with `Error::Timeout` if the list is not finished in time.
+	pub async fn list_files(
+		&mut self, channel: ChannelId, channel_password: Option<String>, path: String,
+	) -> Result<Vec<file_list::FileEntry>> {
+		self.list_with(move |con| con.list_files(channel, channel_password.as_deref(), &path)).await
+	}
+
+	/// Get entries of the server log, see [`Connection::get_server_log`].
+	///
+	/// Fails with `Error::Timeout` if the log is not received in time.
+	///
+	/// [`Connection::get_server_log`]: ../struct.Connection.html#method.get_server_log
+	pub async fn get_server_log(
+		&mut self, lines: u32, reverse: bool, begin_pos: Option<u64>,
+	) -> Result<server_log::ServerLog> {
+		self.list_with(move |con| con.get_server_log(lines, reverse, begin_pos)).await
+	}
+
+	/// Get the list of our offline messages, without their content.
+	///
+	/// Fails with `Error::Timeout` if the list is not finished in time.
+	pub async fn list_offline_messages(
+		&mut self,
+	) -> Result<Vec<offline_messages::OfflineMessage>> {
+		self.list_with(|con| con.list_offline_messages()).await
+	}
+
+	/// Get an offline message including its content.
+	///
+	/// Fails with `Error::Timeout` if the message is not received in time.
+	pub async fn get_offline_message(
+		&mut self, id: u32,
+	) -> Result<Option<offline_messages::OfflineMessage>> {
+		self.list_with(move |con| con.get_offline_message(id)).await
+	}
+
+	/// Get the list of complaints, optionally only about a single client.
+	///
+	/// Fails with `Error::Timeout` if the list is not finished in time.
+	pub async fn list_complaints(
+		&mut self, target: Option<ClientDbId>,
+	) -> Result<Vec<complaints::Complaint>> {
+		self.list_with(move |con| con.list_complaints(target)).await
+	}
+
+	/// Get the list of privilege keys.
+	///
+	/// Fails with `Error::Timeout` if the list is not finished in time.
+	pub async fn list_privilege_keys(&mut self) -> Result<Vec<privilege_keys::PrivilegeKey>> {
+		self.list_with(|con| con.list_privilege_keys()).await
+	}
+
+	/// Get the list of temporary server passwords.
+	///
+	/// Fails with `Error::Timeout` if the list is not finished in time.
+	pub async fn list_temporary_passwords(
+		&mut self,
+	) -> Result<Vec<temp_passwords::TempPassword>> {
+		self.list_with(|con| con.list_temporary_passwords()).await
+	}
+
+	/// Search the channels whose name contains `pattern`, ignoring case.
+	///
+	/// The bookkeeping contains all channels, so the server is only asked if
+	/// we are not connected yet.
+	///
+	/// Fails with `Error::Timeout` if the server does not answer in time.
+	pub async fn find_channels(&mut self, pattern: String) -> Result<Vec<(ChannelId, String)>> {
+		let local_pattern = pattern.to_lowercase();
+		let local = self
+			.with_connection(move |con| {
+				con.get_state().ok().map(|b| {
+					let mut res = b
+						.channels
+						.values()
+						.filter(|c| c.name.to_lowercase().contains(&local_pattern))
+						.map(|c| (c.id, c.name.clone()))
+						.collect::<Vec<_>>();
+					res.sort_by_key(|c| (c.0).0);
+					res
+				})
+			})
+			.await?;
+		if let Some(res) = local {
+			return Ok(res);
+		}
+		self.list_with(move |con| con.find_channels(&pattern)).await
+	}
+
+	/// Search the clients whose nickname contains `pattern`, ignoring case.
+	///
+	/// If all channels are subscribed, the clients are searched in the
+	/// bookkeeping, otherwise the server is asked, see
+	/// [`Connection::find_clients`].
+	///
+	/// Fails with `Error::Timeout` if the server does not answer in time.
+	///
+	/// [`Connection::find_clients`]: ../struct.Connection.html#method.find_clients
+	pub async fn find_clients(&mut self, pattern: String) -> Result<Vec<(ClientId, String)>> {
+		let local_pattern = pattern.clone();
+		let local = self
+			.with_connection(move |con| {
+				con.get_state().ok().filter(|b| b.is_fully_subscribed()).map(|b| {
+					b.find_clients_by_name(&local_pattern)
+						.into_iter()
+						.map(|c| (c.id, c.name.clone()))
+						.collect::<Vec<_>>()
+				})
+			})
+			.await?;
+		if let Some(res) = local {
+			return Ok(res);
+		}
+		self.list_with(move |con| con.find_clients(&pattern)).await
+	}
+
+	/// Change a property of the server, a channel or a client.
+	///
+	/// The function gets the current bookkeeping and returns the command for
+	/// the change, e.g. built with [`Channel::edit`]. The returned future
+	/// resolves when the server acknowledged the change. The bookkeeping is
+	/// not changed optimistically, it is updated when the server notifies us
+	/// about the change.
+	///
+	/// # Example
+	///
+	/// ```no_run
+	/// # use futures::prelude::*;
+	/// # use tsclientlib::prelude::*;
+	/// # use tsclientlib::{ChannelSpec, Connection, ConnectOptions};
+	/// # use tsclientlib::sync::SyncConnection;
+	///
+	/// # #[tokio::main]
+	/// # async fn main() {
+	/// let con: SyncConnection = Connection::new(ConnectOptions::new("localhost")).unwrap().into();
+	/// let mut handle = con.get_handle();
+	/// tokio::spawn(con.for_each(|_| future::ready(())));
+	/// handle.wait_until_connected().await.unwrap();
+	///
+	/// handle
+	///     .edit(|book| {
+	///         let id = book.find_channel(ChannelSpec::Name("Lobby"))?;
+	///         Ok(book.channels[&id].edit().set_topic("Meeting at 10").to_packet())
+	///     })
+	///     .await
+	///     .unwrap();
+	/// # }
+	/// ```
+	///
+	/// [`Channel::edit`]: ../data/struct.Channel.html#method.edit
+	pub async fn edit<F: FnOnce(&data::Connection) -> Result<OutCommand> + Send + 'static>(
+		&mut self, f: F,
+	) -> Result<()> {
+		self.send_with(move |con| {
+			let cmd = f(con.get_state()?)?;
+			con.send_command(cmd)
+		})
+		.await
 	}
 
 	/// Adds a `return_code` to the command and returns if the corresponding
 	/// answer is received. If an error occurs, the future will return an error.
 	#[cfg(feature = "unstable")]
 	pub async fn send_command(&mut self, arg: OutCommand) -> Result<()> {
+		self.send_command_with(arg, self.timeout).await
+	}
+
+	/// Like [`send_command`](#method.send_command), but fails with
+	/// `Error::Timeout` if no answer is received in time.
+	#[cfg(feature = "unstable")]
+	pub async fn send_command_timeout(&mut self, arg: OutCommand, timeout: Duration) -> Result<()> {
+		self.send_command_with(arg, Some(timeout)).await
+	}
+
+	#[cfg(feature = "unstable")]
+	async fn send_command_with(
+		&mut self, arg: OutCommand, timeout: Option<Duration>,
+	) -> Result<()> {
 		let (send, recv) = oneshot::channel();
-		self.send
-			.send(SyncConMessage::SendCommand(arg, send))
-			.await
-			.map_err(|_| Error::ConnectionGone)?;
-		recv.await.map_err(|_| Error::ConnectionGone)?
+		self.request(SyncConMessage::SendCommand(arg, send), recv, timeout).await
+	}
+
+	/// The default timeout for all requests through this handle.
+	///
+	/// If no answer is received in time, requests fail with `Error::Timeout`.
+	/// The timeout is used by all functions which have no explicit timeout
+	/// argument, it does not affect clones which were created before.
+	///
+	/// `None` removes the timeout.
+	///
+	/// # Default
+	/// No timeout.
+	pub fn set_timeout(&mut self, timeout: Option<Duration>) { self.timeout = timeout; }
+
+	/// Get all events of the connection, additionally to the event stream.
+	///
+	/// Every subscriber gets the events independently of the other
+	/// subscribers, which makes it possible to handle events in multiple
+	/// tasks. The events are only sent while the [`SyncConnection`] is polled.
+	/// If a subscriber does not keep up, the oldest events are dropped and it
+	/// receives a `RecvError::Lagged` error.
+	///
+	/// [`SyncConnection`]: struct.SyncConnection.html
+	pub fn subscribe_events(&self) -> broadcast::Receiver<Arc<Vec<events::Event>>> {
+		self.events.subscribe()
+	}
+
+	/// Get the events of some categories, additionally to the event stream.
+	///
+	/// Events are checked against the filter before they are copied, so a
+	/// subscriber which is e.g. only interested in chat messages is not woken
+	/// up for every client which joins the server. Events are dropped if the
+	/// receiver does not keep up.
+	///
+	/// Dropping the receiver removes the subscription, at the latest when the
+	/// next matching event arrives.
+	pub fn subscribe_filtered(&self, filter: EventFilter) -> mpsc::Receiver<events::Event> {
+		let (send, recv) = mpsc::channel(EVENT_BUFFER);
+		self.filtered.lock().unwrap_or_else(|e| e.into_inner()).push((filter, send));
+		recv
+	}
+
+	/// The last published copy of the bookkeeping.
+	///
+	/// This does not wait for the connection, it returns immediately. The
+	/// snapshot is only published after [`watch_snapshots`] was called and
+	/// is `None` while not connected.
+	///
+	/// [`watch_snapshots`]: #method.watch_snapshots
+	pub fn snapshot(&self) -> Option<Arc<data::Connection>> { self.snapshot.borrow().clone() }
+
+	/// Publish a snapshot of the bookkeeping after every batch of events.
+	///
+	/// The current state is published immediately. Afterwards, the returned
+	/// receiver and [`snapshot`] get a new copy whenever the bookkeeping
+	/// changes, so state can be read from other tasks without waiting for
+	/// the connection.
+	///
+	/// A copy shares unchanged clients and channels with the connection.
+	///
+	/// [`snapshot`]: #method.snapshot
+	pub async fn watch_snapshots(
+		&mut self,
+	) -> Result<watch::Receiver<Option<Arc<data::Connection>>>> {
+		self.with_connection(|con| {
+			con.publish_snapshots = true;
+			con.publish_snapshot();
+		})
+		.await?;
+		Ok(self.snapshot.clone())
+	}
+
+	/// Get all incoming audio packets, additionally to the event stream.
+	///
+	/// Works like [`subscribe_events`](#method.subscribe_events).
+	#[cfg(feature = "audio")]
+	pub fn subscribe_audio(&self) -> broadcast::Receiver<Arc<InAudioBuf>> {
+		self.audio.subscribe()
+	}
+
+	/// Send a message to the connection and wait for the answer.
+	async fn request<T>(
+		&mut self, msg: SyncConMessage, recv: oneshot::Receiver<Result<T>>,
+		timeout: Option<Duration>,
+	) -> Result<T> {
+		let send = &mut self.send;
+		let fut = async move {
+			send.send(msg).await.map_err(|_| Error::ConnectionGone)?;
+			recv.await.map_err(|_| Error::ConnectionGone)?
+		};
+		match timeout {
+			Some(timeout) => time::timeout(timeout, fut).await.map_err(|_| Error::Timeout)?,
+			None => fut.await,
+		}
+	}
+
+	/// Send a text message to a client, the channel or the server chat.
+	///
+	/// The future resolves when the server acknowledged the message.
+	pub async fn send_message(&mut self, target: MessageTarget, message: String) -> Result<()> {
+		let (send, recv) = oneshot::channel();
+		self.request(SyncConMessage::SendMessage(target, message, send), recv, self.timeout).await
+	}
+
+	/// Change the nickname of our own client.
+	pub async fn change_name(&mut self, name: String) -> Result<()> {
+		let (send, recv) = oneshot::channel();
+		self.request(SyncConMessage::ChangeName(name, send), recv, self.timeout).await
+	}
+
+	/// Set our own client away with a message or back with `None`.
+	pub async fn set_away(&mut self, message: Option<String>) -> Result<()> {
+		let (send, recv) = oneshot::channel();
+		self.request(SyncConMessage::SetAway(message, send), recv, self.timeout).await
+	}
+
+	/// Move our own client into a channel.
+	///
+	/// If no `password` is given and the channel is the one from the
+	/// connect options, its password is used.
+	pub async fn move_to_channel(
+		&mut self, channel: ChannelId, password: Option<String>,
+	) -> Result<()> {
+		let (send, recv) = oneshot::channel();
+		let msg = SyncConMessage::MoveToChannel(channel, password, send);
+		self.request(msg, recv, self.timeout).await
+	}
+
+	/// A copy of the current bookkeeping.
+	///
+	/// The snapshot is independent of the connection, so it can be inspected
+	/// in other threads without blocking the connection.
+	pub async fn get_state_snapshot(&mut self) -> Result<data::Connection> {
+		self.with_connection(|con| con.get_state().map(data::Connection::snapshot)).await?
 	}
 
 	/// This future resolves once the connection is connected to the server.
 	pub async fn wait_until_connected(&mut self) -> Result<()> {
 		let (send, recv) = oneshot::channel();
-		self.send
-			.send(SyncConMessage::WaitConnected(send))
-			.await
-			.map_err(|_| Error::ConnectionGone)?;
-		recv.await.map_err(|_| Error::ConnectionGone)?
+		self.request(SyncConMessage::WaitConnected(send), recv, self.timeout).await
+	}
+
+	/// Like [`wait_until_connected`](#method.wait_until_connected), but fails
+	/// with `Error::Timeout` if the connection is not established in time.
+	pub async fn wait_until_connected_timeout(&mut self, timeout: Duration) -> Result<()> {
+		let (send, recv) = oneshot::channel();
+		self.request(SyncConMessage::WaitConnected(send), recv, Some(timeout)).await
+	}
+
+	/// Wait until the connection ends and return why it ended.
+	///
+	/// The default timeout does not apply, as a connection may run for a long
+	/// time.
+	///
+	/// # Example
+	///
+	/// ```no_run
+	/// # use tsclientlib::{Connection, ConnectOptions, DisconnectReason};
+	/// # use tsclientlib::sync::SyncConnection;
+	///
+	/// # #[tokio::main]
+	/// # async fn main() {
+	/// let con: SyncConnection = Connection::new(ConnectOptions::new("localhost")).unwrap().into();
+	/// let (mut handle, _running) = con.spawn();
+	/// let reason = handle.wait_until_disconnected().await.unwrap();
+	/// if let DisconnectReason::Kicked { message, .. } = reason {
+	///     println!("Kicked: {}", message.unwrap_or_default());
+	/// }
+	/// # }
+	/// ```
+	pub async fn wait_until_disconnected(&mut self) -> Result<DisconnectReason> {
+		let (send, recv) = oneshot::channel();
+		self.request(SyncConMessage::WaitDisconnected(send), recv, None).await
 	}
 
 	/// Disconnect from the server.
@@ -382,11 +1165,7 @@ impl SyncConnectionHandle {
 	/// ```
 	pub async fn disconnect(&mut self, arg: DisconnectOptions) -> Result<()> {
 		let (send, recv) = oneshot::channel();
-		self.send
-			.send(SyncConMessage::Disconnect(arg, send))
-			.await
-			.map_err(|_| Error::ConnectionGone)?;
-		recv.await.map_err(|_| Error::ConnectionGone)?
+		self.request(SyncConMessage::Disconnect(arg, send), recv, self.timeout).await
 	}
 
 	/// Download a file from a channel of the connected TeamSpeak server.
@@ -407,18 +1186,37 @@ impl SyncConnectionHandle {
 		seek_position: Option<u64>,
 	) -> Result<super::FileDownloadResult>
 	{
-		let (send, recv) = oneshot::channel();
-		self.send
-			.send(SyncConMessage::DownloadFile {
-				channel_id,
-				path,
-				channel_password,
-				seek_position,
-				send,
-			})
+		let timeout = self.timeout;
+		self.download_file_with(channel_id, path, channel_password, seek_position, timeout).await
+	}
+
+	/// Like [`download_file`](#method.download_file), but fails with
+	/// `Error::Timeout` if the server does not answer in time.
+	///
+	/// The timeout only applies until the download starts.
+	pub async fn download_file_timeout(
+		&mut self, channel_id: ChannelId, path: String, channel_password: Option<String>,
+		seek_position: Option<u64>, timeout: Duration,
+	) -> Result<super::FileDownloadResult>
+	{
+		self.download_file_with(channel_id, path, channel_password, seek_position, Some(timeout))
 			.await
-			.map_err(|_| Error::ConnectionGone)?;
-		recv.await.map_err(|_| Error::ConnectionGone)?
+	}
+
+	async fn download_file_with(
+		&mut self, channel_id: ChannelId, path: String, channel_password: Option<String>,
+		seek_position: Option<u64>, timeout: Option<Duration>,
+	) -> Result<super::FileDownloadResult>
+	{
+		let (send, recv) = oneshot::channel();
+		let msg = SyncConMessage::DownloadFile {
+			channel_id,
+			path,
+			channel_password,
+			seek_position,
+			send,
+		};
+		self.request(msg, recv, timeout).await
 	}
 
 	/// Upload a file to a channel of the connected TeamSpeak server.
@@ -440,19 +1238,211 @@ impl SyncConnectionHandle {
 		size: u64, overwrite: bool, resume: bool,
 	) -> Result<super::FileUploadResult>
 	{
-		let (send, recv) = oneshot::channel();
-		self.send
-			.send(SyncConMessage::UploadFile {
-				channel_id,
-				path,
-				channel_password,
-				size,
-				overwrite,
-				resume,
-				send,
-			})
+		let timeout = self.timeout;
+		self.upload_file_with(channel_id, path, channel_password, size, overwrite, resume, timeout)
+			.await
+	}
+
+	/// Like [`upload_file`](#method.upload_file), but fails with
+	/// `Error::Timeout` if the server does not answer in time.
+	///
+	/// The timeout only applies until the upload starts.
+	#[allow(clippy::too_many_arguments)]
+	pub async fn upload_file_timeout(
+		&mut self, channel_id: ChannelId, path: String, channel_password: Option<String>,
+		size: u64, overwrite: bool, resume: bool, timeout: Duration,
+	) -> Result<super::FileUploadResult>
+	{
+		let timeout = Some(timeout);
+		self.upload_file_with(channel_id, path, channel_password, size, overwrite, resume, timeout)
 			.await
-			.map_err(|_| Error::ConnectionGone)?;
-		recv.await.map_err(|_| Error::ConnectionGone)?
+	}
+
+	#[allow(clippy::too_many_arguments)]
+	async fn upload_file_with(
+		&mut self, channel_id: ChannelId, path: String, channel_password: Option<String>,
+		size: u64, overwrite: bool, resume: bool, timeout: Option<Duration>,
+	) -> Result<super::FileUploadResult>
+	{
+		let (send, recv) = oneshot::channel();
+		let msg = SyncConMessage::UploadFile {
+			channel_id,
+			path,
+			channel_password,
+			size,
+			overwrite,
+			resume,
+			send,
+		};
+		self.request(msg, recv, timeout).await
+	}
+}
+
+impl EventFilter {
+	/// If the category of `event` is part of this filter.
+	pub fn matches(self, event: &events::Event) -> bool {
+		let category = match event {
+			events::Event::Message { .. } => Self::CHAT,
+			events::Event::ChannelListFinished => Self::CHANNELS,
+			events::Event::PropertyAdded { id, .. }
+			| events::Event::PropertyChanged { id, .. }
+			| events::Event::PropertyRemoved { id, .. } => Self::of_property(id),
+		};
+		self.intersects(category)
+	}
+
+	fn of_property(id: &events::PropertyId) -> Self {
+		use events::PropertyId;
+		match id {
+			PropertyId::ClientTalkPower(_)
+			| PropertyId::ClientTalkPowerRequest(_)
+			| PropertyId::ClientIsTalker(_)
+			| PropertyId::ClientIsPrioritySpeaker(_) => Self::TALK_STATUS,
+			PropertyId::ClientServerGroup(..) | PropertyId::ClientChannelGroup(_) => {
+				Self::PERMISSIONS
+			}
+			_ => match id.get_struct_name() {
+				"Client" | "OptionalClientData" | "ConnectionClientData" => Self::CLIENTS,
+				"Channel" | "OptionalChannelData" => Self::CHANNELS,
+				"ServerGroup" | "ChannelGroup" => Self::PERMISSIONS,
+				_ => Self::SERVER,
+			},
+		}
+	}
+}
+
+/// Send events to the subscribers whose filter matches and remove
+/// subscribers whose receiver is gone.
+fn send_filtered(subscribers: &FilteredSubscribers, events: &[events::Event]) {
+	let mut subscribers = subscribers.lock().unwrap_or_else(|e| e.into_inner());
+	let mut i = 0;
+	while i < subscribers.len() {
+		let (filter, send) = &mut subscribers[i];
+		let closed = events.iter().filter(|e| filter.matches(e)).any(|e| {
+			// Events for slow subscribers are dropped
+			matches!(send.try_send(e.clone()), Err(mpsc::error::TrySendError::Closed(_)))
+		});
+		if closed {
+			subscribers.swap_remove(i);
+		} else {
+			i += 1;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::tests::get_logger;
+	use crate::{ConnectOptions, Connection, FileTransferHandle, MessageHandle};
+
+	/// A handle to a connection which does not answer.
+	fn stuck_handle() -> (SyncConnectionHandle, mpsc::Receiver<SyncConMessage>) {
+		let (send, recv) = mpsc::channel(1);
+		let mut handle = SyncConnectionHandle::detached();
+		handle.send = send;
+		(handle, recv)
+	}
+
+	#[tokio::test]
+	async fn request_timeout() {
+		let (mut handle, _recv) = stuck_handle();
+		let res = handle.wait_until_connected_timeout(Duration::from_millis(10)).await;
+		assert!(matches!(res, Err(Error::Timeout)));
+		// The default timeout applies to all other requests
+		handle.set_timeout(Some(Duration::from_millis(10)));
+		assert!(matches!(handle.change_name("Bot".into()).await, Err(Error::Timeout)));
+		// Without a timeout, requests wait
+		handle.set_timeout(None);
+		let res = time::timeout(Duration::from_millis(10), handle.change_name("Bot".into())).await;
+		assert!(res.is_err());
+	}
+
+	/// A slow subscriber misses the oldest events, but does not block the
+	/// connection.
+	#[tokio::test]
+	async fn lagging_subscriber() {
+		let opts = ConnectOptions::new("127.0.0.1:1").logger(get_logger());
+		let mut con = SyncConnection::from(Connection::new(opts).unwrap());
+		let mut recv = con.get_handle().subscribe_events();
+		// Event lists of different lengths
+		let lists = (1..=EVENT_BUFFER + 2)
+			.map(|len| vec![events::Event::ChannelListFinished; len])
+			.collect::<Vec<_>>();
+		for list in &lists {
+			con.con.stream_items.push_back(Ok(StreamItem::ConEvents(list.clone())));
+		}
+		for list in &lists {
+			let item = con.next().await.unwrap().unwrap();
+			assert!(matches!(item, SyncStreamItem::ConEvents(e) if e.len() == list.len()));
+		}
+
+		assert!(matches!(recv.try_recv(), Err(broadcast::TryRecvError::Lagged(2))));
+		for list in &lists[2..] {
+			assert_eq!(*recv.try_recv().unwrap(), *list);
+		}
+		assert!(recv.try_recv().is_err());
+	}
+
+	#[test]
+	fn filtered_subscribers() {
+		let handle = SyncConnectionHandle::detached();
+		let mut chat = handle.subscribe_filtered(EventFilter::CHAT);
+		let channels = handle.subscribe_filtered(EventFilter::CHANNELS);
+		let message = events::Event::Message {
+			target: MessageTarget::Server,
+			invoker: ts_bookkeeping::Invoker { name: "Alice".into(), id: ClientId(3), uid: None },
+			message: "Hi".into(),
+		};
+		let events = [events::Event::ChannelListFinished, message.clone()];
+		send_filtered(&handle.filtered, &events);
+		assert_eq!(chat.try_recv().unwrap(), message);
+		assert!(chat.try_recv().is_err());
+
+		// The dropped receiver is removed when the next channel event arrives
+		drop(channels);
+		send_filtered(&handle.filtered, &events[..1]);
+		assert_eq!(handle.filtered.lock().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn snapshot_not_published() {
+		let handle = SyncConnectionHandle::detached();
+		assert!(handle.snapshot().is_none());
+	}
+
+	/// Requests are forgotten when their future is dropped.
+	#[tokio::test]
+	async fn dropped_requests_removed() {
+		let opts = ConnectOptions::new("127.0.0.1:1").logger(get_logger());
+		let mut con = SyncConnection::from(Connection::new(opts).unwrap());
+		let (command_send, command_recv) = oneshot::channel();
+		let (kept_send, _kept_recv) = oneshot::channel();
+		let (download_send, download_recv) = oneshot::channel();
+		let (upload_send, upload_recv) = oneshot::channel();
+		con.commands.insert(MessageHandle(1), command_send);
+		con.commands.insert(MessageHandle(2), kept_send);
+		con.downloads.insert(FileTransferHandle(1), download_send);
+		con.uploads.insert(FileTransferHandle(2), upload_send);
+
+		let waker = futures::task::noop_waker();
+		let mut cx = Context::from_waker(&waker);
+		con.remove_canceled(&mut cx);
+		assert_eq!(con.commands.len(), 2);
+		assert_eq!(con.downloads.len(), 1);
+		assert_eq!(con.uploads.len(), 1);
+
+		drop((command_recv, download_recv, upload_recv));
+		con.remove_canceled(&mut cx);
+		assert_eq!(con.commands.keys().collect::<Vec<_>>(), [&MessageHandle(2)]);
+		assert!(con.downloads.is_empty());
+		assert!(con.uploads.is_empty());
+	}
+
+	#[tokio::test]
+	async fn connection_gone() {
+		let (mut handle, recv) = stuck_handle();
+		drop(recv);
+		assert!(matches!(handle.wait_until_connected().await, Err(Error::ConnectionGone)));
 	}
 }
@@ -0,0 +1,133 @@
+//! Temporary server passwords, which grant access to a server for a limited
+//! time.
+use std::time::Duration;
+
+use time::OffsetDateTime;
+use tsproto_packets::packets::OutCommand;
+use tsproto_types::{ChannelId, Uid};
+
+use crate::{new_command, parse_list, ParseEntry};
+
+/// A temporary password that is stored on the server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TempPassword {
+	pub password: String,
+	pub description: String,
+	/// The nickname of the client that created this password.
+	pub creator_name: String,
+	pub creator: Option<Uid>,
+	pub start: OffsetDateTime,
+	pub end: OffsetDateTime,
+	/// Clients that join with this password are moved to this channel.
+	pub target_channel: Option<ChannelId>,
+	/// The password of the target channel.
+	pub channel_password: Option<String>,
+}
+
+pub(crate) fn add(
+	password: &str, description: &str, duration: Duration, target_channel: Option<ChannelId>,
+	channel_password: Option<&str>,
+) -> OutCommand
+{
+	let mut cmd = new_command("servertemppasswordadd");
+	cmd.write_arg("pw", &password);
+	cmd.write_arg("desc", &description);
+	cmd.write_arg("duration", &duration.as_secs());
+	cmd.write_arg("tcid", &target_channel.map(|c| c.0).unwrap_or_default());
+	cmd.write_arg("tcpw", &channel_password.unwrap_or_default());
+	cmd
+}
+
+pub(crate) fn list() -> OutCommand { new_command("servertemppasswordlist") }
+
+pub(crate) fn delete(password: &str) -> OutCommand {
+	let mut cmd = new_command("servertemppassworddel");
+	cmd.write_arg("pw", &password);
+	cmd
+}
+
+/// Parse the passwords from a `notifyservertemppasswordlist`.
+pub(crate) fn parse(content: &[u8]) -> Vec<TempPassword> {
+	parse_list(content, |args| {
+		let mut password = None;
+		let mut description = String::new();
+		let mut creator_name = String::new();
+		let mut creator = None;
+		let mut start = OffsetDateTime::unix_epoch();
+		let mut end = OffsetDateTime::unix_epoch();
+		let mut target_channel = None;
+		let mut channel_password = None;
+		for (name, value) in args {
+			let timestamp =
+				|| OffsetDateTime::from_unix_timestamp(value.parse().unwrap_or_default());
+			match name {
+				b"pw_clear" => password = Some(value.to_string()),
+				b"desc" => description = value.to_string(),
+				b"nickname" => creator_name = value.to_string(),
+				b"uid" => creator = base64::decode(value.as_ref()).ok().map(Uid),
+				b"start" => start = timestamp(),
+				b"end" => end = timestamp(),
+				b"tcid" => target_channel = value.parse().ok().filter(|c| *c != 0).map(ChannelId),
+				b"tcpw" if !value.is_empty() => channel_password = Some(value.to_string()),
+				_ => {}
+			}
+		}
+		Some(TempPassword {
+			password: password?,
+			description,
+			creator_name,
+			creator,
+			start,
+			end,
+			target_channel,
+			channel_password,
+		})
+	})
+}
+
+impl ParseEntry for TempPassword {
+	fn parse(content: &[u8]) -> Vec<Self> { parse(content) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn parse_list() {
+		let pws = parse(
+			br#"notifyservertemppasswordlist nickname=Admin uid=T3RoZXI= desc=Event pw_clear=secret start=1500000000 end=1500003600 tcid=5 tcpw|nickname=Admin uid=T3RoZXI= desc pw_clear=other start=1500000000 end=1500000060 tcid=0 tcpw"#,
+		);
+		assert_eq!(pws, vec![
+			TempPassword {
+				password: "secret".into(),
+				description: "Event".into(),
+				creator_name: "Admin".into(),
+				creator: Some(Uid(b"Other".to_vec())),
+				start: OffsetDateTime::from_unix_timestamp(1500000000),
+				end: OffsetDateTime::from_unix_timestamp(1500003600),
+				target_channel: Some(ChannelId(5)),
+				channel_password: None,
+			},
+			TempPassword {
+				password: "other".into(),
+				description: String::new(),
+				creator_name: "Admin".into(),
+				creator: Some(Uid(b"Other".to_vec())),
+				start: OffsetDateTime::from_unix_timestamp(1500000000),
+				end: OffsetDateTime::from_unix_timestamp(1500000060),
+				target_channel: None,
+				channel_password: None,
+			},
+		]);
+	}
+
+	#[test]
+	fn add_password() {
+		let cmd = add("secret", "An event", Duration::from_secs(3600), Some(ChannelId(5)), None);
+		assert_eq!(
+			cmd.0.content(),
+			br#"servertemppasswordadd pw=secret desc=An\sevent duration=3600 tcid=5 tcpw"#.as_ref()
+		);
+	}
+}
@@ -1,8 +1,40 @@
-use std::sync::Mutex;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, SocketAddrV6};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use futures::prelude::*;
+use num_traits::FromPrimitive;
 use slog::{o, Drain, Logger};
+use tokio::time;
+use ts_bookkeeping::data;
+use ts_bookkeeping::events::{Event, PropertyId, PropertyValue, TypedEvent};
 use ts_bookkeeping::messages::s2c::InMessage;
-use tsproto_packets::packets::{Direction, Flags, OutPacket, PacketType};
+use tsproto::resend::ResendConfig;
+use tsproto_packets::packets::{
+	AudioData, CodecType, Direction, Flags, OutAudio, OutPacket, PacketType,
+};
+use tsproto_types::crypto::EccKeyPrivP256;
+
+#[macro_use]
+mod harness;
+
+use harness::{mock_connection, ConnectionHarness, Harness, Script, VirtualTime};
+
+use crate::foreign_transfers::{ForeignFileTransfer, TransferDirection, MAX_RECENT_FILE_TRANSFERS};
+use crate::prelude::*;
+use crate::sync::SyncStreamItem;
+use crate::{
+	bans, flood, host_banner_due, local_address_for, new_command, policy, restore, BackgroundError,
+	ChannelId, ChannelSpec, ClientId, CommandError, ConnectOptions, Connection, DisconnectOptions,
+	DisconnectReason, Error, ErrorComponent, ErrorLimiter, HostMessageMode, Identity, LogConfig,
+	MaxClients, MessageHandle, NameCollisionStrategy, PluginMessage, Result, SendingMutedReason,
+	ServerGroupId, StreamItem, TsError, Uid, Verbosity, BACKGROUND_ERROR_INTERVAL, MAX_COMMAND_LEN,
+	MAX_NAME_LEN, NAME_RETRIES,
+};
 
 pub(crate) fn get_logger() -> Logger {
 	let decorator = slog_term::PlainDecorator::new(slog_term::TestStdoutWriter);
@@ -19,11 +51,42 @@ fn parse_msg(msg: &str) -> InMessage {
 	InMessage::new(&logger, &header.header(), msg.as_bytes()).unwrap()
 }
 
-fn test_iconid(input: &str, expected: u32) {
-	let msg = parse_msg(&format!(
+fn initserver(icon_id: &str) -> String {
+	format!(
 		r#"initserver virtualserver_name=TeamSpeak\s]I[\sServer virtualserver_welcomemessage=Welcome\sto\sTeamSpeak,\scheck\s[URL]www.teamspeak.com[\/URL]\sfor\slatest\sinformation virtualserver_platform=Linux virtualserver_version=3.11.0\s[Build:\s1578903157] virtualserver_maxclients=32 virtualserver_created=1571572631 virtualserver_codec_encryption_mode=2 virtualserver_hostmessage virtualserver_hostmessage_mode=0 virtualserver_default_server_group=8 virtualserver_default_channel_group=8 virtualserver_hostbanner_url virtualserver_hostbanner_gfx_url virtualserver_hostbanner_gfx_interval=0 virtualserver_priority_speaker_dimm_modificator=-18.0000 virtualserver_id=1 virtualserver_hostbutton_tooltip virtualserver_hostbutton_url virtualserver_hostbutton_gfx_url virtualserver_name_phonetic virtualserver_ip=0.0.0.0,\s:: virtualserver_ask_for_privilegekey=0 virtualserver_hostbanner_mode=0 virtualserver_channel_temp_delete_delay_default=0 virtualserver_nickname client_nickname=TeamSpeakUser client_version=3.?.?\s[Build:\s5680278000] client_platform=Windows client_input_muted=0 client_output_muted=0 client_outputonly_muted=0 client_input_hardware=1 client_output_hardware=1 client_default_channel client_default_channel_password client_server_password client_meta_data client_version_sign=DX5NIYLvfJEUjuIbCidnoeozxIDRRkpq3I9vVMBmE9L2qnekOoBzSenkzsg2lC9CMv8K5hkEzhr2TYUYSwUXCg== client_security_hash client_key_offset=354 client_away=0 client_away_message client_nickname_phonetic client_default_token client_badges client_myteamspeak_id client_integrations client_active_integrations_info client_myteamspeak_avatar client_signed_badges acn=TeamSpeakUser aclid=2 pv=7 client_talk_power=75 client_needed_serverquery_view_power=75 virtualserver_icon_id={}"#,
-		input
-	));
+		icon_id
+	)
+}
+
+/// Create a bookkeeping connection from an `initserver` packet and apply the
+/// notifications to it.
+fn connection_with(notifications: &[String]) -> data::Connection {
+	let key = EccKeyPrivP256::create().unwrap().to_pub();
+	let mut con = if let InMessage::InitServer(msg) = parse_msg(&initserver("0")) {
+		data::Connection::new(key, &msg)
+	} else {
+		panic!("Failed to parse as initserver");
+	};
+	for notification in notifications {
+		handle(&mut con, notification);
+	}
+	con
+}
+
+/// Apply a notification to the bookkeeping.
+fn handle(con: &mut data::Connection, notification: &str) -> Vec<Event> {
+	con.handle_command(&get_logger(), &parse_msg(notification)).unwrap()
+}
+
+fn client_enterview(id: u16, name: &str, client_type: u8) -> String {
+	format!(
+		r#"notifycliententerview cfid=0 ctid=1 reasonid=0 clid={} client_unique_identifier=Client{}= client_nickname={} client_input_muted=0 client_output_muted=0 client_outputonly_muted=0 client_input_hardware=1 client_output_hardware=1 client_meta_data client_is_recording=0 client_database_id={} client_channel_group_id=8 client_servergroups=8 client_away=0 client_away_message client_type={} client_flag_avatar client_talk_power=75 client_talk_request=0 client_talk_request_msg client_description client_is_talker=0 client_is_priority_speaker=0 client_unread_messages=0 client_nickname_phonetic client_needed_serverquery_view_power=75 client_icon_id=0 client_is_channel_commander=0 client_country client_channel_group_inherited_channel_id=1 client_badges client_myteamspeak_id client_integrations client_myteamspeak_avatar client_signed_badges"#,
+		id, id, name, id, client_type
+	)
+}
+
+fn test_iconid(input: &str, expected: u32) {
+	let msg = parse_msg(&initserver(input));
 	if let InMessage::InitServer(list) = msg {
 		let cmd = list.iter().next().unwrap();
 		assert_eq!(cmd.icon_id, ts_bookkeeping::IconHash(expected));
@@ -40,3 +103,1295 @@ fn negative_iconid() { test_iconid("-96136942", 4198830354); }
 
 #[test]
 fn big_iconid() { test_iconid("18446744073225738240", 3811153920); }
+
+#[test]
+fn query_clients_excluded() {
+	let mut con = connection_with(&[]);
+	for (id, name, typ) in &[(2, "User", 0), (3, "Bot", 1), (4, "Other", 0)] {
+		// Events are still delivered for query clients
+		assert_eq!(handle(&mut con, &client_enterview(*id, name, *typ)).len(), 1);
+	}
+
+	assert_eq!(con.clients.len(), 3);
+	assert_eq!(con.online_clients(false), 2);
+	assert_eq!(con.online_clients(true), 3);
+	assert!(con.normal_clients().all(|c| !c.is_query()));
+
+	let query = con.query_clients().map(|c| c.id).collect::<Vec<_>>();
+	assert_eq!(query, vec![ts_bookkeeping::ClientId(3)]);
+	assert!(con.clients[&ts_bookkeeping::ClientId(3)].client_type.is_query());
+}
+
+fn channel_list_full(id: u64, parent: u64, name: &str, needed_talk_power: i32) -> String {
+	format!(
+		r#"channellist cid={} cpid={} channel_name={} channel_topic channel_codec=4 channel_codec_quality=6 channel_maxclients=-1 channel_maxfamilyclients=-1 channel_order=0 channel_flag_permanent=1 channel_flag_semi_permanent=0 channel_flag_default=0 channel_flag_password=0 channel_codec_latency_factor=1 channel_codec_is_unencrypted=1 channel_delete_delay=0 channel_flag_maxclients_unlimited=1 channel_flag_maxfamilyclients_unlimited=0 channel_flag_maxfamilyclients_inherited=1 channel_needed_talk_power={} channel_name_phonetic channel_icon_id=0 channel_banner_gfx_url channel_banner_mode=0"#,
+		id, parent, name, needed_talk_power
+	)
+}
+
+fn channel_list(id: u64, needed_talk_power: i32) -> String {
+	channel_list_full(id, 0, &format!("Channel\\s{}", id), needed_talk_power)
+}
+
+#[test]
+fn sending_muted_without_talk_power() {
+	// Our own client has the id 2 and talk power 75
+	let mut con = connection_with(&[channel_list(1, 0), client_enterview(2, "Me", 0)]);
+	let mut items = VecDeque::new();
+	let mut muted = None;
+	let mut update = |con: &data::Connection, talk_override| {
+		let m = Connection::get_sending_muted(Some(con), talk_override);
+		Connection::update_sending_muted(&mut muted, m, &mut items);
+		items.pop_front().map(|i| i.unwrap())
+	};
+
+	assert!(con.can_talk_here());
+	assert!(update(&con, None).is_none());
+
+	handle(
+		&mut con,
+		r#"notifychanneledited cid=1 reasonid=10 invokerid=3 invokername=Admin invokeruid=Admin= channel_needed_talk_power=100"#,
+	);
+	assert!(!con.can_talk_here());
+	assert!(matches!(
+		update(&con, None),
+		Some(StreamItem::SendingMuted(SendingMutedReason::NoTalkPower))
+	));
+	// No second event
+	assert!(update(&con, None).is_none());
+
+	// Overwrite manually
+	assert!(matches!(update(&con, Some(true)), Some(StreamItem::SendingUnmuted)));
+	assert!(matches!(
+		update(&con, None),
+		Some(StreamItem::SendingMuted(SendingMutedReason::NoTalkPower))
+	));
+
+	// Get talk power granted
+	handle(&mut con, "notifyclientupdated clid=2 client_is_talker=1");
+	assert!(con.can_talk_here());
+	assert!(matches!(update(&con, None), Some(StreamItem::SendingUnmuted)));
+	assert!(matches!(
+		update(&con, Some(false)),
+		Some(StreamItem::SendingMuted(SendingMutedReason::Override))
+	));
+}
+
+#[test]
+fn parse_plugin_message() {
+	let msgs = PluginMessage::parse(
+		br#"notifyplugincmd name=my_plugin data=some\sdata invokerid=5 invokername=Other invokeruid=T3RoZXI="#,
+	);
+	assert_eq!(msgs, vec![PluginMessage {
+		name: "my_plugin".into(),
+		data: "some data".into(),
+		invoker: ts_bookkeeping::Invoker {
+			name: "Other".into(),
+			id: ts_bookkeeping::ClientId(5),
+			uid: Some(ts_bookkeeping::Uid(b"Other".to_vec())),
+		},
+	}]);
+}
+
+#[test]
+fn background_errors_rate_limited() {
+	let logger = get_logger();
+	let mut limiter = ErrorLimiter::default();
+	let mut items = VecDeque::new();
+	let start = Instant::now();
+	let mut push = |component, error, time| {
+		limiter.push(&logger, &mut items, component, error, time);
+		items
+			.drain(..)
+			.map(|i| match i {
+				Ok(StreamItem::BackgroundError(e)) => (e.component, e.repeated),
+				_ => panic!("Unexpected stream item"),
+			})
+			.collect::<Vec<_>>()
+	};
+
+	assert_eq!(push(ErrorComponent::Parser, Error::NotConnected, start), [(
+		ErrorComponent::Parser,
+		0
+	)]);
+
+	// Repetitions are suppressed
+	for i in 1..=3 {
+		let time = start + Duration::from_secs(i);
+		assert!(push(ErrorComponent::Parser, Error::NotConnected, time).is_empty());
+	}
+	// Other errors are reported, after the last suppressed repetition
+	assert_eq!(push(ErrorComponent::Bookkeeping, Error::NotConnected, start), [
+		(ErrorComponent::Parser, 2),
+		(ErrorComponent::Bookkeeping, 0)
+	]);
+
+	let later = start + BACKGROUND_ERROR_INTERVAL;
+	assert_eq!(push(ErrorComponent::Bookkeeping, Error::NotConnected, later), [(
+		ErrorComponent::Bookkeeping,
+		0
+	)]);
+	assert!(push(ErrorComponent::Bookkeeping, Error::NotConnected, later).is_empty());
+	// Report how often the error was suppressed
+	let later = later + BACKGROUND_ERROR_INTERVAL;
+	assert_eq!(push(ErrorComponent::Bookkeeping, Error::NotConnected, later), [(
+		ErrorComponent::Bookkeeping,
+		1
+	)]);
+}
+
+#[test]
+fn background_error_escalation() {
+	let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+	expect_event!(h, Event::PropertyAdded { .. }, within 100);
+	let client = h.con.get_tsproto_client_mut().unwrap();
+	let config = ResendConfig {
+		normal_timeout: Duration::from_millis(200),
+		..client.resender.get_config().clone()
+	};
+	client.resender.set_config(config);
+
+	// The server stops answering, the lost connection is reported as fatal
+	h.drop_server();
+	h.con.send_command(new_command("clientpoke")).unwrap();
+	expect_item!(h, StreamItem::DisconnectedTemporarily, within 1000);
+	let fatal = h.items.iter().find_map(|i| match i {
+		Ok(StreamItem::BackgroundError(e)) if e.fatal => Some(e),
+		_ => None,
+	});
+	assert!(
+		matches!(fatal, Some(BackgroundError {
+			component: ErrorComponent::Connection,
+			error: Error::ConnectionLost(_),
+			..
+		})),
+		"{:?}",
+		h.items
+	);
+	// and the connection is rebuilt
+	expect_event!(h, Event::PropertyAdded { .. }, within 1000);
+	assert_eq!(h.connector.lock().unwrap().attempts, 2);
+}
+
+#[test]
+fn find_clients_and_channels() {
+	let mut con = connection_with(&[
+		channel_list_full(1, 0, "Lobby", 0),
+		channel_list_full(2, 0, "Games", 0),
+		channel_list_full(3, 2, "Lobby", 0),
+		channel_list_full(4, 3, "AFK", 0),
+		client_enterview(3, "Alice", 0),
+		client_enterview(4, "alice2", 0),
+		client_enterview(5, "Bob", 0),
+	]);
+	con.channels.get_mut(&ChannelId(1)).unwrap().order = ChannelId(0);
+	con.channels.get_mut(&ChannelId(2)).unwrap().order = ChannelId(1);
+
+	let ids = |clients: Vec<&data::Client>| clients.iter().map(|c| c.id.0).collect::<Vec<_>>();
+	assert_eq!(ids(con.find_clients_by_name("ALICE")), [3, 4]);
+	assert_eq!(ids(con.find_clients_by_name("b")), [5]);
+	assert!(con.find_clients_by_name("Carol").is_empty());
+
+	let uid = con.clients[&ts_bookkeeping::ClientId(5)].uid.clone().unwrap();
+	assert_eq!(con.find_client_by_uid(uid.as_ref()).unwrap().name, "Bob");
+	assert!(con.find_client_by_uid(ts_bookkeeping::UidRef(b"unknown")).is_none());
+
+	let path = |p| con.find_channel_by_path(p).map(|c| c.id.0);
+	assert_eq!(path("/Games/Lobby/AFK"), Some(4));
+	assert_eq!(path("Lobby"), Some(1));
+	assert_eq!(path("/Lobby/AFK"), None);
+	assert_eq!(path(""), None);
+
+	assert_eq!(con.find_channel_by_name("afk").unwrap().id, ChannelId(4));
+	// Both are called Lobby, the top-level channel comes first
+	assert_eq!(con.find_channel_by_name("lobby").unwrap().id, ChannelId(1));
+	assert!(con.find_channel_by_name("Music").is_none());
+}
+
+#[test]
+fn fully_subscribed() {
+	let mut con = connection_with(&[channel_list(1, 0), channel_list(2, 0)]);
+	assert!(!con.is_fully_subscribed());
+	handle(&mut con, "notifychannelsubscribed cid=1 es=0");
+	assert!(!con.is_fully_subscribed());
+	handle(&mut con, "notifychannelsubscribed cid=2 es=0");
+	assert!(con.is_fully_subscribed());
+}
+
+#[test]
+fn server_group_membership() {
+	let groups = r#"notifyservergrouplist sgid=6 name=Server\sAdmin type=1 iconid=300 savedb=1 sortid=0 namemode=0 n_modifyp=75 n_member_addp=75 n_member_removep=75|sgid=8 name=Guest type=1 iconid=0 savedb=0 sortid=0 namemode=0 n_modifyp=75 n_member_addp=0 n_member_removep=0"#;
+	let mut con = connection_with(&[groups.into(), client_enterview(3, "Other", 0)]);
+
+	let admin = con.find_server_group_by_name("server admin").unwrap().id;
+	assert_eq!(admin, ServerGroupId(6));
+	assert!(!con.client_in_server_group(ClientId(3), admin));
+	let names = |con: &data::Connection| {
+		con.client_server_groups(ClientId(3)).map(|g| g.name.clone()).collect::<Vec<_>>()
+	};
+	assert_eq!(names(&con), vec!["Guest"]);
+
+	let added = r#"notifyservergroupclientadded invokerid=4 invokername=Admin invokeruid=Admin= name=Other sgid=6 clid=3 cluid=Client3="#;
+	let events = handle(&mut con, added);
+	assert_eq!(events.len(), 1);
+	assert!(matches!(&events[0], Event::PropertyAdded {
+		id: PropertyId::ClientServerGroup(ClientId(3), ServerGroupId(6)),
+		..
+	}));
+	assert!(con.client_in_server_group(ClientId(3), admin));
+	assert_eq!(names(&con), vec!["Guest", "Server Admin"]);
+	// Adding twice does nothing
+	assert!(handle(&mut con, added).is_empty());
+
+	let deleted = added.replace("clientadded", "clientdeleted");
+	let events = handle(&mut con, &deleted);
+	assert!(matches!(&events[0], Event::PropertyRemoved {
+		old: PropertyValue::ServerGroupId(ServerGroupId(6)),
+		..
+	}));
+	assert!(!con.client_in_server_group(ClientId(3), admin));
+}
+
+#[test]
+fn own_client() {
+	let mut con = connection_with(&[]);
+	assert_eq!(con.own_client_id(), ClientId(2));
+	// We do not know ourselves until the server sends our client
+	assert!(con.own_client().is_none());
+	handle(&mut con, &client_enterview(3, "Other", 0));
+	assert!(con.own_client().is_none());
+	handle(&mut con, &client_enterview(2, "Me", 0));
+	assert_eq!(con.own_client().unwrap().name, "Me");
+}
+
+#[test]
+fn max_clients_flags() {
+	let list = channel_list(1, 0)
+		.replace("channel_maxclients=-1", "channel_maxclients=65535")
+		.replace("channel_maxfamilyclients=-1", "channel_maxfamilyclients=65535");
+	let con = connection_with(&[list]);
+	let channel = &con.channels[&ChannelId(1)];
+	assert_eq!(channel.max_clients, Some(MaxClients::Unlimited));
+	assert_eq!(channel.max_family_clients, Some(MaxClients::Inherited));
+}
+
+/// Create a channel with the limits and apply the command as edit.
+#[test]
+fn max_clients_round_trip() {
+	let variants = [MaxClients::Unlimited, MaxClients::Inherited, MaxClients::Limited(7)];
+	for max in &variants[..] {
+		for max_family in &variants[..] {
+			let mut con = connection_with(&[channel_list(1, 0)]);
+			let options =
+				data::ChannelOptions::new("Test").max_clients(*max).max_family_clients(*max_family);
+			let cmd = con.server.add_channel(options);
+			let content = std::str::from_utf8(cmd.0.content()).unwrap();
+			let args = content.strip_prefix("channelcreate ").unwrap();
+			let edit = format!(
+				"notifychanneledited cid=1 reasonid=10 invokerid=3 invokername=Admin \
+				 invokeruid=Admin= {}",
+				args
+			);
+			handle(&mut con, &edit);
+
+			let channel = &con.channels[&ChannelId(1)];
+			// Only the family can inherit the limit
+			let expected = if *max == MaxClients::Inherited { MaxClients::Unlimited } else { *max };
+			assert_eq!(channel.max_clients, Some(expected), "{}", content);
+			assert_eq!(channel.max_family_clients, Some(*max_family), "{}", content);
+		}
+	}
+}
+
+#[test]
+fn edit_commands() {
+	let mut con = connection_with(&[channel_list(1, 0), client_enterview(3, "Other", 0)]);
+
+	let channel = &con.channels[&ChannelId(1)];
+	assert_eq!(
+		channel.edit().set_max_family_clients(MaxClients::Inherited).to_packet().0.content(),
+		b"channeledit cid=1 channel_maxfamilyclients=-1 \
+		  channel_flag_maxfamilyclients_unlimited=0 channel_flag_maxfamilyclients_inherited=1"
+			.as_ref()
+	);
+	assert_eq!(
+		con.clients[&ClientId(3)].edit().set_description("Bot").to_packet().0.content(),
+		b"clientedit clid=3 client_description=Bot".as_ref()
+	);
+	assert_eq!(
+		con.server
+			.edit()
+			.set_hostmessage("Hi")
+			.set_hostmessage_mode(HostMessageMode::Modal)
+			.to_packet()
+			.0
+			.content(),
+		b"serveredit virtualserver_hostmessage=Hi virtualserver_hostmessage_mode=2".as_ref()
+	);
+
+	// The bookkeeping only changes when the server sends the change back
+	let cmd = channel.edit().set_topic("News").to_packet();
+	assert_ne!(channel.topic.as_deref(), Some("News"));
+	let content = std::str::from_utf8(cmd.0.content()).unwrap();
+	let edit = format!(
+		"notifychanneledited reasonid=10 invokerid=2 invokername=Me invokeruid=Me= {}",
+		content.strip_prefix("channeledit ").unwrap()
+	);
+	handle(&mut con, &edit);
+	assert_eq!(con.channels[&ChannelId(1)].topic.as_deref(), Some("News"));
+}
+
+#[test]
+fn unresolved_properties() {
+	let con = connection_with(&[channel_list(1, 0), client_enterview(3, "Other", 0)]);
+
+	let channel = &con.channels[&ChannelId(1)];
+	assert!(!channel.has_description());
+	assert_eq!(channel.description(), None);
+
+	let client = &con.clients[&ClientId(3)];
+	assert!(!client.is_fully_resolved());
+	assert_eq!(client.refresh().0.content(), b"clientgetvariables clid=3".as_ref());
+}
+
+#[test]
+fn channel_description_cache() {
+	let mut con = connection_with(&[channel_list(1, 0)]);
+	assert_eq!(
+		con.channels[&ChannelId(1)].request_description().0.content(),
+		b"channelgetdescription cid=1".as_ref()
+	);
+
+	let answer = "notifychanneledited cid=1 reasonid=10 invokerid=2 invokername=Me invokeruid=Me= \
+	              channel_description=Hello\\sworld";
+	let events = handle(&mut con, answer);
+	assert!(events.iter().any(|e| matches!(e, Event::PropertyAdded {
+		id: PropertyId::OptionalChannelData(ChannelId(1)),
+		..
+	})));
+	assert_eq!(con.channels[&ChannelId(1)].description(), Some("Hello world"));
+
+	handle(&mut con, "notifychanneldescriptionchanged cid=1");
+	assert!(!con.channels[&ChannelId(1)].has_description());
+}
+
+#[test]
+fn host_banner_changed() {
+	let mut con = connection_with(&[]);
+	assert_eq!(con.server.host_banner_gfx_url(), None);
+	assert_eq!(con.server.host_banner_interval(), None);
+
+	let edit = "notifyserveredited reasonid=10 invokerid=2 invokername=Me invokeruid=Me= \
+	            virtualserver_hostbanner_gfx_url=https:\\/\\/example.com\\/banner.png \
+	            virtualserver_hostbanner_gfx_interval=60";
+	let events = handle(&mut con, edit);
+	assert_eq!(con.server.host_banner_gfx_url(), Some("https://example.com/banner.png"));
+	assert_eq!(con.server.host_banner_interval(), Some(::time::Duration::seconds(60)));
+	let typed = events.iter().map(Event::to_typed).collect::<Vec<_>>();
+	assert!(typed.contains(&TypedEvent::HostBannerChanged {
+		property: PropertyId::ServerHostbannerGfxUrl,
+		invoker: events[0].get_invoker().cloned(),
+	}));
+}
+
+/// Answer one http request with every response.
+fn serve_http(responses: Vec<&'static str>) -> SocketAddr {
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	std::thread::spawn(move || {
+		for response in responses {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut request = [0; 1024];
+			let _ = stream.read(&mut request);
+			stream.write_all(response.as_bytes()).unwrap();
+		}
+	});
+	address
+}
+
+#[test]
+fn host_banner_download() {
+	let address = serve_http(vec![
+		"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+		"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nPNG",
+	]);
+	let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+	expect_event!(h, Event::PropertyAdded { .. }, within 100);
+	h.server().send(&format!(
+		"notifyserveredited reasonid=10 invokerid=2 invokername=Me invokeruid=Me= \
+		 virtualserver_hostbanner_gfx_url=http:\\/\\/{}\\/banner.png \
+		 virtualserver_hostbanner_gfx_interval=60",
+		address
+	));
+	expect_event!(h, Event::PropertyChanged { .. }, within 100);
+
+	let mut runtime = tokio::runtime::Runtime::new().unwrap();
+	// A failed download does not count
+	let download = h.con.download_host_banner().unwrap().unwrap();
+	assert!(runtime.block_on(download).is_err());
+	let download = h.con.download_host_banner().unwrap().unwrap();
+	assert_eq!(runtime.block_on(download).unwrap(), b"PNG");
+	assert!(h.con.download_host_banner().unwrap().is_none());
+}
+
+#[test]
+fn foreign_file_transfers() {
+	let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+	expect_event!(h, Event::PropertyAdded { .. }, within 100);
+	h.con.request_file_transfers().unwrap();
+	expect_command!(h, "ftlist", within 100);
+	let transfer = |id: usize, size: u64| {
+		format!(
+			"notifyftlist clid=5 path=files\\/virtualserver_1\\/channel_2 name=\\/file{0} \
+			 size={1} sizedone=0 serverftfid={0} sender=0",
+			id, size
+		)
+	};
+	for id in 0..MAX_RECENT_FILE_TRANSFERS + 5 {
+		h.server().send(&transfer(id, 100));
+	}
+	let last = MAX_RECENT_FILE_TRANSFERS as u16 + 4;
+	let item = h.expect_item(
+		Duration::from_millis(100),
+		|i| matches!(i, Ok(StreamItem::ForeignFileTransfer(t)) if t.id == last),
+		"the last transfer",
+	);
+	assert!(
+		matches!(
+			&item,
+			Ok(StreamItem::ForeignFileTransfer(ForeignFileTransfer {
+				client: ClientId(5),
+				channel: Some(ChannelId(2)),
+				size: 100,
+				direction: TransferDirection::Upload,
+				..
+			}))
+		),
+		"{:?}",
+		item
+	);
+
+	// Only the latest transfers are kept
+	let ids = |h: &ConnectionHarness| {
+		let server = &h.con.get_state().unwrap().server;
+		server.recent_file_transfers().map(|t| t.id).collect::<Vec<_>>()
+	};
+	assert_eq!(ids(&h), (5..=last).collect::<Vec<_>>());
+
+	// Updates are no new transfers
+	h.items.clear();
+	h.server().send(&transfer(10, 200));
+	h.advance(Duration::from_millis(100));
+	assert!(!h.items.iter().any(|i| matches!(i, Ok(StreamItem::ForeignFileTransfer(_)))));
+	assert_eq!(ids(&h), (5..=last).collect::<Vec<_>>());
+	let server = &h.con.get_state().unwrap().server;
+	assert_eq!(server.recent_file_transfers().find(|t| t.id == 10).unwrap().size, 200);
+}
+
+#[test]
+fn sync_snapshots() {
+	let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+	expect_event!(h, Event::PropertyAdded { .. }, within 100);
+	let mut h = h.into_sync();
+	let mut handle = h.con.get_handle();
+	let snapshots = h.wait(Duration::from_secs(1), handle.watch_snapshots()).unwrap();
+	assert_eq!(handle.snapshot().unwrap().server.name, "TeamSpeak ]I[ Server");
+
+	// Events publish a new snapshot
+	h.server().send(
+		"notifyserveredited reasonid=10 invokerid=2 invokername=Me invokeruid=Me= \
+		 virtualserver_name=Renamed",
+	);
+	expect_event!(h, Event::PropertyChanged { .. }, within 100);
+	assert_eq!(handle.snapshot().unwrap().server.name, "Renamed");
+	assert_eq!(snapshots.borrow().as_ref().unwrap().server.name, "Renamed");
+
+	// No snapshot while disconnected
+	h.server().send("notifyclientleftview cfid=1 ctid=0 reasonid=11 reasonmsg=Restart clid=2");
+	expect_item!(h, SyncStreamItem::DisconnectedTemporarily, within 1000);
+	assert!(handle.snapshot().is_none());
+	expect_event!(h, Event::PropertyAdded { .. }, within 1000);
+	assert!(handle.snapshot().is_some());
+}
+
+#[test]
+fn server_connection_info() {
+	let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+	expect_event!(h, Event::PropertyAdded { .. }, within 100);
+	let mut h = h.into_sync();
+	let mut handle = h.con.get_handle();
+	assert!(h.con.get_state().unwrap().server.connection_data.is_none());
+
+	// The notification arrives before the answer to the command
+	h.server().answer = false;
+	let mut info = Box::pin(handle.server_connection_info());
+	h.wait(
+		Duration::from_secs(1),
+		future::poll_fn(|cx| {
+			assert!(info.as_mut().poll(cx).is_pending());
+			Poll::Ready(())
+		}),
+	);
+	let cmd = expect_command!(h, "serverrequestconnectioninfo", within 10);
+	let code = cmd.split(' ').find_map(|a| a.strip_prefix("return_code=")).unwrap();
+	h.server().send(
+		"notifyserverconnectioninfo connection_filetransfer_bandwidth_sent=0 \
+		 connection_filetransfer_bandwidth_received=0 connection_filetransfer_bytes_sent_total=0 \
+		 connection_filetransfer_bytes_received_total=0 connection_packets_sent_total=120 \
+		 connection_bytes_sent_total=9000 connection_packets_received_total=110 \
+		 connection_bytes_received_total=8000 connection_bandwidth_sent_last_second_total=80 \
+		 connection_bandwidth_sent_last_minute_total=90 \
+		 connection_bandwidth_received_last_second_total=70 \
+		 connection_bandwidth_received_last_minute_total=60 connection_connected_time=5000 \
+		 connection_packetloss_total=0.0000 connection_ping=12.0000",
+	);
+	h.server().send(&format!("error id=0 msg=ok return_code={}", code));
+	h.wait(Duration::from_secs(1), info).unwrap();
+	assert!(h.con.get_state().unwrap().server.connection_data.is_some());
+
+	// Without bookkeeping there is nothing to store the answer in
+	let opts = ConnectOptions::new("localhost").bookkeeping(false);
+	let mut h = ConnectionHarness::new(opts, Script::new());
+	h.advance(Duration::from_millis(100));
+	assert!(matches!(h.con.server_connection_info(), Err(Error::BookkeepingDisabled)));
+}
+
+#[test]
+fn sync_throttled_commands() {
+	let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+	expect_event!(h, Event::PropertyAdded { .. }, within 100);
+	let mut h = h.into_sync();
+	let mut handle = h.con.get_handle();
+
+	// Fill the send queue while the server does not acknowledge packets
+	h.server().receiving = false;
+	let waker = futures::task::noop_waker();
+	let mut cx = Context::from_waker(&waker);
+	let mut sent = 0;
+	while h.con.poll_send_ready(&mut cx).is_ready() {
+		new_command("clientpoke").send_unthrottled(&mut h.con).unwrap();
+		sent += 1;
+	}
+	assert_eq!(h.con.get_tsproto_client().unwrap().resender.len(), sent);
+
+	// A command of a handle waits until the queue has space
+	let mut poke = Box::pin(handle.send_command(new_command("clientpoke")));
+	h.wait(
+		Duration::from_secs(1),
+		future::poll_fn(|cx| {
+			assert!(poke.as_mut().poll(cx).is_pending());
+			Poll::Ready(())
+		}),
+	);
+	h.advance(Duration::from_millis(10));
+	assert_eq!(h.con.get_tsproto_client().unwrap().resender.len(), sent);
+
+	// and is sent after the acks arrive
+	h.server().receiving = true;
+	h.wait(Duration::from_secs(1), poke).unwrap();
+	let pokes = h.server().commands.iter().filter(|c| c.starts_with("clientpoke")).count();
+	assert_eq!(pokes, sent + 1);
+}
+
+#[test]
+fn host_banner_interval() {
+	let now = Instant::now();
+	let minute = Duration::from_secs(60);
+	let last = ("https://example.com/banner.png".to_string(), now);
+	let url = last.0.as_str();
+	assert!(host_banner_due(None, url, Some(minute), now));
+	assert!(!host_banner_due(Some(&last), url, Some(minute), now + Duration::from_secs(59)));
+	assert!(host_banner_due(Some(&last), url, Some(minute), now + minute));
+	// Without an interval, the banner does not change
+	assert!(!host_banner_due(Some(&last), url, None, now + Duration::from_secs(3600)));
+	// A new url is always downloaded
+	assert!(host_banner_due(Some(&last), "https://example.com/new.png", None, now));
+}
+
+#[test]
+fn restore_state_with_bookkeeping() {
+	let con = connection_with(&[channel_list(1, 0), client_enterview(2, "TeamSpeakUser", 0)]);
+
+	let mut state = restore::DesiredState::default();
+	let mut password = None;
+	state.update(ClientId(2), b"notifyclientmoved ctid=1 reasonid=0 clid=2", &mut password);
+	let update = b"notifyclientupdated clid=2 client_nickname=TeamSpeakUser client_input_muted=1";
+	state.update(ClientId(2), update, &mut password);
+	// Already in the channel and the name did not change
+	let (cmds, failures) = state.commands(ClientId(2), Some(&con));
+	assert!(failures.is_empty());
+	let cmds = cmds.iter().map(|c| c.0.content()).collect::<Vec<_>>();
+	assert_eq!(cmds, [b"clientupdate client_input_muted=1".as_ref()]);
+
+	// The channel was deleted
+	state.update(ClientId(2), b"notifyclientmoved ctid=5 reasonid=0 clid=2", &mut password);
+	let (_, failures) = state.commands(ClientId(2), Some(&con));
+	assert_eq!(failures, [restore::RestoreFailure::ChannelNotFound(ChannelId(5))]);
+}
+
+#[test]
+fn without_bookkeeping() {
+	let opts = ConnectOptions::new("localhost").bookkeeping(false);
+	let mut h = ConnectionHarness::new(opts, Script::new());
+	h.advance(Duration::from_millis(100));
+	assert!(h.con.is_connected());
+	assert!(matches!(h.con.get_state(), Err(Error::BookkeepingDisabled)));
+	assert!(matches!(h.con.download_host_banner(), Err(Error::BookkeepingDisabled)));
+
+	// Notifications are dropped
+	h.server().send(&client_enterview(3, "Other", 0));
+	h.advance(Duration::from_millis(10));
+	assert!(h.events.is_empty(), "{:?}", h.events);
+
+	// Messages and command results are still streamed
+	h.server().send(
+		"notifyplugincmd name=my_plugin data=hi invokerid=3 invokername=Other invokeruid=T3RoZXI=",
+	);
+	expect_item!(h, StreamItem::PluginMessage(PluginMessage { .. }), within 10);
+	let handle = h.con.use_privilege_key("token").unwrap();
+	expect_command!(h, "privilegekeyuse", within 10);
+	h.expect_item(
+		Duration::from_millis(10),
+		|i| matches!(i, Ok(StreamItem::MessageResult(r, Ok(()))) if *r == handle),
+		"command result",
+	);
+
+	let packet = OutAudio::new(&AudioData::C2S { id: 0, codec: CodecType::OpusVoice, data: &[1] });
+	assert!(h.con.send_audio(packet).unwrap());
+}
+
+#[test]
+fn import_bans() {
+	// Only one command can be sent until the budget is refilled
+	let limit = flood::RateLimit { commands: 1, interval: Duration::from_secs(3600) };
+	let opts = ConnectOptions::new("localhost").bookkeeping(false).rate_limit(Some(limit));
+	let mut h = ConnectionHarness::new(opts, Script::new());
+	h.advance(Duration::from_millis(100));
+
+	let ban = |uid: &str| bans::Ban {
+		uid: Some(uid.into()),
+		reason: "spam".into(),
+		..Default::default()
+	};
+	let list = bans::BanList::new(vec![ban("a"), ban("b"), ban("c")]);
+	let now = ::time::OffsetDateTime::from_unix_timestamp(0);
+	let report = list.import(&[], &bans::ImportOptions::new(), now);
+	let sent = h.con.import_bans(report.commands).unwrap();
+	assert_eq!(sent.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [0, 1, 2]);
+	let cmd = expect_command!(h, "banadd", within 10);
+	assert!(cmd.contains("uid=a"), "{}", cmd);
+	h.advance(Duration::from_millis(100));
+	assert!(h.server().commands.is_empty());
+	assert_eq!(h.con.command_queue_len().unwrap(), 2);
+
+	// The connection drops, resume at the first ban which was not added
+	let added = |handle: MessageHandle| {
+		h.items
+			.iter()
+			.any(|i| matches!(i, Ok(StreamItem::MessageResult(r, Ok(()))) if *r == handle))
+	};
+	let cursor = sent.iter().find(|(_, handle)| !added(*handle)).map(|(i, _)| *i);
+	assert_eq!(cursor, Some(1));
+	let report = list.import(&[], &bans::ImportOptions::new().cursor(1), now);
+	assert_eq!(report.commands.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [1, 2]);
+	assert_eq!(report.cursor, 3);
+}
+
+#[test]
+fn internal_commands_not_throttled() {
+	let limit = flood::RateLimit { commands: 1, interval: Duration::from_secs(3600) };
+	let opts = ConnectOptions::new("localhost").rate_limit(Some(limit)).input_muted(true);
+	let mut h = ConnectionHarness::new(opts, Script::new());
+	// Both commands after connecting are sent, although the limit allows one
+	expect_command!(h, "servergrouplist", within 100);
+	expect_command!(h, "clientupdate", within 10);
+	assert_eq!(h.con.command_queue_len().unwrap(), 0);
+
+	// They still count towards the limit
+	h.con.send_command(new_command("clientpoke")).unwrap();
+	assert_eq!(h.con.command_queue_len().unwrap(), 1);
+}
+
+#[test]
+fn action_policy_paths() {
+	let policy = policy::ActionPolicy::new()
+		.set(policy::ActionKind::DeleteChannel, policy::Category::Denied);
+	let opts = ConnectOptions::new("localhost").action_policy(policy);
+	let mut h = ConnectionHarness::new(opts, Script::new());
+	expect_event!(h, Event::PropertyAdded { .. }, within 100);
+	let delete = || {
+		let mut cmd = new_command("channeldelete");
+		cmd.write_arg("cid", &5);
+		cmd.write_arg("force", &0);
+		cmd
+	};
+	let denied = |r: Result<MessageHandle>| matches!(r, Err(Error::PolicyDenied(_)));
+
+	assert!(denied(h.con.send_command(delete())));
+	assert!(denied(h.con.send_checked_command(delete(), false, false)));
+	let list = h.con.send_list_command::<bans::Ban>(delete()).now_or_never();
+	assert!(matches!(list, Some(Err(Error::PolicyDenied(_)))));
+	for _ in 0..3 {
+		expect_item!(h, StreamItem::PolicyDecision(_), within 10);
+	}
+
+	// Commands of a sync handle
+	let mut h = h.into_sync();
+	let mut handle = h.con.get_handle();
+	let res = h.wait(Duration::from_secs(1), handle.edit(move |_| Ok(delete())));
+	assert!(matches!(res, Err(Error::PolicyDenied(_))), "{:?}", res);
+	h.advance(Duration::from_millis(10));
+	assert!(!h.server().commands.iter().any(|c| c.starts_with("channeldelete")));
+}
+
+/// The answers of lists are collected by their return code.
+#[test]
+fn list_answers() {
+	let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+	expect_event!(h, Event::PropertyAdded { .. }, within 100);
+	h.server().answer = false;
+	let return_code = |cmd: String| {
+		cmd.split(' ').find_map(|a| a.strip_prefix("return_code=")).unwrap().to_string()
+	};
+
+	let bans = h.con.list_bans();
+	let bans_code = return_code(expect_command!(h, "banlist", within 10));
+	let channels = h.con.find_channels("Gam");
+	let channels_code = return_code(expect_command!(h, "channelfind", within 10));
+	let complaints = h.con.list_complaints(None);
+	let complaints_code = return_code(expect_command!(h, "complainlist", within 10));
+
+	// The ban list is split and other notifications arrive in between
+	h.server().send(&format!(
+		"notifybanlist banid=1 ip name=Bad uid reason=spam created=100 duration=0 return_code={}",
+		bans_code
+	));
+	h.server()
+		.send(&format!("notifychannelfind cid=2 channel_name=Games return_code={}", channels_code));
+	h.server().send(&client_enterview(3, "Other", 0));
+	h.server().send(&format!(
+		"notifybanlist banid=2 ip=1.2.3.4 name uid reason created=200 duration=60 return_code={}",
+		bans_code
+	));
+	h.server().send(&format!("error id=0 msg=ok return_code={}", channels_code));
+	h.server().send(&format!("error id=0 msg=ok return_code={}", bans_code));
+	// An empty list is answered with an error
+	h.server().send(&format!(
+		"error id=1281 msg=database\\sempty\\sresult\\sset return_code={}",
+		complaints_code
+	));
+
+	let bans = h.wait(Duration::from_secs(1), bans).unwrap();
+	assert_eq!(bans.iter().map(|b| b.id).collect::<Vec<_>>(), [Some(1), Some(2)]);
+	let channels = h.wait(Duration::from_secs(1), channels).unwrap();
+	assert_eq!(channels, [(ChannelId(2), "Games".to_string())]);
+	assert!(h.wait(Duration::from_secs(1), complaints).unwrap().is_empty());
+	assert!(h.con.get_state().unwrap().clients.contains_key(&ClientId(3)));
+	assert!(!h.items.iter().any(|i| matches!(i, Ok(StreamItem::MessageResult(..)))));
+
+	// A list which is never finished times out
+	let keys = h.con.list_privilege_keys();
+	expect_command!(h, "privilegekeylist", within 10);
+	let res = h.wait(Duration::from_secs(31), keys);
+	assert!(matches!(res, Err(Error::Timeout)), "{:?}", res);
+}
+
+#[test]
+fn own_client_commands() {
+	let con = connection_with(&[]);
+	assert_eq!(
+		con.client_update().set_name("Bot").to_packet().0.content(),
+		b"clientupdate client_nickname=Bot".as_ref()
+	);
+	assert_eq!(
+		con.client_update().set_away(Some("Lunch")).to_packet().0.content(),
+		b"clientupdate client_away=1 client_away_message=Lunch".as_ref()
+	);
+	let back = con.client_update().set_away(None).to_packet();
+	assert!(back.0.content().starts_with(b"clientupdate client_away=0"));
+}
+
+#[test]
+fn client_flags() {
+	let opts = ConnectOptions::new("localhost");
+	assert!(!opts.get_input_muted());
+	assert!(opts.get_input_hardware());
+	let opts = opts.input_muted(true).output_hardware(false);
+	assert!(opts.get_input_muted());
+	assert!(!opts.get_output_hardware());
+
+	// Sent after connecting and when changed
+	let mut h = ConnectionHarness::new(opts, Script::new());
+	let cmd = expect_command!(h, "clientupdate", within 100);
+	assert!(cmd.contains("client_input_muted=1"), "{}", cmd);
+	h.con.set_output_muted(true).unwrap();
+	let cmd = expect_command!(h, "clientupdate", within 10);
+	assert!(cmd.contains("client_output_muted=1"), "{}", cmd);
+
+	// Restored after reconnecting
+	h.server().send("notifyclientupdated clid=2 client_output_muted=1");
+	h.advance(Duration::from_millis(10));
+	assert_eq!(h.con.desired_state().output_muted, Some(true));
+}
+
+#[test]
+fn edit_own_client() {
+	let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+	expect_event!(h, Event::PropertyAdded { .. }, within 100);
+	let edit = h.con.edit_own_client().away(Some("Lunch")).input_muted(true);
+	edit.output_hardware(false).send().unwrap();
+	let cmd = expect_command!(h, "clientupdate", within 10);
+	for arg in &[
+		"client_away=1",
+		"client_away_message=Lunch",
+		"client_input_muted=1",
+		"client_output_hardware=0",
+	] {
+		assert!(cmd.contains(arg), "{}", cmd);
+	}
+	assert!(!cmd.contains("client_nickname"), "{}", cmd);
+	assert!(!cmd.contains("client_output_muted"), "{}", cmd);
+}
+
+#[test]
+fn commander_and_priority_speaker() {
+	let mut con = connection_with(&[client_enterview(3, "Other", 0)]);
+	let client = &con.clients[&ClientId(3)];
+	assert!(!client.is_channel_commander());
+	assert!(!client.is_priority_speaker());
+
+	let msg =
+		"notifyclientupdated clid=3 client_is_channel_commander=1 client_is_priority_speaker=1";
+	let events = handle(&mut con, msg);
+	assert_eq!(events.len(), 2);
+	let client = &con.clients[&ClientId(3)];
+	assert!(client.is_channel_commander());
+	assert!(client.is_priority_speaker());
+
+	let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+	expect_event!(h, Event::PropertyAdded { .. }, within 100);
+	h.con.set_channel_commander(true).unwrap();
+	let cmd = expect_command!(h, "clientupdate", within 10);
+	assert!(cmd.contains("client_is_channel_commander=1"), "{}", cmd);
+	h.con.set_priority_speaker(ClientId(2), true).unwrap();
+	let cmd = expect_command!(h, "clientedit", within 10);
+	assert!(cmd.contains("clid=2 client_is_priority_speaker=1"), "{}", cmd);
+}
+
+#[test]
+fn client_meta_data() {
+	let mut con = connection_with(&[client_enterview(3, "Other", 0)]);
+	assert_eq!(con.clients[&ClientId(3)].meta_data(), "");
+
+	let events = handle(&mut con, r#"notifyclientupdated clid=3 client_meta_data={"state":\s1}"#);
+	assert!(events.iter().any(|e| matches!(e, Event::PropertyChanged {
+		id: PropertyId::ClientMetaData(ClientId(3)),
+		..
+	})));
+	assert_eq!(con.clients[&ClientId(3)].meta_data(), r#"{"state": 1}"#);
+
+	let opts = ConnectOptions::new("localhost").meta_data("x".repeat(MAX_COMMAND_LEN));
+	assert!(matches!(Connection::new(opts), Err(Error::CommandTooLong(_))));
+}
+
+#[test]
+fn local_address_scope() {
+	let server_v4 = "192.0.2.1:9987".parse().unwrap();
+	let server_v6 = SocketAddrV6::new("fe80::1".parse().unwrap(), 9987, 0, 3).into();
+	assert_eq!(local_address_for(None, server_v4), "0.0.0.0:0".parse().unwrap());
+	assert_eq!(local_address_for(None, server_v6), "[::]:0".parse().unwrap());
+
+	// A link-local address gets the scope of the server
+	let local = SocketAddrV6::new("fe80::2".parse().unwrap(), 0, 0, 0);
+	let bound = local_address_for(Some(local.into()), server_v6);
+	assert!(matches!(bound, SocketAddr::V6(a) if a.scope_id() == 3));
+	let local = SocketAddrV6::new("fe80::2".parse().unwrap(), 0, 0, 5).into();
+	assert_eq!(local_address_for(Some(local), server_v6), local);
+}
+
+#[test]
+fn bind_fails_before_resolving() {
+	let options = ConnectOptions::new("does-not-resolve.invalid").bind_device("no-such-device");
+	assert!(matches!(Connection::check_bind(&options), Err(Error::Bind { .. })));
+	let options = ConnectOptions::new("does-not-resolve.invalid");
+	assert!(Connection::check_bind(&options).is_ok());
+}
+
+#[test]
+fn bind_error_classification() {
+	let address = "192.0.2.1:0".parse().unwrap();
+	let bind = |source| Error::Bind { address, source };
+	let missing = std::io::Error::from(std::io::ErrorKind::AddrNotAvailable);
+	assert!(bind(missing).is_temporary());
+	let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+	assert!(!bind(denied).is_temporary());
+	let unsupported = std::io::Error::new(std::io::ErrorKind::Other, "unsupported");
+	assert!(!bind(unsupported).is_temporary());
+	#[cfg(target_os = "linux")]
+	assert!(bind(std::io::Error::from_raw_os_error(libc::ENODEV)).is_temporary());
+}
+
+#[test]
+fn move_with_password() {
+	let con = connection_with(&[channel_list(1, 0), client_enterview(2, "Me", 0)]);
+	let client = &con.clients[&con.own_client];
+	assert_eq!(client.move_to(ChannelId(1), None).0.content(), b"clientmove clid=2 cid=1".as_ref());
+	assert_eq!(
+		client.move_to(ChannelId(1), Some("secret pw")).0.content(),
+		br#"clientmove clid=2 cid=1 cpw=secret\spw"#.as_ref()
+	);
+}
+
+#[test]
+fn join_channel_password() {
+	let opts = ConnectOptions::new("localhost").channel(r"Music/AC\/DC").channel_password("pw");
+	let script = Script::new()
+		.at(0, channel_list_full(1, 0, "Music", 0))
+		.at(0, channel_list_full(2, 1, r"AC\/DC", 0))
+		.at(0, channel_list_full(3, 0, "Lobby", 0))
+		.at(0, client_enterview(2, "Me", 0));
+	let mut h = ConnectionHarness::new(opts, script);
+	expect_event!(h, Event::PropertyAdded { id: PropertyId::Client(_), .. }, within 100);
+	let join = |con: &Connection, spec, password| {
+		let (id, cmd) = con.join_channel_command(spec, password).unwrap();
+		(id, String::from_utf8(cmd.0.content().to_vec()).unwrap())
+	};
+
+	// The password from the options is used for the configured channel
+	let configured = (ChannelId(2), "clientmove clid=2 cid=2 cpw=pw".to_string());
+	assert_eq!(join(&h.con, ChannelSpec::Path(r"/Music/AC\/DC"), None), configured);
+	assert_eq!(join(&h.con, ChannelSpec::Id(ChannelId(2)), None), configured);
+	// but not for other channels
+	let lobby = join(&h.con, ChannelSpec::Name("lob"), None);
+	assert_eq!(lobby, (ChannelId(3), "clientmove clid=2 cid=3".to_string()));
+	// A given password is preferred
+	let given = join(&h.con, ChannelSpec::Id(ChannelId(2)), Some("other"));
+	assert_eq!(given.1, "clientmove clid=2 cid=2 cpw=other");
+
+	// Channel ids in the options are resolved as well
+	let options = ConnectOptions::new("localhost").channel_id(ChannelId(3)).channel_password("x");
+	h.con.options = Arc::new(options);
+	let lobby = join(&h.con, ChannelSpec::Path("Lobby"), None);
+	assert_eq!(lobby.1, "clientmove clid=2 cid=3 cpw=x");
+}
+
+#[test]
+fn changed_event_values() {
+	let mut con = connection_with(&[client_enterview(3, "Other", 0)]);
+	let events = handle(&mut con, "notifyclientupdated clid=3 client_nickname=Renamed");
+	assert_eq!(events.len(), 1);
+	match &events[0] {
+		Event::PropertyChanged { id, old, new, .. } => {
+			assert_eq!(*id, PropertyId::ClientName(ts_bookkeeping::ClientId(3)));
+			assert_eq!(*old, PropertyValue::String("Other".into()));
+			assert_eq!(*new, PropertyValue::String("Renamed".into()));
+		}
+		e => panic!("Expected changed property, got {:?}", e),
+	}
+}
+
+#[test]
+fn typed_events() {
+	let script = Script::new()
+		.at(0, channel_list(1, 0))
+		.at(0, channel_list(2, 0))
+		.at(10, client_enterview(3, "Other", 0))
+		.at(20, "notifyclientmoved ctid=2 reasonid=1 invokerid=2 invokername=Me clid=3")
+		.at(30, "notifyclientupdated clid=3 client_nickname=Renamed")
+		.at(40, "notifyclientpoke invokerid=3 invokername=Renamed invokeruid=T3RoZXI= msg=Hi")
+		.at(50, "notifyclientleftview cfid=2 ctid=0 reasonid=5 invokerid=2 invokername=Me clid=3");
+	let mut h = Harness::new(script);
+	h.advance(Duration::from_millis(60));
+	let events = h.typed.drain(..).collect::<Vec<_>>();
+	let client = ts_bookkeeping::ClientId(3);
+
+	assert!(matches!(events[0], TypedEvent::ChannelAdded { channel: ChannelId(1), .. }));
+	assert!(matches!(events[1], TypedEvent::ChannelAdded { channel: ChannelId(2), .. }));
+	assert!(matches!(events[2], TypedEvent::ClientAdded { client: c, .. } if c == client));
+	match &events[3] {
+		TypedEvent::ClientMoved { client: c, from, to, reason, invoker } => {
+			assert_eq!((*c, *from, *to), (client, ChannelId(1), ChannelId(2)));
+			assert_eq!(reason.map(|r| r as u8), Some(1));
+			assert_eq!(invoker.as_ref().unwrap().name, "Me");
+		}
+		e => panic!("Expected moved client, got {:?}", e),
+	}
+	assert_eq!(events[4], TypedEvent::PropertyChanged {
+		property: PropertyId::ClientName(client),
+		old: PropertyValue::String("Other".into()),
+		new: PropertyValue::String("Renamed".into()),
+		invoker: None,
+	});
+	match &events[5] {
+		TypedEvent::Poked { invoker, message } => {
+			assert_eq!(invoker.id, client);
+			assert_eq!(message, "Hi");
+		}
+		e => panic!("Expected poke, got {:?}", e),
+	}
+	match &events[6] {
+		TypedEvent::ClientRemoved { client: c, reason, invoker } => {
+			assert_eq!(c.name, "Renamed");
+			assert_eq!(reason.map(|r| r as u8), Some(5));
+			assert_eq!(invoker.as_ref().unwrap().id, ts_bookkeeping::ClientId(2));
+		}
+		e => panic!("Expected removed client, got {:?}", e),
+	}
+	assert_eq!(events.len(), 7);
+}
+
+#[test]
+fn disconnect_reason() {
+	let reason = |msg: &str| DisconnectReason::from_message(ClientId(2), &parse_msg(msg));
+	// Other clients do not matter
+	assert_eq!(reason("notifyclientleftview cfid=1 ctid=0 reasonid=5 clid=3"), None);
+	assert_eq!(reason("notifyclientleftview cfid=1 ctid=2 reasonid=4 clid=2"), None);
+	assert_eq!(
+		reason("notifyclientleftview cfid=1 ctid=0 reasonid=8 clid=2"),
+		Some(DisconnectReason::Client)
+	);
+	match reason(
+		"notifyclientleftview cfid=1 ctid=0 reasonid=5 invokerid=3 invokername=Admin \
+		 reasonmsg=Bye clid=2",
+	) {
+		Some(DisconnectReason::Kicked { invoker, message }) => {
+			assert_eq!(invoker.unwrap().name, "Admin");
+			assert_eq!(message.as_deref(), Some("Bye"));
+		}
+		r => panic!("Expected kick, got {:?}", r),
+	}
+	match reason("notifyclientleftview cfid=1 ctid=0 reasonid=6 bantime=60 clid=2") {
+		Some(DisconnectReason::Banned { duration, .. }) => {
+			assert_eq!(duration, Some(::time::Duration::seconds(60)));
+		}
+		r => panic!("Expected ban, got {:?}", r),
+	}
+	match reason("notifyclientleftview cfid=1 ctid=0 reasonid=6 bantime=0 clid=2") {
+		Some(r @ DisconnectReason::Banned { duration: None, .. }) => assert!(r.is_removal()),
+		r => panic!("Expected permanent ban, got {:?}", r),
+	}
+	assert_eq!(
+		reason("notifyclientleftview cfid=1 ctid=0 reasonid=11 reasonmsg=Restart clid=2"),
+		Some(DisconnectReason::ServerShutdown { message: Some("Restart".into()) })
+	);
+	assert!(!DisconnectReason::ServerShutdown { message: None }.is_removal());
+}
+
+#[test]
+fn reconnect_after_disconnect_reason() {
+	let connect = || {
+		let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+		expect_event!(h, Event::PropertyAdded { .. }, within 100);
+		h
+	};
+	let attempts = |h: &ConnectionHarness| h.connector.lock().unwrap().attempts;
+
+	// Kicked
+	let mut h = connect();
+	h.server().send(
+		"notifyclientleftview cfid=1 ctid=0 reasonid=5 invokerid=3 invokername=Admin \
+		 invokeruid=Admin= reasonmsg=Bye clid=2",
+	);
+	h.expect_end(Duration::from_secs(1));
+	assert!(matches!(h.con.disconnect_reason(), Some(DisconnectReason::Kicked { .. })));
+	h.advance(Duration::from_secs(10));
+	assert_eq!(attempts(&h), 1);
+
+	// Banned for a minute
+	let mut h = connect();
+	h.server().send(
+		"notifyclientleftview cfid=1 ctid=0 reasonid=6 invokerid=3 invokername=Admin \
+		 invokeruid=Admin= bantime=60 clid=2",
+	);
+	h.expect_end(Duration::from_secs(1));
+	match h.con.disconnect_reason() {
+		Some(DisconnectReason::Banned { duration, .. }) => {
+			assert_eq!(*duration, Some(::time::Duration::seconds(60)));
+		}
+		r => panic!("Expected ban, got {:?}", r),
+	}
+	h.advance(Duration::from_secs(10));
+	assert_eq!(attempts(&h), 1);
+
+	// The server shuts down
+	let mut h = connect();
+	h.server().send("notifyclientleftview cfid=1 ctid=0 reasonid=11 reasonmsg=Restart clid=2");
+	expect_item!(h, StreamItem::DisconnectedTemporarily, within 1000);
+	expect_event!(h, Event::PropertyAdded { .. }, within 1000);
+	assert_eq!(attempts(&h), 2);
+	assert!(!h.ended);
+	assert_eq!(h.con.disconnect_reason(), None);
+}
+
+#[test]
+fn error_classification() {
+	// connect_failed_banned
+	let banned = CommandError {
+		id: TsError::from_u32(0xd01).unwrap(),
+		message: "connection failed, you are banned".into(),
+		extra_message: Some("You may retry in 600 seconds.".into()),
+		failed_permission: None,
+		return_code: None,
+	};
+	assert!(banned.id.is_ban());
+	let e = Error::ConnectTs(banned.clone());
+	assert!(e.is_temporary());
+	assert_eq!(e.retry_after(), Some(Duration::from_secs(600)));
+
+	let permanent = Error::ConnectTs(CommandError { extra_message: None, ..banned.clone() });
+	assert!(!permanent.is_temporary());
+	assert_eq!(permanent.retry_after(), None);
+
+	assert!(Error::InitserverTimeout.is_temporary());
+	assert!(!Error::IdentityLevel(30).is_temporary());
+	let failed = Error::ConnectionFailed {
+		address: "localhost".into(),
+		errors: vec![Error::IdentityLevel(30), e],
+	};
+	assert!(failed.is_temporary());
+	assert_eq!(failed.retry_after(), Some(Duration::from_secs(600)));
+
+	// Only the message of the server is parsed
+	let other = CommandError { extra_message: Some("Wait 3 seconds".into()), ..banned };
+	assert_eq!(other.retry_after(), None);
+	// The server does not tell how long to wait after flooding
+	let flooding = CommandError {
+		id: TsError::ClientIsFlooding,
+		message: "client is flooding".into(),
+		extra_message: None,
+		failed_permission: None,
+		return_code: None,
+	};
+	assert_eq!(flooding.retry_after(), None);
+}
+
+#[test]
+fn server_uid_mismatch() {
+	let key = EccKeyPrivP256::create().unwrap().to_pub();
+	let actual = Uid(key.get_uid_no_base64().unwrap());
+	let e = Error::ServerUidMismatch { expected: Uid(vec![0; 20]), actual };
+	assert!(!e.is_temporary());
+	let msg = e.to_string();
+	assert!(msg.contains(&key.get_uid().unwrap()), "{}", msg);
+	assert!(msg.contains("AAAAAAAAAAAAAAAAAAAAAAAAAAA="), "{}", msg);
+}
+
+#[test]
+fn name_collision() {
+	let number = NameCollisionStrategy::AppendNumber;
+	assert_eq!(number.get_name("Bot", 0).unwrap(), "Bot");
+	assert_eq!(number.get_name("Bot", 1).unwrap(), "Bot1");
+	assert_eq!(number.get_name("Bot", NAME_RETRIES).unwrap(), format!("Bot{}", NAME_RETRIES));
+	assert_eq!(number.get_name("Bot", NAME_RETRIES + 1), None);
+	assert_eq!(NameCollisionStrategy::Fail.get_name("Bot", 1), None);
+
+	// The suffix replaces the end of long names
+	let long = "ä".repeat(MAX_NAME_LEN);
+	let name = number.get_name(&long, 2).unwrap();
+	assert_eq!(name.chars().count(), MAX_NAME_LEN);
+	assert!(name.ends_with("ä2"));
+
+	let random = NameCollisionStrategy::AppendRandom.get_name("Bot", 1).unwrap();
+	assert!(random.starts_with("Bot"));
+	assert!(random[3..].parse::<u16>().is_ok(), "{}", random);
+}
+
+#[tokio::test]
+async fn initserver_timeout() {
+	time::pause();
+	let logger = get_logger();
+	// A server which never sends initserver after the handshake
+	let (mut client, _server) = mock_connection(&logger);
+	let timeout = Duration::from_millis(100);
+	let (res, ()) = future::join(
+		Connection::wait_initserver_timeout(&logger, &mut client, timeout),
+		time::advance(timeout),
+	)
+	.await;
+	assert!(matches!(res, Err(Error::InitserverTimeout)), "{:?}", res.err());
+}
+
+#[test]
+fn connect_shares_options() {
+	let identity = Identity::create_with_level(0).unwrap();
+	let opts = ConnectOptions::new("127.0.0.1:1").logger(get_logger()).identity(identity);
+	let con = Connection::new(opts).unwrap();
+	// The connect future holds the second reference
+	assert_eq!(Arc::strong_count(&con.options), 2);
+	assert!(con.get_options().get_identity().is_some());
+}
+
+/// Creating the identities of multiple connections does not block the
+/// executor thread.
+#[tokio::test]
+async fn create_identity_blocking() {
+	let logger = get_logger();
+	let mut cons = (0..20)
+		.map(|_| {
+			let opts = ConnectOptions::new("127.0.0.1:1").logger(logger.clone());
+			Connection::new(opts).unwrap()
+		})
+		.collect::<Vec<_>>();
+	// The uid is known before the level is computed
+	let uid = cons[0].own_uid().unwrap();
+
+	// The test runtime has a single thread, so the ticker only runs if the
+	// identities are created on the blocking thread pool.
+	let ticks = Arc::new(AtomicUsize::new(0));
+	let ticker_ticks = ticks.clone();
+	tokio::spawn(async move {
+		loop {
+			ticker_ticks.fetch_add(1, Ordering::SeqCst);
+			tokio::task::yield_now().await;
+		}
+	});
+
+	let items = cons.iter_mut().map(|con| async move { con.events().next().await });
+	for item in future::join_all(items).await {
+		assert!(matches!(item, Some(Ok(StreamItem::IdentityLevelIncreased))));
+	}
+	assert!(ticks.load(Ordering::SeqCst) > 0);
+	for con in &cons {
+		assert!(con.get_identity().level().unwrap() >= 8);
+	}
+	assert_eq!(cons[0].own_uid().unwrap(), uid);
+}
+
+#[test]
+fn own_uid() {
+	// The uid of this key is shown by the official client
+	let key = EccKeyPrivP256::from_ts(
+		"MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54a\
+		eDr0OP1ITsC/50CIA8M5nmDBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI",
+	)
+	.unwrap();
+	let opts =
+		ConnectOptions::new("127.0.0.1:1").logger(get_logger()).identity(Identity::new(key, 0));
+	let con = Connection::new(opts).unwrap();
+	assert_eq!(con.own_uid().unwrap().to_string(), "lks7QL5OVMKo4pZ79cEOI5r5oEA=");
+}
+
+#[tokio::test]
+async fn shutdown_stops_identity_thread() {
+	let identity = Identity::create().unwrap();
+	let opts = ConnectOptions::new("127.0.0.1:1").logger(get_logger()).identity(identity);
+	let mut con = Connection::new(opts).unwrap();
+	con.increase_identity_level(20).unwrap();
+	let shutdown = con.shutdown(DisconnectOptions::new());
+	tokio::time::timeout(Duration::from_secs(10), shutdown).await.unwrap().unwrap();
+}
+
+#[test]
+fn log_config() {
+	let opts = ConnectOptions::new("localhost");
+	assert!(!opts.get_log_config().is_enabled());
+
+	let config = opts.clone().log_commands(true).log_incoming_packets(true).get_log_config();
+	assert_eq!(config, LogConfig { incoming: Verbosity::Packets, outgoing: Verbosity::Commands });
+
+	let config = opts.clone().log_packets(true).get_log_config();
+	assert_eq!(config, LogConfig::new(Verbosity::Packets));
+
+	let config = opts.log_udp_packets(true).log_outgoing_packets(true).get_log_config();
+	assert_eq!(config, LogConfig::new(Verbosity::UdpPackets));
+}
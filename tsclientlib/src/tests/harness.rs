@@ -0,0 +1,803 @@
+//! A deterministic harness for tests that feed server notifications to a
+//! connection.
+//!
+//! A [`Script`] declares notifications at virtual timestamps. The [`Harness`]
+//! owns a virtual clock, which delivers the notifications and runs the timers
+//! of the client. Time only advances in steps of one millisecond, so every
+//! run produces the same interleaving of notifications and client timers.
+//!
+//! Notifications with the same timestamp are delivered in script order. With
+//! [`Harness::with_seed`], they are shuffled instead, the same seed always
+//! results in the same order.
+//!
+//! New tests which depend on timing should use this pattern:
+//!
+//! ```ignore
+//! let script = Script::new()
+//!     .at(10, client_enterview(3, "Other", 0))
+//!     .at(50, "notifyclientupdated clid=3 client_nickname=Renamed");
+//! let mut h = Harness::new(script).timer(|now, con| { /* Commands sent by the client */ vec![] });
+//! expect_event!(h, Event::PropertyAdded { .. }, within 20);
+//! expect_command!(h, "clientupdate", within 1000);
+//! ```
+//!
+//! Tests of a whole [`Connection`] use the [`ConnectionHarness`] instead. It
+//! connects to a [`MockServer`] over an in-memory socket and runs tokio with
+//! a paused clock, which advances in the same steps. The script is sent by
+//! the server, the commands of the client are received by the server.
+//!
+//! ```ignore
+//! let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), script);
+//! expect_event!(h, Event::PropertyAdded { .. }, within 20);
+//! let cmd = expect_command!(h, "servergrouplist", within 0);
+//! h.server().send("notifyclientupdated clid=3 client_nickname=Renamed");
+//! expect_item!(h, StreamItem::TypedEvents(_), within 10);
+//! ```
+//!
+//! [`ConnectionHarness::into_sync`] drives a [`SyncConnection`] instead, its
+//! handles are answered with [`ConnectionHarness::wait`].
+//!
+//! [`Script`]: struct.Script.html
+//! [`Harness`]: struct.Harness.html
+//! [`Harness::with_seed`]: struct.Harness.html#method.with_seed
+//! [`Connection`]: ../../struct.Connection.html
+//! [`ConnectionHarness`]: struct.ConnectionHarness.html
+//! [`MockServer`]: struct.MockServer.html
+//! [`ConnectionHarness::into_sync`]: struct.ConnectionHarness.html#method.into_sync
+//! [`SyncConnection`]: ../../sync/struct.SyncConnection.html
+//! [`ConnectionHarness::wait`]: struct.ConnectionHarness.html#method.wait
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use itertools::Itertools;
+use num_traits::ToPrimitive;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use slog::{o, Logger};
+use tokio::runtime::{self, Runtime};
+use tokio::time;
+use ts_bookkeeping::data;
+use ts_bookkeeping::events::{Event, TypedEvent};
+use tsproto::client;
+use tsproto::connection::{
+	ConnectedParams, Connection as ProtoConnection, Socket, StreamItem as ProtoStreamItem,
+};
+use tsproto::resend::{PartialPacketId, ResenderState};
+use tsproto_packets::packets::{Direction, Flags, OutCommand, OutPacket, PacketType};
+use tsproto_types::crypto::{EccKeyPrivP256, EccKeyPubP256};
+
+use super::{connection_with, get_logger, parse_msg};
+use crate::sync::{SyncConnection, SyncStreamItem};
+use crate::{
+	ConnectFuture, ConnectOptions, Connection, ConnectionState, Connector, Result, StreamItem,
+};
+
+/// Wait until an event matches the pattern, at most the given number of
+/// milliseconds in virtual time.
+macro_rules! expect_event {
+	($h:expr, $pat:pat, within $ms:expr) => {
+		$h.expect_event(
+			std::time::Duration::from_millis($ms),
+			|e| matches!(e, $pat),
+			stringify!($pat),
+		)
+	};
+}
+
+/// Wait until the client sends a command with this name, at most the given
+/// number of milliseconds in virtual time.
+macro_rules! expect_command {
+	($h:expr, $name:expr, within $ms:expr) => {
+		$h.expect_command($name, std::time::Duration::from_millis($ms))
+	};
+}
+
+/// Wait until the connection returns a stream item which matches the pattern,
+/// at most the given number of milliseconds in virtual time.
+macro_rules! expect_item {
+	($h:expr, $pat:pat, within $ms:expr) => {
+		$h.expect_item(
+			std::time::Duration::from_millis($ms),
+			|i| matches!(i, Ok($pat)),
+			stringify!($pat),
+		)
+	};
+}
+
+/// The virtual time which passes in every step.
+const STEP: Duration = Duration::from_millis(1);
+
+/// The id of our own client, the same as in the `initserver` of the tests.
+const OWN_CLIENT_ID: u16 = 2;
+
+/// A timer of the client, it gets the current virtual time and returns the
+/// commands which should be sent.
+type ClientTimer = Box<dyn FnMut(Instant, &data::Connection) -> Vec<OutCommand>>;
+
+/// A connection which the [`ConnectionHarness`] can drive.
+///
+/// [`ConnectionHarness`]: struct.ConnectionHarness.html
+pub(crate) trait DrivenConnection: DerefMut<Target = Connection> {
+	type Item: fmt::Debug;
+
+	fn poll_item(&mut self, cx: &mut Context) -> Poll<Option<Self::Item>>;
+
+	/// Split the events from other stream items.
+	fn into_events(item: Self::Item) -> std::result::Result<Vec<Event>, Self::Item>;
+}
+
+/// Something which runs in steps of virtual time.
+pub(crate) trait VirtualTime {
+	/// The virtual time since the start.
+	fn elapsed(&self) -> Duration;
+
+	/// Run everything which is due and advance the virtual time by one step.
+	fn step(&mut self);
+
+	/// Run steps for this time.
+	fn advance(&mut self, time: Duration) {
+		let end = self.elapsed() + time;
+		while self.elapsed() < end {
+			self.step();
+		}
+	}
+
+	/// Run steps until `f` returns something, at most for the given virtual
+	/// time.
+	fn run_until<T, F: FnMut(&mut Self) -> Option<T>>(
+		&mut self, within: Duration, mut f: F,
+	) -> Option<T> {
+		let end = self.elapsed() + within;
+		loop {
+			if let Some(r) = f(self) {
+				return Some(r);
+			}
+			if self.elapsed() > end {
+				return None;
+			}
+			self.step();
+		}
+	}
+}
+
+/// Notifications which the server sends at virtual timestamps.
+#[derive(Default)]
+pub(crate) struct Script {
+	entries: Vec<(Duration, String)>,
+}
+
+pub(crate) struct Harness {
+	logger: Logger,
+	pub con: data::Connection,
+	start: Instant,
+	/// The virtual time since the start.
+	now: Duration,
+	/// Notifications which were not yet delivered, sorted by time.
+	pending: VecDeque<(Duration, String)>,
+	timers: Vec<ClientTimer>,
+	/// Events which were not yet checked.
+	pub events: VecDeque<Event>,
+	/// The typed events which were not yet checked.
+	pub typed: VecDeque<TypedEvent>,
+	/// Commands which were sent by the client and not yet checked.
+	pub commands: VecDeque<OutCommand>,
+}
+
+#[derive(Default)]
+struct MockSocketState {
+	/// The packets which were not yet received, indexed by the receiving side.
+	buffers: [VecDeque<Vec<u8>>; 2],
+	wakers: [Option<Waker>; 2],
+}
+
+/// One side of an in-memory udp connection.
+struct MockSocket {
+	state: Arc<Mutex<MockSocketState>>,
+	side: usize,
+	local: SocketAddr,
+	peer: SocketAddr,
+}
+
+/// The server side of a connection, which skips the handshake.
+pub(crate) struct MockServer {
+	con: ProtoConnection,
+	/// Answer every command which has a return code with `ok`.
+	pub answer: bool,
+	/// If packets of the client are received and acknowledged, otherwise
+	/// they wait in the socket.
+	pub receiving: bool,
+	/// Commands which were sent by the client and not yet checked.
+	pub commands: VecDeque<String>,
+}
+
+/// Creates a new [`MockServer`] every time the [`Connection`] connects.
+///
+/// [`MockServer`]: struct.MockServer.html
+/// [`Connection`]: ../../struct.Connection.html
+#[derive(Default)]
+pub(crate) struct MockConnector {
+	/// How often the connection tried to connect.
+	pub attempts: u32,
+	/// Servers of new connections, which were not yet picked up.
+	servers: VecDeque<MockServer>,
+}
+
+/// Drives a [`Connection`] which connects to [`MockServer`]s.
+///
+/// [`Connection`]: ../../struct.Connection.html
+/// [`MockServer`]: struct.MockServer.html
+pub(crate) struct ConnectionHarness<C: DrivenConnection = Connection> {
+	pub con: C,
+	pub connector: Arc<Mutex<MockConnector>>,
+	/// The server of the current connection.
+	server: Option<MockServer>,
+	/// The virtual time since the start.
+	now: Duration,
+	/// Notifications which were not yet sent, sorted by time.
+	pending: VecDeque<(Duration, String)>,
+	/// Events which were not yet checked.
+	pub events: VecDeque<Event>,
+	/// Stream items, except for `ConEvents`, which were not yet checked.
+	pub items: VecDeque<C::Item>,
+	/// If the stream of the connection ended.
+	pub ended: bool,
+	/// Declared last, so it is dropped after the connection.
+	runtime: Runtime,
+}
+
+impl Script {
+	pub fn new() -> Self { Self::default() }
+
+	/// Send a notification after this many milliseconds.
+	pub fn at<S: Into<String>>(mut self, ms: u64, notification: S) -> Self {
+		self.entries.push((Duration::from_millis(ms), notification.into()));
+		self
+	}
+
+	/// The notifications sorted by time, shuffled within the same time if a
+	/// seed is given.
+	fn into_pending(mut self, seed: Option<u64>) -> VecDeque<(Duration, String)> {
+		// Stable sort, so equal timestamps keep the script order
+		self.entries.sort_by_key(|(t, _)| *t);
+		let mut pending = Vec::with_capacity(self.entries.len());
+		let mut rng = seed.map(StdRng::seed_from_u64);
+		for (_, group) in &self.entries.into_iter().group_by(|(t, _)| *t) {
+			let mut group = group.collect::<Vec<_>>();
+			if let Some(rng) = &mut rng {
+				group.shuffle(rng);
+			}
+			pending.extend(group);
+		}
+		pending.into()
+	}
+}
+
+impl Harness {
+	pub fn new(script: Script) -> Self { Self::create(script, None) }
+
+	/// Deliver notifications with the same timestamp in a random but
+	/// reproducible order.
+	pub fn with_seed(script: Script, seed: u64) -> Self { Self::create(script, Some(seed)) }
+
+	fn create(script: Script, seed: Option<u64>) -> Self {
+		Self {
+			logger: get_logger(),
+			con: connection_with(&[]),
+			start: Instant::now(),
+			now: Duration::from_secs(0),
+			pending: script.into_pending(seed),
+			timers: Vec::new(),
+			events: VecDeque::new(),
+			typed: VecDeque::new(),
+			commands: VecDeque::new(),
+		}
+	}
+
+	/// Add a timer of the client, which is run after the notifications of
+	/// every step.
+	pub fn timer<F>(mut self, f: F) -> Self
+	where F: FnMut(Instant, &data::Connection) -> Vec<OutCommand> + 'static {
+		self.timers.push(Box::new(f));
+		self
+	}
+
+	/// The current virtual time.
+	pub fn now(&self) -> Instant { self.start + self.now }
+
+	/// Run steps until an event matches, panics if no event matches within
+	/// the given virtual time.
+	pub fn expect_event<F: Fn(&Event) -> bool>(
+		&mut self, within: Duration, f: F, description: &str,
+	) -> Event {
+		self.run_until(within, |h| take_matching(&mut h.events, &f)).unwrap_or_else(|| {
+			panic!("Expected event {} within {:?}, got {:?}", description, within, self.events)
+		})
+	}
+
+	/// Run steps until the client sends a command with this name, panics if no
+	/// command is sent within the given virtual time.
+	pub fn expect_command(&mut self, name: &str, within: Duration) -> OutCommand {
+		self.run_until(within, |h| {
+			take_matching(&mut h.commands, |c| command_name(c.0.content()) == name.as_bytes())
+		})
+		.unwrap_or_else(|| panic!("Expected command {} within {:?}", name, within))
+	}
+}
+
+impl VirtualTime for Harness {
+	fn elapsed(&self) -> Duration { self.now }
+
+	/// Deliver the notifications of the current time, run the timers and
+	/// advance the virtual time.
+	fn step(&mut self) {
+		while self.pending.front().map(|(t, _)| *t <= self.now).unwrap_or_default() {
+			let (_, notification) = self.pending.pop_front().unwrap();
+			let events = self.con.handle_command(&self.logger, &parse_msg(&notification)).unwrap();
+			self.typed.extend(events.iter().map(Event::to_typed));
+			self.events.extend(events);
+		}
+		let now = self.now();
+		for timer in &mut self.timers {
+			self.commands.extend(timer(now, &self.con));
+		}
+		self.now += STEP;
+	}
+}
+
+impl MockSocket {
+	/// Two sockets which are connected to each other.
+	fn pair(a: SocketAddr, b: SocketAddr) -> (Self, Self) {
+		let state = Arc::new(Mutex::new(MockSocketState::default()));
+		(Self { state: state.clone(), side: 0, local: a, peer: b }, Self {
+			state,
+			side: 1,
+			local: b,
+			peer: a,
+		})
+	}
+}
+
+impl Socket for MockSocket {
+	fn poll_recv_from(
+		&self, cx: &mut Context, buf: &mut [u8],
+	) -> Poll<io::Result<(usize, SocketAddr)>> {
+		let mut state = self.state.lock().unwrap();
+		if let Some(packet) = state.buffers[self.side].pop_front() {
+			let len = std::cmp::min(buf.len(), packet.len());
+			buf[..len].copy_from_slice(&packet[..len]);
+			Poll::Ready(Ok((len, self.peer)))
+		} else {
+			state.wakers[self.side] = Some(cx.waker().clone());
+			Poll::Pending
+		}
+	}
+
+	fn poll_send_to(&self, _: &mut Context, buf: &[u8], _: &SocketAddr) -> Poll<io::Result<usize>> {
+		let mut state = self.state.lock().unwrap();
+		let other = 1 - self.side;
+		state.buffers[other].push_back(buf.to_vec());
+		if let Some(waker) = state.wakers[other].take() {
+			waker.wake();
+		}
+		Poll::Ready(Ok(buf.len()))
+	}
+
+	fn local_addr(&self) -> io::Result<SocketAddr> { Ok(self.local) }
+}
+
+/// Set a connection to connected without a handshake.
+fn set_connected(con: &mut ProtoConnection, other_key: EccKeyPubP256) {
+	con.resender.set_state(&con.logger, ResenderState::Connected);
+	for p_type in &[PacketType::Command, PacketType::Ack] {
+		let i = p_type.to_usize().unwrap();
+		con.codec.outgoing_p_ids[i] = PartialPacketId { generation_id: 0, packet_id: 1 };
+		con.codec.incoming_p_ids[i] = PartialPacketId { generation_id: 0, packet_id: 1 };
+	}
+	let mut params = ConnectedParams::new(other_key, [0; 64], [0x42; 8]);
+	params.c_id = OWN_CLIENT_ID;
+	con.params = Some(params);
+}
+
+/// A client which is connected to a mock server.
+///
+/// The connection must be created inside a tokio runtime.
+pub(crate) fn mock_connection(logger: &Logger) -> (client::Client, MockServer) {
+	let client_addr = "127.0.0.1:50000".parse().unwrap();
+	let server_addr = "127.0.0.1:9987".parse().unwrap();
+	let (client_socket, server_socket) = MockSocket::pair(client_addr, server_addr);
+	let client_key = EccKeyPrivP256::create().unwrap();
+	let server_key = EccKeyPrivP256::create().unwrap();
+	let client_pub = client_key.to_pub();
+
+	let mut client = client::Client::new(
+		logger.new(o!("side" => "client")),
+		server_addr,
+		Box::new(client_socket),
+		client_key,
+	);
+	let mut server = ProtoConnection::new(
+		false,
+		logger.new(o!("side" => "server")),
+		client_addr,
+		Box::new(server_socket),
+	);
+	set_connected(&mut client, server_key.to_pub());
+	set_connected(&mut server, client_pub);
+	(client, MockServer { con: server, answer: true, receiving: true, commands: VecDeque::new() })
+}
+
+impl MockServer {
+	/// Send a command to the client.
+	pub fn send(&mut self, command: &str) {
+		let mut packet =
+			OutPacket::new_with_dir(Direction::S2C, Flags::empty(), PacketType::Command);
+		packet.data_mut().extend_from_slice(command.as_bytes());
+		self.con.send_packet(packet).unwrap();
+	}
+
+	/// Receive the packets of the client, returns if something happened.
+	fn poll(&mut self, cx: &mut Context) -> bool {
+		if !self.receiving {
+			return false;
+		}
+		let mut progress = false;
+		while let Poll::Ready(Some(item)) = self.con.poll_next_unpin(cx) {
+			progress = true;
+			if let Ok(ProtoStreamItem::Command(cmd)) = item {
+				let content = String::from_utf8_lossy(cmd.data().packet().content()).into_owned();
+				if self.answer {
+					let code = content.split(' ').find_map(|a| a.strip_prefix("return_code="));
+					if let Some(code) = code {
+						self.send(&format!("error id=0 msg=ok return_code={}", code));
+					}
+				}
+				self.commands.push_back(content);
+			}
+		}
+		progress
+	}
+}
+
+impl MockConnector {
+	fn connect(
+		&mut self, logger: &Logger, name: &str,
+	) -> Result<(client::Client, data::Connection, String)> {
+		self.attempts += 1;
+		let (client, server) = mock_connection(logger);
+		self.servers.push_back(server);
+		Ok((client, connection_with(&[]), name.to_string()))
+	}
+}
+
+impl DrivenConnection for Connection {
+	type Item = Result<StreamItem>;
+
+	fn poll_item(&mut self, cx: &mut Context) -> Poll<Option<Self::Item>> { self.poll_next(cx) }
+
+	fn into_events(item: Self::Item) -> std::result::Result<Vec<Event>, Self::Item> {
+		match item {
+			Ok(StreamItem::ConEvents(e)) => Ok(e),
+			item => Err(item),
+		}
+	}
+}
+
+impl DrivenConnection for SyncConnection {
+	type Item = Result<SyncStreamItem>;
+
+	fn poll_item(&mut self, cx: &mut Context) -> Poll<Option<Self::Item>> {
+		self.poll_next_unpin(cx)
+	}
+
+	fn into_events(item: Self::Item) -> std::result::Result<Vec<Event>, Self::Item> {
+		match item {
+			Ok(SyncStreamItem::ConEvents(e)) => Ok(e),
+			item => Err(item),
+		}
+	}
+}
+
+impl ConnectionHarness {
+	pub fn new(options: ConnectOptions, script: Script) -> Self {
+		let mut runtime = runtime::Builder::new().basic_scheduler().enable_time().build().unwrap();
+		let connector = Arc::new(Mutex::new(MockConnector::default()));
+		let con = runtime.block_on(async {
+			time::pause();
+			let logger = get_logger();
+			let mut con = Connection::new(options.logger(logger.clone())).unwrap();
+			let name = con.options.name.to_string();
+			let shared = connector.clone();
+			let mut connect: Connector = Box::new(move || -> ConnectFuture {
+				future::ready(shared.lock().unwrap().connect(&logger, &name)).boxed()
+			});
+			con.state = ConnectionState::Connecting(connect());
+			con.connector = Some(connect);
+			con
+		});
+
+		Self {
+			con,
+			connector,
+			server: None,
+			now: Duration::from_secs(0),
+			pending: script.into_pending(None),
+			events: VecDeque::new(),
+			items: VecDeque::new(),
+			ended: false,
+			runtime,
+		}
+	}
+
+	/// Drive the connection as [`SyncConnection`] from now on.
+	///
+	/// Stream items which were not yet checked are dropped.
+	///
+	/// [`SyncConnection`]: ../../sync/struct.SyncConnection.html
+	pub fn into_sync(self) -> ConnectionHarness<SyncConnection> {
+		let Self { con, connector, server, now, pending, events, items: _, ended, runtime } = self;
+		let con = SyncConnection::from(con);
+		let items = VecDeque::new();
+		ConnectionHarness { con, connector, server, now, pending, events, items, ended, runtime }
+	}
+}
+
+impl<C: DrivenConnection> ConnectionHarness<C> {
+	/// The server of the current connection.
+	pub fn server(&mut self) -> &mut MockServer {
+		self.server.as_mut().expect("The connection is not connected")
+	}
+
+	/// Drop the server of the current connection, packets of the client are
+	/// not answered anymore.
+	pub fn drop_server(&mut self) { self.server = None; }
+
+	/// Run steps until an event matches, panics if no event matches within
+	/// the given virtual time.
+	pub fn expect_event<F: Fn(&Event) -> bool>(
+		&mut self, within: Duration, f: F, description: &str,
+	) -> Event {
+		self.run_until(within, |h| take_matching(&mut h.events, &f)).unwrap_or_else(|| {
+			panic!("Expected event {} within {:?}, got {:?}", description, within, self.events)
+		})
+	}
+
+	/// Run steps until the server receives a command with this name, panics if
+	/// no command is received within the given virtual time.
+	pub fn expect_command(&mut self, name: &str, within: Duration) -> String {
+		self.run_until(within, |h| {
+			let commands = &mut h.server.as_mut()?.commands;
+			take_matching(commands, |c| command_name(c.as_bytes()) == name.as_bytes())
+		})
+		.unwrap_or_else(|| panic!("Expected command {} within {:?}", name, within))
+	}
+
+	/// Run steps until the stream of the connection ends, panics if it does not
+	/// end within the given virtual time.
+	pub fn expect_end(&mut self, within: Duration) {
+		if self.run_until(within, |h| if h.ended { Some(()) } else { None }).is_none() {
+			panic!("Expected the connection to end within {:?}, got {:?}", within, self.items);
+		}
+	}
+
+	/// Run steps until a stream item matches, panics if no item matches within
+	/// the given virtual time.
+	pub fn expect_item<F: Fn(&C::Item) -> bool>(
+		&mut self, within: Duration, f: F, description: &str,
+	) -> C::Item {
+		self.run_until(within, |h| take_matching(&mut h.items, &f)).unwrap_or_else(|| {
+			panic!("Expected item {} within {:?}, got {:?}", description, within, self.items)
+		})
+	}
+
+	/// Run steps until the future completes, panics if it does not complete
+	/// within the given virtual time.
+	///
+	/// Used to wait for the handles of a [`SyncConnection`], which are
+	/// answered while the connection is driven.
+	///
+	/// [`SyncConnection`]: ../../sync/struct.SyncConnection.html
+	pub fn wait<F: Future>(&mut self, within: Duration, f: F) -> F::Output {
+		let mut f = Box::pin(f);
+		let waker = futures::task::noop_waker();
+		let mut cx = Context::from_waker(&waker);
+		self.run_until(within, |h| match h.runtime.enter(|| f.as_mut().poll(&mut cx)) {
+			Poll::Ready(r) => Some(r),
+			Poll::Pending => None,
+		})
+		.unwrap_or_else(|| panic!("Expected the future to complete within {:?}", within))
+	}
+}
+
+impl<C: DrivenConnection> VirtualTime for ConnectionHarness<C> {
+	fn elapsed(&self) -> Duration { self.now }
+
+	/// Let the server send the notifications of the current time, poll the
+	/// connection and the server until nothing happens anymore and advance
+	/// the virtual time.
+	fn step(&mut self) {
+		let Self { con, connector, server, now, pending, events, items, ended, runtime } = self;
+		runtime.block_on(async {
+			future::poll_fn(|cx| {
+				loop {
+					let new_server = connector.lock().unwrap().servers.pop_front();
+					if new_server.is_some() {
+						*server = new_server;
+					}
+					let mut progress = false;
+					if let Some(server) = server {
+						while pending.front().map(|(t, _)| *t <= *now).unwrap_or_default() {
+							let (_, notification) = pending.pop_front().unwrap();
+							server.send(&notification);
+						}
+						progress = server.poll(cx);
+					}
+					while !*ended {
+						match con.poll_item(cx) {
+							Poll::Pending => break,
+							Poll::Ready(None) => *ended = true,
+							Poll::Ready(Some(item)) => match C::into_events(item) {
+								Ok(e) => events.extend(e),
+								Err(item) => items.push_back(item),
+							},
+						}
+						progress = true;
+					}
+					if !progress {
+						return Poll::Ready(());
+					}
+				}
+			})
+			.await;
+			time::advance(STEP).await;
+		});
+		*now += STEP;
+	}
+}
+
+/// Remove and return the first element which matches.
+fn take_matching<T, F: Fn(&T) -> bool>(list: &mut VecDeque<T>, f: F) -> Option<T> {
+	let i = list.iter().position(f)?;
+	list.remove(i)
+}
+
+fn command_name(content: &[u8]) -> &[u8] { content.split(|b| *b == b' ').next().unwrap_or(content) }
+
+mod test {
+	use ts_bookkeeping::events::PropertyId;
+	use ts_bookkeeping::ClientId;
+	use tsproto_packets::packets::{Direction, Flags, PacketType};
+
+	use super::super::client_enterview;
+	use super::*;
+	use crate::status_display::{Status, StatusDisplay, Update};
+
+	fn script() -> Script {
+		Script::new()
+			.at(10, client_enterview(3, "Other", 0))
+			.at(50, "notifyclientupdated clid=3 client_nickname=Renamed")
+			.at(50, client_enterview(4, "New", 0))
+			.at(50, client_enterview(5, "Newer", 0))
+	}
+
+	#[test]
+	fn notifications_at_virtual_time() {
+		let mut h = Harness::new(script());
+		h.advance(Duration::from_millis(10));
+		assert!(h.events.is_empty());
+		let added = PropertyId::Client(ClientId(3));
+		let e = expect_event!(h, Event::PropertyAdded { .. }, within 1);
+		assert!(matches!(e, Event::PropertyAdded { id, .. } if id == added));
+		h.advance(Duration::from_millis(39));
+		assert!(h.events.is_empty());
+		expect_event!(h, Event::PropertyChanged { .. }, within 1);
+		assert_eq!(h.con.clients[&ClientId(3)].name, "Renamed");
+	}
+
+	#[test]
+	#[should_panic(expected = "Expected event")]
+	fn missing_event() {
+		let mut h = Harness::new(script());
+		expect_event!(h, Event::PropertyChanged { .. }, within 40);
+	}
+
+	/// The order of notifications at the same time only depends on the seed.
+	#[test]
+	fn seeded_order() {
+		let order = |seed| {
+			let mut h = Harness::with_seed(script(), seed);
+			h.advance(Duration::from_millis(100));
+			h.events.into_iter().collect::<Vec<_>>()
+		};
+		for seed in 0..10 {
+			assert_eq!(order(seed), order(seed));
+		}
+	}
+
+	/// A timer of the client interleaves deterministically with the
+	/// notifications of the server.
+	#[test]
+	fn client_timer() {
+		let name = Arc::new(Mutex::new(String::new()));
+		let name2 = name.clone();
+		let mut display = StatusDisplay::new(move || Status::new(name2.lock().unwrap().clone()))
+			.interval(Duration::from_millis(100))
+			.description_interval(Duration::from_secs(1));
+		let mut h = Harness::new(script()).timer(move |now, con| {
+			if let Some(client) = con.clients.get(&ClientId(3)) {
+				*name.lock().unwrap() = client.name.clone();
+			}
+			match display.next_update(now) {
+				Some(Update::Description(d)) => {
+					let mut cmd = OutCommand::new(
+						Direction::C2S,
+						Flags::empty(),
+						PacketType::Command,
+						"clientedit",
+					);
+					cmd.write_arg("client_description", &d);
+					display.finished(Update::Description(d), Ok(()));
+					vec![cmd]
+				}
+				_ => Vec::new(),
+			}
+		});
+
+		assert_eq!(
+			expect_command!(h, "clientedit", within 0).0.content(),
+			b"clientedit client_description".as_ref()
+		);
+		// The description interval delays the update
+		let cmd = expect_command!(h, "clientedit", within 1000);
+		assert_eq!(h.now() - h.start, Duration::from_millis(1001));
+		assert_eq!(cmd.0.content(), br#"clientedit client_description=Renamed"#.as_ref());
+	}
+
+	#[test]
+	fn connection_sends_script() {
+		let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), script());
+		expect_event!(h, Event::PropertyAdded { id: PropertyId::Server, .. }, within 0);
+		expect_command!(h, "servergrouplist", within 0);
+		assert_eq!(h.connector.lock().unwrap().attempts, 1);
+
+		let added = PropertyId::Client(ClientId(3));
+		expect_event!(h, Event::PropertyAdded { id, .. } if id == added, within 20);
+		assert_eq!(h.elapsed(), Duration::from_millis(10));
+		expect_item!(h, StreamItem::TypedEvents(_), within 0);
+		expect_event!(h, Event::PropertyChanged { .. }, within 50);
+		assert_eq!(h.con.get_state().unwrap().clients[&ClientId(3)].name, "Renamed");
+	}
+
+	/// Commands of the client are received by the server and answered.
+	#[test]
+	fn connection_commands() {
+		let mut h = ConnectionHarness::new(ConnectOptions::new("localhost"), Script::new());
+		expect_event!(h, Event::PropertyAdded { .. }, within 0);
+		let handle = h.con.send_command(poke()).unwrap();
+		let cmd = expect_command!(h, "clientpoke", within 10);
+		assert!(cmd.contains("return_code="), "{}", cmd);
+		let item = expect_item!(h, StreamItem::MessageResult(..), within 10).unwrap();
+		assert!(matches!(item, StreamItem::MessageResult(r, Ok(())) if r == handle));
+
+		// Unanswered commands wait
+		h.server().answer = false;
+		h.con.send_command(poke()).unwrap();
+		expect_command!(h, "clientpoke", within 10);
+		h.advance(Duration::from_secs(1));
+		assert!(h.items.iter().all(|i| !matches!(i, Ok(StreamItem::MessageResult(..)))));
+	}
+
+	fn poke() -> OutCommand {
+		let mut cmd =
+			OutCommand::new(Direction::C2S, Flags::empty(), PacketType::Command, "clientpoke");
+		cmd.write_arg("clid", &3);
+		cmd
+	}
+}
@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tsproto::Identity;
+use tsproto_types::crypto::EccKeyPrivP256;
+
+const LEVEL: u8 = 16;
+const THREADS: &[usize] = &[1, 2, 4, 8];
+/// Fresh keys for the speedup summary, the needed counter differs a lot
+/// between keys.
+const SUMMARY_KEYS: usize = 8;
+
+fn bench_hash_cash(c: &mut Criterion) {
+	// Use the same fresh key for all thread counts, so they search for the
+	// same counter.
+	let key = EccKeyPrivP256::create().unwrap();
+
+	let mut group = c.benchmark_group(format!("hash cash level {}", LEVEL));
+	group.sample_size(10);
+	group.bench_function("sequential", |b| {
+		b.iter(|| {
+			let mut id = Identity::new(key.clone(), 0);
+			id.upgrade_level(LEVEL).unwrap();
+			id
+		})
+	});
+	for threads in THREADS {
+		group.bench_with_input(BenchmarkId::new("parallel", threads), threads, |b, threads| {
+			b.iter(|| {
+				let mut id = Identity::new(key.clone(), 0);
+				id.upgrade_level_parallel(LEVEL, *threads).unwrap();
+				assert!(id.level().unwrap() >= LEVEL);
+				id
+			})
+		});
+	}
+	group.finish();
+
+	print_speedup();
+}
+
+/// Print the speedup of every thread count compared to the sequential
+/// search, summed over the same fresh keys.
+fn print_speedup() {
+	let keys = (0..SUMMARY_KEYS).map(|_| EccKeyPrivP256::create().unwrap()).collect::<Vec<_>>();
+	let time = |threads: Option<usize>| -> Duration {
+		keys.iter()
+			.map(|key| {
+				let mut id = Identity::new(key.clone(), 0);
+				let start = Instant::now();
+				match threads {
+					Some(threads) => id.upgrade_level_parallel(LEVEL, threads).unwrap(),
+					None => id.upgrade_level(LEVEL).unwrap(),
+				}
+				start.elapsed()
+			})
+			.sum()
+	};
+
+	let sequential = time(None);
+	println!("hash cash level {} on {} keys", LEVEL, SUMMARY_KEYS);
+	println!("sequential: {:?}", sequential);
+	for threads in THREADS {
+		let parallel = time(Some(*threads));
+		println!(
+			"{} threads: {:?}, {:.2}× speedup",
+			threads,
+			parallel,
+			sequential.as_secs_f64() / parallel.as_secs_f64()
+		);
+	}
+}
+
+criterion_group!(benches, bench_hash_cash);
+criterion_main!(benches);
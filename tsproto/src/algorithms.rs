@@ -1,4 +1,5 @@
 //! Handle packet splitting and cryptography
+use std::io::Write;
 use std::u64;
 
 use aes::block_cipher_trait::generic_array::typenum::consts::U16;
@@ -235,10 +236,36 @@ pub fn compute_iv_mac(
 	Ok((shared_iv, shared_mac))
 }
 
+/// Computes hash cash levels for a single public key.
+///
+/// The serialized key stays in a buffer and only the counter is replaced, so
+/// trying many counters does not allocate.
+pub struct HashCash {
+	buf: Vec<u8>,
+	omega_len: usize,
+}
+
+impl HashCash {
+	/// `omega` is the public key as returned by `EccKeyPubP256::to_ts`.
+	pub fn new(omega: &str) -> Self {
+		let mut buf = Vec::with_capacity(omega.len() + 20);
+		buf.extend_from_slice(omega.as_bytes());
+		Self { buf, omega_len: omega.len() }
+	}
+
+	/// The hash cash level of this key with the counter `offset`.
+	pub fn level(&mut self, offset: u64) -> u8 {
+		self.buf.truncate(self.omega_len);
+		write!(self.buf, "{}", offset).unwrap();
+		hash_cash_level(&self.buf)
+	}
+}
+
 pub fn hash_cash(key: &EccKeyPubP256, level: u8) -> Result<u64> {
 	let omega = key.to_ts().map_err(Error::IdentityCrypto)?;
+	let mut hash_cash = HashCash::new(&omega);
 	let mut offset = 0;
-	while offset < u64::MAX && get_hash_cash_level(&omega, offset) < level {
+	while offset < u64::MAX && hash_cash.level(offset) < level {
 		offset += 1;
 	}
 	Ok(offset)
@@ -246,10 +273,11 @@ pub fn hash_cash(key: &EccKeyPubP256, level: u8) -> Result<u64> {
 
 #[inline]
 pub fn get_hash_cash_level(omega: &str, offset: u64) -> u8 {
-	let data = digest::digest(
-		&digest::SHA1_FOR_LEGACY_USE_ONLY,
-		format!("{}{}", omega, offset).as_bytes(),
-	);
+	hash_cash_level(format!("{}{}", omega, offset).as_bytes())
+}
+
+fn hash_cash_level(data: &[u8]) -> u8 {
+	let data = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data);
 	let mut res = 0;
 	for &d in data.as_ref() {
 		if d == 0 {
@@ -285,7 +313,7 @@ mod tests {
 	use crate::license::Licenses;
 	use crate::packets::PacketType;
 	use crate::utils;
-	use tsproto_types::crypto::EccKeyPubEd25519;
+	use tsproto_types::crypto::{EccKeyPrivP256, EccKeyPubEd25519};
 
 	#[test]
 	fn test_fake_crypt() {
@@ -453,4 +481,14 @@ mod tests {
 
 		println!("Decrypted: {:?}", String::from_utf8_lossy(&dec));
 	}
+
+	#[test]
+	fn hash_cash_reuses_buffer() {
+		let key = EccKeyPrivP256::create().unwrap().to_pub().to_ts().unwrap();
+		let mut hash_cash = HashCash::new(&key);
+		// The number of digits shrinks again after the large counter
+		for &offset in [0, 9, 10, 999, 1000, u64::MAX, 5].iter() {
+			assert_eq!(hash_cash.level(offset), get_hash_cash_level(&key, offset));
+		}
+	}
 }
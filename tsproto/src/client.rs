@@ -38,6 +38,8 @@ pub enum Error {
 	RsaPuzzle,
 	#[error("Expected {0} but got {1}")]
 	UnexpectedPacket(&'static str, String),
+	#[error("Failed to solve RSA puzzle: {0}")]
+	RsaPuzzleTask(#[source] tokio::task::JoinError),
 	#[error("Requested RSA puzzle level {0} is too high")]
 	RsaPuzzleTooHighLevel(u32),
 	#[error("Invalid packet: {0}")]
@@ -298,40 +300,8 @@ impl Client {
 					let n = **n;
 					let random2 = **random2;
 
-					let mut time_reporter = slog_perf::TimeReporter::new_with_level(
-						"Solve RSA puzzle",
-						self.con.logger.clone(),
-						Level::Info,
-					);
-					time_reporter.start("");
-
-					// Use gmp for faster computations if it is
-					// available.
-					#[cfg(feature = "rug")]
-					let y = {
-						let mut e = Integer::new();
-						let n = Integer::from_digits(&n[..], Order::Msf);
-						let x = Integer::from_digits(&x[..], Order::Msf);
-						e.set_bit(level, true);
-						let y = x.pow_mod(&e, &n).map_err(|_| Error::RsaPuzzle)?;
-						let mut yi = [0; 64];
-						y.write_digits(&mut yi, Order::Msf);
-						yi
-					};
-
-					#[cfg(not(feature = "rug"))]
-					let y = {
-						let xi = BigUint::from_bytes_be(&x);
-						let ni = BigUint::from_bytes_be(&n);
-						let e = BigUint::one() << level as usize;
-						let yi = xi.modpow(&e, &ni);
-						info!(self.con.logger, "Solve RSA puzzle";
-							"level" => level, "x" => %xi, "n" => %ni,
-							"y" => %yi);
-						algs::biguint_to_array(&yi)
-					};
-
-					time_reporter.finish();
+					let logger = self.con.logger.clone();
+					let y = solve_rsa_puzzle_blocking(logger, x, n, level).await?;
 
 					// Create the command string
 					// omega is an ASN.1-DER encoded public key from
@@ -671,6 +641,52 @@ impl Client {
 	}
 }
 
+/// Solve the RSA puzzle on the blocking thread pool of tokio.
+///
+/// Solving the puzzle takes a while for high levels, running it inline would
+/// stall all other connections on the same executor thread.
+async fn solve_rsa_puzzle_blocking(
+	logger: Logger, x: [u8; 64], n: [u8; 64], level: u32,
+) -> Result<[u8; 64]> {
+	tokio::task::spawn_blocking(move || solve_rsa_puzzle(&logger, &x, &n, level))
+		.await
+		.map_err(Error::RsaPuzzleTask)?
+}
+
+/// Compute `y = x ^ (2 ^ level) % n`.
+fn solve_rsa_puzzle(logger: &Logger, x: &[u8; 64], n: &[u8; 64], level: u32) -> Result<[u8; 64]> {
+	let mut time_reporter =
+		slog_perf::TimeReporter::new_with_level("Solve RSA puzzle", logger.clone(), Level::Info);
+	time_reporter.start("");
+
+	// Use gmp for faster computations if it is available.
+	#[cfg(feature = "rug")]
+	let y = {
+		let mut e = Integer::new();
+		let n = Integer::from_digits(&n[..], Order::Msf);
+		let x = Integer::from_digits(&x[..], Order::Msf);
+		e.set_bit(level, true);
+		let y = x.pow_mod(&e, &n).map_err(|_| Error::RsaPuzzle)?;
+		let mut yi = [0; 64];
+		y.write_digits(&mut yi, Order::Msf);
+		yi
+	};
+
+	#[cfg(not(feature = "rug"))]
+	let y = {
+		let xi = BigUint::from_bytes_be(x);
+		let ni = BigUint::from_bytes_be(n);
+		let e = BigUint::one() << level as usize;
+		let yi = xi.modpow(&e, &ni);
+		info!(logger, "Solve RSA puzzle"; "level" => level, "x" => %xi, "n" => %ni,
+			"y" => %yi);
+		algs::biguint_to_array(&yi)
+	};
+
+	time_reporter.finish();
+	Ok(y)
+}
+
 impl Deref for Client {
 	type Target = Connection;
 	fn deref(&self) -> &Self::Target { &self.con }
@@ -709,6 +725,7 @@ mod tests {
 	use std::cell::Cell;
 	use std::collections::VecDeque;
 	use std::io;
+	use std::sync::atomic::{AtomicUsize, Ordering};
 	use std::sync::{Arc, Mutex};
 	use std::task::Waker;
 
@@ -719,8 +736,12 @@ mod tests {
 	use tokio::time::{self, Duration};
 
 	use super::*;
-	use crate::connection::Event;
-	use crate::resend::PartialPacketId;
+	use crate::connection::{Event, EventListener};
+	use crate::observe;
+	use crate::packet_codec::PacketCodec;
+	use crate::resend::{
+		CongestionPhase, CongestionStats, PartialPacketId, ResendConfig, Resender,
+	};
 
 	#[derive(Clone, Debug)]
 	struct SimulatedSocketState {
@@ -864,7 +885,7 @@ mod tests {
 			_ => {}
 		};
 
-		state.server.event_listeners.push(Box::new(listener));
+		state.server.event_listeners.push(keep(listener));
 
 		tokio::select!(
 			(r, err) = future::join(
@@ -897,7 +918,7 @@ mod tests {
 			_ => {}
 		};
 
-		state.server.event_listeners.push(Box::new(listener));
+		state.server.event_listeners.push(keep(listener));
 
 		let mut cmd = OutCommand::new(
 			Direction::C2S,
@@ -951,7 +972,7 @@ mod tests {
 			_ => {}
 		};
 
-		state.server.event_listeners.push(Box::new(listener));
+		state.server.event_listeners.push(keep(listener));
 
 		tokio::select!(
 			r = time::timeout(Duration::from_secs(5), recv) => {
@@ -964,6 +985,330 @@ mod tests {
 		bail!("Unexpected disconnect");
 	}
 
+	/// Pings and pongs can be observed, although they are not returned by
+	/// the connection stream.
+	#[tokio::test]
+	async fn test_observe_packets() -> Result<()> {
+		let mut state = TestConnection::new()?;
+		state.set_connected().await;
+
+		let listeners = state.server.event_listeners.len();
+		let mut pongs = observe::subscribe_packet_type(&mut state.server, PacketType::Pong);
+		let mut all = observe::subscribe_all(&mut state.server);
+		let packet = OutPacket::new_with_dir(Direction::S2C, Flags::UNENCRYPTED, PacketType::Ping);
+		state.server.send_packet(packet)?;
+
+		let received = tokio::select!(
+			r = time::timeout(Duration::from_secs(5), pongs.next()) => {
+				let pong = r?.unwrap();
+				assert!(pong.incoming);
+				assert_eq!(pong.packet()?.header().packet_type(), PacketType::Pong);
+
+				let ping = all.next().await.unwrap();
+				assert!(!ping.incoming);
+				assert_eq!(ping.packet_type, PacketType::Ping);
+				assert_eq!(all.next().await.unwrap(), pong);
+				true
+			}
+			_ = state.client.wait_disconnect() => false,
+			_ = state.server.wait_disconnect() => false,
+		);
+		if !received {
+			bail!("Unexpected disconnect");
+		}
+
+		// Dropped streams are removed with the next packet
+		drop((pongs, all));
+		let packet = OutPacket::new_with_dir(Direction::S2C, Flags::UNENCRYPTED, PacketType::Ping);
+		state.server.send_packet(packet)?;
+		assert_eq!(state.server.event_listeners.len(), listeners);
+		Ok(())
+	}
+
+	/// An idle connection sends pings and measures the round trip time.
+	#[tokio::test]
+	async fn test_keep_alive() -> Result<()> {
+		let mut state = TestConnection::new()?;
+		state.set_connected().await;
+		assert_eq!(state.client.resender.get_last_ping(), None);
+		// Only the client should send pings
+		state.server.resender.set_config(ResendConfig {
+			ping_interval: Duration::from_secs(60),
+			..Default::default()
+		});
+
+		let (send, recv) = oneshot::channel();
+		let send = Cell::new(Some(send));
+		let listener = move |event: &Event| match event {
+			Event::ReceivePacket(packet) => {
+				if packet.header().packet_type() == PacketType::Pong {
+					if let Some(s) = send.replace(None) {
+						s.send(()).unwrap();
+					}
+				}
+			}
+			_ => {}
+		};
+
+		state.client.event_listeners.push(keep(listener));
+
+		tokio::select!(
+			r = time::timeout(Duration::from_secs(5), recv) => {
+				r??;
+			}
+			_ = state.client.wait_disconnect() => bail!("Unexpected disconnect"),
+			_ = state.server.wait_disconnect() => bail!("Unexpected disconnect"),
+		);
+		assert!(state.client.resender.get_last_ping().is_some());
+		Ok(())
+	}
+
+	/// Pings are sent when nothing was received for an interval, even if
+	/// packets were sent in between.
+	#[tokio::test]
+	async fn test_ping_after_send() -> Result<()> {
+		let mut state = TestConnection::new()?;
+		state.set_connected().await;
+		let con = &mut *state.client;
+		con.resender.set_config(ResendConfig {
+			ping_interval: Duration::from_millis(10),
+			..Default::default()
+		});
+		let waker = futures::task::noop_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		time::delay_for(Duration::from_millis(20)).await;
+		// Updates the time of the last sent packet
+		con.resender.set_state(&con.logger, ResenderState::Connected);
+		Resender::poll_ping(con, &mut cx)?;
+		assert_eq!(con.stats.get_packets_sent(PacketType::Ping), 1);
+		Ok(())
+	}
+
+	/// Changing the config starts the round trip time estimation again.
+	#[tokio::test]
+	async fn test_set_config_resets_srtt() -> Result<()> {
+		let mut state = TestConnection::new()?;
+		state.set_connected().await;
+		let con = &mut *state.client;
+		let waker = futures::task::noop_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		con.send_packet(test_command(0))?;
+		Resender::poll_resend(con, &mut cx)?;
+		time::delay_for(Duration::from_millis(10)).await;
+		Resender::ack_packet(con, &mut cx, PacketType::Command, 1);
+		assert_ne!(con.resender.get_srtt(), ResendConfig::default().srtt);
+
+		let config = ResendConfig {
+			srtt: Duration::from_millis(200),
+			srtt_dev: Duration::from_millis(10),
+			..Default::default()
+		};
+		con.resender.set_config(config.clone());
+		assert_eq!(con.resender.get_srtt(), config.srtt);
+		assert_eq!(con.resender.get_srtt_dev(), config.srtt_dev);
+		Ok(())
+	}
+
+	/// A listener which is never removed.
+	fn keep<F: for<'a> Fn(&'a Event<'a>) + Send + 'static>(listener: F) -> EventListener {
+		Box::new(move |event| {
+			listener(event);
+			true
+		})
+	}
+
+	fn test_command(i: u16) -> OutPacket {
+		let mut cmd = OutCommand::new(Direction::C2S, Flags::empty(), PacketType::Command, "test");
+		cmd.write_arg("i", &i);
+		cmd.into_packet()
+	}
+
+	/// Acks for newer packets resend the oldest packet before its timeout.
+	#[tokio::test]
+	async fn test_fast_retransmit() -> Result<()> {
+		let mut state = TestConnection::new()?;
+		state.set_connected().await;
+		let con = &mut *state.client;
+		let waker = futures::task::noop_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		for i in 0..4 {
+			con.send_packet(test_command(i))?;
+		}
+		Resender::poll_resend(con, &mut cx)?;
+		assert_eq!(con.stats.packets_resent, 0);
+
+		// Packet 1 got lost
+		Resender::ack_packet(con, &mut cx, PacketType::Command, 2);
+		Resender::poll_resend(con, &mut cx)?;
+		assert_eq!(con.stats.packets_resent, 0);
+		Resender::ack_packet(con, &mut cx, PacketType::Command, 3);
+		Resender::poll_resend(con, &mut cx)?;
+		assert_eq!(con.stats.packets_resent, 1);
+		// Further acks do not resend it again
+		Resender::ack_packet(con, &mut cx, PacketType::Command, 4);
+		Resender::poll_resend(con, &mut cx)?;
+		assert_eq!(con.stats.packets_resent, 1);
+
+		// The ack of the resent packet does not change the round trip time
+		let srtt = con.resender.get_srtt();
+		Resender::ack_packet(con, &mut cx, PacketType::Command, 1);
+		assert_eq!(con.resender.get_srtt(), srtt);
+		assert!(con.resender.is_empty());
+		assert_eq!(con.resender.get_acked_packets(), 4);
+		Ok(())
+	}
+
+	/// Reordered, duplicated and broken command packets are counted.
+	#[tokio::test]
+	async fn test_traffic_stats() -> Result<()> {
+		let mut state = TestConnection::new()?;
+		state.set_connected().await;
+		let waker = futures::task::noop_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		let mut packets = Vec::new();
+		for i in 0..3 {
+			let mut cmd = OutCommand::new(
+				Direction::S2C,
+				Flags::empty(),
+				PacketType::Command,
+				"notifytextmessage",
+			);
+			cmd.write_arg("msg", &format!("message {}", i));
+			for p in PacketCodec::encode_packet(&mut state.server, cmd.into_packet())? {
+				packets.push(p.data().data().to_vec());
+			}
+		}
+		let con = &mut *state.client;
+
+		// Packet 2 arrives before packet 1
+		PacketCodec::handle_udp_packet(con, &mut cx, packets[1].clone())?;
+		assert_eq!(con.stats.packets_out_of_order, 1);
+		PacketCodec::handle_udp_packet(con, &mut cx, packets[0].clone())?;
+		assert_eq!(con.stats.packets_out_of_order, 1);
+		assert_eq!(con.stats.packets_dropped, 0);
+		assert_eq!(con.stream_items.len(), 2);
+
+		// A duplicate and a packet with a wrong mac are dropped
+		PacketCodec::handle_udp_packet(con, &mut cx, packets[0].clone())?;
+		let mut broken = packets[2].clone();
+		broken[0] ^= 1;
+		PacketCodec::handle_udp_packet(con, &mut cx, broken)?;
+		assert_eq!(con.stats.packets_dropped, 2);
+		assert_eq!(con.stats.packets_out_of_order, 1);
+		Ok(())
+	}
+
+	/// A full send queue wakes the waiting task when acks make space again.
+	#[tokio::test]
+	async fn test_send_ready() -> Result<()> {
+		struct Counter(AtomicUsize);
+		impl futures::task::ArcWake for Counter {
+			fn wake_by_ref(arc_self: &Arc<Self>) { arc_self.0.fetch_add(1, Ordering::SeqCst); }
+		}
+
+		let mut state = TestConnection::new()?;
+		state.set_connected().await;
+		let con = &mut *state.client;
+		let counter = Arc::new(Counter(AtomicUsize::new(0)));
+		let waker = futures::task::waker(counter.clone());
+		let mut cx = Context::from_waker(&waker);
+
+		let mut sent = 0;
+		while con.poll_send_ready(&mut cx).is_ready() {
+			sent += 1;
+			con.send_packet(test_command(sent))?;
+		}
+		assert!(con.is_send_queue_full());
+		assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+		// Use another waker for acks, so only the send waker is counted
+		let noop = futures::task::noop_waker();
+		Resender::ack_packet(con, &mut Context::from_waker(&noop), PacketType::Command, 1);
+		assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+		assert!(con.poll_send_ready(&mut cx).is_ready());
+		Ok(())
+	}
+
+	/// The window grows with every ack until the first loss.
+	#[tokio::test]
+	async fn test_slow_start() -> Result<()> {
+		let mut state = TestConnection::new()?;
+		state.set_connected().await;
+		let con = &mut *state.client;
+		let waker = futures::task::noop_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		for i in 0..10 {
+			con.send_packet(test_command(i))?;
+		}
+		Resender::poll_resend(con, &mut cx)?;
+		assert_eq!(con.resender.get_congestion_stats(), CongestionStats {
+			phase: CongestionPhase::SlowStart,
+			window: 1,
+			w_max: crate::UDP_SINK_CAPACITY as u16,
+		});
+
+		for i in 1..=5 {
+			Resender::ack_packet(con, &mut cx, PacketType::Command, i);
+			Resender::poll_resend(con, &mut cx)?;
+			assert_eq!(con.resender.get_congestion_stats().window, 1 + i);
+		}
+
+		// Packet 6 gets lost
+		Resender::ack_packet(con, &mut cx, PacketType::Command, 8);
+		Resender::ack_packet(con, &mut cx, PacketType::Command, 9);
+		Resender::poll_resend(con, &mut cx)?;
+		assert_eq!(con.stats.packets_resent, 1);
+		assert_eq!(con.resender.get_congestion_stats(), CongestionStats {
+			phase: CongestionPhase::CongestionAvoidance,
+			window: 2,
+			w_max: 8,
+		});
+		Ok(())
+	}
+
+	/// Slow start begins again when the window shrinks to one packet.
+	#[tokio::test]
+	async fn test_slow_start_restart() -> Result<()> {
+		let mut state = TestConnection::new()?;
+		state.set_connected().await;
+		let con = &mut *state.client;
+		con.resender.set_config(ResendConfig {
+			srtt: Duration::from_millis(1),
+			..Default::default()
+		});
+		let waker = futures::task::noop_waker();
+		let mut cx = Context::from_waker(&waker);
+
+		con.send_packet(test_command(0))?;
+		Resender::poll_resend(con, &mut cx)?;
+		// Resend after the retransmission timeout
+		time::delay_for(Duration::from_millis(20)).await;
+		Resender::poll_resend(con, &mut cx)?;
+		assert_eq!(con.stats.packets_resent, 1);
+		// Slow start grows up to the w_max from before the loss
+		let w_max = crate::UDP_SINK_CAPACITY as u16;
+		assert_eq!(con.resender.get_congestion_stats(), CongestionStats {
+			phase: CongestionPhase::SlowStart,
+			window: 1,
+			w_max,
+		});
+
+		let srtt = con.resender.get_srtt();
+		Resender::ack_packet(con, &mut cx, PacketType::Command, 1);
+		assert_eq!(con.resender.get_srtt(), srtt);
+		assert_eq!(con.resender.get_congestion_stats(), CongestionStats {
+			phase: CongestionPhase::SlowStart,
+			window: 2,
+			w_max,
+		});
+		Ok(())
+	}
+
 	/// Check that the packet id wraps around.
 	#[tokio::test]
 	async fn test_generation_id() -> Result<()> {
@@ -1001,7 +1346,7 @@ mod tests {
 			_ => {}
 		};
 
-		state.client.event_listeners.push(Box::new(listener));
+		state.client.event_listeners.push(keep(listener));
 
 		for i in 0..count {
 			let mut cmd = OutCommand::new(
@@ -1024,4 +1369,35 @@ mod tests {
 		);
 		bail!("Unexpected disconnect");
 	}
+
+	/// Solving RSA puzzles of multiple connections does not block the
+	/// executor thread.
+	#[tokio::test]
+	async fn test_rsa_puzzle_blocking() -> Result<()> {
+		let logger = slog::Logger::root(slog::Discard, o!());
+		let mut n = [0xff; 64];
+		n[0] = 0x7f;
+		let mut x = [0; 64];
+		x[63] = 3;
+		let level = 10_000;
+		let expected = solve_rsa_puzzle(&logger, &x, &n, level)?;
+
+		// The test runtime has a single thread, so the ticker only runs if the
+		// puzzles wait for the blocking thread pool.
+		let ticks = Arc::new(AtomicUsize::new(0));
+		let ticker_ticks = ticks.clone();
+		tokio::spawn(async move {
+			loop {
+				ticker_ticks.fetch_add(1, Ordering::SeqCst);
+				tokio::task::yield_now().await;
+			}
+		});
+
+		let puzzles = (0..20).map(|_| solve_rsa_puzzle_blocking(logger.clone(), x, n, level));
+		for y in future::join_all(puzzles).await {
+			assert_eq!(y?, expected);
+		}
+		assert!(ticks.load(Ordering::SeqCst) > 0);
+		Ok(())
+	}
 }
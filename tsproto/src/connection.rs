@@ -10,6 +10,7 @@ use aes::block_cipher_trait::generic_array::typenum::consts::U16;
 use aes::block_cipher_trait::generic_array::GenericArray;
 use futures::prelude::*;
 use num_traits::ToPrimitive;
+use serde::Serialize;
 use slog::{o, Logger};
 use tokio::net::UdpSocket;
 use tsproto_packets::packets::*;
@@ -81,7 +82,36 @@ pub enum StreamItem {
 	Error(Error),
 }
 
-type EventListener = Box<dyn for<'a> Fn(&'a Event<'a>) -> () + Send>;
+/// Counts the packets and bytes that went over the network.
+///
+/// All arrays are indexed by the `PacketType` of the packets. Resent packets
+/// are counted every time they are sent.
+///
+/// The counters only increase, so monitoring tools can compute rates by
+/// comparing two snapshots.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct TrafficStats {
+	pub packets_sent: [u64; 9],
+	pub bytes_sent: [u64; 9],
+	pub packets_received: [u64; 9],
+	pub bytes_received: [u64; 9],
+	/// The number of packets that were sent again because they were not
+	/// acknowledged in time.
+	pub packets_resent: u64,
+	/// The number of command packets that were received before a packet with
+	/// a lower id.
+	pub packets_out_of_order: u64,
+	/// The number of received packets that were discarded, e.g. because they
+	/// were duplicates or could not be decrypted.
+	pub packets_dropped: u64,
+}
+
+/// Gets called for every [`Event`] of a connection.
+///
+/// The listener is removed when it returns `false`.
+///
+/// [`Event`]: enum.Event.html
+pub type EventListener = Box<dyn for<'a> Fn(&'a Event<'a>) -> bool + Send>;
 
 /// Represents a currently alive connection.
 pub struct Connection {
@@ -97,6 +127,8 @@ pub struct Connection {
 	pub codec: PacketCodec,
 	pub udp_socket: Box<dyn Socket + Send>,
 	udp_buffer: Vec<u8>,
+	/// Statistics about sent and received packets.
+	pub stats: TrafficStats,
 
 	/// A buffer of packets that should be returned from the stream.
 	///
@@ -136,6 +168,30 @@ impl Default for CachedKey {
 	}
 }
 
+impl TrafficStats {
+	pub(crate) fn count_sent(&mut self, packet: &OutUdpPacket) {
+		let type_i = packet.packet_type().to_usize().unwrap();
+		self.packets_sent[type_i] += 1;
+		self.bytes_sent[type_i] += packet.data().data().len() as u64;
+	}
+
+	/// The number of packets which were sent with this packet type.
+	pub fn get_packets_sent(&self, p_type: PacketType) -> u64 {
+		self.packets_sent[p_type.to_usize().unwrap()]
+	}
+
+	/// The number of packets which were received with this packet type.
+	pub fn get_packets_received(&self, p_type: PacketType) -> u64 {
+		self.packets_received[p_type.to_usize().unwrap()]
+	}
+
+	/// The total number of sent bytes.
+	pub fn get_total_bytes_sent(&self) -> u64 { self.bytes_sent.iter().sum() }
+
+	/// The total number of received bytes.
+	pub fn get_total_bytes_received(&self) -> u64 { self.bytes_received.iter().sum() }
+}
+
 impl ConnectedParams {
 	/// Fills the parameters for a connection with their default state.
 	pub fn new(public_key: EccKeyPubP256, shared_iv: [u8; 64], shared_mac: [u8; 8]) -> Self {
@@ -166,6 +222,7 @@ impl Connection {
 			codec: Default::default(),
 			udp_socket,
 			udp_buffer: Default::default(),
+			stats: Default::default(),
 
 			stream_items: Default::default(),
 			acks_to_send: Default::default(),
@@ -212,11 +269,7 @@ impl Connection {
 		(in_recv_win, gen_id, cur_next, limit)
 	}
 
-	pub fn send_event(&self, event: &Event) {
-		for l in &self.event_listeners {
-			l(event)
-		}
-	}
+	pub fn send_event(&mut self, event: &Event) { self.event_listeners.retain(|l| l(event)); }
 
 	pub fn hand_back_buffer(&mut self, buffer: Vec<u8>) {
 		if self.udp_buffer.capacity() < MAX_UDP_PACKET_LENGTH
@@ -276,6 +329,7 @@ impl Connection {
 		&mut self, cx: &mut Context, udp_buffer: Vec<u8>, addr: SocketAddr,
 	) -> Result<()> {
 		if addr != self.address {
+			self.stats.packets_dropped += 1;
 			self.stream_items.push_back(StreamItem::Error(Error::WrongAddress));
 			return Ok(());
 		}
@@ -284,6 +338,7 @@ impl Connection {
 		let packet = InUdpPacket(match InPacket::try_new(dir, &udp_buffer) {
 			Ok(r) => r,
 			Err(e) => {
+				self.stats.packets_dropped += 1;
 				self.stream_items.push_back(StreamItem::Error(Error::PacketParse("udp", e)));
 				return Ok(());
 			}
@@ -291,6 +346,10 @@ impl Connection {
 		let event = Event::ReceiveUdpPacket(&packet);
 		self.send_event(&event);
 
+		let type_i = packet.0.header().packet_type().to_usize().unwrap();
+		self.stats.packets_received[type_i] += 1;
+		self.stats.bytes_received[type_i] += udp_buffer.len() as u64;
+
 		self.resender.received_packet();
 		PacketCodec::handle_udp_packet(self, cx, udp_buffer)?;
 
@@ -309,6 +368,7 @@ impl Connection {
 			"Encoding an ack packet should only yield a single packet"
 		);
 		let packet = udp_packets.pop().unwrap();
+		self.stats.count_sent(&packet);
 
 		match self.poll_send_udp_packet(cx, &packet) {
 			Poll::Ready(r) => r,
@@ -341,6 +401,7 @@ impl Connection {
 
 	/// Add an udp packet to the send queue.
 	pub fn send_udp_packet(&mut self, packet: OutUdpPacket) {
+		self.stats.count_sent(&packet);
 		match packet.packet_type() {
 			PacketType::Init | PacketType::Command | PacketType::CommandLow => {
 				Resender::send_packet(self, packet);
@@ -371,8 +432,10 @@ impl Connection {
 			Poll::Pending => Poll::Pending,
 			Poll::Ready(size) => {
 				let event = Event::SendUdpPacket(&packet);
+				// Listeners which want to be removed are removed with the next
+				// event that is sent with a mutable connection.
 				for l in event_listeners {
-					l(&event)
+					l(&event);
 				}
 
 				if size != data.len() {
@@ -389,6 +452,18 @@ impl Connection {
 
 	pub fn is_send_queue_full(&self) -> bool { self.resender.is_full() }
 	pub fn is_send_queue_empty(&self) -> bool { self.resender.is_empty() }
+	/// The number of reliable packets that were not yet acknowledged.
+	pub fn send_queue_len(&self) -> usize { self.resender.len() }
+
+	/// Returns `Ready` if the send queue is not full.
+	///
+	/// Otherwise, the current task is woken up when the other side acknowledged
+	/// enough packets. The connection has to be polled in the meantime, to
+	/// receive the acknowledgements.
+	///
+	/// Only commands and init packets go through the send queue, acks, pings
+	/// and audio packets can always be sent.
+	pub fn poll_send_ready(&mut self, cx: &mut Context) -> Poll<()> { self.resender.poll_ready(cx) }
 }
 
 /// Pull for events.
@@ -13,6 +13,9 @@
 
 use std::fmt;
 use std::num::ParseIntError;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
 
 use serde::de::{Unexpected, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -25,6 +28,7 @@ pub mod client;
 pub mod connection;
 pub mod license;
 pub mod log;
+pub mod observe;
 pub mod packet_codec;
 pub mod resend;
 pub mod utils;
@@ -36,6 +40,9 @@ git_testament::git_testament!(TESTAMENT);
 #[doc(hidden)]
 pub fn get_testament() -> &'static git_testament::GitTestament<'static> { &TESTAMENT }
 
+/// The version of this crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 type Result<T> = std::result::Result<T, Error>;
 
 /// The maximum number of bytes for a fragmented packet.
@@ -67,6 +74,12 @@ pub const ROOT_KEY: [u8; 32] = [
 	0x75, 0x55, 0xb2, 0x9d, 0xcc, 0xec, 0x73, 0xcd, 0x18, 0x75, 0x0f, 0x99, 0x38, 0x12, 0x40, 0x8a,
 ];
 
+/// The number of counters that are tried at once when improving the hash cash
+/// level of an identity.
+///
+/// Progress is reported and cancellation is checked after every chunk.
+pub const HASH_CASH_CHUNK_SIZE: u64 = 0x10000;
+
 /// The maximum amount of ack pachets that a connection intermediately stores.
 ///
 /// When this amount is stored, no new packets will be polled from the UDP
@@ -107,6 +120,10 @@ pub enum Error {
 	ComputeIv(#[source] tsproto_types::crypto::Error),
 	#[error("Connection timed out: {0}")]
 	Timeout(&'static str),
+	#[error("Failed to compute hash cash: {0}")]
+	HashCash(#[source] tokio::task::JoinError),
+	#[error("Invalid identity: {0}")]
+	InvalidIdentity(&'static str),
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -148,9 +165,17 @@ fn deserialize_id_key<'de, D: Deserializer<'de>>(
 
 impl Identity {
 	#[inline]
-	pub fn create() -> Result<Self> {
+	pub fn create() -> Result<Self> { Self::create_with_level(8) }
+
+	/// Create a new identity with at least the given security level.
+	///
+	/// Every additional level doubles the expected time for the creation.
+	/// Use [`improve_level_until`] to get progress reports.
+	///
+	/// [`improve_level_until`]: #method.improve_level_until
+	pub fn create_with_level(target: u8) -> Result<Self> {
 		let mut res = Self::new(EccKeyPrivP256::create().map_err(Error::IdentityCrypto)?, 0);
-		res.upgrade_level(8)?;
+		res.upgrade_level(target)?;
 		Ok(res)
 	}
 
@@ -178,6 +203,44 @@ impl Identity {
 		Ok(res)
 	}
 
+	/// Parse an identity how the official client stores it.
+	///
+	/// Format: counter || 'V' || obfuscated key
+	///
+	/// This is the `identity` value of an identity that was exported from the
+	/// official client, without the surrounding quotes.
+	pub fn from_ts_obfuscated(data: &str) -> Result<Self> {
+		let pos = data.find('V').ok_or(Error::InvalidIdentity("missing counter"))?;
+		let counter = data[..pos].parse().map_err(|_| Error::InvalidIdentity("invalid counter"))?;
+		let key =
+			EccKeyPrivP256::from_ts_obfuscated(&data[pos + 1..]).map_err(Error::IdentityCrypto)?;
+		Ok(Self::new(key, counter))
+	}
+
+	/// Parse an identity from the ini file that the official client exports.
+	///
+	/// Only the `identity` entry of the `[Identity]` section is used.
+	pub fn from_ts_ini(ini: &str) -> Result<Self> {
+		let mut in_section = false;
+		for line in ini.lines().map(str::trim) {
+			if line.starts_with('[') {
+				in_section = line == "[Identity]";
+			} else if in_section && line.starts_with("identity=") {
+				let value = line["identity=".len()..].trim_matches('"');
+				return Self::from_ts_obfuscated(value);
+			}
+		}
+		Err(Error::InvalidIdentity("no identity entry in the [Identity] section"))
+	}
+
+	/// Store the identity how the official client stores it.
+	///
+	/// The counter is stored together with the key, `max_counter` is lost.
+	pub fn to_ts_obfuscated(&self) -> Result<String> {
+		let key = self.key.to_ts_obfuscated().map_err(Error::IdentityCrypto)?;
+		Ok(format!("{}V{}", self.counter, key))
+	}
+
 	#[inline]
 	pub fn key(&self) -> &EccKeyPrivP256 { &self.key }
 	#[inline]
@@ -201,13 +264,204 @@ impl Identity {
 
 	/// Compute a better hash cash level.
 	pub fn upgrade_level(&mut self, target: u8) -> Result<()> {
+		self.improve_level_until(target, |_, _| {})
+	}
+
+	/// Compute a better hash cash level with multiple threads.
+	///
+	/// The counters are split into chunks, which are handed out to `threads`
+	/// workers. All workers stop after their current chunk when one of them
+	/// reached the target level.
+	///
+	/// The found counter is not necessarily the smallest one with this level.
+	pub fn upgrade_level_parallel(&mut self, target: u8, threads: usize) -> Result<()> {
+		let omega = self.key.to_pub().to_ts().map_err(Error::IdentityCrypto)?;
+		let current = algs::HashCash::new(&omega).level(self.counter);
+		if current >= target {
+			return Ok(());
+		}
+
+		let omega = Arc::new(omega);
+		let next = Arc::new(AtomicU64::new(self.max_counter));
+		let done = Arc::new(AtomicBool::new(false));
+		let workers = (0..threads.max(1))
+			.map(|_| {
+				let omega = omega.clone();
+				let next = next.clone();
+				let done = done.clone();
+				thread::spawn(move || {
+					let mut hash_cash = algs::HashCash::new(&omega);
+					// The best level and its counter
+					let mut best = (0, 0);
+					while !done.load(Ordering::Relaxed) {
+						let start = next.fetch_add(HASH_CASH_CHUNK_SIZE, Ordering::Relaxed);
+						if start > u64::max_value() - HASH_CASH_CHUNK_SIZE {
+							// All counters were tried
+							done.store(true, Ordering::Relaxed);
+							break;
+						}
+						for offset in start..start + HASH_CASH_CHUNK_SIZE {
+							let level = hash_cash.level(offset);
+							if level > best.0 {
+								best = (level, offset);
+								if level >= target {
+									done.store(true, Ordering::Relaxed);
+									return best;
+								}
+							}
+						}
+					}
+					best
+				})
+			})
+			.collect::<Vec<_>>();
+
+		let results = workers
+			.into_iter()
+			.map(|w| w.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+			.collect::<Vec<_>>();
+		// Prefer the smallest counter that reached the target
+		let (level, offset) = results
+			.iter()
+			.filter(|(level, _)| *level >= target)
+			.min_by_key(|(_, offset)| *offset)
+			.or_else(|| results.iter().max_by_key(|(level, _)| *level))
+			.cloned()
+			.unwrap();
+
+		if level > current {
+			self.counter = offset;
+		}
+		// Workers only stop early if all counters were tried
+		self.max_counter = if level >= target { offset } else { u64::max_value() };
+		Ok(())
+	}
+
+	/// Compute a better hash cash level and report the progress.
+	///
+	/// `progress` is called regularly with the best level that was found so
+	/// far and the counter that will be tried next.
+	pub fn improve_level_until<F: FnMut(u8, u64)>(
+		&mut self, target: u8, mut progress: F,
+	) -> Result<()> {
+		loop {
+			let done = self.improve_level_steps(target, HASH_CASH_CHUNK_SIZE)?;
+			progress(self.level()?, self.max_counter);
+			if done {
+				return Ok(());
+			}
+		}
+	}
+
+	/// Try the next `steps` counters to reach a better hash cash level.
+	///
+	/// The counter is set to the best one that was found. The work is resumed
+	/// at `max_counter` on the next call.
+	///
+	/// Returns `true` if the target level is reached or all counters were
+	/// tried.
+	pub fn improve_level_steps(&mut self, target: u8, steps: u64) -> Result<bool> {
 		let omega = self.key.to_pub().to_ts().map_err(Error::IdentityCrypto)?;
+		let mut hash_cash = algs::HashCash::new(&omega);
+		let mut best = hash_cash.level(self.counter);
+		if best >= target {
+			return Ok(true);
+		}
+
+		let end = self.max_counter.saturating_add(steps);
 		let mut offset = self.max_counter;
-		while offset < u64::max_value() && algs::get_hash_cash_level(&omega, offset) < target {
+		while offset < end {
+			let level = hash_cash.level(offset);
+			if level > best {
+				best = level;
+				self.counter = offset;
+				if level >= target {
+					self.max_counter = offset;
+					return Ok(true);
+				}
+			}
 			offset += 1;
 		}
-		self.counter = offset;
 		self.max_counter = offset;
-		Ok(())
+		Ok(offset == u64::max_value())
+	}
+
+	/// Compute a better hash cash level on the blocking thread pool of tokio.
+	///
+	/// The work is split into chunks, `progress` is called after every chunk
+	/// like in [`improve_level_until`]. Dropping the future cancels the
+	/// computation after the current chunk.
+	///
+	/// [`improve_level_until`]: #method.improve_level_until
+	pub async fn improve_level_async<F: FnMut(u8, u64)>(
+		mut self, target: u8, mut progress: F,
+	) -> Result<Self> {
+		loop {
+			let (id, done) = tokio::task::spawn_blocking(move || {
+				let done = self.improve_level_steps(target, HASH_CASH_CHUNK_SIZE);
+				(self, done)
+			})
+			.await
+			.map_err(Error::HashCash)?;
+			self = id;
+			let done = done?;
+			progress(self.level()?, self.max_counter);
+			if done {
+				return Ok(self);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TEST_PRIV_KEY: &str = "MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTA\
+		O2+k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITsC/50CIA8M5nmDB\
+		nmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI";
+	/// `TEST_PRIV_KEY` with the counter 123, as stored by the official client.
+	const TEST_OBFUSCATED: &str = "123VOfydZwzLwCCWm3smUImF+mI+6W1fV1lhW1UpASZABXEQe1h0XnoPAA8\
+		ZEABhJXoDSF4Id2FYdj82Un0GfFZ5X1Nce18DCUoFDCFJBlcSCgN6AmNCT0AGVlYBcUUBLGMBLGRFcC81MENJQThN\
+		NW5tREJubURNL2daLy80QUFBQUFBQUFBQUFBQUFBQUFBQUFaUnpPSQ==";
+
+	#[test]
+	fn obfuscated_identity() {
+		let id = Identity::from_ts_obfuscated(TEST_OBFUSCATED).unwrap();
+		let key = EccKeyPrivP256::from_ts(TEST_PRIV_KEY).unwrap();
+		assert_eq!(id.key().to_short(), key.to_short());
+		assert_eq!(id.counter(), 123);
+
+		let id2 = Identity::from_ts_obfuscated(&id.to_ts_obfuscated().unwrap()).unwrap();
+		assert_eq!(id2.key().to_short(), key.to_short());
+		assert_eq!(id2.counter(), 123);
+	}
+
+	#[test]
+	fn ini_identity() {
+		let ini = format!(
+			"[Identity]\nid=Bot\nidentity=\"{}\"\nnickname=Bot\nphonetic_nickname=\n",
+			TEST_OBFUSCATED
+		);
+		let id = Identity::from_ts_ini(&ini).unwrap();
+		let key = EccKeyPrivP256::from_ts(TEST_PRIV_KEY).unwrap();
+		assert_eq!(id.key().to_short(), key.to_short());
+		assert_eq!(id.counter(), 123);
+
+		assert!(Identity::from_ts_ini("[Other]\nidentity=1V\n").is_err());
+		assert!(Identity::from_ts_obfuscated("VOfydZ").is_err());
+	}
+
+	#[test]
+	fn upgrade_level_parallel() {
+		let mut id = Identity::new(EccKeyPrivP256::create().unwrap(), 0);
+		id.upgrade_level_parallel(10, 4).unwrap();
+		assert!(id.level().unwrap() >= 10);
+		assert_eq!(id.counter(), id.max_counter());
+
+		// Nothing changes if the level is already reached
+		let counter = id.counter();
+		id.upgrade_level_parallel(8, 4).unwrap();
+		assert_eq!(id.counter(), counter);
 	}
 }
@@ -1,11 +1,54 @@
 use std::fmt::Debug;
 use std::str;
 
-use slog::{debug, o, Logger};
+use slog::{debug, info, o, Logger};
 use tsproto_packets::packets::{OutUdpPacket, PacketType};
 
 use crate::connection::{Connection, Event};
 
+/// How much is logged for packets in one direction.
+///
+/// Every level includes the output of the lower levels.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Verbosity {
+	/// Log nothing.
+	Off,
+	/// Log the text of commands at info level.
+	Commands,
+	/// Log the content of all packets in high-level form.
+	Packets,
+	/// Additionally log the headers of udp packets.
+	UdpPackets,
+}
+
+/// What is logged by [`add_logger_with_config`].
+///
+/// # Default
+/// Nothing is logged.
+///
+/// [`add_logger_with_config`]: fn.add_logger_with_config.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LogConfig {
+	/// Received packets.
+	pub incoming: Verbosity,
+	/// Sent packets.
+	pub outgoing: Verbosity,
+}
+
+impl Default for LogConfig {
+	fn default() -> Self { Self { incoming: Verbosity::Off, outgoing: Verbosity::Off } }
+}
+
+impl LogConfig {
+	/// Log packets in both directions with the same verbosity.
+	pub fn new(verbosity: Verbosity) -> Self { Self { incoming: verbosity, outgoing: verbosity } }
+
+	/// If anything is logged.
+	pub fn is_enabled(&self) -> bool {
+		self.incoming != Verbosity::Off || self.outgoing != Verbosity::Off
+	}
+}
+
 fn prepare_logger(logger: &Logger, is_client: bool, incoming: bool) -> Logger {
 	let in_s = if incoming {
 		if !cfg!(windows) { "\x1b[1;32mIN\x1b[0m" } else { "IN" }
@@ -45,9 +88,9 @@ pub fn log_command(
 	// detect if a packet is incoming or not.
 	let logger = prepare_logger(logger, is_client, incoming);
 	if p_type == PacketType::Command {
-		debug!(logger, "Command"; "content" => cmd);
+		info!(logger, "Command"; "content" => cmd);
 	} else {
-		debug!(logger, "CommandLow"; "content" => cmd);
+		info!(logger, "CommandLow"; "content" => cmd);
 	}
 }
 
@@ -57,17 +100,27 @@ pub fn log_command(
 /// 1 - Print packets
 /// 2 - Print udp packets
 pub fn add_logger(logger: Logger, verbosity: u8, con: &mut Connection) {
+	let verbosity = match verbosity {
+		0 => Verbosity::Commands,
+		1 => Verbosity::Packets,
+		_ => Verbosity::UdpPackets,
+	};
+	add_logger_with_config(logger, LogConfig::new(verbosity), con);
+}
+
+/// Print the content of packets, configured separately for each direction.
+pub fn add_logger_with_config(logger: Logger, config: LogConfig, con: &mut Connection) {
 	let is_client = con.is_client;
-	let listener = Box::new(move |event: &Event| match event {
+	let log = move |event: &Event| match event {
 		Event::ReceiveUdpPacket(packet) => {
-			if verbosity > 0 {
+			if config.incoming >= Verbosity::UdpPackets {
 				log_udp_packet(&logger, is_client, true, packet.0.header());
 			}
 		}
 		Event::ReceivePacket(packet) => {
-			if verbosity > 1 {
+			if config.incoming >= Verbosity::Packets {
 				log_packet(&logger, is_client, true, packet);
-			} else {
+			} else if config.incoming == Verbosity::Commands {
 				let p_type = packet.header().packet_type();
 				if p_type.is_command() {
 					if let Ok(s) = str::from_utf8(packet.content()) {
@@ -77,14 +130,14 @@ pub fn add_logger(logger: Logger, verbosity: u8, con: &mut Connection) {
 			}
 		}
 		Event::SendUdpPacket(packet) => {
-			if verbosity > 0 {
+			if config.outgoing >= Verbosity::UdpPackets {
 				log_out_udp_packet(&logger, is_client, false, packet);
 			}
 		}
 		Event::SendPacket(packet) => {
-			if verbosity > 1 {
+			if config.outgoing >= Verbosity::Packets {
 				log_packet(&logger, is_client, false, &packet.packet());
-			} else {
+			} else if config.outgoing == Verbosity::Commands {
 				let p_type = packet.header().packet_type();
 				if p_type.is_command() {
 					if let Ok(s) = str::from_utf8(packet.content()) {
@@ -93,7 +146,10 @@ pub fn add_logger(logger: Logger, verbosity: u8, con: &mut Connection) {
 				}
 			}
 		}
-	});
+	};
 
-	con.event_listeners.push(listener);
+	con.event_listeners.push(Box::new(move |event: &Event| {
+		log(event);
+		true
+	}));
 }
@@ -0,0 +1,102 @@
+//! Observe the packets of a connection, e.g. for diagnostics.
+//!
+//! The [`StreamItem`]s of a connection only contain commands, audio and init
+//! packets. Acks, pings and pongs are handled by the connection itself. The
+//! streams of this module get a copy of every decrypted packet of a type, or
+//! of all packets, in both directions.
+//!
+//! A stream ends when the connection is dropped. A dropped stream is removed
+//! from the connection with the next packet.
+//!
+//! [`StreamItem`]: ../connection/enum.StreamItem.html
+use futures::channel::mpsc;
+use tsproto_packets::packets::{Direction, InPacket, PacketType};
+
+use crate::connection::{Connection, Event};
+
+/// A stream of observed packets.
+pub type PacketStream = mpsc::UnboundedReceiver<ObservedPacket>;
+
+/// A packet which was received or sent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ObservedPacket {
+	/// `true` if the packet was received, `false` if it was sent.
+	pub incoming: bool,
+	/// The direction from the header of the packet.
+	pub direction: Direction,
+	pub packet_type: PacketType,
+	/// The header and the decrypted content of the packet.
+	pub data: Vec<u8>,
+}
+
+impl ObservedPacket {
+	/// Parse the observed data as packet.
+	pub fn packet(&self) -> Result<InPacket, tsproto_packets::Error> {
+		InPacket::try_new(self.direction, &self.data)
+	}
+
+	/// Copy the packet of a [`ReceivePacket`] or [`SendPacket`] event.
+	///
+	/// Other events and packets whose type does not pass the `filter` return
+	/// `None` without being copied.
+	///
+	/// [`ReceivePacket`]: ../connection/enum.Event.html#variant.ReceivePacket
+	/// [`SendPacket`]: ../connection/enum.Event.html#variant.SendPacket
+	pub fn from_event<F: Fn(PacketType) -> bool>(event: &Event, filter: F) -> Option<Self> {
+		match event {
+			Event::ReceivePacket(packet) => {
+				let header = packet.header();
+				let packet_type = header.packet_type();
+				if !filter(packet_type) {
+					return None;
+				}
+				Some(Self {
+					incoming: true,
+					direction: header.direction(),
+					packet_type,
+					data: [header.data(), packet.content()].concat(),
+				})
+			}
+			Event::SendPacket(packet) => {
+				let packet_type = packet.header().packet_type();
+				if !filter(packet_type) {
+					return None;
+				}
+				Some(Self {
+					incoming: false,
+					direction: packet.direction(),
+					packet_type,
+					data: packet.data().to_vec(),
+				})
+			}
+			_ => None,
+		}
+	}
+}
+
+/// Get all packets of one type.
+pub fn subscribe_packet_type(con: &mut Connection, packet_type: PacketType) -> PacketStream {
+	subscribe(con, move |t| t == packet_type)
+}
+
+/// Get all packets, regardless of their type.
+pub fn subscribe_all(con: &mut Connection) -> PacketStream { subscribe(con, |_| true) }
+
+fn subscribe<F: Fn(PacketType) -> bool + Send + 'static>(
+	con: &mut Connection, filter: F,
+) -> PacketStream {
+	let (send, recv) = mpsc::unbounded();
+	let listener = Box::new(move |event: &Event| {
+		// Remove the listener without copying the packet if the stream was
+		// dropped
+		if send.is_closed() {
+			return false;
+		}
+		match ObservedPacket::from_event(event, &filter) {
+			Some(packet) => send.unbounded_send(packet).is_ok(),
+			None => true,
+		}
+	});
+	con.event_listeners.push(listener);
+	recv
+}
@@ -55,7 +55,7 @@ impl PacketCodec {
 
 		if let Some(params) = &con.params {
 			if p_type == PacketType::Init {
-				con.stream_items.push_back(StreamItem::Error(Error::UnexpectedInitPacket));
+				Self::drop_packet(con, Error::UnexpectedInitPacket);
 				return Ok(());
 			}
 			if !con.is_client {
@@ -63,7 +63,7 @@ impl PacketCodec {
 				// Accept any client id for the first few acks
 				let is_first_ack = gen_id == 0 && id <= 3 && p_type == PacketType::Ack;
 				if c_id != params.c_id && !is_first_ack {
-					con.stream_items.push_back(StreamItem::Error(Error::WrongClientId(c_id)));
+					Self::drop_packet(con, Error::WrongClientId(c_id));
 					return Ok(());
 				}
 			}
@@ -97,7 +97,7 @@ impl PacketCodec {
 					}) {
 						Ok(r) => r,
 						Err(e) => {
-							con.stream_items.push_back(StreamItem::Error(e));
+							Self::drop_packet(con, e);
 							return Ok(());
 						}
 					}
@@ -106,17 +106,17 @@ impl PacketCodec {
 					match algs::decrypt(&packet, gen_id, &params.shared_iv, &mut params.key_cache) {
 						Ok(r) => r,
 						Err(e) => {
-							con.stream_items.push_back(StreamItem::Error(e));
+							Self::drop_packet(con, e);
 							return Ok(());
 						}
 					}
 				} else {
 					// Failed to fake decrypt the packet
-					con.stream_items.push_back(StreamItem::Error(Error::WrongMac {
+					Self::drop_packet(con, Error::WrongMac {
 						p_type,
 						generation_id: gen_id,
 						packet_id: id,
-					}));
+					});
 					return Ok(());
 				};
 
@@ -124,7 +124,7 @@ impl PacketCodec {
 				(&mut packet_data[start..]).copy_from_slice(&new_content);
 			} else if algs::must_encrypt(p_type) {
 				// Check if it is ok for the packet to be unencrypted
-				con.stream_items.push_back(StreamItem::Error(Error::UnallowedUnencryptedPacket));
+				Self::drop_packet(con, Error::UnallowedUnencryptedPacket);
 				return Ok(());
 			}
 
@@ -230,12 +230,7 @@ impl PacketCodec {
 			if p_type == PacketType::Command || p_type == PacketType::CommandLow {
 				ack = true;
 			}
-			con.stream_items.push_back(StreamItem::Error(Error::NotInReceiveWindow {
-				id,
-				next: cur_next,
-				limit,
-				p_type,
-			}));
+			Self::drop_packet(con, Error::NotInReceiveWindow { id, next: cur_next, limit, p_type });
 		}
 
 		// Send ack
@@ -246,6 +241,12 @@ impl PacketCodec {
 		Ok(())
 	}
 
+	/// Discard a received packet and report the reason as an error.
+	fn drop_packet(con: &mut Connection, error: Error) {
+		con.stats.packets_dropped += 1;
+		con.stream_items.push_back(StreamItem::Error(error));
+	}
+
 	/// Handle `Command` and `CommandLow` packets.
 	///
 	/// They have to be handled in the right order.
@@ -339,6 +340,7 @@ impl PacketCodec {
 			// Out of order
 			warn!(con.logger, "Out of order command packet"; "got" => id,
 				"expected" => cur_next);
+			con.stats.packets_out_of_order += 1;
 			let (limit, next_gen) = cur_next.overflowing_add(MAX_QUEUE_LEN);
 			if (!next_gen && id >= cur_next && id < limit)
 				|| (next_gen && (id >= cur_next || id < limit))
@@ -4,7 +4,7 @@ use std::convert::From;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::ops::{Add, Sub};
-use std::task::{Context, Poll};
+use std::task::{Context, Poll, Waker};
 
 use futures::prelude::*;
 use num_traits::ToPrimitive;
@@ -15,12 +15,11 @@ use tsproto_packets::packets::*;
 use crate::connection::{Connection, StreamItem};
 use crate::{Error, Result, UDP_SINK_CAPACITY};
 
-// TODO implement fast retransmit: 2 Acks received but earlier packet not acked -> retransmit
-// TODO implement slow start and redo slow start when send window reaches 1, also reset all tries then
-
 // Use cubic for congestion control: https://en.wikipedia.org/wiki/CUBIC_TCP
 // But scaling with number of sent packets instead of time because we might not
 // send packets that often.
+// Before the first loss and when the window shrinks to one packet, slow start
+// is used instead, which increases the window by one for every ack.
 
 /// Congestion windows gets down to 0.3*w_max for BETA=0.7
 const BETA: f32 = 0.7;
@@ -28,6 +27,27 @@ const BETA: f32 = 0.7;
 const C: f32 = 0.5;
 /// Store that many pings, if all of them get lost, it is a timeout.
 const PING_COUNT: usize = 30;
+/// Resend the oldest packet after that many newer packets were acknowledged.
+const FAST_RETRANSMIT_ACKS: u8 = 2;
+
+/// The phase of the congestion control.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum CongestionPhase {
+	/// The send window grows by one packet for every acknowledged packet.
+	SlowStart,
+	/// The send window follows the CUBIC function.
+	CongestionAvoidance,
+}
+
+/// The state of the congestion control, useful for debugging.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CongestionStats {
+	pub phase: CongestionPhase,
+	/// The number of packets that can be in-flight concurrently.
+	pub window: u16,
+	/// The send window before the last reduction.
+	pub w_max: u16,
+}
 
 /// Events to inform a resender of the current state of a connection.
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
@@ -70,6 +90,11 @@ struct SendRecord {
 	pub sent: Instant,
 	pub id: SendRecordId,
 	pub packet: OutUdpPacket,
+	/// If the packet was sent more than once.
+	///
+	/// Unlike `tries`, this is not reset when slow start begins again. The
+	/// round trip time is only measured with packets that were not resent.
+	pub resent: bool,
 }
 
 #[derive(Debug)]
@@ -99,8 +124,26 @@ pub struct Resender {
 	state: ResenderState,
 	/// A list of the last sent pings that were not yet acknowledged.
 	last_pings: Vec<Ping>,
+	/// The round trip time of the last answered ping.
+	last_ping_rtt: Option<Duration>,
+	/// How many packets were acknowledged by the other side.
+	acked_packets: u64,
+	/// How many packets were acknowledged since the oldest packet in the queue
+	/// was last acknowledged, per packet type.
+	newer_acks: [u8; 3],
+	/// The packet that should be resent without waiting for the timeout.
+	fast_retransmit: Option<PacketId>,
+	/// When the last fast retransmit reduced the send window.
+	///
+	/// Packets that were sent before belong to the same window and do not
+	/// reduce it again.
+	last_fast_retransmit: Option<Instant>,
 
 	// Congestion control
+	/// The send window while in slow start.
+	///
+	/// Slow start ends when the window reaches `w_max` or a packet gets lost.
+	slow_start_window: Option<u16>,
 	/// The maximum send window before the last reduction.
 	w_max: u16,
 	/// The time when the last packet loss occured.
@@ -112,7 +155,7 @@ pub struct Resender {
 	/// increase the send window in this case.
 	no_congestion_since: Option<Instant>,
 
-	/// When the last packet was added to the send queue or received.
+	/// When the last packet was received.
 	///
 	/// This is used to decide when to send ping packets.
 	last_receive: Instant,
@@ -127,19 +170,31 @@ pub struct Resender {
 	ping_timeout: Delay,
 	/// The timer used for disconnecting the connection.
 	state_timeout: Delay,
+	/// Woken up when the send queue is not full anymore.
+	send_waker: Option<Waker>,
 }
 
+/// Timeouts and start values for resending packets.
 #[derive(Clone, Debug)]
 pub struct ResendConfig {
-	// Close the connection after no packet is received for this duration.
+	/// Close the connection during the handshake if a packet is not
+	/// acknowledged for this duration.
 	pub connecting_timeout: Duration,
+	/// The same timeout for an established connection.
 	pub normal_timeout: Duration,
+	/// The same timeout while disconnecting.
 	pub disconnect_timeout: Duration,
 
 	/// Start value for the Smoothed Round Trip Time.
 	pub srtt: Duration,
 	/// Start value for the deviation of the srtt.
 	pub srtt_dev: Duration,
+
+	/// Send a ping packet when no packet was received for this duration.
+	///
+	/// Sent packets do not delay the ping, they do not show that the other
+	/// side is still alive.
+	pub ping_interval: Duration,
 }
 
 impl Ord for PartialPacketId {
@@ -266,7 +321,13 @@ impl Default for Resender {
 			config: Default::default(),
 			state: ResenderState::Connecting,
 			last_pings: Default::default(),
+			last_ping_rtt: None,
+			acked_packets: 0,
+			newer_acks: Default::default(),
+			fast_retransmit: None,
+			last_fast_retransmit: None,
 
+			slow_start_window: Some(1),
 			w_max: UDP_SINK_CAPACITY as u16,
 			last_loss: now,
 			no_congestion_since: Some(now),
@@ -276,6 +337,7 @@ impl Default for Resender {
 			last_send: now,
 			ping_timeout: tokio::time::delay_for(std::time::Duration::from_secs(1)),
 			state_timeout: tokio::time::delay_for(std::time::Duration::from_secs(1)),
+			send_waker: None,
 		}
 	}
 }
@@ -297,10 +359,13 @@ impl Resender {
 		}
 
 		// Remove from ordered queue
-		let queue = &mut con.resender.full_send_queue[Self::packet_type_to_index(p_type)];
+		let type_i = Self::packet_type_to_index(p_type);
+		let queue = &mut con.resender.full_send_queue[type_i];
 		let mut queue_iter = queue.iter();
 		if let Some((first, _)) = queue_iter.next() {
-			let id = if first.packet_id == p_id {
+			let first = *first;
+			let is_first = first.packet_id == p_id;
+			let id = if is_first {
 				let p_id = if let Some((_, rec2)) = queue_iter.next() {
 					// Ack all until the next packet
 					rec2.id.id.part
@@ -315,7 +380,7 @@ impl Resender {
 				let id = PacketId { packet_type: p_type, part: p_id - 1 };
 				con.stream_items.push_back(StreamItem::AckPacket(id));
 
-				*first
+				first
 			} else {
 				PartialPacketId {
 					generation_id: if p_id < first.packet_id {
@@ -328,8 +393,22 @@ impl Resender {
 			};
 
 			if let Some(rec) = queue.remove(&id) {
+				con.resender.acked_packets += 1;
+				con.resender.grow_slow_start();
+				let newer_acks = &mut con.resender.newer_acks[type_i];
+				if is_first {
+					*newer_acks = 0;
+				} else {
+					// The first packet is probably lost, if newer packets
+					// arrive, resend it without waiting for the timeout.
+					*newer_acks = newer_acks.saturating_add(1);
+					if *newer_acks == FAST_RETRANSMIT_ACKS {
+						con.resender.fast_retransmit =
+							Some(PacketId { packet_type: p_type, part: first });
+					}
+				}
 				// Update srtt if the packet was not resent
-				if rec.id.tries == 1 {
+				if rec.id.tries == 1 && !rec.resent {
 					let now = Instant::now();
 					con.resender.update_srtt(now - rec.sent);
 				}
@@ -337,6 +416,11 @@ impl Resender {
 				// Notify the waker that we can send another packet from the
 				// send queue.
 				cx.waker().wake_by_ref();
+				if !con.resender.is_full() {
+					if let Some(waker) = con.resender.send_waker.take() {
+						waker.wake();
+					}
+				}
 			}
 		}
 	}
@@ -344,8 +428,9 @@ impl Resender {
 	pub fn ack_ping(con: &mut Connection, p_id: u16) {
 		if let Ok(i) = con.resender.last_pings.binary_search_by_key(&p_id, |p| p.id.packet_id) {
 			let ping = con.resender.last_pings.remove(i);
-			let now = Instant::now();
-			con.resender.update_srtt(now - ping.sent);
+			let rtt = Instant::now() - ping.sent;
+			con.resender.last_ping_rtt = Some(rtt);
+			con.resender.update_srtt(rtt);
 		}
 	}
 
@@ -373,13 +458,61 @@ impl Resender {
 
 	pub fn get_state(&self) -> ResenderState { self.state }
 
+	pub fn get_config(&self) -> &ResendConfig { &self.config }
+	/// Change the configuration.
+	///
+	/// This also resets the smoothed round trip time and its deviation to
+	/// `srtt` and `srtt_dev` of the new configuration.
+	pub fn set_config(&mut self, config: ResendConfig) {
+		// The current round trip time is part of the config
+		self.config = config;
+	}
+
+	/// The current smoothed round trip time.
+	pub fn get_srtt(&self) -> Duration { self.config.srtt }
+	/// The current deviation of the smoothed round trip time.
+	pub fn get_srtt_dev(&self) -> Duration { self.config.srtt_dev }
+	/// The round trip time of the last ping, which was answered with a pong.
+	pub fn get_last_ping(&self) -> Option<Duration> { self.last_ping_rtt }
+	/// The number of packets that were acknowledged by the other side.
+	pub fn get_acked_packets(&self) -> u64 { self.acked_packets }
+
+	pub fn get_congestion_stats(&self) -> CongestionStats {
+		CongestionStats {
+			phase: if self.slow_start_window.is_some() {
+				CongestionPhase::SlowStart
+			} else {
+				CongestionPhase::CongestionAvoidance
+			},
+			window: self.get_window(),
+			w_max: self.w_max,
+		}
+	}
+
 	/// If the send queue is full if it reached the congestion window size or
 	/// it contains packets that were not yet sent once.
-	pub fn is_full(&self) -> bool { self.full_send_queue.len() >= self.get_window() as usize }
+	pub fn is_full(&self) -> bool { self.len() >= self.get_window() as usize }
 
 	/// If the send queue is empty.
 	pub fn is_empty(&self) -> bool { self.full_send_queue.iter().all(|q| q.is_empty()) }
 
+	/// The number of packets in the send queue.
+	///
+	/// These packets were not yet acknowledged.
+	pub fn len(&self) -> usize { self.full_send_queue.iter().map(BTreeMap::len).sum() }
+
+	/// Returns `Ready` if the send queue is not full.
+	///
+	/// Otherwise, the task is woken up when enough packets were acknowledged.
+	pub fn poll_ready(&mut self, cx: &mut Context) -> Poll<()> {
+		if self.is_full() {
+			self.send_waker = Some(cx.waker().clone());
+			Poll::Pending
+		} else {
+			Poll::Ready(())
+		}
+	}
+
 	/// Take the first packets from `to_send_ordered` and put them into
 	/// `to_send`.
 	///
@@ -436,6 +569,9 @@ impl Resender {
 	///
 	/// The CUBIC congestion control window.
 	fn get_window(&self) -> u16 {
+		if let Some(window) = self.slow_start_window {
+			return window;
+		}
 		let time = self.no_congestion_since.unwrap_or_else(Instant::now) - self.last_loss;
 		let res = C
 			* (time.as_secs_f32() - (self.w_max as f32 * BETA / C).powf(1.0 / 3.0)).powf(3.0)
@@ -450,6 +586,42 @@ impl Resender {
 		}
 	}
 
+	/// Increase the window in slow start after a packet was acknowledged.
+	fn grow_slow_start(&mut self) {
+		if let Some(window) = &mut self.slow_start_window {
+			*window += 1;
+			if *window >= self.w_max {
+				// Continue with CUBIC at the same window
+				self.slow_start_window = None;
+				let k = (f32::from(self.w_max) * BETA / C).powf(1.0 / 3.0);
+				let now = self.no_congestion_since.unwrap_or_else(Instant::now);
+				self.last_loss = now - Duration::from_secs_f32(k);
+			}
+		}
+	}
+
+	/// Reduce the congestion window after a packet loss.
+	///
+	/// If the window shrinks to a single packet, start again with slow start
+	/// up to the `w_max` from before the loss and count all sent packets as
+	/// sent once. The send queue has to be rebuilt afterwards.
+	fn reduce_window(&mut self) {
+		let w_max = self.w_max;
+		self.w_max = self.get_window();
+		self.slow_start_window = None;
+		self.last_loss = Instant::now();
+		self.no_congestion_since = None;
+		if self.get_window() <= 1 {
+			self.w_max = w_max;
+			self.slow_start_window = Some(1);
+			for queue in &mut self.full_send_queue {
+				for rec in queue.values_mut() {
+					rec.id.tries = rec.id.tries.min(1);
+				}
+			}
+		}
+	}
+
 	/// Add another duration to the stored smoothed rtt.
 	fn update_srtt(&mut self, rtt: Duration) {
 		let diff =
@@ -464,6 +636,7 @@ impl Resender {
 			sent: Instant::now(),
 			id: SendRecordId { last: Instant::now(), tries: 0, id: (&packet).into() },
 			packet,
+			resent: false,
 		};
 
 		let i = Self::packet_type_to_index(rec.id.id.packet_type);
@@ -478,6 +651,10 @@ impl Resender {
 		// Send a packet at least every second
 		let max_send_rto = Duration::from_secs(1);
 
+		if let Some(id) = con.resender.fast_retransmit {
+			Self::poll_fast_retransmit(con, cx, id)?;
+		}
+
 		// Check if there are packets to send.
 		loop {
 			let now = Instant::now();
@@ -536,6 +713,7 @@ impl Resender {
 				Poll::Ready(r) => {
 					r?;
 					if rec.tries != 0 {
+						con.stats.count_sent(&full_rec.packet);
 						let to_s = if con.is_client { "S" } else { "C" };
 						warn!(con.logger, "Resend";
 							"id" => ?rec.id,
@@ -555,17 +733,16 @@ impl Resender {
 					full_rec.id = rec.clone();
 
 					if rec.tries != 1 {
+						full_rec.resent = true;
 						drop(rec);
+						con.stats.packets_resent += 1;
 						// Double srtt on packet loss
 						con.resender.config.srtt *= 2;
 						if con.resender.config.srtt > timeout {
 							con.resender.config.srtt = timeout;
 						}
 
-						// Handle congestion window
-						con.resender.w_max = con.resender.get_window();
-						con.resender.last_loss = Instant::now();
-						con.resender.no_congestion_since = None;
+						con.resender.reduce_window();
 						con.resender.rebuild_send_queue();
 					}
 				}
@@ -575,6 +752,59 @@ impl Resender {
 		Ok(())
 	}
 
+	/// Resend a packet that was probably lost, because newer packets were
+	/// acknowledged.
+	fn poll_fast_retransmit(con: &mut Connection, cx: &mut Context, id: PacketId) -> Result<()> {
+		let type_i = Self::packet_type_to_index(id.packet_type);
+		let full_rec = if let Some(r) = con.resender.full_send_queue[type_i].get_mut(&id.part) {
+			r
+		} else {
+			// Acknowledged in the meantime
+			con.resender.fast_retransmit = None;
+			return Ok(());
+		};
+		if full_rec.id.tries == 0 {
+			// Not sent yet
+			con.resender.fast_retransmit = None;
+			return Ok(());
+		}
+
+		match Connection::static_poll_send_udp_packet(
+			&*con.udp_socket,
+			&con.address,
+			&con.event_listeners,
+			cx,
+			&full_rec.packet,
+		) {
+			// Try again with the next poll
+			Poll::Pending => return Ok(()),
+			Poll::Ready(r) => r?,
+		}
+
+		let now = Instant::now();
+		con.stats.count_sent(&full_rec.packet);
+		warn!(con.logger, "Fast retransmit";
+			"id" => ?id,
+			"tries" => full_rec.id.tries,
+			"last" => format!("{:?} ago", now - full_rec.id.last),
+		);
+		full_rec.id.last = now;
+		full_rec.id.tries += 1;
+		full_rec.resent = true;
+		let sent = full_rec.sent;
+		con.resender.fast_retransmit = None;
+		con.stats.packets_resent += 1;
+
+		// Reduce the congestion window once per window
+		if con.resender.last_fast_retransmit.map(|t| sent > t).unwrap_or(true) {
+			con.resender.reduce_window();
+			con.resender.last_fast_retransmit = Some(now);
+		}
+		// The send queue contains the old record
+		con.resender.rebuild_send_queue();
+		Ok(())
+	}
+
 	/// Returns an error if the timeout is exceeded and the connection is
 	/// considered dead or another unrecoverable error occurs.
 	pub fn poll_ping(con: &mut Connection, cx: &mut Context) -> Result<()> {
@@ -599,11 +829,12 @@ impl Resender {
 
 		loop {
 			let now = Instant::now();
-			let ping_secs = 1;
-			let mut next_ping = con.resender.last_receive + Duration::from_secs(ping_secs);
+			let interval = con.resender.config.ping_interval;
+			// Sending packets does not show that the other side is alive
+			let mut next_ping = con.resender.last_receive + interval;
 			if let Some(p) = con.resender.last_pings.last() {
 				if p.sent > con.resender.last_receive {
-					next_ping = p.sent + Duration::from_secs(ping_secs);
+					next_ping = p.sent + interval;
 				} else {
 					// We received a packet, clear the ping queue
 					con.resender.last_pings.clear();
@@ -647,6 +878,8 @@ impl Default for ResendConfig {
 
 			srtt: Duration::from_millis(500),
 			srtt_dev: Duration::from_millis(0),
+
+			ping_interval: Duration::from_secs(1),
 		}
 	}
 }
@@ -0,0 +1,106 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use slog::{o, Discard, Logger};
+use ts_bookkeeping::data::Connection;
+use ts_bookkeeping::messages::s2c::InMessage;
+use tsproto_packets::packets::{Direction, Flags, OutPacket, PacketType};
+use tsproto_types::crypto::{EccKeyPrivP256, EccKeyPubP256};
+
+const CHANNELS: u16 = 1000;
+
+const INIT_SERVER: &str = r#"initserver virtualserver_name=Server virtualserver_welcomemessage=Welcome\sto\sthe\sserver virtualserver_platform=Linux virtualserver_version=3.11.0\s[Build:\s1578903157] virtualserver_maxclients=32 virtualserver_created=1571572631 virtualserver_codec_encryption_mode=2 virtualserver_hostmessage virtualserver_hostmessage_mode=0 virtualserver_default_server_group=8 virtualserver_default_channel_group=8 virtualserver_hostbanner_url virtualserver_hostbanner_gfx_url virtualserver_hostbanner_gfx_interval=0 virtualserver_priority_speaker_dimm_modificator=-18.0000 virtualserver_id=1 virtualserver_hostbutton_tooltip virtualserver_hostbutton_url virtualserver_hostbutton_gfx_url virtualserver_name_phonetic virtualserver_ip=0.0.0.0 virtualserver_ask_for_privilegekey=0 virtualserver_hostbanner_mode=0 virtualserver_channel_temp_delete_delay_default=0 virtualserver_nickname acn=User aclid=1 pv=7 client_talk_power=75 client_needed_serverquery_view_power=75 virtualserver_icon_id=0"#;
+
+/// Counts all allocations, including reallocations.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { System.dealloc(ptr, layout) }
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+		System.realloc(ptr, layout, new_size)
+	}
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Run `f` and return the number of allocations it made.
+fn count_allocations<T>(f: impl FnOnce() -> T) -> usize {
+	let before = ALLOCATIONS.load(Ordering::Relaxed);
+	let res = f();
+	let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+	drop(res);
+	allocations
+}
+
+/// The commands a client gets after connecting: initserver and one
+/// channellist per channel, with escaped names like on real servers.
+fn create_burst() -> Vec<String> {
+	let mut burst = vec![INIT_SERVER.to_string()];
+	for id in 1..=CHANNELS {
+		burst.push(format!(
+			r#"channellist cid={0} cpid=0 channel_name=Channel\s{0}\s\p\sTalk channel_topic=Topic\sof\schannel\s{0} channel_codec=4 channel_codec_quality=7 channel_maxclients=-1 channel_maxfamilyclients=-1 channel_order={1} channel_flag_permanent=1 channel_flag_semi_permanent=0 channel_flag_default=0 channel_flag_password=0 channel_codec_latency_factor=1 channel_codec_is_unencrypted=1 channel_delete_delay=0 channel_flag_maxclients_unlimited=1 channel_flag_maxfamilyclients_unlimited=0 channel_flag_maxfamilyclients_inherited=1 channel_needed_talk_power=0 channel_forced_silence=0 channel_name_phonetic channel_icon_id=0 channel_flag_private=0"#,
+			id,
+			id - 1
+		));
+	}
+	burst
+}
+
+/// Parse all commands into messages.
+fn parse_burst(logger: &Logger, burst: &[String]) -> Vec<InMessage> {
+	let header = OutPacket::new_with_dir(Direction::S2C, Flags::empty(), PacketType::Command);
+	burst
+		.iter()
+		.map(|cmd| InMessage::new(logger, &header.header(), cmd.as_bytes()).unwrap())
+		.collect()
+}
+
+/// Parse all commands and apply them to a new connection.
+fn handle_burst(logger: &Logger, key: &EccKeyPubP256, burst: &[String]) -> Connection {
+	let header = OutPacket::new_with_dir(Direction::S2C, Flags::empty(), PacketType::Command);
+	let mut con = match InMessage::new(logger, &header.header(), burst[0].as_bytes()).unwrap() {
+		InMessage::InitServer(msg) => Connection::new(key.clone(), &msg),
+		_ => unreachable!("Failed to parse initserver"),
+	};
+	for cmd in &burst[1..] {
+		let msg = InMessage::new(logger, &header.header(), cmd.as_bytes()).unwrap();
+		con.handle_command(logger, &msg).unwrap();
+	}
+	con
+}
+
+fn burst(c: &mut Criterion) {
+	let logger = Logger::root(Discard, o!());
+	let burst = create_burst();
+	let key = EccKeyPrivP256::create().unwrap().to_pub();
+
+	let per_command = |allocations: usize| allocations as f64 / burst.len() as f64;
+	println!(
+		"initserver and {} channels: {:.2} allocations per command for parsing, {:.2} with the \
+		 bookkeeping",
+		CHANNELS,
+		per_command(count_allocations(|| parse_burst(&logger, &burst))),
+		per_command(count_allocations(|| handle_burst(&logger, &key, &burst)))
+	);
+
+	c.bench_function("parse initserver and channellist burst", |b| {
+		b.iter(|| parse_burst(&logger, &burst))
+	});
+	c.bench_function("initserver and channellist burst", |b| {
+		b.iter(|| handle_burst(&logger, &key, &burst))
+	});
+}
+
+criterion_group!(benches, burst);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+use std::sync::Mutex;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use slog::{o, Drain, Logger};
+use ts_bookkeeping::data::Connection;
+use ts_bookkeeping::messages::s2c::InMessage;
+use tsproto_packets::packets::{Direction, Flags, OutPacket, PacketType};
+use tsproto_types::crypto::EccKeyPrivP256;
+
+const CLIENTS: u16 = 2000;
+
+const INIT_SERVER: &str = r#"initserver virtualserver_name=Server virtualserver_welcomemessage virtualserver_platform=Linux virtualserver_version=3.11.0\s[Build:\s1578903157] virtualserver_maxclients=32 virtualserver_created=1571572631 virtualserver_codec_encryption_mode=2 virtualserver_hostmessage virtualserver_hostmessage_mode=0 virtualserver_default_server_group=8 virtualserver_default_channel_group=8 virtualserver_hostbanner_url virtualserver_hostbanner_gfx_url virtualserver_hostbanner_gfx_interval=0 virtualserver_priority_speaker_dimm_modificator=-18.0000 virtualserver_id=1 virtualserver_hostbutton_tooltip virtualserver_hostbutton_url virtualserver_hostbutton_gfx_url virtualserver_name_phonetic virtualserver_ip=0.0.0.0 virtualserver_ask_for_privilegekey=0 virtualserver_hostbanner_mode=0 virtualserver_channel_temp_delete_delay_default=0 virtualserver_nickname acn=User aclid=1 pv=7 client_talk_power=75 client_needed_serverquery_view_power=75 virtualserver_icon_id=0"#;
+
+fn get_logger() -> Logger {
+	let decorator = slog_term::PlainDecorator::new(slog_term::TestStdoutWriter);
+	let drain = Mutex::new(slog_term::FullFormat::new(decorator).build()).fuse();
+
+	Logger::root(drain, o!())
+}
+
+fn parse(logger: &Logger, msg: &str) -> InMessage {
+	let header = OutPacket::new_with_dir(Direction::S2C, Flags::empty(), PacketType::Command);
+	InMessage::new(logger, &header.header(), msg.as_bytes()).unwrap()
+}
+
+/// Create a connection with a lot of clients.
+fn create_connection(logger: &Logger) -> Connection {
+	let key = EccKeyPrivP256::create().unwrap().to_pub();
+	let mut con = match parse(logger, INIT_SERVER) {
+		InMessage::InitServer(msg) => Connection::new(key, &msg),
+		_ => unreachable!("Failed to parse initserver"),
+	};
+
+	for id in 1..=CLIENTS {
+		let msg = format!(
+			r#"notifycliententerview cfid=0 ctid=1 reasonid=0 clid={0} client_unique_identifier=Client{0}= client_nickname=Client\s{0} client_input_muted=0 client_output_muted=0 client_outputonly_muted=0 client_input_hardware=1 client_output_hardware=1 client_meta_data client_is_recording=0 client_database_id={0} client_channel_group_id=8 client_servergroups=8 client_away=0 client_away_message client_type=0 client_flag_avatar client_talk_power=75 client_talk_request=0 client_talk_request_msg client_description client_is_talker=0 client_is_priority_speaker=0 client_unread_messages=0 client_nickname_phonetic client_needed_serverquery_view_power=75 client_icon_id=0 client_is_channel_commander=0 client_country client_channel_group_inherited_channel_id=1 client_badges client_myteamspeak_id client_integrations client_myteamspeak_avatar client_signed_badges"#,
+			id
+		);
+		con.handle_command(logger, &parse(logger, &msg)).unwrap();
+	}
+	con
+}
+
+fn snapshot(c: &mut Criterion) {
+	let logger = get_logger();
+	let con = create_connection(&logger);
+	c.bench_function("snapshot", |b| b.iter(|| con.snapshot()));
+}
+
+fn mutate(c: &mut Criterion) {
+	let logger = get_logger();
+	let mut con = create_connection(&logger);
+	let msg = parse(&logger, "notifyclientupdated clid=1000 client_away=1 client_away_message=Away");
+	c.bench_function("mutate", |b| b.iter(|| con.handle_command(&logger, &msg).unwrap()));
+}
+
+fn mutate_snapshot(c: &mut Criterion) {
+	let logger = get_logger();
+	let mut con = create_connection(&logger);
+	let msg = parse(&logger, "notifyclientupdated clid=1000 client_away=1 client_away_message=Away");
+	c.bench_function("mutate after snapshot", |b| {
+		b.iter(|| {
+			let snapshot = con.snapshot();
+			con.handle_command(&logger, &msg).unwrap();
+			snapshot
+		})
+	});
+}
+
+criterion_group!(benches, snapshot, mutate, mutate_snapshot);
+criterion_main!(benches);
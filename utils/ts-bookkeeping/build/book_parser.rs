@@ -12,3 +12,15 @@ pub struct BookDeclarations<'a>(pub &'a book::BookDeclarations);
 impl Default for BookDeclarations<'static> {
 	fn default() -> Self { BookDeclarations(&tsproto_structs::book::DATA) }
 }
+
+/// The module which serializes a field of this type, if the type has no
+/// stable representation on its own.
+fn serde_with(rust_type: &str) -> Option<&'static str> {
+	match rust_type {
+		"OffsetDateTime" => Some("crate::serde_time::timestamp"),
+		"Option<OffsetDateTime>" => Some("crate::serde_time::option_timestamp"),
+		"Duration" => Some("crate::serde_time::duration"),
+		"Option<Duration>" => Some("crate::serde_time::option_duration"),
+		_ => None,
+	}
+}
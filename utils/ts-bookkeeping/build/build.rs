@@ -2,6 +2,7 @@ use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
+use std::process::Command;
 
 mod book_parser;
 mod book_to_messages_parser;
@@ -49,4 +50,35 @@ fn main() {
 	// Properties
 	let mut structs = File::create(&path.join("properties.rs")).unwrap();
 	write!(&mut structs, "{}", Properties::default()).unwrap();
+
+	// Declarations version
+	let decls = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap())
+		.join("..")
+		.join("tsproto-structs")
+		.join("declarations");
+	let git = |args: &[&str]| {
+		Command::new("git")
+			.args(args)
+			.current_dir(&decls)
+			.output()
+			.ok()
+			.filter(|o| o.status.success())
+			.and_then(|o| String::from_utf8(o.stdout).ok())
+			.map(|s| s.trim().to_string())
+	};
+	let version = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".into());
+	println!("cargo:rustc-env=DECLARATIONS_VERSION={}", version);
+
+	// Run again when another commit of the declarations is checked out. The
+	// declarations themselves are tracked through tsproto-structs.
+	println!("cargo:rerun-if-changed=build");
+	if let Some(head) = git(&["rev-parse", "--git-path", "HEAD"]) {
+		println!("cargo:rerun-if-changed={}", decls.join(head).display());
+	}
+	// Commits on a branch only change the branch, not HEAD
+	if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"]) {
+		if let Some(path) = git(&["rev-parse", "--git-path", &branch]) {
+			println!("cargo:rerun-if-changed={}", decls.join(path).display());
+		}
+	}
 }
@@ -1,18 +1,39 @@
-use std::collections::{HashMap, HashSet};
+//! The bookkeeping state of a connection.
+//!
+//! All structs implement `Serialize` and `Deserialize`, so the state of a
+//! connection can be stored or sent to e.g. a web dashboard with
+//! `serde_json::to_string(&connection)`. The representation is:
+//! - Ids like `ClientId` and `ChannelId` are plain numbers, maps of clients and
+//!   channels use them as keys.
+//! - A `Uid` is the array of its bytes.
+//! - Points in time are unix timestamps in seconds, durations are milliseconds.
+//! - Enums are the name of the variant, `MaxClients::Limited(n)` is
+//!   `{"Limited": n}`.
+use std::collections::HashSet;
 use std::net::{IpAddr, SocketAddr};
 use std::{iter, mem, u16};
 
+use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use slog::{debug, Logger};
 use time::{Duration, OffsetDateTime};
-use tsproto_packets::packets::OutCommand;
+use tsproto_packets::packets::{Direction, Flags, OutCommand, PacketType};
 use tsproto_types::crypto::EccKeyPubP256;
 use tsproto_types::*;
 
 use crate::events::{Event, ExtraInfo, PropertyId, PropertyValue, PropertyValueRef};
 use crate::messages::s2c::InMessage;
-use crate::messages::{c2s, s2c};
-use crate::{Error, MessageTarget, Result};
+use crate::messages::{c2s, s2c, OutMessageTrait};
+use crate::{ChannelSpec, Error, MessageTarget, Result};
+
+use self::exts::M2BClientUpdateExt;
+
+/// The map type that stores clients, channels and groups.
+///
+/// This is a persistent map which shares its structure on clone, so cloning a
+/// whole connection is cheap and only the touched entries are copied when
+/// mutating a clone.
+pub type HashMap<K, V> = im::HashMap<K, V>;
 
 include!(concat!(env!("OUT_DIR"), "/m2bdecls.rs"));
 include!(concat!(env!("OUT_DIR"), "/structs.rs"));
@@ -24,17 +45,51 @@ pub mod exts {
 	include!(concat!(env!("OUT_DIR"), "/b2mdecls.rs"));
 }
 
-macro_rules! max_clients {
-	($msg:ident) => {{
-		if $msg.is_max_clients_unlimited.unwrap_or_default() {
-			Some(MaxClients::Unlimited)
-		} else if $msg.max_clients.map(|i| i >= 0 && i <= u16::MAX as i32).unwrap_or_default() {
-			Some(MaxClients::Limited($msg.max_clients.unwrap() as u16))
-		} else {
-			// Max clients is less than zero or too high so ignore it
-			None
-			}
-		}};
+/// Get the maximum number of clients from the number and the flags, which
+/// are sent together.
+///
+/// If a flag is set, the number is `-1`. Returns `None` if the number is
+/// invalid or missing, e.g. if an edit only unsets a flag.
+fn max_clients_from_flags(
+	max_clients: Option<i32>, unlimited: Option<bool>, inherited: Option<bool>,
+) -> Option<MaxClients> {
+	if unlimited.unwrap_or_default() {
+		Some(MaxClients::Unlimited)
+	} else if inherited.unwrap_or_default() {
+		Some(MaxClients::Inherited)
+	} else {
+		// Max clients is less than zero or too high so ignore it
+		max_clients
+			.filter(|i| *i >= 0 && *i <= i32::from(u16::MAX))
+			.map(|i| MaxClients::Limited(i as u16))
+	}
+}
+
+fn non_empty(s: &str) -> Option<&str> { if s.is_empty() { None } else { Some(s) } }
+
+/// Split a channel path at every `/` which is not escaped as `\/`.
+///
+/// `\\` is a single backslash, other backslashes are kept as they are.
+fn split_channel_path(path: &str) -> Vec<String> {
+	let mut res = Vec::new();
+	let mut name = String::new();
+	let mut chars = path.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' => match chars.next() {
+				Some(c @ '/') | Some(c @ '\\') => name.push(c),
+				Some(c) => {
+					name.push('\\');
+					name.push(c);
+				}
+				None => name.push('\\'),
+			},
+			'/' => res.push(mem::take(&mut name)),
+			c => name.push(c),
+		}
+	}
+	res.push(name);
+	res
 }
 
 macro_rules! copy_attrs {
@@ -87,11 +142,13 @@ impl Connection {
 
 				optional_data: None,
 				connection_data: None,
+				recent_file_transfers: Default::default(),
 			),
 			clients: HashMap::new(),
 			channels: HashMap::new(),
 			channel_groups: HashMap::new(),
 			server_groups: HashMap::new(),
+			channel_index: HashMap::new(),
 		}
 	}
 
@@ -144,6 +201,141 @@ impl Connection {
 					handled = true;
 				}
 			}
+			InMessage::ServerGroupClientAdded(msg) => {
+				for msg in msg.iter() {
+					let client = if let Ok(client) = self.get_mut_client(msg.client_id) {
+						client
+					} else {
+						continue;
+					};
+					if !client.server_groups.contains(&msg.server_group_id) {
+						client.server_groups.push(msg.server_group_id);
+						events.push(Event::PropertyAdded {
+							id: PropertyId::ClientServerGroup(msg.client_id, msg.server_group_id),
+							invoker: Some(Invoker {
+								name: msg.invoker_name.clone(),
+								id: msg.invoker_id,
+								uid: msg.invoker_uid.clone(),
+							}),
+							extra: ExtraInfo { reason: None },
+						});
+					}
+				}
+				handled = true;
+			}
+			InMessage::ServerGroupClientDeleted(msg) => {
+				for msg in msg.iter() {
+					let client = if let Ok(client) = self.get_mut_client(msg.client_id) {
+						client
+					} else {
+						continue;
+					};
+					if let Some(i) =
+						client.server_groups.iter().position(|g| *g == msg.server_group_id)
+					{
+						client.server_groups.remove(i);
+						events.push(Event::PropertyRemoved {
+							id: PropertyId::ClientServerGroup(msg.client_id, msg.server_group_id),
+							old: PropertyValue::ServerGroupId(msg.server_group_id),
+							invoker: Some(Invoker {
+								name: msg.invoker_name.clone(),
+								id: msg.invoker_id,
+								uid: msg.invoker_uid.clone(),
+							}),
+							extra: ExtraInfo { reason: None },
+						});
+					}
+				}
+				handled = true;
+			}
+			InMessage::ChannelEdited(msg) => {
+				// The answer to `channelgetdescription`
+				for msg in msg.iter() {
+					if let Some(description) = &msg.description {
+						self.set_channel_description(
+							msg.channel_id,
+							description.clone(),
+							msg.get_invoker(),
+							Some(msg.reason),
+							&mut events,
+						);
+					}
+				}
+			}
+			InMessage::ChannelDescriptionChanged(msg) => {
+				// The new description is not sent, so forget the cached one
+				for msg in msg.iter() {
+					if let Ok(channel) = self.get_mut_channel(msg.channel_id) {
+						if let Some(old) = channel.optional_data.take() {
+							events.push(Event::PropertyRemoved {
+								id: PropertyId::OptionalChannelData(msg.channel_id),
+								old: PropertyValue::OptionalChannelData(old),
+								invoker: None,
+								extra: ExtraInfo { reason: None },
+							});
+						}
+					}
+				}
+				handled = true;
+			}
+			InMessage::ClientEnterView(msg) => {
+				for msg in msg.iter() {
+					if let Ok(client) = self.get_mut_client(msg.client_id) {
+						if let Some(badges) = &msg.badges {
+							client.badges = Badges::parse(badges);
+						}
+						client.country = msg.country.clone().filter(|c| !c.is_empty());
+						client.meta_data = msg.metadata.clone();
+					}
+				}
+			}
+			InMessage::ClientUpdated(msg) => {
+				for msg in msg.iter() {
+					let client = if let Ok(client) = self.get_mut_client(msg.client_id) {
+						client
+					} else {
+						continue;
+					};
+					if let Some(badges) = &msg.badges {
+						let badges = Badges::parse(badges);
+						if badges != client.badges {
+							let old = mem::replace(&mut client.badges, badges.clone());
+							events.push(Event::PropertyChanged {
+								id: PropertyId::ClientBadges(msg.client_id),
+								old: PropertyValue::Badges(old),
+								new: PropertyValue::Badges(badges),
+								invoker: msg.get_invoker(),
+								extra: ExtraInfo { reason: None },
+							});
+						}
+					}
+					if let Some(meta_data) = &msg.metadata {
+						if *meta_data != client.meta_data {
+							let old = mem::replace(&mut client.meta_data, meta_data.clone());
+							events.push(Event::PropertyChanged {
+								id: PropertyId::ClientMetaData(msg.client_id),
+								old: PropertyValue::String(old),
+								new: PropertyValue::String(meta_data.clone()),
+								invoker: msg.get_invoker(),
+								extra: ExtraInfo { reason: None },
+							});
+						}
+					}
+					if let Some(country) = &msg.country {
+						let country = Some(country.clone()).filter(|c| !c.is_empty());
+						if country != client.country {
+							let old = mem::replace(&mut client.country, country.clone());
+							events.push(Event::PropertyChanged {
+								id: PropertyId::ClientCountry(msg.client_id),
+								old: PropertyValue::OptionString(old),
+								new: PropertyValue::OptionString(country),
+								invoker: msg.get_invoker(),
+								extra: ExtraInfo { reason: None },
+							});
+						}
+					}
+				}
+			}
 			InMessage::CommandError(_) => handled = true,
 			InMessage::ChannelListFinished(_) => {
 				events.push(Event::ChannelListFinished);
@@ -160,6 +352,7 @@ impl Connection {
 					events.push(Event::PropertyChanged {
 						id: PropertyId::ClientName(client.id),
 						old: PropertyValue::String(old),
+						new: PropertyValue::String(client.name.clone()),
 						invoker: None,
 						extra: ExtraInfo { reason: None },
 					});
@@ -171,9 +364,36 @@ impl Connection {
 			debug!(logger, "Unhandled message"; "message" => msg.get_command_name());
 		}
 
+		// Moves are applied by the generated code, update the index here
+		for event in &events {
+			if let Event::PropertyChanged {
+				id: PropertyId::ClientChannel(client),
+				old: PropertyValue::ChannelId(from),
+				new: PropertyValue::ChannelId(to),
+				..
+			} = event
+			{
+				self.unindex_client(*from, *client);
+				self.index_client(*to, *client);
+			}
+		}
+
 		Ok(events)
 	}
 
+	fn index_client(&mut self, channel: ChannelId, client: ClientId) {
+		self.channel_index.entry(channel).or_default().insert(client);
+	}
+
+	fn unindex_client(&mut self, channel: ChannelId, client: ClientId) {
+		if let Some(clients) = self.channel_index.get_mut(&channel) {
+			clients.remove(&client);
+			if clients.is_empty() {
+				self.channel_index.remove(&channel);
+			}
+		}
+	}
+
 	fn get_server(&self) -> Result<&Server> { Ok(&self.server) }
 	fn get_mut_server(&mut self) -> Result<&mut Server> { Ok(&mut self.server) }
 
@@ -217,10 +437,21 @@ impl Connection {
 	fn add_client(
 		&mut self, client: ClientId, r: Client, _: &mut Vec<Event>,
 	) -> Result<Option<Client>> {
-		Ok(self.clients.insert(client, r))
+		self.index_client(r.channel, client);
+		let old = self.clients.insert(client, r);
+		if let Some(old) = &old {
+			if old.channel != self.clients[&client].channel {
+				self.unindex_client(old.channel, client);
+			}
+		}
+		Ok(old)
 	}
 	fn remove_client(&mut self, client: ClientId, _: &mut Vec<Event>) -> Result<Option<Client>> {
-		Ok(self.clients.remove(&client))
+		let old = self.clients.remove(&client);
+		if let Some(old) = &old {
+			self.unindex_client(old.channel, client);
+		}
+		Ok(old)
 	}
 
 	fn get_connection_client_data(&self, client: ClientId) -> Result<&ConnectionClientData> {
@@ -280,6 +511,36 @@ impl Connection {
 		}
 	}
 
+	fn set_channel_description(
+		&mut self, channel_id: ChannelId, description: String, invoker: Option<Invoker>,
+		reason: Option<Reason>, events: &mut Vec<Event>,
+	) {
+		let channel = if let Ok(channel) = self.get_mut_channel(channel_id) {
+			channel
+		} else {
+			return;
+		};
+		if let Some(data) = &mut channel.optional_data {
+			if data.description != description {
+				let old = mem::replace(&mut data.description, description);
+				events.push(Event::PropertyChanged {
+					id: PropertyId::OptionalChannelDataDescription(channel_id),
+					old: PropertyValue::String(old),
+					new: PropertyValue::String(data.description.clone()),
+					invoker,
+					extra: ExtraInfo { reason },
+				});
+			}
+		} else {
+			channel.optional_data = Some(OptionalChannelData { channel_id, description });
+			events.push(Event::PropertyAdded {
+				id: PropertyId::OptionalChannelData(channel_id),
+				invoker,
+				extra: ExtraInfo { reason },
+			});
+		}
+	}
+
 	fn get_file(&self, _channel: ChannelId, _path: &str) -> Result<&File> {
 		unimplemented!("Files are not yet implemented")
 	}
@@ -293,18 +554,12 @@ impl Connection {
 	fn max_clients_cc_fun(
 		&self, msg: &s2c::InChannelCreatedPart, _: &mut Vec<Event>,
 	) -> Result<(Option<MaxClients>, Option<MaxClients>)> {
-		let ch = max_clients!(msg);
-		let ch_fam = if msg.is_max_family_clients_unlimited {
-			Some(MaxClients::Unlimited)
-		} else if msg.inherits_max_family_clients.unwrap_or_default() {
-			Some(MaxClients::Inherited)
-		} else if msg.max_family_clients.map(|i| i >= 0 && i <= u16::MAX as i32).unwrap_or_default()
-		{
-			Some(MaxClients::Limited(msg.max_family_clients.unwrap() as u16))
-		} else {
-			// Max clients is less than zero or too high so ignore it
-			None
-		};
+		let ch = max_clients_from_flags(msg.max_clients, msg.is_max_clients_unlimited, None);
+		let ch_fam = max_clients_from_flags(
+			msg.max_family_clients,
+			Some(msg.is_max_family_clients_unlimited),
+			msg.inherits_max_family_clients,
+		);
 		Ok((ch, ch_fam))
 	}
 	fn max_clients_ce_fun(
@@ -312,31 +567,27 @@ impl Connection {
 	) -> Result<()> {
 		let channel = self.get_mut_channel(channel_id)?;
 
-		let ch = max_clients!(msg);
+		let ch = max_clients_from_flags(msg.max_clients, msg.is_max_clients_unlimited, None);
 		if let Some(ch) = ch {
 			events.push(Event::PropertyChanged {
 				id: PropertyId::ChannelMaxClients(channel_id),
 				old: PropertyValue::OptionMaxClients(channel.max_clients.take()),
+				new: PropertyValue::OptionMaxClients(Some(ch)),
 				invoker: msg.get_invoker(),
 				extra: ExtraInfo { reason: Some(msg.reason) },
 			});
 			channel.max_clients = Some(ch);
 		}
-		let ch_fam = if msg.is_max_family_clients_unlimited.unwrap_or_default() {
-			Some(MaxClients::Unlimited)
-		} else if msg.inherits_max_family_clients.unwrap_or_default() {
-			Some(MaxClients::Inherited)
-		} else if msg.max_family_clients.map(|i| i >= 0 && i <= u16::MAX as i32).unwrap_or_default()
-		{
-			Some(MaxClients::Limited(msg.max_family_clients.unwrap() as u16))
-		} else {
-			// Max clients is less than zero or too high so ignore it
-			None
-		};
+		let ch_fam = max_clients_from_flags(
+			msg.max_family_clients,
+			msg.is_max_family_clients_unlimited,
+			msg.inherits_max_family_clients,
+		);
 		if let Some(ch_fam) = ch_fam {
 			events.push(Event::PropertyChanged {
 				id: PropertyId::ChannelMaxFamilyClients(channel_id),
 				old: PropertyValue::OptionMaxClients(channel.max_family_clients.take()),
+				new: PropertyValue::OptionMaxClients(Some(ch_fam)),
 				invoker: msg.get_invoker(),
 				extra: ExtraInfo { reason: Some(msg.reason) },
 			});
@@ -347,27 +598,15 @@ impl Connection {
 	fn max_clients_cl_fun(
 		&self, msg: &s2c::InChannelListPart, _: &mut Vec<Event>,
 	) -> Result<(Option<MaxClients>, Option<MaxClients>)> {
-		let max_clients: i32 = msg.max_clients;
-		let ch = if msg.is_max_clients_unlimited {
-			Some(MaxClients::Unlimited)
-		} else if max_clients >= 0 && max_clients <= u16::MAX as i32 {
-			Some(MaxClients::Limited(max_clients as u16))
-		} else {
-			// Max clients is less than zero or too high so ignore it
-			None
-		};
-
-		let max_clients: i32 = msg.max_family_clients;
-		let ch_fam = if msg.is_max_family_clients_unlimited {
-			Some(MaxClients::Unlimited)
-		} else if msg.inherits_max_family_clients {
-			Some(MaxClients::Inherited)
-		} else if max_clients >= 0 && max_clients <= u16::MAX as i32 {
-			Some(MaxClients::Limited(max_clients as u16))
-		} else {
-			// Max clients is less than zero or too high so ignore it
-			Some(MaxClients::Unlimited)
-		};
+		let ch =
+			max_clients_from_flags(Some(msg.max_clients), Some(msg.is_max_clients_unlimited), None);
+		let ch_fam = max_clients_from_flags(
+			Some(msg.max_family_clients),
+			Some(msg.is_max_family_clients_unlimited),
+			Some(msg.inherits_max_family_clients),
+		)
+		// The channel list always contains the family limit, fall back to unlimited
+		.or(Some(MaxClients::Unlimited));
 		Ok((ch, ch_fam))
 	}
 
@@ -398,6 +637,7 @@ impl Connection {
 		events.push(Event::PropertyChanged {
 			id: PropertyId::ChannelChannelType(channel_id),
 			old: PropertyValue::ChannelType(channel.channel_type),
+			new: PropertyValue::ChannelType(typ),
 			invoker: msg.get_invoker(),
 			extra: ExtraInfo { reason: Some(msg.reason) },
 		});
@@ -449,6 +689,7 @@ impl Connection {
 				events.push(Event::PropertyChanged {
 					id: PropertyId::ClientAwayMessage(client_id),
 					old: PropertyValue::OptionString(client.away_message.take()),
+					new: PropertyValue::OptionString(away.clone()),
 					invoker: msg.get_invoker(),
 					extra: ExtraInfo { reason: None },
 				});
@@ -460,6 +701,7 @@ impl Connection {
 					events.push(Event::PropertyChanged {
 						id: PropertyId::ClientAwayMessage(client_id),
 						old: PropertyValue::OptionString(client.away_message.take()),
+						new: PropertyValue::OptionString(Some(away_message.clone())),
 						invoker: msg.get_invoker(),
 						extra: ExtraInfo { reason: None },
 					});
@@ -500,6 +742,7 @@ impl Connection {
 			events.push(Event::PropertyChanged {
 				id: PropertyId::ClientTalkPowerRequest(client_id),
 				old: PropertyValue::OptionTalkPowerRequest(client.talk_power_request.take()),
+				new: PropertyValue::OptionTalkPowerRequest(talk_request.clone()),
 				invoker: msg.get_invoker(),
 				extra: ExtraInfo { reason: None },
 			});
@@ -525,6 +768,7 @@ impl Connection {
 		events.push(Event::PropertyChanged {
 			id: PropertyId::ChannelSubscribed(channel_id),
 			old: PropertyValue::Bool(channel.subscribed),
+			new: PropertyValue::Bool(true),
 			invoker: None,
 			extra: ExtraInfo { reason: None },
 		});
@@ -541,6 +785,7 @@ impl Connection {
 		events.push(Event::PropertyChanged {
 			id: PropertyId::ChannelSubscribed(channel_id),
 			old: PropertyValue::Bool(channel.subscribed),
+			new: PropertyValue::Bool(false),
 			invoker: None,
 			extra: ExtraInfo { reason: None },
 		});
@@ -553,6 +798,7 @@ impl Connection {
 			.filter_map(|c| if c.channel == channel_id { Some(c.id) } else { None })
 			.collect::<Vec<_>>();
 		for id in remove_clients {
+			self.unindex_client(channel_id, id);
 			events.push(Event::PropertyRemoved {
 				id: PropertyId::Client(id),
 				old: PropertyValue::Client(self.clients.remove(&id).unwrap()),
@@ -569,11 +815,12 @@ impl Connection {
 		// [ C:7 | O:_ ]
 		// [ C:5 | O:7 ] ─>X
 		// [ C:_ | O:5 ]     (Upd: O -> 7)
-		self.channels.values_mut().any(|c| {
+		self.channels.iter_mut().any(|(_, c)| {
 			if c.order == channel_id && c.id != channel_id {
 				events.push(Event::PropertyChanged {
 					id: PropertyId::ChannelOrder(c.id),
 					old: PropertyValue::ChannelId(c.order),
+					new: PropertyValue::ChannelId(channel_order),
 					invoker: None,
 					extra: ExtraInfo { reason: None },
 				});
@@ -595,11 +842,12 @@ impl Connection {
 		// [ C:_ | O:7 ]    (Upd: O -> 5)
 		//
 		// Also work for the first channel, the order will be 0.
-		self.channels.values_mut().any(|c| {
+		self.channels.iter_mut().any(|(_, c)| {
 			if c.order == channel_order && c.parent == channel_parent && c.id != channel_id {
 				events.push(Event::PropertyChanged {
 					id: PropertyId::ChannelOrder(c.id),
 					old: PropertyValue::ChannelId(c.order),
+					new: PropertyValue::ChannelId(channel_id),
 					invoker: None,
 					extra: ExtraInfo { reason: None },
 				});
@@ -645,6 +893,7 @@ impl Connection {
 				events.push(Event::PropertyChanged {
 					id: PropertyId::ChannelOrder(channel.id),
 					old: PropertyValue::ChannelId(channel.order),
+					new: PropertyValue::ChannelId(order),
 					invoker: None,
 					extra: ExtraInfo { reason: None },
 				});
@@ -663,6 +912,66 @@ impl Connection {
 }
 
 impl Client {
+	/// If this client is a server query client.
+	pub fn is_query(&self) -> bool { self.client_type.is_query() }
+
+	/// The badges which this client shows next to its name.
+	#[inline]
+	pub fn badges(&self) -> &Badges { &self.badges }
+
+	/// Free-form data which the client sets, e.g. to exchange state between
+	/// bots.
+	#[inline]
+	pub fn meta_data(&self) -> &str { &self.meta_data }
+
+	/// If this client talks as channel commander.
+	#[inline]
+	pub fn is_channel_commander(&self) -> bool { self.is_channel_commander }
+
+	/// If other clients get quieter while this client talks.
+	#[inline]
+	pub fn is_priority_speaker(&self) -> bool { self.is_priority_speaker }
+
+	/// The ISO 3166 code of the country of this client, e.g. `DE`.
+	///
+	/// Only known if the server has a geo database.
+	#[inline]
+	pub fn country(&self) -> Option<&str> { self.country.as_deref() }
+
+	/// The English name of the country of this client.
+	pub fn country_name(&self) -> Option<&'static str> {
+		self.country().and_then(crate::countries::country_name)
+	}
+
+	/// If the optional data of this client is known.
+	///
+	/// Properties like the version or the creation time are not sent when a
+	/// client appears, they are only known after a [`refresh`].
+	///
+	/// [`refresh`]: #method.refresh
+	pub fn is_fully_resolved(&self) -> bool { self.optional_data.is_some() }
+
+	/// Request all properties of this client, including the optional data.
+	pub fn refresh(&self) -> OutCommand {
+		let mut cmd = OutCommand::new(
+			Direction::C2S,
+			Flags::empty(),
+			PacketType::Command,
+			"clientgetvariables",
+		);
+		cmd.write_arg("clid", &self.id.0);
+		cmd
+	}
+
+	/// Move this client into another channel.
+	pub fn move_to(&self, channel: ChannelId, password: Option<&str>) -> OutCommand {
+		c2s::OutClientMoveMessage::new(&mut iter::once(c2s::OutClientMovePart {
+			client_id: self.id,
+			channel_id: channel,
+			channel_password: password,
+		}))
+	}
+
 	// Book to messages
 	fn password_b2m<'a>(password: &'a str) -> &'a str { password }
 	fn channel_id_b2m(&self, channel: ChannelId) -> ChannelId { channel }
@@ -684,22 +993,47 @@ impl Channel {
 
 	fn max_clients_fun_b2m(max_clients: MaxClients) -> (i32, bool) {
 		match max_clients {
-			MaxClients::Inherited => (0, false),
-			MaxClients::Unlimited => (0, true),
+			// Only the family can inherit the limit
+			MaxClients::Inherited | MaxClients::Unlimited => (-1, true),
 			MaxClients::Limited(num) => (num.into(), false),
 		}
 	}
 
 	fn max_family_clients_fun_b2m(max_clients: MaxClients) -> (i32, bool, bool) {
 		match max_clients {
-			MaxClients::Inherited => (0, false, true),
-			MaxClients::Unlimited => (0, true, false),
+			MaxClients::Inherited => (-1, false, true),
+			MaxClients::Unlimited => (-1, true, false),
 			MaxClients::Limited(num) => (num.into(), false, false),
 		}
 	}
 
 	fn channel_id_b2m(&self, channel: ChannelId) -> ChannelId { channel }
 
+	/// The description of this channel, `None` if it is not known yet.
+	///
+	/// The server does not send descriptions with the channel list.
+	pub fn description(&self) -> Option<&str> {
+		self.optional_data.as_ref().map(|d| d.description.as_str())
+	}
+
+	/// If the description of this channel is known.
+	pub fn has_description(&self) -> bool { self.optional_data.is_some() }
+
+	/// Request the description of this channel.
+	///
+	/// The description is stored in the bookkeeping when the answer arrives.
+	/// It is removed again when the server reports that it changed.
+	pub fn request_description(&self) -> OutCommand {
+		let mut cmd = OutCommand::new(
+			Direction::C2S,
+			Flags::empty(),
+			PacketType::Command,
+			"channelgetdescription",
+		);
+		cmd.write_arg("cid", &self.id.0);
+		cmd
+	}
+
 	pub fn set_subscribed(&self, subscribed: bool) -> OutCommand {
 		if subscribed {
 			c2s::OutChannelSubscribeMessage::new(&mut iter::once(c2s::OutChannelSubscribePart {
@@ -713,6 +1047,17 @@ impl Channel {
 	}
 }
 
+/// A channel and its sub-channels in display order.
+///
+/// The tree of all channels is returned by [`Connection::channel_tree`].
+///
+/// [`Connection::channel_tree`]: struct.Connection.html#method.channel_tree
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ChannelNode {
+	pub id: ChannelId,
+	pub children: Vec<ChannelNode>,
+}
+
 /// The `ChannelOptions` are used to set initial properties of a new channel.
 ///
 /// A channel can be created with [`ServerMut::add_channel`]. The only necessary
@@ -837,28 +1182,107 @@ impl<'a> ChannelOptions<'a> {
 	}
 }
 
+/// Only this many transfers are kept in [`Server::recent_file_transfers`].
+///
+/// [`Server::recent_file_transfers`]: struct.Server.html#method.recent_file_transfers
+pub const MAX_RECENT_FILE_TRANSFERS: usize = 50;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum TransferDirection {
+	/// The client uploads a file to the server.
+	Upload,
+	/// The client downloads a file from the server.
+	Download,
+}
+
+/// A file transfer of another client.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ForeignFileTransfer {
+	/// The id of the transfer on the server, which can be used to stop it.
+	pub id: u16,
+	pub client: ClientId,
+	/// The channel which contains the file.
+	///
+	/// This is `None` for files that do not belong to a channel, e.g. avatars.
+	pub channel: Option<ChannelId>,
+	/// The path of the file inside the channel.
+	pub path: String,
+	pub size: u64,
+	pub direction: TransferDirection,
+}
+
 impl Server {
+	/// The latest file transfers of other clients, the oldest first.
+	///
+	/// At most [`MAX_RECENT_FILE_TRANSFERS`] are kept, older transfers are
+	/// removed first. The server only tells us about transfers of other clients
+	/// if we have the permission to see them.
+	///
+	/// [`MAX_RECENT_FILE_TRANSFERS`]: constant.MAX_RECENT_FILE_TRANSFERS.html
+	pub fn recent_file_transfers(&self) -> impl Iterator<Item = &ForeignFileTransfer> {
+		self.recent_file_transfers.iter()
+	}
+
+	/// Add a transfer which the server announced to the recent transfers.
+	///
+	/// Returns `false` if the transfer was already known, it is updated then.
+	pub fn add_file_transfer(&mut self, transfer: ForeignFileTransfer) -> bool {
+		let list = &mut self.recent_file_transfers;
+		if let Some(t) =
+			list.iter_mut().find(|t| t.id == transfer.id && t.client == transfer.client)
+		{
+			*t = transfer;
+			return false;
+		}
+		if list.len() >= MAX_RECENT_FILE_TRANSFERS {
+			list.pop_front();
+		}
+		list.push_back(transfer);
+		true
+	}
+
+	/// The website which is opened when the banner is clicked.
+	pub fn host_banner_url(&self) -> Option<&str> { non_empty(&self.hostbanner_url) }
+
+	/// The url of the banner image.
+	pub fn host_banner_gfx_url(&self) -> Option<&str> { non_empty(&self.hostbanner_gfx_url) }
+
+	/// How often the banner image should be downloaded again, `None` if it
+	/// does not change.
+	pub fn host_banner_interval(&self) -> Option<Duration> {
+		Some(self.hostbanner_gfx_interval).filter(|i| i.is_positive())
+	}
+
+	/// The message which is shown when connecting, depending on the
+	/// `hostmessage_mode`.
+	pub fn host_message(&self) -> Option<&str> { non_empty(&self.hostmessage) }
+
+	/// The website which is opened when the host button is clicked.
+	pub fn host_button_url(&self) -> Option<&str> { non_empty(&self.hostbutton_url) }
+
+	/// Stop a file transfer of another client.
+	///
+	/// If `delete` is set, the partially transferred file is deleted.
+	pub fn stop_foreign_transfer(&self, transfer_id: u16, delete: bool) -> OutCommand {
+		let mut cmd =
+			OutCommand::new(Direction::C2S, Flags::empty(), PacketType::Command, "ftstop");
+		cmd.write_arg("serverftfid", &transfer_id);
+		cmd.write_arg("delete", &(delete as u8));
+		cmd
+	}
+
 	pub fn add_channel(&self, options: ChannelOptions) -> OutCommand {
-		let inherits_max_family_clients = options
-			.max_family_clients
-			.as_ref()
-			.and_then(|m| if let MaxClients::Inherited = m { Some(true) } else { None });
-		let is_max_family_clients_unlimited = options
-			.max_family_clients
-			.as_ref()
-			.and_then(|m| if let MaxClients::Unlimited = m { Some(true) } else { None });
-		let max_family_clients = options
-			.max_family_clients
-			.as_ref()
-			.and_then(|m| if let MaxClients::Limited(n) = m { Some(*n as i32) } else { None });
-		let is_max_clients_unlimited = options
-			.max_clients
-			.as_ref()
-			.and_then(|m| if let MaxClients::Unlimited = m { Some(true) } else { None });
-		let max_clients = options
-			.max_clients
-			.as_ref()
-			.and_then(|m| if let MaxClients::Limited(n) = m { Some(*n as i32) } else { None });
+		// Write the number and the flags, the server defaults to unlimited
+		let (max_clients, is_max_clients_unlimited) =
+			match options.max_clients.map(Channel::max_clients_fun_b2m) {
+				Some((num, unlimited)) => (Some(num), Some(unlimited)),
+				None => (None, None),
+			};
+		let (max_family_clients, is_max_family_clients_unlimited, inherits_max_family_clients) =
+			match options.max_family_clients.map(Channel::max_family_clients_fun_b2m) {
+				Some((num, unlimited, inherited)) => (Some(num), Some(unlimited), Some(inherited)),
+				None => (None, None, None),
+			};
 
 		let is_permanent = options
 			.channel_type
@@ -901,6 +1325,18 @@ impl Server {
 		}))
 	}
 
+	/// Request the connection statistics of the server.
+	///
+	/// The answer is stored in the `connection_data` of the server.
+	pub fn request_connection_info(&self) -> OutCommand {
+		OutCommand::new(
+			Direction::C2S,
+			Flags::empty(),
+			PacketType::Command,
+			"serverrequestconnectioninfo",
+		)
+	}
+
 	/// Subscribe or unsubscribe from all channels.
 	pub fn set_subscribed(&self, subscribed: bool) -> OutCommand {
 		if subscribed {
@@ -941,6 +1377,306 @@ impl Connection {
 		}
 	}
 
+	/// The id of our own client.
+	///
+	/// The server assigns a new id on every connect, so the id should not be
+	/// stored across reconnects, use this function or [`own_client`] instead.
+	///
+	/// [`own_client`]: #method.own_client
+	pub fn own_client_id(&self) -> ClientId { self.own_client }
+
+	/// Our own client.
+	///
+	/// This is `None` after connecting, until the server sent the
+	/// `notifycliententerview` for ourselves. To change properties of the own
+	/// client, use the command which is created by [`client_update`], or
+	/// `Connection::edit_own_client` in tsclientlib.
+	///
+	/// [`client_update`]: #method.client_update
+	pub fn own_client(&self) -> Option<&Client> { self.clients.get(&self.own_client) }
+
+	/// If our own client is allowed to talk in its current channel.
+	///
+	/// The server drops audio packets if we do not have enough talk power
+	/// and were not granted talk power explicitly.
+	pub fn can_talk_here(&self) -> bool {
+		let own_client = match self.own_client() {
+			Some(c) => c,
+			// We do not know about ourselves yet, so we do not know better
+			None => return true,
+		};
+		if own_client.is_talker {
+			return true;
+		}
+		match self.channels.get(&own_client.channel) {
+			Some(channel) => own_client.talk_power >= channel.needed_talk_power,
+			None => true,
+		}
+	}
+
+	/// All server query clients which are currently visible.
+	///
+	/// Query clients are excluded from [`normal_clients`] and
+	/// [`online_clients`] by default, this returns only them.
+	///
+	/// [`normal_clients`]: #method.normal_clients
+	/// [`online_clients`]: #method.online_clients
+	pub fn query_clients(&self) -> impl Iterator<Item = &Client> {
+		self.clients.values().filter(|c| c.is_query())
+	}
+
+	/// All visible clients which are not server query clients.
+	pub fn normal_clients(&self) -> impl Iterator<Item = &Client> {
+		self.clients.values().filter(|c| !c.is_query())
+	}
+
+	/// The number of visible clients.
+	///
+	/// Server query clients are only counted if `include_query` is set.
+	pub fn online_clients(&self, include_query: bool) -> usize {
+		if include_query { self.clients.len() } else { self.normal_clients().count() }
+	}
+
+	/// Get the id of a channel, by its id, path or name.
+	///
+	/// Returns [`Error::AmbiguousChannel`] with all matching channels if a
+	/// [`ChannelSpec::Name`] matches more than one channel.
+	///
+	/// [`Error::AmbiguousChannel`]: ../enum.Error.html#variant.AmbiguousChannel
+	/// [`ChannelSpec::Name`]: ../enum.ChannelSpec.html#variant.Name
+	pub fn find_channel(&self, spec: ChannelSpec) -> Result<ChannelId> {
+		match spec {
+			ChannelSpec::Id(id) => {
+				if self.channels.contains_key(&id) {
+					Ok(id)
+				} else {
+					Err(Error::NotFound("Channel", id.to_string()))
+				}
+			}
+			ChannelSpec::Path(path) => self
+				.find_channel_by_path(path)
+				.map(|c| c.id)
+				.ok_or_else(|| Error::NotFound("Channel", path.to_string())),
+			ChannelSpec::Name(name) => {
+				let name = name.to_lowercase();
+				let mut matches = self
+					.channels
+					.values()
+					.filter(|c| c.name.to_lowercase().contains(&name))
+					.collect::<Vec<_>>();
+				if matches.len() > 1 {
+					// Prefer an exact match
+					let mut exact = matches.iter().filter(|c| c.name.to_lowercase() == name);
+					if let (Some(c), None) = (exact.next(), exact.next()) {
+						return Ok(c.id);
+					}
+					let mut ids = matches.iter().map(|c| c.id).collect::<Vec<_>>();
+					ids.sort_by_key(|c| c.0);
+					return Err(Error::AmbiguousChannel(name, ids));
+				}
+				matches.pop().map(|c| c.id).ok_or_else(|| Error::NotFound("Channel", name))
+			}
+		}
+	}
+
+	/// The sub-channels of a channel in the order in which they are
+	/// displayed.
+	///
+	/// Use `ChannelId(0)` to get the top-level channels. The `order` of a
+	/// channel is the id of the channel above it, channels after a broken
+	/// link, e.g. to a deleted channel, are appended at the end.
+	pub fn channels_sorted(&self, parent: ChannelId) -> Vec<&Channel> {
+		let mut children =
+			self.channels.values().filter(|c| c.parent == parent).collect::<Vec<_>>();
+		// Sort to get a stable order for broken chains
+		children.sort_by_key(|c| c.id.0);
+		let mut res = Vec::with_capacity(children.len());
+		let mut prev = ChannelId(0);
+		while !children.is_empty() {
+			let i = children
+				.iter()
+				.position(|c| c.order == prev)
+				// Start a new chain with a channel whose predecessor is gone
+				.or_else(|| {
+					children.iter().position(|c| !children.iter().any(|o| o.id == c.order))
+				})
+				// Only cycles are left
+				.unwrap_or(0);
+			let channel = children.remove(i);
+			prev = channel.id;
+			res.push(channel);
+		}
+		res
+	}
+
+	/// All channels as a tree in display order.
+	///
+	/// Channels whose parent does not exist are appended at the end of the
+	/// top-level channels.
+	pub fn channel_tree(&self) -> Vec<ChannelNode> {
+		let mut visited = HashSet::new();
+		let mut res = self.channel_nodes(ChannelId(0), &mut visited);
+		let mut rest = self
+			.channels
+			.values()
+			.filter(|c| !visited.contains(&c.id))
+			.map(|c| (self.channels.contains_key(&c.parent), c.id))
+			.collect::<Vec<_>>();
+		// Orphans first, then channels in a cycle of parents
+		rest.sort_by_key(|(has_parent, id)| (*has_parent, id.0));
+		for (_, id) in rest {
+			if visited.insert(id) {
+				res.push(ChannelNode { id, children: self.channel_nodes(id, &mut visited) });
+			}
+		}
+		res
+	}
+
+	fn channel_nodes(
+		&self, parent: ChannelId, visited: &mut HashSet<ChannelId>,
+	) -> Vec<ChannelNode> {
+		let mut res = Vec::new();
+		for channel in self.channels_sorted(parent) {
+			if visited.insert(channel.id) {
+				let children = self.channel_nodes(channel.id, visited);
+				res.push(ChannelNode { id: channel.id, children });
+			}
+		}
+		res
+	}
+
+	/// Find a channel by the names of the channel and all its parents,
+	/// separated by `/`, e.g. `/Lobby/Games`.
+	///
+	/// A `/` inside a channel name is escaped as `\/` and a backslash as `\\`,
+	/// e.g. `Music/AC\/DC`.
+	///
+	/// If a parent contains more than one channel with the same name, the
+	/// first in display order is used.
+	pub fn find_channel_by_path(&self, path: &str) -> Option<&Channel> {
+		let mut res = None;
+		let mut parent = ChannelId(0);
+		for name in split_channel_path(path).iter().filter(|n| !n.is_empty()) {
+			let channel = self.channels_sorted(parent).into_iter().find(|c| &c.name == name)?;
+			parent = channel.id;
+			res = Some(channel);
+		}
+		res
+	}
+
+	/// Find a channel by its name, ignoring case.
+	///
+	/// If more than one channel has this name, the first in display order is
+	/// returned. Use [`find_channel`] with [`ChannelSpec::Name`] to search for
+	/// a part of the name.
+	///
+	/// [`find_channel`]: #method.find_channel
+	/// [`ChannelSpec::Name`]: ../enum.ChannelSpec.html#variant.Name
+	pub fn find_channel_by_name(&self, name: &str) -> Option<&Channel> {
+		let name = name.to_lowercase();
+		let mut stack = self.channel_tree();
+		stack.reverse();
+		while let Some(node) = stack.pop() {
+			let channel = &self.channels[&node.id];
+			if channel.name.to_lowercase() == name {
+				return Some(channel);
+			}
+			stack.extend(node.children.into_iter().rev());
+		}
+		None
+	}
+
+	/// The visible clients in a channel.
+	pub fn channel_clients(&self, channel: ChannelId) -> impl Iterator<Item = &Client> {
+		self.channel_index
+			.get(&channel)
+			.into_iter()
+			.flatten()
+			.filter_map(move |id| self.clients.get(id))
+	}
+
+	/// Find a visible client by its unique identifier.
+	pub fn find_client_by_uid(&self, uid: UidRef) -> Option<&Client> {
+		self.clients.values().find(|c| c.uid.as_ref().map(|u| u.0.as_slice()) == Some(uid.0))
+	}
+
+	/// Find all visible clients whose nickname contains `name`, ignoring case.
+	///
+	/// The clients are sorted by their id.
+	pub fn find_clients_by_name(&self, name: &str) -> Vec<&Client> {
+		let name = name.to_lowercase();
+		let mut res = self
+			.clients
+			.values()
+			.filter(|c| c.name.to_lowercase().contains(&name))
+			.collect::<Vec<_>>();
+		res.sort_by_key(|c| c.id.0);
+		res
+	}
+
+	/// If all channels are subscribed, so every client on the server is
+	/// visible.
+	///
+	/// Otherwise, clients in other channels can only be found by asking the
+	/// server.
+	pub fn is_fully_subscribed(&self) -> bool { self.channels.values().all(|c| c.subscribed) }
+
+	/// Find a server group by its name, ignoring case.
+	pub fn find_server_group_by_name(&self, name: &str) -> Option<&ServerGroup> {
+		let name = name.to_lowercase();
+		self.server_groups.values().find(|g| g.name.to_lowercase() == name)
+	}
+
+	/// The known server groups of a client.
+	///
+	/// Groups which are not in the server group list are skipped.
+	pub fn client_server_groups(&self, client: ClientId) -> impl Iterator<Item = &ServerGroup> {
+		self.clients
+			.get(&client)
+			.into_iter()
+			.flat_map(|c| c.server_groups.iter())
+			.filter_map(move |g| self.server_groups.get(g))
+	}
+
+	/// If a visible client is a member of this server group.
+	pub fn client_in_server_group(&self, client: ClientId, group: ServerGroupId) -> bool {
+		self.clients.get(&client).map(|c| c.server_groups.contains(&group)).unwrap_or_default()
+	}
+
+	/// A copy of the current state, which is not affected by later changes.
+	///
+	/// The clients, channels and groups are shared with this connection until
+	/// one of them is modified, so taking a snapshot is cheap even for big
+	/// servers.
+	pub fn snapshot(&self) -> Self { self.clone() }
+
+	/// Send a message to the plugins of other clients.
+	///
+	/// The `targets` are only used if the `mode` is `PluginTargetMode::Client`.
+	pub fn send_plugin_message(
+		&self, name: &str, data: &str, mode: PluginTargetMode, targets: &[ClientId],
+	) -> OutCommand {
+		let mut cmd =
+			OutCommand::new(Direction::C2S, Flags::empty(), PacketType::Command, "plugincmd");
+		cmd.write_arg("name", &name);
+		cmd.write_arg("data", &data);
+		cmd.write_arg("targetmode", &mode.to_u32().unwrap());
+		if mode == PluginTargetMode::Client {
+			for (i, target) in targets.iter().enumerate() {
+				if i != 0 {
+					cmd.start_new_part();
+				}
+				cmd.write_arg("target", &target.0);
+			}
+		}
+		cmd
+	}
+
+	/// Change the badges of our own client.
+	pub fn set_badges(&self, badges: &Badges) -> OutCommand {
+		self.client_update().set_badges(&badges.to_string()).to_packet()
+	}
+
 	pub fn disconnect(&self, options: crate::DisconnectOptions) -> OutCommand {
 		c2s::OutDisconnectMessage::new(&mut iter::once(c2s::OutDisconnectPart {
 			reason: options.reason,
@@ -976,6 +1712,16 @@ impl Client {
 		}))
 	}
 
+	/// Request the connection statistics of this client.
+	///
+	/// The answer is stored in the `connection_data` of the client.
+	pub fn request_connection_info(&self) -> OutCommand {
+		let mut cmd =
+			OutCommand::new(Direction::C2S, Flags::empty(), PacketType::Command, "getconnectioninfo");
+		cmd.write_arg("clid", &self.id.0);
+		cmd
+	}
+
 	pub fn poke(&self, message: &str) -> OutCommand {
 		c2s::OutClientPokeRequestMessage::new(&mut iter::once(c2s::OutClientPokeRequestPart {
 			client_id: self.id,
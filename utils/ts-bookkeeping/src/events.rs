@@ -34,12 +34,14 @@ pub enum Event {
 	PropertyAdded { id: PropertyId, invoker: Option<Invoker>, extra: ExtraInfo },
 	/// The attribute with this id has changed.
 	///
-	/// The second tuple item holds the old value of the changed attribute.
+	/// `old` holds the value before the change and `new` the current value of
+	/// the changed attribute.
 	///
 	/// E.g. a client changes its nickname or switches to another channel.
 	PropertyChanged {
 		id: PropertyId,
 		old: PropertyValue,
+		new: PropertyValue,
 		invoker: Option<Invoker>,
 		extra: ExtraInfo,
 	},
@@ -72,6 +74,54 @@ pub enum Event {
 	},
 }
 
+/// An [`Event`], classified by what happened.
+///
+/// The generic property events are turned into e.g. joining, leaving and
+/// moving clients. Changed properties contain the old and the new value.
+///
+/// Use [`Event::to_typed`] to convert an event.
+///
+/// [`Event`]: enum.Event.html
+/// [`Event::to_typed`]: enum.Event.html#method.to_typed
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[non_exhaustive]
+pub enum TypedEvent {
+	/// A client joined the server or became visible.
+	ClientAdded { client: ClientId, reason: Option<Reason>, invoker: Option<Invoker> },
+	/// A client left the server or is not visible anymore, e.g. because it was
+	/// kicked or the channel was unsubscribed.
+	ClientRemoved { client: Client, reason: Option<Reason>, invoker: Option<Invoker> },
+	/// A client switched channels.
+	ClientMoved {
+		client: ClientId,
+		from: ChannelId,
+		to: ChannelId,
+		reason: Option<Reason>,
+		invoker: Option<Invoker>,
+	},
+	ChannelAdded { channel: ChannelId, invoker: Option<Invoker> },
+	ChannelRemoved { channel: Channel, invoker: Option<Invoker> },
+	/// Another object was added, e.g. a server group.
+	PropertyAdded { property: PropertyId, invoker: Option<Invoker> },
+	/// Another object was removed.
+	PropertyRemoved { property: PropertyId, old: PropertyValue, invoker: Option<Invoker> },
+	PropertyChanged {
+		property: PropertyId,
+		old: PropertyValue,
+		new: PropertyValue,
+		invoker: Option<Invoker>,
+	},
+	ChannelListFinished,
+	/// The url, image or refresh interval of the host banner changed, so the
+	/// banner image should be downloaded again.
+	///
+	/// This is sent once for every changed property.
+	HostBannerChanged { property: PropertyId, invoker: Option<Invoker> },
+	/// A text message in the server or channel chat or a private message.
+	MessageReceived { target: MessageTarget, invoker: Invoker, message: String },
+	Poked { invoker: Invoker, message: String },
+}
+
 impl Event {
 	pub fn get_invoker(&self) -> Option<&Invoker> {
 		match self {
@@ -82,4 +132,75 @@ impl Event {
 			Event::Message { invoker, .. } => Some(invoker),
 		}
 	}
+
+	/// Classify this event.
+	pub fn to_typed(&self) -> TypedEvent {
+		match self {
+			Event::PropertyAdded { id, invoker, extra } => match id {
+				PropertyId::Client(client) => TypedEvent::ClientAdded {
+					client: *client,
+					reason: extra.reason,
+					invoker: invoker.clone(),
+				},
+				PropertyId::Channel(channel) => {
+					TypedEvent::ChannelAdded { channel: *channel, invoker: invoker.clone() }
+				}
+				_ => TypedEvent::PropertyAdded { property: id.clone(), invoker: invoker.clone() },
+			},
+			Event::PropertyRemoved { id, old, invoker, extra } => match old {
+				PropertyValue::Client(client) => TypedEvent::ClientRemoved {
+					client: client.clone(),
+					reason: extra.reason,
+					invoker: invoker.clone(),
+				},
+				PropertyValue::Channel(channel) => TypedEvent::ChannelRemoved {
+					channel: channel.clone(),
+					invoker: invoker.clone(),
+				},
+				_ => TypedEvent::PropertyRemoved {
+					property: id.clone(),
+					old: old.clone(),
+					invoker: invoker.clone(),
+				},
+			},
+			Event::PropertyChanged { id, old, new, invoker, extra } => match (id, old, new) {
+				(
+					PropertyId::ClientChannel(client),
+					PropertyValue::ChannelId(from),
+					PropertyValue::ChannelId(to),
+				) => TypedEvent::ClientMoved {
+					client: *client,
+					from: *from,
+					to: *to,
+					reason: extra.reason,
+					invoker: invoker.clone(),
+				},
+				_ if matches!(
+					id,
+					PropertyId::ServerHostbannerUrl
+						| PropertyId::ServerHostbannerGfxUrl
+						| PropertyId::ServerHostbannerGfxInterval
+						| PropertyId::ServerHostbannerMode
+				) =>
+				{
+					TypedEvent::HostBannerChanged { property: id.clone(), invoker: invoker.clone() }
+				}
+				_ => TypedEvent::PropertyChanged {
+					property: id.clone(),
+					old: old.clone(),
+					new: new.clone(),
+					invoker: invoker.clone(),
+				},
+			},
+			Event::ChannelListFinished => TypedEvent::ChannelListFinished,
+			Event::Message { target: MessageTarget::Poke(_), invoker, message } => {
+				TypedEvent::Poked { invoker: invoker.clone(), message: message.clone() }
+			}
+			Event::Message { target, invoker, message } => TypedEvent::MessageReceived {
+				target: *target,
+				invoker: invoker.clone(),
+				message: message.clone(),
+			},
+		}
+	}
 }
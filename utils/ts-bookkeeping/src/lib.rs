@@ -3,22 +3,36 @@ use std::net::{IpAddr, SocketAddr};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use url::Url;
 
+pub mod countries;
 pub mod data;
 pub mod events;
 pub mod messages;
+mod serde_time;
+
+#[cfg(test)]
+mod tests;
 
 // Reexports
 pub use tsproto_types::errors::Error as TsError;
 pub use tsproto_types::versions::Version;
 pub use tsproto_types::{
-	ChannelGroupId, ChannelId, ChannelPermissionHint, ChannelType, ClientDbId, ClientId,
+	Badges, ChannelGroupId, ChannelId, ChannelPermissionHint, ChannelType, ClientDbId, ClientId,
 	ClientPermissionHint, ClientType, Codec, CodecEncryptionMode, GroupNamingMode, GroupType,
 	HostBannerMode, HostMessageMode, IconHash, Invoker, InvokerRef, LicenseType, LogLevel,
 	MaxClients, Permission, PermissionType, PluginTargetMode, Reason, ServerGroupId,
 	TalkPowerRequest, TextMessageTargetMode, TokenType, Uid, UidRef,
 };
 
+/// The version of this crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The commit of the [tsdeclarations](https://github.com/ReSpeak/tsdeclarations)
+/// which were used to generate the messages and the bookkeeping.
+///
+/// This is `unknown` if the declarations are not a git repository.
+pub const DECLARATIONS_VERSION: &str = env!("DECLARATIONS_VERSION");
+
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Error, Debug)]
@@ -30,6 +44,8 @@ pub enum Error {
 	UnknownTextMessageTargetMode,
 	#[error("{0} {1} not found")]
 	NotFound(&'static str, String),
+	#[error("{0:?} matches more than one channel: {1:?}")]
+	AmbiguousChannel(String, Vec<ChannelId>),
 	#[error("{0} should be removed but does not exist")]
 	RemoveNotFound(&'static str),
 	#[error("Value is None")]
@@ -41,7 +57,10 @@ pub enum Error {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum ServerAddress {
 	SocketAddr(SocketAddr),
+	/// A hostname, nickname or `ts3server://` invite link.
 	Other(String),
+	/// A list of servers, they are tried one after another.
+	Multiple(Vec<ServerAddress>),
 }
 
 impl From<SocketAddr> for ServerAddress {
@@ -56,11 +75,76 @@ impl<'a> From<&'a str> for ServerAddress {
 	fn from(addr: &'a str) -> Self { ServerAddress::Other(addr.to_string()) }
 }
 
+impl From<Vec<ServerAddress>> for ServerAddress {
+	fn from(addrs: Vec<ServerAddress>) -> Self { ServerAddress::Multiple(addrs) }
+}
+
+impl ServerAddress {
+	/// Connect to this port, even if the address contains a different port or
+	/// resolving the address finds a different port.
+	///
+	/// For hostnames, the port is appended as `host:port`, replacing an
+	/// existing port. For `ts3server://` links, the `port` parameter is set,
+	/// replacing existing `port` parameters.
+	pub fn with_port(self, port: u16) -> Self {
+		match self {
+			ServerAddress::SocketAddr(mut a) => {
+				a.set_port(port);
+				ServerAddress::SocketAddr(a)
+			}
+			ServerAddress::Other(a) if a.starts_with("ts3server://") => {
+				let mut url = match Url::parse(&a) {
+					Ok(url) => url,
+					// Invalid links fail when they are resolved
+					Err(_) => return ServerAddress::Other(a),
+				};
+				let params = url
+					.query_pairs()
+					.filter(|(key, _)| key != "port")
+					.map(|(key, value)| (key.into_owned(), value.into_owned()))
+					.collect::<Vec<_>>();
+				url.query_pairs_mut()
+					.clear()
+					.extend_pairs(params)
+					.append_pair("port", &port.to_string());
+				ServerAddress::Other(url.to_string())
+			}
+			ServerAddress::Other(a) => {
+				let host = match (a.find(':'), a.rfind(':'), a.rfind(']')) {
+					// [ipv6]:port
+					(_, Some(colon), Some(bracket)) if bracket < colon => &a[..colon],
+					// [ipv6]
+					(_, _, Some(_)) => &a,
+					// host:port
+					(Some(first), Some(last), None) if first == last => &a[..first],
+					// ipv6
+					(Some(_), _, None) => return ServerAddress::Other(format!("[{}]:{}", a, port)),
+					// host
+					_ => &a,
+				};
+				ServerAddress::Other(format!("{}:{}", host, port))
+			}
+			ServerAddress::Multiple(addrs) => {
+				ServerAddress::Multiple(addrs.into_iter().map(|a| a.with_port(port)).collect())
+			}
+		}
+	}
+}
+
 impl fmt::Display for ServerAddress {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
 			ServerAddress::SocketAddr(a) => fmt::Display::fmt(a, f),
 			ServerAddress::Other(a) => fmt::Display::fmt(a, f),
+			ServerAddress::Multiple(addrs) => {
+				for (i, a) in addrs.iter().enumerate() {
+					if i != 0 {
+						write!(f, ", ")?;
+					}
+					fmt::Display::fmt(a, f)?;
+				}
+				Ok(())
+			}
 		}
 	}
 }
@@ -74,6 +158,26 @@ pub enum MessageTarget {
 	Poke(ClientId),
 }
 
+/// Specifies a channel of a server.
+///
+/// The channel can be looked up with [`data::Connection::find_channel`].
+///
+/// [`data::Connection::find_channel`]: data/struct.Connection.html#method.find_channel
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChannelSpec<'a> {
+	Id(ChannelId),
+	/// The names of the channel and all its parents, separated by `/`, e.g.
+	/// `Lobby/Games`.
+	///
+	/// A `/` inside a name is escaped as `\/`, e.g. `Music/AC\/DC`.
+	Path(&'a str),
+	/// A part of the channel name, ignoring case.
+	///
+	/// This has to match a single channel, except if the name matches one
+	/// channel exactly.
+	Name(&'a str),
+}
+
 /// The configuration to create a new connection.
 #[derive(Deserialize, Serialize)]
 pub struct ConnectOptions {
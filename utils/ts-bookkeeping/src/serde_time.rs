@@ -0,0 +1,56 @@
+//! Stable serde representations for the time types of the bookkeeping.
+//!
+//! Points in time are stored as unix timestamps in seconds and durations as
+//! milliseconds, instead of the internal representation of the `time` crate.
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use time::{Duration, OffsetDateTime};
+
+pub(crate) mod timestamp {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(t: &OffsetDateTime, s: S) -> Result<S::Ok, S::Error> {
+		t.timestamp().serialize(s)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<OffsetDateTime, D::Error> {
+		Ok(OffsetDateTime::from_unix_timestamp(i64::deserialize(d)?))
+	}
+}
+
+pub(crate) mod option_timestamp {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(t: &Option<OffsetDateTime>, s: S) -> Result<S::Ok, S::Error> {
+		t.map(|t| t.timestamp()).serialize(s)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(
+		d: D,
+	) -> Result<Option<OffsetDateTime>, D::Error> {
+		Ok(Option::<i64>::deserialize(d)?.map(OffsetDateTime::from_unix_timestamp))
+	}
+}
+
+pub(crate) mod duration {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+		(d.whole_milliseconds() as i64).serialize(s)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+		Ok(Duration::milliseconds(i64::deserialize(d)?))
+	}
+}
+
+pub(crate) mod option_duration {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+		d.map(|d| d.whole_milliseconds() as i64).serialize(s)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+		Ok(Option::<i64>::deserialize(d)?.map(Duration::milliseconds))
+	}
+}
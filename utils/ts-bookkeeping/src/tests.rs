@@ -0,0 +1,240 @@
+use std::sync::Mutex;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use slog::{o, Drain, Logger};
+use tsproto_packets::packets::{Direction, Flags, OutPacket, PacketType};
+use tsproto_types::crypto::EccKeyPrivP256;
+
+use crate::data::{self, ChannelNode};
+use crate::events::{Event, PropertyId, PropertyValue, PropertyValueRef};
+use crate::messages::s2c::InMessage;
+use crate::{ChannelId, ChannelSpec, ClientId, Error};
+
+const INIT_SERVER: &str = r#"initserver virtualserver_name=TeamSpeak\s]I[\sServer virtualserver_welcomemessage=Welcome\sto\sTeamSpeak,\scheck\s[URL]www.teamspeak.com[\/URL]\sfor\slatest\sinformation virtualserver_platform=Linux virtualserver_version=3.11.0\s[Build:\s1578903157] virtualserver_maxclients=32 virtualserver_created=1571572631 virtualserver_codec_encryption_mode=2 virtualserver_hostmessage virtualserver_hostmessage_mode=0 virtualserver_default_server_group=8 virtualserver_default_channel_group=8 virtualserver_hostbanner_url virtualserver_hostbanner_gfx_url virtualserver_hostbanner_gfx_interval=0 virtualserver_priority_speaker_dimm_modificator=-18.0000 virtualserver_id=1 virtualserver_hostbutton_tooltip virtualserver_hostbutton_url virtualserver_hostbutton_gfx_url virtualserver_name_phonetic virtualserver_ip=0.0.0.0,\s:: virtualserver_ask_for_privilegekey=0 virtualserver_hostbanner_mode=0 virtualserver_channel_temp_delete_delay_default=0 virtualserver_nickname client_nickname=TeamSpeakUser client_version=3.?.?\s[Build:\s5680278000] client_platform=Windows client_input_muted=0 client_output_muted=0 client_outputonly_muted=0 client_input_hardware=1 client_output_hardware=1 client_default_channel client_default_channel_password client_server_password client_meta_data client_version_sign=DX5NIYLvfJEUjuIbCidnoeozxIDRRkpq3I9vVMBmE9L2qnekOoBzSenkzsg2lC9CMv8K5hkEzhr2TYUYSwUXCg== client_security_hash client_key_offset=354 client_away=0 client_away_message client_nickname_phonetic client_default_token client_badges client_myteamspeak_id client_integrations client_active_integrations_info client_myteamspeak_avatar client_signed_badges acn=TeamSpeakUser aclid=2 pv=7 client_talk_power=75 client_needed_serverquery_view_power=75 virtualserver_icon_id=0"#;
+
+fn get_logger() -> Logger {
+	let decorator = slog_term::PlainDecorator::new(slog_term::TestStdoutWriter);
+	let drain = Mutex::new(slog_term::FullFormat::new(decorator).build()).fuse();
+
+	Logger::root(drain, o!())
+}
+
+fn parse_msg(logger: &Logger, msg: &str) -> InMessage {
+	let header = OutPacket::new_with_dir(Direction::S2C, Flags::empty(), PacketType::Command);
+	InMessage::new(logger, &header.header(), msg.as_bytes()).unwrap()
+}
+
+/// Create a bookkeeping connection and apply the notifications to it.
+fn connection_with(notifications: &[String]) -> data::Connection {
+	let logger = get_logger();
+	let key = EccKeyPrivP256::create().unwrap().to_pub();
+	let mut con = match parse_msg(&logger, INIT_SERVER) {
+		InMessage::InitServer(msg) => data::Connection::new(key, &msg),
+		_ => panic!("Failed to parse as initserver"),
+	};
+	for notification in notifications {
+		handle(&mut con, notification);
+	}
+	con
+}
+
+/// Apply a notification to the bookkeeping.
+fn handle(con: &mut data::Connection, notification: &str) -> Vec<Event> {
+	let logger = get_logger();
+	con.handle_command(&logger, &parse_msg(&logger, notification)).unwrap()
+}
+
+fn channel_list(id: u64, parent: u64, name: &str) -> String {
+	format!(
+		r#"channellist cid={} cpid={} channel_name={} channel_topic channel_codec=4 channel_codec_quality=6 channel_maxclients=-1 channel_maxfamilyclients=-1 channel_order=0 channel_flag_permanent=1 channel_flag_semi_permanent=0 channel_flag_default=0 channel_flag_password=0 channel_codec_latency_factor=1 channel_codec_is_unencrypted=1 channel_delete_delay=0 channel_flag_maxclients_unlimited=1 channel_flag_maxfamilyclients_unlimited=0 channel_flag_maxfamilyclients_inherited=1 channel_needed_talk_power=0 channel_name_phonetic channel_icon_id=0 channel_banner_gfx_url channel_banner_mode=0"#,
+		id, parent, name
+	)
+}
+
+fn client_enterview(id: u16, name: &str) -> String {
+	format!(
+		r#"notifycliententerview cfid=0 ctid=1 reasonid=0 clid={} client_unique_identifier=Client{}= client_nickname={} client_input_muted=0 client_output_muted=0 client_outputonly_muted=0 client_input_hardware=1 client_output_hardware=1 client_meta_data client_is_recording=0 client_database_id={} client_channel_group_id=8 client_servergroups=8 client_away=0 client_away_message client_type=0 client_flag_avatar client_talk_power=75 client_talk_request=0 client_talk_request_msg client_description client_is_talker=0 client_is_priority_speaker=0 client_unread_messages=0 client_nickname_phonetic client_needed_serverquery_view_power=75 client_icon_id=0 client_is_channel_commander=0 client_country client_channel_group_inherited_channel_id=1 client_badges client_myteamspeak_id client_integrations client_myteamspeak_avatar client_signed_badges"#,
+		id, id, name, id
+	)
+}
+
+#[test]
+fn snapshot_isolation() {
+	let mut con = connection_with(&[client_enterview(3, "Other")]);
+	let snapshot = con.snapshot();
+
+	handle(&mut con, "notifyclientupdated clid=3 client_nickname=Renamed");
+	handle(&mut con, &client_enterview(4, "New"));
+
+	assert_eq!(con.clients[&ClientId(3)].name, "Renamed");
+	assert_eq!(snapshot.clients[&ClientId(3)].name, "Other");
+	assert_eq!(con.clients.len(), 2);
+	assert_eq!(snapshot.clients.len(), 1);
+	// Unchanged maps are still shared with the snapshot
+	assert!(con.channels.ptr_eq(&snapshot.channels));
+	assert!(con.server_groups.ptr_eq(&snapshot.server_groups));
+	assert!(!con.clients.ptr_eq(&snapshot.clients));
+}
+
+#[test]
+fn serialize_snapshot() {
+	let con = connection_with(&[channel_list(1, 0, "Lobby"), client_enterview(3, "Other")]);
+
+	let json = serde_json::to_string(&con).unwrap();
+	let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+	assert_eq!(value["server"]["created"], 1571572631);
+	assert_eq!(value["channels"]["1"]["name"], "Lobby");
+	assert_eq!(value["channels"]["1"]["max_clients"], "Unlimited");
+	assert_eq!(value["channels"]["1"]["max_family_clients"], "Inherited");
+	assert_eq!(value["server"]["temp_channel_default_delete_delay"], 0);
+	assert_eq!(value["clients"]["3"]["name"], "Other");
+
+	assert_eq!(serde_json::from_str::<data::Connection>(&json).unwrap(), con);
+}
+
+#[test]
+fn find_channel() {
+	let con = connection_with(&[
+		channel_list(1, 0, "Lobby"),
+		channel_list(2, 0, "Games"),
+		channel_list(3, 2, "Lobby"),
+		channel_list(4, 2, "Minecraft\\sLobby"),
+		channel_list(5, 2, "AC\\/DC"),
+	]);
+
+	assert_eq!(con.find_channel(ChannelSpec::Id(ChannelId(2))).unwrap(), ChannelId(2));
+	assert_eq!(con.find_channel(ChannelSpec::Path("Games/Lobby")).unwrap(), ChannelId(3));
+	assert_eq!(con.find_channel(ChannelSpec::Path("/Lobby")).unwrap(), ChannelId(1));
+	assert_eq!(con.find_channel(ChannelSpec::Path(r"Games/AC\/DC")).unwrap(), ChannelId(5));
+	assert_eq!(con.find_channel(ChannelSpec::Name("minec")).unwrap(), ChannelId(4));
+	assert_eq!(con.find_channel(ChannelSpec::Name("GAMES")).unwrap(), ChannelId(2));
+
+	match con.find_channel(ChannelSpec::Name("lobby")) {
+		Err(Error::AmbiguousChannel(_, ids)) => {
+			assert_eq!(ids, vec![ChannelId(1), ChannelId(3), ChannelId(4)]);
+		}
+		r => panic!("Expected ambiguous channel, got {:?}", r),
+	}
+	assert!(matches!(con.find_channel(ChannelSpec::Id(ChannelId(9))), Err(Error::NotFound(..))));
+	assert!(matches!(con.find_channel(ChannelSpec::Path("Lobby/Games")), Err(Error::NotFound(..))));
+	assert!(matches!(con.find_channel(ChannelSpec::Path("Games/AC/DC")), Err(Error::NotFound(..))));
+	assert!(matches!(con.find_channel(ChannelSpec::Name("Music")), Err(Error::NotFound(..))));
+}
+
+#[test]
+fn channel_tree() {
+	// (id, parent, order)
+	let channels = [(1, 0, 0), (2, 0, 1), (3, 0, 2), (4, 2, 0), (5, 2, 4), (6, 2, 99), (7, 42, 0)];
+	let mut rng = StdRng::seed_from_u64(0);
+	for _ in 0..10 {
+		let mut shuffled = channels.to_vec();
+		shuffled.shuffle(&mut rng);
+		let list = shuffled.iter().map(|(id, _, _)| channel_list(*id, 0, "Channel"));
+		let mut con = connection_with(&list.collect::<Vec<_>>());
+		// Set the parent and order afterwards, so they are not adjusted
+		for (id, parent, order) in shuffled {
+			let channel = con.channels.get_mut(&ChannelId(id)).unwrap();
+			channel.parent = ChannelId(parent);
+			channel.order = ChannelId(order);
+		}
+
+		let sorted = |parent| {
+			con.channels_sorted(ChannelId(parent)).iter().map(|c| c.id.0).collect::<Vec<_>>()
+		};
+		assert_eq!(sorted(0), [1, 2, 3]);
+		assert_eq!(sorted(2), [4, 5, 6]);
+		assert!(sorted(1).is_empty());
+
+		let leaf = |id| ChannelNode { id: ChannelId(id), children: Vec::new() };
+		assert_eq!(con.channel_tree(), vec![
+			leaf(1),
+			ChannelNode { id: ChannelId(2), children: vec![leaf(4), leaf(5), leaf(6)] },
+			leaf(3),
+			leaf(7),
+		]);
+	}
+}
+
+#[test]
+fn channel_clients_index() {
+	let channels = (1..=4).map(|id| channel_list(id, 0, "Channel")).collect::<Vec<_>>();
+	let mut con = connection_with(&channels);
+	let mut rng = StdRng::seed_from_u64(0);
+	for _ in 0..500 {
+		let client = rng.gen_range(3, 10);
+		let channel = rng.gen_range(1, 5);
+		let known = con.clients.contains_key(&ClientId(client));
+		let msg = match rng.gen_range(0, 4) {
+			_ if !known => client_enterview(client, "Client"),
+			0 => format!("notifyclientleftview cfid=1 ctid=0 reasonid=8 clid={}", client),
+			_ => format!("notifyclientmoved ctid={} reasonid=0 clid={}", channel, client),
+		};
+		handle(&mut con, &msg);
+
+		for channel in 0..=5 {
+			let channel = ChannelId(channel);
+			let mut indexed = con.channel_clients(channel).map(|c| c.id.0).collect::<Vec<_>>();
+			let mut scanned = con
+				.clients
+				.values()
+				.filter(|c| c.channel == channel)
+				.map(|c| c.id.0)
+				.collect::<Vec<_>>();
+			indexed.sort_unstable();
+			scanned.sort_unstable();
+			assert_eq!(indexed, scanned, "Clients in channel {:?} after {}", channel, msg);
+		}
+	}
+}
+
+#[test]
+fn client_badges() {
+	let mut con = connection_with(&[client_enterview(3, "Other")]);
+	assert!(con.clients[&ClientId(3)].badges().is_empty());
+
+	let update = r#"notifyclientupdated clid=3 client_badges=Overwolf=1:badges=c9e97536-5a2d-4c8e-a135-af404587a472"#;
+	let events = handle(&mut con, update);
+	let badges = con.clients[&ClientId(3)].badges();
+	assert!(badges.overwolf);
+	assert_eq!(badges.badge_guids, ["c9e97536-5a2d-4c8e-a135-af404587a472"]);
+	match &events[..] {
+		[Event::PropertyChanged { id: PropertyId::ClientBadges(ClientId(3)), old, new, .. }] => {
+			assert_eq!(*old, PropertyValue::Badges(Default::default()));
+			assert_eq!(*new, PropertyValue::Badges(badges.clone()));
+		}
+		e => panic!("Expected changed badges, got {:?}", e),
+	}
+	assert_eq!(
+		con.get_property(&PropertyId::ClientBadges(ClientId(3))).unwrap(),
+		PropertyValueRef::Badges(badges)
+	);
+	// The same badges again do not change anything
+	assert!(handle(&mut con, update).is_empty());
+
+	assert_eq!(
+		con.set_badges(badges).0.content(),
+		b"clientupdate client_badges=Overwolf=1:badges=c9e97536-5a2d-4c8e-a135-af404587a472"
+			.as_ref()
+	);
+}
+
+#[test]
+fn client_country() {
+	let mut con = connection_with(&[client_enterview(3, "Other")]);
+	assert_eq!(con.clients[&ClientId(3)].country(), None);
+
+	let events = handle(&mut con, "notifyclientupdated clid=3 client_country=DE");
+	assert!(events.iter().any(|e| matches!(e, Event::PropertyChanged {
+		id: PropertyId::ClientCountry(ClientId(3)),
+		..
+	})));
+	let client = &con.clients[&ClientId(3)];
+	assert_eq!(client.country(), Some("DE"));
+	assert_eq!(client.country_name(), Some("Germany"));
+	assert_eq!(
+		con.get_property(&PropertyId::ClientCountry(ClientId(3))).unwrap(),
+		PropertyValueRef::OptionString(Some("DE"))
+	);
+}
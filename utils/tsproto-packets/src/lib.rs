@@ -10,6 +10,8 @@ pub mod packets;
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// The version of this crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const S2C_HEADER_LEN: usize = 11;
 pub const C2S_HEADER_LEN: usize = 13;
 
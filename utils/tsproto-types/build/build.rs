@@ -5,10 +5,12 @@ use std::path::Path;
 
 mod enums;
 mod errors;
+mod permissions;
 mod versions;
 
 use crate::enums::Enums;
 use crate::errors::Errors;
+use crate::permissions::Permissions;
 use crate::versions::Versions;
 
 fn main() {
@@ -24,6 +26,10 @@ fn main() {
 	let mut structs = File::create(&path.join("errors.rs")).unwrap();
 	write!(&mut structs, "{}", Errors::default()).unwrap();
 
+	// Permissions
+	let mut structs = File::create(&path.join("permissions.rs")).unwrap();
+	write!(&mut structs, "{}", Permissions::default()).unwrap();
+
 	// Versions
 	let mut structs = File::create(&path.join("versions.rs")).unwrap();
 	write!(&mut structs, "{}", Versions::default()).unwrap();
@@ -0,0 +1,16 @@
+use std::ops::Deref;
+
+use heck::*;
+use t4rust_derive::Template;
+use tsproto_structs::permissions::*;
+use tsproto_structs::{doc_comment, indent};
+
+#[derive(Template)]
+#[TemplatePath = "build/Permissions.tt"]
+#[derive(Default, Debug)]
+pub struct Permissions;
+
+impl Deref for Permissions {
+	type Target = Vec<EnumValue>;
+	fn deref(&self) -> &Self::Target { &DATA.0 }
+}
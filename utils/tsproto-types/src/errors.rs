@@ -1,9 +1,28 @@
 use std::fmt;
 
 use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::ToPrimitive as _;
 
 include!(concat!(env!("OUT_DIR"), "/errors.rs"));
 
+impl Error {
+	/// The category of an error are the upper bits of its number, e.g.
+	/// `0xa00` for all permission errors.
+	fn category(self) -> u32 { self.to_u32().unwrap() & !0xff }
+
+	/// If the error is about a missing permission, an insufficient power or
+	/// an invalid permission.
+	///
+	/// These are the errors from `0xa00` to `0xaff`.
+	pub fn is_permission_error(&self) -> bool { self.category() == 0xa00 }
+
+	/// If the error is about a ban, e.g. connecting or renaming failed
+	/// because of a ban, or the client was banned for flooding.
+	///
+	/// These are the errors from `0xd00` to `0xdff`.
+	pub fn is_ban(&self) -> bool { self.category() == 0xd00 }
+}
+
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { fmt::Debug::fmt(self, f) }
 }
@@ -1,17 +1,29 @@
 use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
 use std::u64;
 
 use bitflags::bitflags;
 use num_derive::{FromPrimitive, ToPrimitive};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use time::OffsetDateTime;
 
 pub mod crypto;
 pub mod errors;
+pub mod permissions;
 pub mod versions;
 
 include!(concat!(env!("OUT_DIR"), "/enums.rs"));
 
+/// The version of this crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Names which are used instead of a uid, e.g. for the server itself.
+const RESERVED_UIDS: &[&[u8]] = &[b"ServerAdmin", b"serveradmin", b"ServerQuery"];
+/// The length of a uid, which is a sha1 hash.
+const UID_LEN: usize = 20;
+
 /// A `ClientId` identifies a client which is connected to a server.
 ///
 /// Every client that we see on a server has a `ClientId`, even our own
@@ -27,8 +39,11 @@ pub struct Uid(pub Vec<u8>);
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct UidRef<'a>(pub &'a [u8]);
-impl<'a> Into<Uid> for UidRef<'a> {
-	fn into(self) -> Uid { Uid(self.0.into()) }
+impl<'a> From<UidRef<'a>> for Uid {
+	fn from(uid: UidRef<'a>) -> Self { Uid(uid.0.into()) }
+}
+impl<'a> From<&'a Uid> for UidRef<'a> {
+	fn from(uid: &'a Uid) -> Self { uid.as_ref() }
 }
 impl Uid {
 	pub fn as_ref(&self) -> UidRef { UidRef(self.0.as_ref()) }
@@ -40,6 +55,37 @@ impl Uid {
 	pub fn as_avatar(&self) -> String { self.as_ref().as_avatar() }
 
 	pub fn is_server_admin(&self) -> bool { self.as_ref().is_server_admin() }
+
+	/// Compute the uid of a public key.
+	pub fn from_public_key(key: &crypto::EccKeyPubP256) -> Result<Self, crypto::Error> {
+		key.get_uid_no_base64().map(Uid)
+	}
+
+	/// If this is a hash of the right length or a reserved name.
+	pub fn is_valid(&self) -> bool { self.as_ref().is_valid() }
+
+	/// If this is a reserved name like `ServerAdmin` instead of a hash.
+	pub fn is_reserved(&self) -> bool { self.as_ref().is_reserved() }
+
+	/// The base64 encoded uid without the trailing `=`.
+	pub fn to_short(&self) -> String { self.as_ref().to_short() }
+
+	/// If the string is this uid, with or without the trailing `=`.
+	pub fn matches_str(&self, s: &str) -> bool { self.as_ref().matches_str(s) }
+
+	/// If the string looks like a uid.
+	///
+	/// Accepts base64 encoded hashes, with or without padding, and reserved
+	/// names.
+	pub fn is_valid_str(s: &str) -> bool {
+		if RESERVED_UIDS.contains(&s.as_bytes()) {
+			return true;
+		}
+		let short = s.strip_suffix('=').unwrap_or(s);
+		short.len() == 27
+			&& short.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+			&& base64::decode(short).map(|u| u.len() == UID_LEN).unwrap_or(false)
+	}
 }
 
 impl UidRef<'_> {
@@ -57,6 +103,26 @@ impl UidRef<'_> {
 	}
 
 	pub fn is_server_admin(&self) -> bool { self.0 == b"ServerAdmin" }
+
+	pub fn is_valid(&self) -> bool { self.0.len() == UID_LEN || self.is_reserved() }
+
+	pub fn is_reserved(&self) -> bool { RESERVED_UIDS.contains(&self.0) }
+
+	pub fn to_short(&self) -> String {
+		if self.is_reserved() {
+			String::from_utf8_lossy(self.0).into_owned()
+		} else {
+			base64::encode_config(self.0, base64::STANDARD_NO_PAD)
+		}
+	}
+
+	pub fn matches_str(&self, s: &str) -> bool {
+		if self.is_reserved() {
+			s.as_bytes() == self.0
+		} else {
+			s.trim_end_matches('=') == self.to_short()
+		}
+	}
 }
 
 /// The database id of a client.
@@ -99,6 +165,14 @@ pub enum ClientType {
 	},
 }
 
+impl ClientType {
+	/// If this is a server query client.
+	///
+	/// Query clients are usually bots or administration tools and are not
+	/// visible to normal users in the channel tree.
+	pub fn is_query(&self) -> bool { matches!(self, ClientType::Query { .. }) }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum MaxClients {
 	Unlimited,
@@ -112,6 +186,55 @@ pub struct TalkPowerRequest {
 	pub message: String,
 }
 
+/// The badges which a client shows next to its name.
+///
+/// They are sent as `client_badges` in the form
+/// `Overwolf=0:badges=<guid>,<guid>`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct Badges {
+	/// If the client shows the Overwolf badge.
+	pub overwolf: bool,
+	/// The ids of the other badges, in the order in which they are shown.
+	pub badge_guids: Vec<String>,
+}
+
+impl Badges {
+	/// Parse the `client_badges` string of a client.
+	///
+	/// Unknown parts are ignored, an empty string has no badges.
+	pub fn parse(s: &str) -> Self {
+		let mut res = Self::default();
+		for part in s.split(':') {
+			let mut split = part.splitn(2, '=');
+			let key = split.next().unwrap_or_default().trim();
+			let value = split.next().unwrap_or_default().trim();
+			if key.eq_ignore_ascii_case("overwolf") {
+				res.overwolf = value == "1";
+			} else if key.eq_ignore_ascii_case("badges") {
+				res.badge_guids = value
+					.split(',')
+					.filter(|g| !g.is_empty())
+					.map(|g| g.to_string())
+					.collect();
+			}
+		}
+		res
+	}
+
+	#[inline]
+	pub fn is_empty(&self) -> bool { !self.overwolf && self.badge_guids.is_empty() }
+}
+
+impl fmt::Display for Badges {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Overwolf={}", self.overwolf as u8)?;
+		if !self.badge_guids.is_empty() {
+			write!(f, ":badges={}", self.badge_guids.join(","))?;
+		}
+		Ok(())
+	}
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Invoker {
 	pub name: String,
@@ -132,12 +255,26 @@ impl Invoker {
 	}
 }
 
+/// Failed to parse an id from a string.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("Invalid id: {0}")]
+pub struct ParseIdError(#[from] pub ParseIntError);
+
 impl fmt::Display for ClientId {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", self.0) }
 }
 impl fmt::Display for Uid {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.as_ref().fmt(f) }
+}
+/// Reserved names like `ServerAdmin` are written as they are, other uids are
+/// base64 encoded.
+impl fmt::Display for UidRef<'_> {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "{}", base64::encode(&self.0))
+		if self.is_reserved() {
+			write!(f, "{}", String::from_utf8_lossy(self.0))
+		} else {
+			write!(f, "{}", base64::encode(self.0))
+		}
 	}
 }
 impl fmt::Display for ClientDbId {
@@ -152,3 +289,146 @@ impl fmt::Display for ServerGroupId {
 impl fmt::Display for ChannelGroupId {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", self.0) }
 }
+impl fmt::Display for IconHash {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", self.0) }
+}
+/// Writes `Name (id=5, uid=…)`, the uid is left out if it is unknown.
+impl fmt::Display for InvokerRef<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} (id={}", self.name, self.id)?;
+		if let Some(uid) = &self.uid {
+			write!(f, ", uid={}", uid)?;
+		}
+		write!(f, ")")
+	}
+}
+impl fmt::Display for Invoker {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.as_ref().fmt(f) }
+}
+
+macro_rules! id_from_str {
+	($($id:ident),*) => {
+		$(impl FromStr for $id {
+			type Err = ParseIdError;
+			fn from_str(s: &str) -> Result<Self, Self::Err> { Ok($id(s.parse()?)) }
+		})*
+	};
+}
+
+id_from_str!(ClientId, ClientDbId, ChannelId, ServerGroupId, ChannelGroupId);
+
+/// Servers send icon ids as signed or unsigned numbers and sometimes as
+/// 64 bit numbers, which are truncated to 32 bit.
+impl FromStr for IconHash {
+	type Err = ParseIdError;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(IconHash(if s.starts_with('-') {
+			s.parse::<i32>().map(|i| i as u32)?
+		} else {
+			s.parse::<u64>().map(|i| i as u32)?
+		}))
+	}
+}
+
+/// Accepts base64 encoded uids, also without padding, and reserved names like
+/// `ServerAdmin`.
+impl FromStr for Uid {
+	type Err = std::convert::Infallible;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if RESERVED_UIDS.contains(&s.as_bytes()) {
+			return Ok(Uid(s.as_bytes().to_vec()));
+		}
+		Ok(Uid(if let Ok(uid) = base64::decode(s) { uid } else { s.as_bytes().to_vec() }))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_ids() {
+		assert_eq!("5".parse(), Ok(ClientId(5)));
+		assert_eq!(ClientId(5).to_string().parse(), Ok(ClientId(5)));
+		assert_eq!("18446744073709551615".parse(), Ok(ChannelId(u64::MAX)));
+		assert!("65536".parse::<ClientId>().is_err());
+		assert!("-1".parse::<ServerGroupId>().is_err());
+		assert!("".parse::<ChannelGroupId>().is_err());
+	}
+
+	#[test]
+	fn parse_icon_hash() {
+		assert_eq!("96136942".parse(), Ok(IconHash(96136942)));
+		assert_eq!("-96136942".parse(), Ok(IconHash(4198830354)));
+		assert_eq!("18446744073225738240".parse(), Ok(IconHash(3811153920)));
+		assert!("-3000000000".parse::<IconHash>().is_err());
+	}
+
+	#[test]
+	fn uid_round_trip() {
+		for s in &["ZG9lcyBpdCB3b3JrPw==", "ServerAdmin", "ServerQuery"] {
+			let uid: Uid = s.parse().unwrap();
+			assert_eq!(&uid.to_string(), s);
+			assert_eq!(Uid::from(UidRef::from(&uid)), uid);
+		}
+	}
+
+	#[test]
+	fn uid_from_key() {
+		// The uid which is shown by the official client
+		let key = crypto::EccKeyPrivP256::from_ts(
+			"MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+\
+			 u54aeDr0OP1ITsC/50CIA8M5nmDBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI",
+		)
+		.unwrap();
+		let uid = Uid::from_public_key(&key.to_pub()).unwrap();
+		assert!(uid.is_valid());
+		assert!(!uid.is_reserved());
+		assert_eq!(uid.to_string(), "lks7QL5OVMKo4pZ79cEOI5r5oEA=");
+		assert_eq!(uid.to_short(), "lks7QL5OVMKo4pZ79cEOI5r5oEA");
+		assert!(uid.matches_str("lks7QL5OVMKo4pZ79cEOI5r5oEA="));
+		assert!(uid.matches_str("lks7QL5OVMKo4pZ79cEOI5r5oEA"));
+		assert!(!uid.matches_str("lks7QL5OVMKo4pZ79cEOI5r5oEB="));
+		assert_eq!("lks7QL5OVMKo4pZ79cEOI5r5oEA".parse(), Ok(uid));
+	}
+
+	#[test]
+	fn validate_uid_str() {
+		for s in &["lks7QL5OVMKo4pZ79cEOI5r5oEA=", "Qc5R3gbqEUavjC3sj1HuEBGnOzE", "ServerAdmin"] {
+			assert!(Uid::is_valid_str(s), "{}", s);
+		}
+		for s in &["", "lks7QL5OVMKo4pZ79cEOI5r5o=", "lks7QL5OVMKo4pZ79cEOI5r5oE-=", "admin"] {
+			assert!(!Uid::is_valid_str(s), "{}", s);
+		}
+		let admin: Uid = "serveradmin".parse().unwrap();
+		assert!(admin.is_reserved());
+		assert_eq!(admin.to_string(), "serveradmin");
+		let query: Uid = "ServerQuery".parse().unwrap();
+		assert!(query.is_reserved());
+	}
+
+	#[test]
+	fn display_invoker() {
+		let mut invoker = Invoker { name: "Bot".into(), id: ClientId(5), uid: None };
+		assert_eq!(invoker.to_string(), "Bot (id=5)");
+		invoker.uid = Some("ZG9lcyBpdCB3b3JrPw==".parse().unwrap());
+		assert_eq!(invoker.to_string(), "Bot (id=5, uid=ZG9lcyBpdCB3b3JrPw==)");
+	}
+
+	#[test]
+	fn parse_badges() {
+		let badges = Badges::parse(
+			"Overwolf=1:badges=1cb07348-34a4-4741-b50f-c41e584370f7,50bbdbc8-0f2a-46eb-9808-602225b49627",
+		);
+		assert!(badges.overwolf);
+		assert_eq!(badges.badge_guids, [
+			"1cb07348-34a4-4741-b50f-c41e584370f7",
+			"50bbdbc8-0f2a-46eb-9808-602225b49627"
+		]);
+		assert_eq!(Badges::parse(&badges.to_string()), badges);
+
+		assert_eq!(Badges::parse("overwolf=0"), Badges::default());
+		assert!(Badges::parse("").is_empty());
+		assert_eq!(Badges::default().to_string(), "Overwolf=0");
+	}
+}
@@ -0,0 +1,96 @@
+//! Map permission names like `i_channel_needed_join_power` to their ids.
+//!
+//! The names and their order come from the declarations. The numeric id of a
+//! permission is its position in this list, which is the order of a TeamSpeak 3
+//! server. The server sends its list of permissions as answer to
+//! `permissionlist`, which can be used to check the ids of other servers.
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::FromPrimitive as _;
+
+use crate::Permission;
+
+include!(concat!(env!("OUT_DIR"), "/permissions.rs"));
+
+/// The type of the value of a permission.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PermissionValueType {
+	/// Granted with `1`, the name starts with `b_`.
+	Bool,
+	/// A power or another number, the name starts with `i_`.
+	Int,
+}
+
+impl PermissionId {
+	/// The name of this permission, e.g. `i_channel_needed_join_power`.
+	pub fn name(self) -> &'static str { NAMES[self as usize] }
+
+	/// Find a permission by its name.
+	pub fn from_name(name: &str) -> Option<Self> {
+		NAMES.iter().position(|n| *n == name).and_then(Self::from_usize)
+	}
+
+	pub fn value_type(self) -> PermissionValueType {
+		if self.name().starts_with("b_") {
+			PermissionValueType::Bool
+		} else {
+			PermissionValueType::Int
+		}
+	}
+
+	/// The value which grants this permission, if there is a single one.
+	///
+	/// Boolean permissions are granted with `1`, integer permissions have no
+	/// default value.
+	pub fn grant_value(self) -> Option<i32> {
+		match self.value_type() {
+			PermissionValueType::Bool => Some(1),
+			PermissionValueType::Int => None,
+		}
+	}
+
+	/// Get the permission with the numeric id.
+	pub fn from_permission(permission: Permission) -> Option<Self> {
+		Self::from_u32(permission.0)
+	}
+}
+
+impl From<PermissionId> for Permission {
+	fn from(id: PermissionId) -> Self { Permission(id as u32) }
+}
+
+impl Permission {
+	/// Find a permission by its name.
+	pub fn from_name(name: &str) -> Option<Self> { PermissionId::from_name(name).map(Into::into) }
+
+	/// The name of this permission, `None` if the id is unknown.
+	pub fn name(self) -> Option<&'static str> {
+		PermissionId::from_permission(self).map(PermissionId::name)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn name_round_trip() {
+		for (i, name) in NAMES.iter().enumerate() {
+			let id = PermissionId::from_name(name).unwrap();
+			assert_eq!(id as usize, i);
+			assert_eq!(id.name(), *name);
+			assert_eq!(Permission::from(id).name(), Some(*name));
+		}
+		assert_eq!(Permission::from_name("i_does_not_exist"), None);
+		assert_eq!(Permission(u32::MAX).name(), None);
+	}
+
+	#[test]
+	fn value_type() {
+		let join_power = PermissionId::from_name("i_channel_needed_join_power").unwrap();
+		assert_eq!(join_power.value_type(), PermissionValueType::Int);
+		assert_eq!(join_power.grant_value(), None);
+		let create = PermissionId::from_name("b_channel_create_permanent").unwrap();
+		assert_eq!(create.value_type(), PermissionValueType::Bool);
+		assert_eq!(create.grant_value(), Some(1));
+	}
+}
@@ -1,9 +1,63 @@
 use std::fmt;
 
+use thiserror::Error;
+
 include!(concat!(env!("OUT_DIR"), "/versions.rs"));
 
+/// The length of a version signature in bytes.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// The signature of a custom version does not have the right length.
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+#[error("Version signatures are 64 bytes long, got {0} bytes")]
+pub struct InvalidSignatureLength(pub usize);
+
+impl Version {
+	/// A version which is not part of the known versions.
+	///
+	/// Servers check the signature, so it has to be taken from an official
+	/// client with the same platform and version string.
+	pub fn custom(
+		platform: String, version: String, signature: Vec<u8>,
+	) -> Result<Self, InvalidSignatureLength> {
+		let res = Version::Custom { platform, version, signature };
+		res.validate()?;
+		Ok(res)
+	}
+
+	/// Check the length of the signature.
+	///
+	/// Known versions are always valid.
+	pub fn validate(&self) -> Result<(), InvalidSignatureLength> {
+		let len = self.get_signature().len();
+		if len == SIGNATURE_LEN { Ok(()) } else { Err(InvalidSignatureLength(len)) }
+	}
+}
+
 impl fmt::Display for Version {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		write!(f, "{} {}", self.get_platform(), self.get_version_string())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn custom_version() {
+		let version = Version::custom(
+			"Linux".into(),
+			"3.5.3 [Build: 1587971024]".into(),
+			vec![0; SIGNATURE_LEN],
+		)
+		.unwrap();
+		assert_eq!(version.get_platform(), "Linux");
+		assert_eq!(version.get_version_string(), "3.5.3 [Build: 1587971024]");
+		assert_eq!(version.get_signature(), &[0; SIGNATURE_LEN][..]);
+
+		let res = Version::custom("Linux".into(), "3.5.3".into(), vec![0; 63]);
+		assert_eq!(res, Err(InvalidSignatureLength(63)));
+		assert_eq!(Version::Windows_3_X_X__1.validate(), Ok(()));
+	}
+}